@@ -95,8 +95,8 @@ fn example_put() {
 }
 
 fn example_observe() {
-    let mut client = CoAPClient::new("127.0.0.1:5683").unwrap();
-    client.observe("/hello/put", |msg| {
+    let client = CoAPClient::new("127.0.0.1:5683").unwrap();
+    let _observation = client.observe("/hello/put", |msg| {
         println!("resource changed {}", String::from_utf8(msg.payload).unwrap());
     }).unwrap();
 
@@ -0,0 +1,363 @@
+use std::io::{Error, ErrorKind, Result};
+use std::net::ToSocketAddrs;
+use std::time::{Duration, Instant};
+
+use super::cbor::{self, Value};
+use super::client::CoAPClient;
+use super::dtls_client::DTLSCoAPClient;
+use super::message::packet::ContentFormat;
+use super::message::request::{CoAPRequest, Method};
+use super::message::response::Status;
+use super::message::IsMessage;
+use super::oscore::SecurityContext;
+use super::rng::OsRandom;
+
+/// The token endpoint of an authorization server.
+pub const TOKEN_PATH: &str = "token";
+/// The resource tokens are posted to on a resource server.
+pub const AUTHZ_INFO_PATH: &str = "authz-info";
+
+// CBOR abbreviations of the ACE parameters (RFC 9200 §8.10, RFC 9203 §9.3)
+const ACCESS_TOKEN: i64 = 1;
+const EXPIRES_IN: i64 = 2;
+const AUDIENCE: i64 = 5;
+const CNF: i64 = 8;
+const SCOPE: i64 = 9;
+const CLIENT_ID: i64 = 24;
+const GRANT_TYPE: i64 = 33;
+const ACE_PROFILE: i64 = 38;
+const NONCE1: i64 = 40;
+const NONCE2: i64 = 42;
+const ACE_CLIENT_RECIPIENTID: i64 = 43;
+const ACE_SERVER_RECIPIENTID: i64 = 44;
+
+const GRANT_CLIENT_CREDENTIALS: i64 = 2;
+const PROFILE_COAP_DTLS: i64 = 1;
+const PROFILE_COAP_OSCORE: i64 = 2;
+
+const CNF_COSE_KEY: i64 = 1;
+const CNF_OSCORE: i64 = 4;
+const COSE_KEY_KID: i64 = 2;
+const COSE_KEY_K: i64 = -1;
+const OSCORE_ID: i64 = 0;
+const OSCORE_MS: i64 = 2;
+const OSCORE_SALT: i64 = 5;
+const OSCORE_CONTEXT_ID: i64 = 6;
+
+const DEFAULT_REFRESH_MARGIN: u64 = 30; // 30s
+const NONCE_LENGTH: usize = 8;
+
+/// The proof-of-possession material bound to an access token.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Confirmation {
+    /// OSCORE_Input_Material of the OSCORE profile (RFC 9203).
+    Oscore {
+        id: Vec<u8>,
+        master_secret: Vec<u8>,
+        salt: Vec<u8>,
+        context_id: Option<Vec<u8>>,
+    },
+    /// A symmetric key of the DTLS profile (RFC 9202), used as PSK with its kid as identity.
+    Psk { kid: Vec<u8>, key: Vec<u8> },
+}
+
+#[derive(Clone, Debug)]
+pub struct AccessToken {
+    pub token: Vec<u8>,
+    pub confirmation: Confirmation,
+    /// When the token expires, `None` if the authorization server gave no lifetime.
+    pub expires_at: Option<Instant>,
+}
+
+impl AccessToken {
+    fn from_response(response: &Value) -> Result<AccessToken> {
+        let token = response
+            .get(ACCESS_TOKEN)
+            .and_then(Value::as_bytes)
+            .ok_or(Error::new(ErrorKind::InvalidData, "missing access_token"))?;
+        let expires_at = response
+            .get(EXPIRES_IN)
+            .and_then(Value::as_integer)
+            .map(|seconds| Instant::now() + Duration::from_secs(seconds.max(0) as u64));
+
+        let cnf = response
+            .get(CNF)
+            .ok_or(Error::new(ErrorKind::InvalidData, "missing cnf"))?;
+        let confirmation = match response.get(ACE_PROFILE).and_then(Value::as_integer) {
+            Some(PROFILE_COAP_OSCORE) => {
+                let material = cnf
+                    .get(CNF_OSCORE)
+                    .ok_or(Error::new(ErrorKind::InvalidData, "missing OSCORE_Input_Material"))?;
+                Confirmation::Oscore {
+                    id: Self::bytes(material, OSCORE_ID)?,
+                    master_secret: Self::bytes(material, OSCORE_MS)?,
+                    salt: Self::bytes(material, OSCORE_SALT).unwrap_or_default(),
+                    context_id: Self::bytes(material, OSCORE_CONTEXT_ID).ok(),
+                }
+            }
+            Some(PROFILE_COAP_DTLS) => {
+                let key = cnf
+                    .get(CNF_COSE_KEY)
+                    .ok_or(Error::new(ErrorKind::InvalidData, "missing COSE_Key"))?;
+                Confirmation::Psk {
+                    kid: Self::bytes(key, COSE_KEY_KID)?,
+                    key: Self::bytes(key, COSE_KEY_K)?,
+                }
+            }
+            _ => return Err(Error::new(ErrorKind::InvalidData, "unsupported ace_profile")),
+        };
+
+        Ok(AccessToken {
+            token: token.clone(),
+            confirmation,
+            expires_at,
+        })
+    }
+
+    fn bytes(map: &Value, key: i64) -> Result<Vec<u8>> {
+        map.get(key)
+            .and_then(Value::as_bytes)
+            .cloned()
+            .ok_or(Error::new(ErrorKind::InvalidData, "missing token parameter"))
+    }
+
+    /// Checks whether the token expires within the given margin.
+    pub fn expires_within(&self, margin: Duration) -> bool {
+        match self.expires_at {
+            Some(expires_at) => Instant::now() + margin >= expires_at,
+            None => false,
+        }
+    }
+}
+
+/// A client of an ACE authorization server (RFC 9200) using the client credentials grant.
+///
+/// Tokens are cached and requested again once they are within the refresh margin of
+/// their expiry, so every call presenting a token to a resource server uses a valid one.
+pub struct AceClient {
+    client_id: String,
+    audience: String,
+    scope: String,
+    refresh_margin: Duration,
+    token: Option<AccessToken>,
+}
+
+impl AceClient {
+    pub fn new(client_id: &str, audience: &str, scope: &str) -> AceClient {
+        AceClient {
+            client_id: client_id.to_string(),
+            audience: audience.to_string(),
+            scope: scope.to_string(),
+            refresh_margin: Duration::new(DEFAULT_REFRESH_MARGIN, 0),
+            token: None,
+        }
+    }
+
+    /// Set how long before expiry a token is refreshed.
+    pub fn set_refresh_margin(&mut self, margin: Duration) {
+        self.refresh_margin = margin;
+    }
+
+    /// Request a new access token from the authorization server.
+    pub fn request_token(&mut self, authorization_server: &CoAPClient) -> Result<&AccessToken> {
+        let request = Value::Map(vec![
+            (Value::Integer(GRANT_TYPE), Value::Integer(GRANT_CLIENT_CREDENTIALS)),
+            (Value::Integer(CLIENT_ID), Value::Text(self.client_id.clone())),
+            (Value::Integer(AUDIENCE), Value::Text(self.audience.clone())),
+            (Value::Integer(SCOPE), Value::Text(self.scope.clone())),
+        ]);
+        let response = Self::post(authorization_server, TOKEN_PATH, &request)?;
+
+        self.token = Some(AccessToken::from_response(&response)?);
+        Ok(self.token.as_ref().unwrap())
+    }
+
+    /// Returns the cached token, requesting a new one when there is none or it is about to expire.
+    pub fn access_token(&mut self, authorization_server: &CoAPClient) -> Result<&AccessToken> {
+        let refresh = match self.token {
+            Some(ref token) => token.expires_within(self.refresh_margin),
+            None => true,
+        };
+        if refresh {
+            return self.request_token(authorization_server);
+        }
+        Ok(self.token.as_ref().unwrap())
+    }
+
+    /// Posts the token to a resource server and derives the OSCORE context of the
+    /// OSCORE profile (RFC 9203 §4). `recipient_id` is the OSCORE Recipient ID the client
+    /// picks for itself.
+    pub fn establish_oscore(
+        &mut self,
+        authorization_server: &CoAPClient,
+        resource_server: &CoAPClient,
+        recipient_id: &[u8],
+    ) -> Result<SecurityContext> {
+        let token = self.access_token(authorization_server)?.clone();
+        let (master_secret, salt, context_id) = match token.confirmation {
+            Confirmation::Oscore {
+                master_secret,
+                salt,
+                context_id,
+                ..
+            } => (master_secret, salt, context_id),
+            _ => return Err(Error::new(ErrorKind::InvalidInput, "token is not for the OSCORE profile")),
+        };
+
+        let mut nonce1 = [0; NONCE_LENGTH];
+        OsRandom.try_fill_bytes(&mut nonce1)?;
+        let request = Value::Map(vec![
+            (Value::Integer(ACCESS_TOKEN), Value::Bytes(token.token)),
+            (Value::Integer(NONCE1), Value::Bytes(nonce1.to_vec())),
+            (Value::Integer(ACE_CLIENT_RECIPIENTID), Value::Bytes(recipient_id.to_vec())),
+        ]);
+        let response = Self::post(resource_server, AUTHZ_INFO_PATH, &request)?;
+
+        let nonce2 = AccessToken::bytes(&response, NONCE2)?;
+        let sender_id = AccessToken::bytes(&response, ACE_SERVER_RECIPIENTID)?;
+        let master_salt = [
+            Value::Bytes(salt).to_vec(),
+            Value::Bytes(nonce1.to_vec()).to_vec(),
+            Value::Bytes(nonce2).to_vec(),
+        ]
+        .concat();
+
+        Ok(SecurityContext {
+            master_secret,
+            master_salt,
+            sender_id,
+            recipient_id: recipient_id.to_vec(),
+            id_context: context_id,
+        })
+    }
+
+    /// Posts the token to a resource server and connects to it over DTLS with the PSK
+    /// bound to the token (RFC 9202).
+    pub fn connect_dtls<A: ToSocketAddrs>(
+        &mut self,
+        authorization_server: &CoAPClient,
+        resource_server: &CoAPClient,
+        dtls_addr: A,
+    ) -> Result<DTLSCoAPClient> {
+        let token = self.access_token(authorization_server)?.clone();
+        let (kid, key) = match token.confirmation {
+            Confirmation::Psk { kid, key } => (kid, key),
+            _ => return Err(Error::new(ErrorKind::InvalidInput, "token is not for the DTLS profile")),
+        };
+
+        Self::exchange(resource_server, AUTHZ_INFO_PATH, token.token)?;
+        DTLSCoAPClient::new_with_psk(dtls_addr, &kid, &key)
+    }
+
+    fn post(client: &CoAPClient, path: &str, payload: &Value) -> Result<Value> {
+        let payload = Self::exchange(client, path, payload.to_vec())?;
+        match cbor::decode(&payload) {
+            Ok((value, _)) => Ok(value),
+            Err(e) => Err(Error::new(ErrorKind::InvalidData, e)),
+        }
+    }
+
+    /// POSTs an application/ace+cbor payload, expecting 2.01 Created.
+    fn exchange(client: &CoAPClient, path: &str, payload: Vec<u8>) -> Result<Vec<u8>> {
+        let mut request = CoAPRequest::new();
+        request.set_method(Method::Post);
+        request.set_path(path);
+        request.message.set_content_format(ContentFormat::ApplicationAceCbor);
+        request.set_payload(payload);
+        client.send(&request)?;
+
+        let response = client.receive()?;
+        if *response.get_status() != Status::Created {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!("{} rejected: {:?}", path, response.get_status()),
+            ));
+        }
+        Ok(response.message.payload)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::*;
+    use std::sync::{Arc, Mutex};
+
+    fn spawn_authorization_server(expires_in: i64, hits: Arc<Mutex<usize>>) -> u16 {
+        server::test::spawn_server(move |req: CoAPRequest| {
+            let hits = hits.clone();
+            async move {
+                let (request, _) = cbor::decode(&req.message.payload).unwrap();
+                assert_eq!(request.get(GRANT_TYPE), Some(&Value::Integer(GRANT_CLIENT_CREDENTIALS)));
+                assert_eq!(request.get(CLIENT_ID), Some(&Value::Text("client".to_string())));
+                *hits.lock().unwrap() += 1;
+
+                let material = Value::Map(vec![
+                    (Value::Integer(OSCORE_ID), Value::Bytes(vec![0x01])),
+                    (Value::Integer(OSCORE_MS), Value::Bytes(vec![0x0F; 16])),
+                    (Value::Integer(OSCORE_SALT), Value::Bytes(vec![0x5A; 8])),
+                ]);
+                let mut response = req.response?;
+                response.set_status(Status::Created);
+                response.set_payload(Value::Map(vec![
+                    (Value::Integer(ACCESS_TOKEN), Value::Bytes(b"token".to_vec())),
+                    (Value::Integer(EXPIRES_IN), Value::Integer(expires_in)),
+                    (Value::Integer(ACE_PROFILE), Value::Integer(PROFILE_COAP_OSCORE)),
+                    (Value::Integer(CNF), Value::Map(vec![(Value::Integer(CNF_OSCORE), material)])),
+                ]).to_vec());
+                Some(response)
+            }
+        }).recv().unwrap()
+    }
+
+    #[test]
+    fn test_establish_oscore() {
+        let hits = Arc::new(Mutex::new(0));
+        let as_port = spawn_authorization_server(3600, hits.clone());
+        let rs_port = server::test::spawn_server(|req: CoAPRequest| async move {
+            let (request, _) = cbor::decode(&req.message.payload).unwrap();
+            assert_eq!(request.get(ACCESS_TOKEN), Some(&Value::Bytes(b"token".to_vec())));
+            assert_eq!(request.get(ACE_CLIENT_RECIPIENTID), Some(&Value::Bytes(vec![0x0A])));
+
+            let mut response = req.response?;
+            response.set_status(Status::Created);
+            response.set_payload(Value::Map(vec![
+                (Value::Integer(NONCE2), Value::Bytes(vec![0x22; 8])),
+                (Value::Integer(ACE_SERVER_RECIPIENTID), Value::Bytes(vec![0x0B])),
+            ]).to_vec());
+            Some(response)
+        }).recv().unwrap();
+
+        let authorization_server = CoAPClient::new(format!("127.0.0.1:{}", as_port)).unwrap();
+        let resource_server = CoAPClient::new(format!("127.0.0.1:{}", rs_port)).unwrap();
+        let mut client = AceClient::new("client", "rs", "read");
+
+        let context = client.establish_oscore(&authorization_server, &resource_server, &[0x0A]).unwrap();
+        assert_eq!(context.master_secret, vec![0x0F; 16]);
+        assert_eq!(context.sender_id, vec![0x0B]);
+        assert_eq!(context.recipient_id, vec![0x0A]);
+        assert_eq!(context.master_salt.len(), 3 * (1 + 8));
+        assert_eq!(context.master_salt[..9], Value::Bytes(vec![0x5A; 8]).to_vec()[..]);
+        assert_eq!(context.master_salt[18..], Value::Bytes(vec![0x22; 8]).to_vec()[..]);
+
+        client.establish_oscore(&authorization_server, &resource_server, &[0x0A]).unwrap();
+        assert_eq!(*hits.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_token_refresh() {
+        let hits = Arc::new(Mutex::new(0));
+        let as_port = spawn_authorization_server(10, hits.clone());
+        let authorization_server = CoAPClient::new(format!("127.0.0.1:{}", as_port)).unwrap();
+
+        let mut client = AceClient::new("client", "rs", "read");
+        client.set_refresh_margin(Duration::from_secs(5));
+        assert!(!client.access_token(&authorization_server).unwrap().expires_within(Duration::from_secs(5)));
+        client.access_token(&authorization_server).unwrap();
+        assert_eq!(*hits.lock().unwrap(), 1);
+
+        client.set_refresh_margin(Duration::from_secs(20));
+        client.access_token(&authorization_server).unwrap();
+        assert_eq!(*hits.lock().unwrap(), 2);
+    }
+}
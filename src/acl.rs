@@ -0,0 +1,105 @@
+use std::net::IpAddr;
+
+use super::context::RequestContext;
+
+/// An allow list of the peers permitted to reach protected resources, by network or by
+/// authenticated identity. Anything not allowed is denied.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Acl {
+    networks: Vec<(IpAddr, u8)>,
+    identities: Vec<Vec<u8>>,
+}
+
+impl Acl {
+    /// Creates an ACL denying every peer.
+    pub fn new() -> Acl {
+        Self::default()
+    }
+
+    /// Allows the peers within a network, e.g. `("10.0.0.0".parse()?, 8)`.
+    pub fn allow_network(mut self, network: IpAddr, prefix_len: u8) -> Acl {
+        self.networks.push((network, prefix_len));
+        self
+    }
+
+    /// Allows peers on the loopback interface.
+    pub fn allow_loopback(self) -> Acl {
+        self.allow_network(IpAddr::from([127, 0, 0, 0]), 8)
+            .allow_network(IpAddr::from([0u16, 0, 0, 0, 0, 0, 0, 1]), 128)
+    }
+
+    /// Allows the peers authenticated with an identity, e.g. a PSK identity or an OSCORE
+    /// Sender ID.
+    pub fn allow_identity(mut self, identity: &[u8]) -> Acl {
+        self.identities.push(identity.to_vec());
+        self
+    }
+
+    /// Checks whether the peer of a request is allowed.
+    pub fn permits(&self, context: &RequestContext) -> bool {
+        if let Some(ref identity) = context.identity {
+            if self.identities.contains(identity) {
+                return true;
+            }
+        }
+        match context.peer {
+            Some(peer) => self.permits_addr(peer.ip()),
+            None => false,
+        }
+    }
+
+    fn permits_addr(&self, addr: IpAddr) -> bool {
+        let addr = match addr {
+            IpAddr::V6(v6) => v6.to_ipv4().filter(|_| v6.segments()[5] == 0xffff).map_or(addr, IpAddr::V4),
+            IpAddr::V4(_) => addr,
+        };
+        self.networks.iter().any(|&(network, prefix_len)| match (network, addr) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                prefix_matches(&network.octets(), &addr.octets(), prefix_len)
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                prefix_matches(&network.octets(), &addr.octets(), prefix_len)
+            }
+            _ => false,
+        })
+    }
+}
+
+fn prefix_matches(network: &[u8], addr: &[u8], prefix_len: u8) -> bool {
+    let prefix_len = (prefix_len as usize).min(network.len() * 8);
+    let whole = prefix_len / 8;
+    if network[..whole] != addr[..whole] {
+        return false;
+    }
+    let rest = prefix_len % 8;
+    rest == 0 || (network[whole] ^ addr[whole]) >> (8 - rest) == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::context::Transport;
+
+    fn context(peer: &str, identity: Option<&[u8]>) -> RequestContext {
+        let mut context = RequestContext::new(Some(peer.parse().unwrap()), Transport::Udp);
+        context.identity = identity.map(|identity| identity.to_vec());
+        context
+    }
+
+    #[test]
+    fn test_acl() {
+        let acl = Acl::new()
+            .allow_loopback()
+            .allow_network("10.1.0.0".parse().unwrap(), 20)
+            .allow_identity(b"operator");
+
+        assert!(acl.permits(&context("127.0.0.1:5683", None)));
+        assert!(acl.permits(&context("[::1]:5683", None)));
+        assert!(acl.permits(&context("10.1.15.3:5683", None)));
+        assert!(acl.permits(&context("[::ffff:10.1.2.3]:5683", None)));
+        assert!(!acl.permits(&context("10.1.16.3:5683", None)));
+        assert!(!acl.permits(&context("192.0.2.1:5683", Some(b"device"))));
+        assert!(acl.permits(&context("192.0.2.1:5683", Some(b"operator"))));
+        assert!(!Acl::new().permits(&context("127.0.0.1:5683", None)));
+    }
+}
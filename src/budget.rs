@@ -0,0 +1,195 @@
+//! Capping the memory an endpoint holds on behalf of its peers.
+//!
+//! A `MemoryBudget` bounds the outstanding exchanges of a client, the bytes of cached
+//! responses, the observe registrations of a server and the streamed bodies it keeps for
+//! block-wise transfers. Clones share the usage, so one budget set on several clients and
+//! servers caps them together. Without a budget each of them is unlimited.
+//!
+//! Exceeding the budget fails a client request with a `BudgetExceeded` error, skips storing
+//! a response in the cache, answers an observe registration with the representation only,
+//! and answers a request for a streamed body with 5.03 Service Unavailable.
+
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// What a budget limits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BudgetResource {
+    /// Exchanges awaiting their response.
+    Exchanges,
+    /// Bytes of responses held by response caches, as encoded on the wire.
+    CachedBytes,
+    /// Observe registrations.
+    Observers,
+    /// Streamed bodies kept between the blocks of a block-wise transfer.
+    BlockContexts,
+}
+
+impl BudgetResource {
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+impl fmt::Display for BudgetResource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            BudgetResource::Exchanges => "exchanges",
+            BudgetResource::CachedBytes => "cached bytes",
+            BudgetResource::Observers => "observers",
+            BudgetResource::BlockContexts => "block contexts",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A reservation was refused because it would exceed the limit of a resource.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BudgetExceeded {
+    pub resource: BudgetResource,
+    pub limit: usize,
+}
+
+impl fmt::Display for BudgetExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "memory budget exceeded: at most {} {}", self.limit, self.resource)
+    }
+}
+
+impl std::error::Error for BudgetExceeded {}
+
+impl From<BudgetExceeded> for io::Error {
+    fn from(e: BudgetExceeded) -> io::Error {
+        io::Error::other(e)
+    }
+}
+
+/// How much of each resource is in use.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BudgetUsage {
+    pub exchanges: usize,
+    pub cached_bytes: usize,
+    pub observers: usize,
+    pub block_contexts: usize,
+}
+
+/// Limits on the memory held by the endpoints sharing the budget.
+#[derive(Clone, Debug)]
+pub struct MemoryBudget {
+    limits: [Option<usize>; 4],
+    used: Arc<[AtomicUsize; 4]>,
+}
+
+impl Default for MemoryBudget {
+    fn default() -> MemoryBudget {
+        MemoryBudget::new()
+    }
+}
+
+impl MemoryBudget {
+    /// Creates a budget without limits, to which limits are added with the `with_*` methods.
+    pub fn new() -> MemoryBudget {
+        MemoryBudget {
+            limits: [None; 4],
+            used: Arc::new(Default::default()),
+        }
+    }
+
+    pub fn with_max_exchanges(self, max: usize) -> MemoryBudget {
+        self.with_limit(BudgetResource::Exchanges, max)
+    }
+
+    pub fn with_max_cached_bytes(self, max: usize) -> MemoryBudget {
+        self.with_limit(BudgetResource::CachedBytes, max)
+    }
+
+    pub fn with_max_observers(self, max: usize) -> MemoryBudget {
+        self.with_limit(BudgetResource::Observers, max)
+    }
+
+    pub fn with_max_block_contexts(self, max: usize) -> MemoryBudget {
+        self.with_limit(BudgetResource::BlockContexts, max)
+    }
+
+    fn with_limit(mut self, resource: BudgetResource, max: usize) -> MemoryBudget {
+        self.limits[resource.index()] = Some(max);
+        self
+    }
+
+    /// The limit of a resource, `None` if it is unlimited.
+    pub fn limit(&self, resource: BudgetResource) -> Option<usize> {
+        self.limits[resource.index()]
+    }
+
+    /// The current usage of the endpoints sharing the budget.
+    pub fn usage(&self) -> BudgetUsage {
+        let used = |resource: BudgetResource| self.used[resource.index()].load(Ordering::SeqCst);
+        BudgetUsage {
+            exchanges: used(BudgetResource::Exchanges),
+            cached_bytes: used(BudgetResource::CachedBytes),
+            observers: used(BudgetResource::Observers),
+            block_contexts: used(BudgetResource::BlockContexts),
+        }
+    }
+
+    /// Takes `amount` of a resource, or nothing if that would exceed its limit.
+    pub(crate) fn reserve(&self, resource: BudgetResource, amount: usize) -> Result<(), BudgetExceeded> {
+        let used = &self.used[resource.index()];
+        let limit = self.limits[resource.index()];
+        let mut current = used.load(Ordering::SeqCst);
+        loop {
+            let wanted = current.saturating_add(amount);
+            if let Some(limit) = limit {
+                if wanted > limit {
+                    return Err(BudgetExceeded { resource, limit });
+                }
+            }
+            match used.compare_exchange(current, wanted, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => return Ok(()),
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    /// Gives back `amount` of a resource taken with `reserve`.
+    pub(crate) fn release(&self, resource: BudgetResource, amount: usize) {
+        let used = &self.used[resource.index()];
+        let mut current = used.load(Ordering::SeqCst);
+        loop {
+            match used.compare_exchange(current, current.saturating_sub(amount), Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => return,
+                Err(actual) => current = actual,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_reserve() {
+        let budget = MemoryBudget::new().with_max_observers(2).with_max_cached_bytes(100);
+        let shared = budget.clone();
+        assert!(budget.reserve(BudgetResource::Observers, 1).is_ok());
+        assert!(shared.reserve(BudgetResource::Observers, 1).is_ok());
+        let exceeded = budget.reserve(BudgetResource::Observers, 1).unwrap_err();
+        assert_eq!(exceeded, BudgetExceeded { resource: BudgetResource::Observers, limit: 2 });
+        assert_eq!(exceeded.to_string(), "memory budget exceeded: at most 2 observers");
+
+        assert!(budget.reserve(BudgetResource::CachedBytes, 101).is_err());
+        assert!(budget.reserve(BudgetResource::CachedBytes, 100).is_ok());
+        assert!(budget.reserve(BudgetResource::Exchanges, 1000).is_ok());
+        shared.release(BudgetResource::Observers, 1);
+        assert_eq!(budget.usage(), BudgetUsage {
+            exchanges: 1000,
+            cached_bytes: 100,
+            observers: 1,
+            block_contexts: 0,
+        });
+        assert_eq!(budget.limit(BudgetResource::Exchanges), None);
+    }
+}
@@ -0,0 +1,503 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use log::debug;
+
+use super::budget::{BudgetResource, MemoryBudget};
+use super::message::header::{class_to_code, MessageClass};
+use super::message::request::CoAPRequest;
+use super::message::response::{CoAPResponse, Status};
+use super::uri;
+
+const URI_HOST: usize = 3;
+const URI_PATH: usize = 11;
+const PROXY_URI: usize = 35;
+
+/// Identifies cached responses by request method and cache-relevant options
+/// (RFC 7252 §5.6).
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    code: u8,
+    options: Vec<(usize, Vec<u8>)>,
+}
+
+impl CacheKey {
+    /// Computes the cache key of a request, skipping the options marked NoCacheKey.
+    ///
+    /// Options naming the resource are normalized (RFC 7252 §6.3), so that requests for
+    /// equivalent URIs share a key: the Uri-Host is compared case-insensitively, a single
+    /// empty Uri-Path stands for no path and the Proxy-Uri is normalized with
+    /// `uri::normalize`.
+    pub fn from_request(request: &CoAPRequest) -> CacheKey {
+        let mut options = Vec::new();
+        for (number, values) in request.message.options() {
+            if is_no_cache_key(*number) {
+                continue;
+            }
+            if *number == URI_PATH && values.len() == 1 && values.front().is_some_and(|value| value.is_empty()) {
+                continue;
+            }
+
+            for value in values.iter() {
+                let value = match *number {
+                    URI_HOST => value.to_ascii_lowercase(),
+                    PROXY_URI => match uri::normalize(&String::from_utf8_lossy(value)) {
+                        Ok(normalized) => normalized.into_bytes(),
+                        Err(_) => value.clone(),
+                    },
+                    _ => value.clone(),
+                };
+                options.push((*number, value));
+            }
+        }
+
+        CacheKey {
+            code: class_to_code(&request.message.header.code),
+            options,
+        }
+    }
+
+    /// Encodes the key for stores that index entries by bytes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = vec![self.code];
+        for (number, value) in self.options.iter() {
+            buf.extend_from_slice(&(*number as u16).to_be_bytes());
+            buf.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            buf.extend_from_slice(value);
+        }
+        buf
+    }
+}
+
+/// Checks the NoCacheKey bits of an option number (RFC 7252 §5.4.6).
+pub fn is_no_cache_key(number: usize) -> bool {
+    number & 0x1E == 0x1C
+}
+
+/// Checks whether a response may be stored in a cache.
+pub fn is_cacheable(response: &CoAPResponse) -> bool {
+    match response.message.header.code {
+        MessageClass::Response(Status::Content) => true,
+        MessageClass::Response(_) => class_to_code(&response.message.header.code) >> 5 >= 4,
+        _ => false,
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub revalidations: u64,
+    pub evictions: u64,
+    pub entries: usize,
+}
+
+/// Storage behind a `ResponseCache`.
+///
+/// The cache decides freshness and revalidation itself; a store only keeps entries. The TTL
+/// passed to `put` is the freshness lifetime of the response: a store may drop the entry
+/// after it, although entries kept longer can still be revalidated by ETag. Stores that
+/// serialize responses, e.g. to share them between gateway replicas, should write the
+/// `remaining_max_age` into Max-Age and rebuild entries with `CoAPResponse::received`.
+pub trait CacheStore: Send {
+    /// Returns the response stored under the key, fresh or not.
+    fn get(&mut self, key: &CacheKey) -> Option<CoAPResponse>;
+
+    /// Stores a response, returning true if another entry was evicted to make room.
+    fn put(&mut self, key: CacheKey, response: CoAPResponse, ttl: Duration) -> bool;
+
+    /// Removes the response stored under the key.
+    fn evict(&mut self, key: &CacheKey) -> Option<CoAPResponse>;
+
+    /// The number of stored entries.
+    fn len(&self) -> usize;
+
+    /// Whether the store holds no entries.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// An in-memory store holding a bounded number of entries, evicting the entry whose TTL
+/// ends first when full.
+pub struct MemoryStore {
+    entries: HashMap<CacheKey, (CoAPResponse, Instant)>,
+    max_entries: usize,
+}
+
+impl MemoryStore {
+    pub fn new(max_entries: usize) -> MemoryStore {
+        MemoryStore {
+            entries: HashMap::new(),
+            max_entries,
+        }
+    }
+}
+
+impl CacheStore for MemoryStore {
+    fn get(&mut self, key: &CacheKey) -> Option<CoAPResponse> {
+        self.entries.get(key).map(|(response, _)| response.clone())
+    }
+
+    fn put(&mut self, key: CacheKey, response: CoAPResponse, ttl: Duration) -> bool {
+        if self.max_entries == 0 {
+            return false;
+        }
+
+        let mut evicted = false;
+        if !self.entries.contains_key(&key) && self.entries.len() >= self.max_entries {
+            let victim = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, expires_at))| *expires_at)
+                .map(|(key, _)| key.clone());
+            if let Some(victim) = victim {
+                self.entries.remove(&victim);
+                evicted = true;
+            }
+        }
+        self.entries.insert(key, (response, Instant::now() + ttl));
+        evicted
+    }
+
+    fn evict(&mut self, key: &CacheKey) -> Option<CoAPResponse> {
+        self.entries.remove(key).map(|(response, _)| response)
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+/// A cache of received responses honoring Max-Age, kept in a `CacheStore`.
+pub struct ResponseCache {
+    store: Box<dyn CacheStore>,
+    stats: CacheStats,
+    budget: MemoryBudget,
+    // the bytes each stored entry counts against the budget
+    sizes: HashMap<CacheKey, usize>,
+}
+
+impl ResponseCache {
+    /// Creates an in-memory cache holding at most `max_entries` responses.
+    pub fn new(max_entries: usize) -> ResponseCache {
+        Self::with_store(MemoryStore::new(max_entries))
+    }
+
+    /// Creates a cache kept in the given store.
+    pub fn with_store<S: CacheStore + 'static>(store: S) -> ResponseCache {
+        ResponseCache {
+            store: Box::new(store),
+            stats: CacheStats::default(),
+            budget: MemoryBudget::new(),
+            sizes: HashMap::new(),
+        }
+    }
+
+    /// Counts the stored responses against the cached bytes of a budget. A response that
+    /// does not fit in the budget is not stored.
+    pub fn set_memory_budget(&mut self, budget: MemoryBudget) {
+        let cached = self.sizes.values().sum();
+        self.budget.release(BudgetResource::CachedBytes, cached);
+        // the entries already stored are counted even beyond the limit
+        let _ = budget.reserve(BudgetResource::CachedBytes, cached);
+        self.budget = budget;
+    }
+
+    /// Returns a fresh response stored under the key.
+    pub fn get(&mut self, key: &CacheKey) -> Option<CoAPResponse> {
+        match self.store.get(key) {
+            Some(response) if response.is_fresh() => {
+                self.stats.hits += 1;
+                Some(response)
+            }
+            _ => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Returns the ETag of a stale response so it can be revalidated upstream.
+    pub fn get_stale_etag(&mut self, key: &CacheKey) -> Option<Vec<u8>> {
+        match self.store.get(key) {
+            Some(response) if !response.is_fresh() => response.message.get_etag().cloned(),
+            _ => None,
+        }
+    }
+
+    /// Stores a response if it is cacheable, evicting another entry when the cache is full.
+    pub fn insert(&mut self, key: CacheKey, response: CoAPResponse) {
+        if is_cacheable(&response) {
+            self.store(key, response);
+        }
+    }
+
+    /// Stores a response whatever its code, for responses the caller found cacheable
+    /// otherwise, e.g. OSCORE responses whose outer code is always 2.04.
+    pub fn store(&mut self, key: CacheKey, response: CoAPResponse) {
+        let size = response.message.to_bytes().map_or(response.message.payload.len(), |bytes| bytes.len());
+        if let Some(replaced) = self.sizes.remove(&key) {
+            self.budget.release(BudgetResource::CachedBytes, replaced);
+        }
+        if let Err(e) = self.budget.reserve(BudgetResource::CachedBytes, size) {
+            debug!("not caching response: {}", e);
+            // the entry replaced is outdated
+            self.store.evict(&key);
+            self.stats.entries = self.store.len();
+            return;
+        }
+        self.sizes.insert(key.clone(), size);
+
+        let ttl = response.get_max_age();
+        if self.store.put(key, response, ttl) {
+            self.stats.evictions += 1;
+            self.release_evicted();
+        }
+        self.stats.entries = self.store.len();
+    }
+
+    /// Refreshes a stored response with the 2.03 Valid response that confirmed it.
+    pub fn revalidate(&mut self, key: &CacheKey, valid: &CoAPResponse) -> Option<CoAPResponse> {
+        let mut response = self.store.get(key)?;
+        response.received_at = valid.received_at;
+        response
+            .message
+            .set_max_age(valid.get_max_age().as_secs() as u32);
+        if self.store.put(key.clone(), response.clone(), valid.get_max_age()) {
+            self.release_evicted();
+        }
+        self.stats.revalidations += 1;
+        Some(response)
+    }
+
+    /// Removes the response stored under the key.
+    pub fn remove(&mut self, key: &CacheKey) -> Option<CoAPResponse> {
+        let response = self.store.evict(key);
+        if let Some(size) = self.sizes.remove(key) {
+            self.budget.release(BudgetResource::CachedBytes, size);
+        }
+        self.stats.entries = self.store.len();
+        response
+    }
+
+    // gives back the bytes of the entries the store evicted to make room
+    fn release_evicted(&mut self) {
+        let store = &mut self.store;
+        let evicted: Vec<CacheKey> = self.sizes
+            .keys()
+            .filter(|key| store.get(key).is_none())
+            .cloned()
+            .collect();
+        for key in evicted {
+            let size = self.sizes.remove(&key).unwrap();
+            self.budget.release(BudgetResource::CachedBytes, size);
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats.clone()
+    }
+}
+
+impl Drop for ResponseCache {
+    fn drop(&mut self) {
+        self.budget.release(BudgetResource::CachedBytes, self.sizes.values().sum());
+    }
+}
+
+/// Returns the freshness left on a response, used to rewrite Max-Age when
+/// serving it from a cache.
+pub fn remaining_max_age(response: &CoAPResponse) -> Duration {
+    match response.expires_at() {
+        Some(expires_at) => expires_at.saturating_duration_since(Instant::now()),
+        None => Duration::from_secs(0),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::message::packet::{CoAPOption, Packet};
+    use super::super::message::IsMessage;
+
+    fn response_with(status: Status, max_age: u32) -> CoAPResponse {
+        let mut packet = Packet::new();
+        packet.header.code = MessageClass::Response(status);
+        packet.set_max_age(max_age);
+        CoAPResponse::received(packet)
+    }
+
+    #[test]
+    fn test_cache_key() {
+        let mut request1 = CoAPRequest::new();
+        request1.set_path("/sensor/temp");
+        request1.add_option(CoAPOption::Size1, vec![0x10]);
+
+        let mut request2 = CoAPRequest::new();
+        request2.set_path("/sensor/temp");
+
+        let mut request3 = CoAPRequest::new();
+        request3.set_path("/sensor/humidity");
+
+        assert!(is_no_cache_key(60));
+        assert!(!is_no_cache_key(11));
+        assert_eq!(CacheKey::from_request(&request1), CacheKey::from_request(&request2));
+        assert_ne!(CacheKey::from_request(&request2), CacheKey::from_request(&request3));
+        assert_ne!(CacheKey::from_request(&request2).to_bytes(), CacheKey::from_request(&request3).to_bytes());
+
+        // equivalent URIs share a key
+        request2.add_option(CoAPOption::UriHost, b"Sensor.Example".to_vec());
+        request3.set_path("/sensor/temp");
+        request3.add_option(CoAPOption::UriHost, b"sensor.example".to_vec());
+        assert_eq!(CacheKey::from_request(&request2), CacheKey::from_request(&request3));
+        let mut root = CoAPRequest::new();
+        root.set_path("/");
+        assert_eq!(CacheKey::from_request(&root), CacheKey::from_request(&CoAPRequest::new()));
+        let proxied = |proxy_uri: &str| {
+            let mut request = CoAPRequest::new();
+            request.add_option(CoAPOption::ProxyUri, proxy_uri.as_bytes().to_vec());
+            CacheKey::from_request(&request)
+        };
+        assert_eq!(proxied("coap://Example.com:5683/%7Ea"), proxied("coap://example.com/~a"));
+        assert_ne!(proxied("coap://example.com/a"), proxied("coap://example.com/b"));
+    }
+
+    #[test]
+    fn test_cacheable() {
+        assert!(is_cacheable(&response_with(Status::Content, 60)));
+        assert!(is_cacheable(&response_with(Status::NotFound, 60)));
+        assert!(!is_cacheable(&response_with(Status::Changed, 60)));
+    }
+
+    #[test]
+    fn test_get_and_stats() {
+        let mut request = CoAPRequest::new();
+        request.set_path("/a");
+        let key = CacheKey::from_request(&request);
+
+        let mut cache = ResponseCache::new(2);
+        assert!(cache.get(&key).is_none());
+
+        cache.insert(key.clone(), response_with(Status::Content, 60));
+        assert!(cache.get(&key).is_some());
+
+        let stats = cache.stats();
+        assert_eq!(stats.hits, 1);
+        assert_eq!(stats.misses, 1);
+        assert_eq!(stats.entries, 1);
+    }
+
+    #[test]
+    fn test_stale_revalidation() {
+        let mut request = CoAPRequest::new();
+        request.set_path("/a");
+        let key = CacheKey::from_request(&request);
+
+        let mut stale = response_with(Status::Content, 0);
+        stale.message.set_etag(b"v1".to_vec());
+
+        let mut cache = ResponseCache::new(2);
+        cache.insert(key.clone(), stale);
+        assert!(cache.get(&key).is_none());
+        assert_eq!(cache.get_stale_etag(&key), Some(b"v1".to_vec()));
+
+        let valid = response_with(Status::Valid, 60);
+        assert!(cache.revalidate(&key, &valid).is_some());
+        assert!(cache.get(&key).is_some());
+        assert_eq!(cache.get_stale_etag(&key), None);
+        assert_eq!(cache.stats().revalidations, 1);
+    }
+
+    #[test]
+    fn test_max_entries() {
+        let mut cache = ResponseCache::new(2);
+        let mut keys = Vec::new();
+        for (i, max_age) in [30, 10, 60].iter().enumerate() {
+            let mut request = CoAPRequest::new();
+            request.set_path(&format!("/{}", i));
+            let key = CacheKey::from_request(&request);
+            cache.insert(key.clone(), response_with(Status::Content, *max_age));
+            keys.push(key);
+        }
+
+        let stats = cache.stats();
+        assert_eq!(stats.entries, 2);
+        assert_eq!(stats.evictions, 1);
+        assert!(cache.get(&keys[1]).is_none());
+        assert!(cache.get(&keys[0]).is_some());
+        assert!(cache.get(&keys[2]).is_some());
+    }
+
+    #[test]
+    fn test_memory_budget() {
+        let response = response_with(Status::Content, 60);
+        let size = response.message.to_bytes().unwrap().len();
+        let budget = MemoryBudget::new().with_max_cached_bytes(size * 2);
+        let mut cache = ResponseCache::new(2);
+        cache.set_memory_budget(budget.clone());
+
+        let mut keys = Vec::new();
+        for i in 0..3 {
+            let mut request = CoAPRequest::new();
+            request.set_path(&format!("/{}", i));
+            let key = CacheKey::from_request(&request);
+            cache.insert(key.clone(), response.clone());
+            keys.push(key);
+        }
+        // the third response exceeds the budget
+        assert!(cache.get(&keys[2]).is_none());
+        assert_eq!(cache.stats().entries, 2);
+        assert_eq!(budget.usage().cached_bytes, size * 2);
+
+        cache.remove(&keys[0]);
+        cache.insert(keys[2].clone(), response.clone());
+        assert!(cache.get(&keys[2]).is_some());
+        assert_eq!(budget.usage().cached_bytes, size * 2);
+
+        let mut small = ResponseCache::new(1);
+        small.set_memory_budget(budget.clone());
+        cache.remove(&keys[1]);
+        small.insert(keys[0].clone(), response.clone());
+        // evicted by the store, the entry gives its bytes back
+        small.insert(keys[1].clone(), response_with(Status::Content, 90));
+        assert_eq!(budget.usage().cached_bytes, size * 2);
+        drop(small);
+        drop(cache);
+        assert_eq!(budget.usage().cached_bytes, 0);
+    }
+
+    #[test]
+    fn test_custom_store() {
+        struct CheckedStore(MemoryStore);
+
+        impl CacheStore for CheckedStore {
+            fn get(&mut self, key: &CacheKey) -> Option<CoAPResponse> {
+                self.0.get(key)
+            }
+
+            fn put(&mut self, key: CacheKey, response: CoAPResponse, ttl: Duration) -> bool {
+                assert_eq!(ttl, Duration::from_secs(60));
+                self.0.put(key, response, ttl)
+            }
+
+            fn evict(&mut self, key: &CacheKey) -> Option<CoAPResponse> {
+                self.0.evict(key)
+            }
+
+            fn len(&self) -> usize {
+                self.0.len()
+            }
+        }
+
+        let mut request = CoAPRequest::new();
+        request.set_path("/a");
+        let key = CacheKey::from_request(&request);
+
+        let mut cache = ResponseCache::with_store(CheckedStore(MemoryStore::new(1)));
+        cache.insert(key.clone(), response_with(Status::Content, 60));
+        cache.insert(key.clone(), response_with(Status::Changed, 60));
+        assert!(cache.get(&key).is_some());
+        assert!(cache.remove(&key).is_some());
+        assert_eq!(cache.stats().entries, 0);
+    }
+}
@@ -0,0 +1,144 @@
+//! A document describing what a server supports, served at `/.well-known/coap`.
+//!
+//! The document is a CBOR map with the keys `max_message_size`, `content_formats`,
+//! `block_sizes` and `observe`. A server serves it once enabled with
+//! `Server::expose_capabilities`; a client fetches it with `CoAPClient::capabilities` to pick
+//! its block size or content format instead of probing the server.
+
+use std::io;
+
+use super::cbor::{self, Value};
+use super::message::packet::ContentFormat;
+use super::message::request::{CoAPRequest, Method};
+use super::message::response::{CoAPResponse, Status};
+
+/// The path the capability document is served at.
+pub const CAPABILITY_PATH: &str = ".well-known/coap";
+
+/// What a server supports.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Capabilities {
+    /// The largest message the server reads, in bytes.
+    pub max_message_size: usize,
+    /// The content formats the server declares to accept and produce, empty if undeclared.
+    pub content_formats: Vec<u32>,
+    /// The block sizes the server serves block-wise transfers in, ascending.
+    pub block_sizes: Vec<usize>,
+    /// Whether the server accepts observe registrations.
+    pub observe: bool,
+}
+
+impl Capabilities {
+    /// Encodes the document as a CBOR map.
+    pub fn to_cbor(&self) -> Vec<u8> {
+        let text = |text: &str| Value::Text(text.to_string());
+        let integers = |values: Vec<i64>| Value::Array(values.into_iter().map(Value::Integer).collect());
+        Value::Map(vec![
+            (text("max_message_size"), Value::Integer(self.max_message_size as i64)),
+            (text("content_formats"), integers(self.content_formats.iter().map(|&f| f as i64).collect())),
+            (text("block_sizes"), integers(self.block_sizes.iter().map(|&s| s as i64).collect())),
+            (text("observe"), Value::Bool(self.observe)),
+        ]).to_vec()
+    }
+
+    /// Decodes a document produced by `to_cbor`, ignoring keys added by later versions.
+    pub fn from_cbor(buf: &[u8]) -> io::Result<Capabilities> {
+        let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, format!("invalid capabilities: {}", what));
+        let (value, _) = cbor::decode(buf).map_err(|_| invalid("not CBOR"))?;
+        let entries = match value {
+            Value::Map(entries) => entries,
+            _ => return Err(invalid("not a map")),
+        };
+        let field = |name: &str| {
+            entries
+                .iter()
+                .find(|(key, _)| key.as_text() == Some(name))
+                .map(|(_, value)| value)
+                .ok_or_else(|| invalid(name))
+        };
+        let integers = |name: &str| match field(name)? {
+            Value::Array(values) => values
+                .iter()
+                .map(|value| value.as_integer().filter(|&i| i >= 0).ok_or_else(|| invalid(name)))
+                .collect::<io::Result<Vec<i64>>>(),
+            _ => Err(invalid(name)),
+        };
+        Ok(Capabilities {
+            max_message_size: field("max_message_size")?
+                .as_integer()
+                .filter(|&size| size >= 0)
+                .ok_or_else(|| invalid("max_message_size"))? as usize,
+            content_formats: integers("content_formats")?.into_iter().map(|f| f as u32).collect(),
+            block_sizes: integers("block_sizes")?.into_iter().map(|s| s as usize).collect(),
+            observe: match field("observe")? {
+                Value::Bool(observe) => *observe,
+                _ => return Err(invalid("observe")),
+            },
+        })
+    }
+
+    /// The largest block size both this document and `preferred` allow, e.g. the client's
+    /// own limit, `None` if the server serves no block of at most that size.
+    pub fn block_size(&self, preferred: usize) -> Option<usize> {
+        self.block_sizes.iter().cloned().filter(|&size| size <= preferred).max()
+    }
+
+    /// Whether the server declared the content format, or declared none.
+    pub fn supports_format(&self, format: u32) -> bool {
+        self.content_formats.is_empty() || self.content_formats.contains(&format)
+    }
+
+    /// Answers a request for the document, or returns None for other paths.
+    pub(crate) fn respond(&self, request: &CoAPRequest) -> Option<CoAPResponse> {
+        if request.get_path() != CAPABILITY_PATH {
+            return None;
+        }
+        let mut response = request.response.clone()?;
+        if *request.get_method() != Method::Get {
+            response.set_error(Status::MethodNotAllowed, "the capability document is read-only");
+            return Some(response);
+        }
+        response.message.set_content_format(ContentFormat::ApplicationCBOR);
+        response.message.payload = self.to_cbor();
+        Some(response)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::*;
+    use super::super::server;
+
+    #[test]
+    fn test_capabilities() {
+        let (port_tx, port_rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new().unwrap().block_on(async move {
+                let mut server = Server::new("127.0.0.1:0").unwrap();
+                server.set_memory_budget(MemoryBudget::new().with_max_observers(0));
+                server.expose_capabilities(vec![0, 60]);
+                server.set_capability_block_sizes(vec![1024, 512, 64, 512]);
+                port_tx.send(server.socket_addr().unwrap().port()).unwrap();
+                server.run(|req: CoAPRequest| async { req.response }).await.unwrap();
+            })
+        });
+        let client = CoAPClient::new(format!("127.0.0.1:{}", port_rx.recv().unwrap())).unwrap();
+        let capabilities = client.capabilities().unwrap();
+        assert_eq!(capabilities, Capabilities {
+            max_message_size: 65536,
+            content_formats: vec![0, 60],
+            block_sizes: vec![64, 512, 1024],
+            observe: false,
+        });
+        assert_eq!(Capabilities::from_cbor(&capabilities.to_cbor()).unwrap(), capabilities);
+        assert_eq!(capabilities.block_size(1000), Some(512));
+        assert_eq!(capabilities.block_size(32), None);
+        assert!(capabilities.supports_format(60) && !capabilities.supports_format(50));
+
+        // without the document exposed the handler answers
+        let server_port = server::test::spawn_server(|req: CoAPRequest| async { req.response }).recv().unwrap();
+        let client = CoAPClient::new(format!("127.0.0.1:{}", server_port)).unwrap();
+        assert!(client.capabilities().unwrap_err().to_string().contains("invalid capabilities"));
+    }
+}
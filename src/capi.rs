@@ -0,0 +1,273 @@
+//! A C API over the client, for firmware and tooling in other languages, e.g. Python through
+//! ctypes. Built with the `capi` feature.
+//!
+//! The header is generated with cbindgen from the configuration at the crate root:
+//!
+//! ```text
+//! cbindgen --config cbindgen.toml --crate coap --output include/coap.h
+//! ```
+//!
+//! and the library is linked as a shared or static library built with
+//! `cargo rustc --release --features capi --crate-type cdylib` (or `staticlib`).
+//!
+//! Responses are handed to a callback with the response code, e.g. `0x45` for 2.05, and the
+//! payload, which is only valid during the call. Functions return `COAP_OK` or a negative
+//! error code. A panic does not unwind into the caller but is returned as `COAP_ERR_PANIC`,
+//! or as null by the functions returning a pointer.
+
+#![allow(non_camel_case_types)]
+
+use std::ffi::CStr;
+use std::io::ErrorKind;
+use std::os::raw::{c_char, c_int, c_void};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+
+use super::client::{CoAPClient, ObservationHandle};
+use super::message::header::class_to_code;
+use super::message::packet::Packet;
+use super::message::request::{CoAPRequest, Method};
+use super::message::IsMessage;
+
+pub const COAP_OK: c_int = 0;
+/// A pointer was null or a string was not UTF-8.
+pub const COAP_ERR_INVALID_ARGUMENT: c_int = -1;
+/// The request could not be sent or its response received.
+pub const COAP_ERR_IO: c_int = -2;
+/// The request was not answered in time.
+pub const COAP_ERR_TIMEOUT: c_int = -3;
+/// The library panicked, which leaves the client usable but is a bug worth reporting.
+pub const COAP_ERR_PANIC: c_int = -4;
+
+/// Called with the user data, the response code and the payload of a response or
+/// notification.
+pub type coap_response_cb = extern "C" fn(user_data: *mut c_void, code: u8, payload: *const u8, len: usize);
+
+/// A client, created with `coap_client_new` and freed with `coap_client_free`.
+pub struct coap_client_t {
+    client: CoAPClient,
+}
+
+/// An observation, created with `coap_observe` and cancelled with `coap_observation_free`.
+pub struct coap_observation_t {
+    _handle: ObservationHandle,
+}
+
+// the user data is only handed back to the callback, whose thread safety is the caller's
+struct UserData(*mut c_void);
+
+unsafe impl Send for UserData {}
+
+/// Creates a client for the server at `addr`, e.g. `"192.0.2.1:5683"`, or returns null.
+///
+/// # Safety
+///
+/// `addr` must be a null-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn coap_client_new(addr: *const c_char) -> *mut coap_client_t {
+    guard(ptr::null_mut(), || {
+        let addr = match to_str(addr) {
+            Some(addr) => addr,
+            None => return ptr::null_mut(),
+        };
+        match CoAPClient::new(addr) {
+            Ok(client) => Box::into_raw(Box::new(coap_client_t { client })),
+            Err(_) => ptr::null_mut(),
+        }
+    })
+}
+
+/// Frees a client. Observations created with it keep running until freed.
+///
+/// # Safety
+///
+/// `client` must come from `coap_client_new` and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn coap_client_free(client: *mut coap_client_t) {
+    if !client.is_null() {
+        guard((), || drop(Box::from_raw(client)));
+    }
+}
+
+/// Fetches the resource at `path` and hands the response to `callback`.
+///
+/// # Safety
+///
+/// `client` must come from `coap_client_new` and `path` must be a null-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn coap_get(
+    client: *mut coap_client_t,
+    path: *const c_char,
+    callback: coap_response_cb,
+    user_data: *mut c_void,
+) -> c_int {
+    guard(COAP_ERR_PANIC, || request(client, Method::Get, path, Vec::new(), callback, user_data))
+}
+
+/// Writes `len` bytes of `payload` to the resource at `path` and hands the response to
+/// `callback`.
+///
+/// # Safety
+///
+/// `client` must come from `coap_client_new`, `path` must be a null-terminated string and
+/// `payload` must point to `len` bytes, or be null if `len` is 0.
+#[no_mangle]
+pub unsafe extern "C" fn coap_put(
+    client: *mut coap_client_t,
+    path: *const c_char,
+    payload: *const u8,
+    len: usize,
+    callback: coap_response_cb,
+    user_data: *mut c_void,
+) -> c_int {
+    guard(COAP_ERR_PANIC, || {
+        let payload = match len {
+            0 => Vec::new(),
+            _ if payload.is_null() => return COAP_ERR_INVALID_ARGUMENT,
+            _ => slice::from_raw_parts(payload, len).to_vec(),
+        };
+        request(client, Method::Put, path, payload, callback, user_data)
+    })
+}
+
+/// Observes the resource at `path`, handing the current representation and every
+/// notification to `callback`, which is called from a background thread. Returns null if
+/// the registration failed.
+///
+/// # Safety
+///
+/// `client` must come from `coap_client_new`, `path` must be a null-terminated string and
+/// `user_data` must stay valid until the observation is freed.
+#[no_mangle]
+pub unsafe extern "C" fn coap_observe(
+    client: *mut coap_client_t,
+    path: *const c_char,
+    callback: coap_response_cb,
+    user_data: *mut c_void,
+) -> *mut coap_observation_t {
+    guard(ptr::null_mut(), || {
+        let (client, path) = match (client.as_ref(), to_str(path)) {
+            (Some(client), Some(path)) => (client, path),
+            _ => return ptr::null_mut(),
+        };
+        let user_data = UserData(user_data);
+        let handle = client.client.observe(path, move |packet| {
+            let user_data = &user_data;
+            respond(&packet, callback, user_data.0);
+        });
+        match handle {
+            Ok(handle) => Box::into_raw(Box::new(coap_observation_t { _handle: handle })),
+            Err(_) => ptr::null_mut(),
+        }
+    })
+}
+
+/// Deregisters from the server and frees the observation.
+///
+/// # Safety
+///
+/// `observation` must come from `coap_observe` and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn coap_observation_free(observation: *mut coap_observation_t) {
+    if !observation.is_null() {
+        guard((), || drop(Box::from_raw(observation)));
+    }
+}
+
+unsafe fn request(
+    client: *mut coap_client_t,
+    method: Method,
+    path: *const c_char,
+    payload: Vec<u8>,
+    callback: coap_response_cb,
+    user_data: *mut c_void,
+) -> c_int {
+    let (client, path) = match (client.as_ref(), to_str(path)) {
+        (Some(client), Some(path)) => (client, path),
+        _ => return COAP_ERR_INVALID_ARGUMENT,
+    };
+    let mut request = CoAPRequest::new();
+    request.set_message_id(client.client.next_message_id());
+    request.set_token(client.client.next_token());
+    request.set_method(method);
+    request.set_path(path);
+    request.set_payload(payload);
+    match client.client.request(&mut request) {
+        Ok(response) => {
+            respond(&response.message, callback, user_data);
+            COAP_OK
+        }
+        Err(ref e) if e.kind() == ErrorKind::TimedOut || e.kind() == ErrorKind::WouldBlock => COAP_ERR_TIMEOUT,
+        Err(_) => COAP_ERR_IO,
+    }
+}
+
+// a panic must not unwind across the C boundary, which is undefined behavior
+fn guard<T, F: FnOnce() -> T>(fallback: T, body: F) -> T {
+    panic::catch_unwind(AssertUnwindSafe(body)).unwrap_or(fallback)
+}
+
+fn respond(packet: &Packet, callback: coap_response_cb, user_data: *mut c_void) {
+    let code = class_to_code(&packet.header.code);
+    callback(user_data, code, packet.payload.as_ptr(), packet.payload.len());
+}
+
+unsafe fn to_str<'a>(text: *const c_char) -> Option<&'a str> {
+    if text.is_null() {
+        return None;
+    }
+    CStr::from_ptr(text).to_str().ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::*;
+    use std::ffi::CString;
+
+    extern "C" fn collect(user_data: *mut c_void, code: u8, payload: *const u8, len: usize) {
+        let responses = unsafe { &mut *(user_data as *mut Vec<(u8, Vec<u8>)>) };
+        responses.push((code, unsafe { slice::from_raw_parts(payload, len) }.to_vec()));
+    }
+
+    #[test]
+    fn test_capi() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let tx = std::sync::Mutex::new(tx);
+        let server_port = server::test::spawn_server(move |req: CoAPRequest| {
+            let ids = (req.message.header.get_message_id(), req.message.get_token().clone());
+            tx.lock().unwrap().send(ids).unwrap();
+            async { req.response }
+        })
+        .recv()
+        .unwrap();
+        let addr = CString::new(format!("127.0.0.1:{}", server_port)).unwrap();
+        let path = CString::new("/sensors/temp").unwrap();
+        let mut responses: Vec<(u8, Vec<u8>)> = Vec::new();
+        let user_data = &mut responses as *mut _ as *mut c_void;
+        unsafe {
+            let client = coap_client_new(addr.as_ptr());
+            assert!(!client.is_null());
+            assert_eq!(coap_get(client, path.as_ptr(), collect, user_data), COAP_OK);
+            assert_eq!(coap_put(client, path.as_ptr(), b"21.5".as_ptr(), 4, collect, user_data), COAP_OK);
+            assert_eq!(coap_put(client, path.as_ptr(), ptr::null(), 4, collect, user_data), COAP_ERR_INVALID_ARGUMENT);
+            assert_eq!(coap_get(client, ptr::null(), collect, user_data), COAP_ERR_INVALID_ARGUMENT);
+            coap_client_free(client);
+            assert!(coap_client_new(ptr::null()).is_null());
+        }
+        // the echoing server answers 2.05 with the request payload
+        assert_eq!(responses, vec![(0x45, Vec::new()), (0x45, b"21.5".to_vec())]);
+        // every request has its own message ID and token
+        let (get, put) = (rx.recv().unwrap(), rx.recv().unwrap());
+        assert_ne!(get.0, put.0);
+        assert!(!get.1.is_empty());
+        assert_ne!(get.1, put.1);
+    }
+
+    #[test]
+    fn test_panic_guarded() {
+        assert_eq!(guard(COAP_ERR_PANIC, || -> c_int { panic!("bug") }), COAP_ERR_PANIC);
+        assert_eq!(guard(COAP_ERR_PANIC, || COAP_OK), COAP_OK);
+    }
+}
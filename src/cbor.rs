@@ -0,0 +1,290 @@
+//! Minimal CBOR (RFC 8949) encoding and decoding for the structures used by
+//! the security and content-format layers. Only definite-length items are supported.
+
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Integer(i64),
+    Bytes(Vec<u8>),
+    Text(String),
+    Array(Vec<Value>),
+    Map(Vec<(Value, Value)>),
+    Float(f64),
+    Bool(bool),
+    Null,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CborError {
+    /// The buffer ended inside an item starting at the offset.
+    Truncated { offset: usize },
+    /// The item at the offset uses a feature this decoder does not support.
+    Unsupported { offset: usize },
+    /// A text string at the offset is not valid UTF-8.
+    InvalidText { offset: usize },
+}
+
+impl fmt::Display for CborError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CborError::Truncated { offset } => write!(f, "truncated CBOR item at offset {}", offset),
+            CborError::Unsupported { offset } => write!(f, "unsupported CBOR item at offset {}", offset),
+            CborError::InvalidText { offset } => write!(f, "invalid CBOR text at offset {}", offset),
+        }
+    }
+}
+
+impl std::error::Error for CborError {}
+
+impl Value {
+    /// Appends the encoding of the value to the buffer.
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        match *self {
+            Value::Integer(n) if n >= 0 => encode_head(buf, 0, n as u64),
+            Value::Integer(n) => encode_head(buf, 1, (-1 - n) as u64),
+            Value::Bytes(ref bytes) => {
+                encode_head(buf, 2, bytes.len() as u64);
+                buf.extend_from_slice(bytes);
+            }
+            Value::Text(ref text) => {
+                encode_head(buf, 3, text.len() as u64);
+                buf.extend_from_slice(text.as_bytes());
+            }
+            Value::Array(ref items) => {
+                encode_head(buf, 4, items.len() as u64);
+                for item in items {
+                    item.encode(buf);
+                }
+            }
+            Value::Map(ref entries) => {
+                encode_head(buf, 5, entries.len() as u64);
+                for (key, value) in entries {
+                    key.encode(buf);
+                    value.encode(buf);
+                }
+            }
+            Value::Float(n) => {
+                buf.push(0xFB);
+                buf.extend_from_slice(&n.to_bits().to_be_bytes());
+            }
+            Value::Bool(false) => buf.push(0xF4),
+            Value::Bool(true) => buf.push(0xF5),
+            Value::Null => buf.push(0xF6),
+        }
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.encode(&mut buf);
+        buf
+    }
+
+    pub fn as_integer(&self) -> Option<i64> {
+        match *self {
+            Value::Integer(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&Vec<u8>> {
+        match *self {
+            Value::Bytes(ref bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    pub fn as_text(&self) -> Option<&str> {
+        match *self {
+            Value::Text(ref text) => Some(text),
+            _ => None,
+        }
+    }
+
+    /// Looks up an integer key in a map.
+    pub fn get(&self, key: i64) -> Option<&Value> {
+        match *self {
+            Value::Map(ref entries) => entries
+                .iter()
+                .find(|(k, _)| *k == Value::Integer(key))
+                .map(|(_, v)| v),
+            _ => None,
+        }
+    }
+}
+
+fn encode_head(buf: &mut Vec<u8>, major: u8, value: u64) {
+    let major = major << 5;
+    if value < 24 {
+        buf.push(major | value as u8);
+    } else if value <= 0xFF {
+        buf.push(major | 24);
+        buf.push(value as u8);
+    } else if value <= 0xFFFF {
+        buf.push(major | 25);
+        buf.extend_from_slice(&(value as u16).to_be_bytes());
+    } else if value <= 0xFFFF_FFFF {
+        buf.push(major | 26);
+        buf.extend_from_slice(&(value as u32).to_be_bytes());
+    } else {
+        buf.push(major | 27);
+        buf.extend_from_slice(&value.to_be_bytes());
+    }
+}
+
+/// Encodes a sequence of values (RFC 8742) back to back.
+pub fn encode_sequence(values: &[Value]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for value in values {
+        value.encode(&mut buf);
+    }
+    buf
+}
+
+/// Decodes the item at the start of the buffer, returning it with the number of bytes used.
+pub fn decode(buf: &[u8]) -> Result<(Value, usize), CborError> {
+    decode_at(buf, 0)
+}
+
+/// Decodes a CBOR sequence that must span the whole buffer.
+pub fn decode_sequence(buf: &[u8]) -> Result<Vec<Value>, CborError> {
+    let mut values = Vec::new();
+    let mut idx = 0;
+    while idx < buf.len() {
+        let (value, end) = decode_at(buf, idx)?;
+        values.push(value);
+        idx = end;
+    }
+    Ok(values)
+}
+
+fn decode_head(buf: &[u8], offset: usize) -> Result<(u8, u64, usize), CborError> {
+    let initial = *buf.get(offset).ok_or(CborError::Truncated { offset })?;
+    let major = initial >> 5;
+    let info = initial & 0x1F;
+    let width = match info {
+        0..=23 => return Ok((major, info as u64, offset + 1)),
+        24 => 1,
+        25 => 2,
+        26 => 4,
+        27 => 8,
+        _ => return Err(CborError::Unsupported { offset }),
+    };
+
+    let end = offset + 1 + width;
+    if end > buf.len() {
+        return Err(CborError::Truncated { offset });
+    }
+    let value = buf[offset + 1..end].iter().fold(0u64, |acc, &b| acc << 8 | b as u64);
+    Ok((major, value, end))
+}
+
+/// Converts an IEEE 754 half-precision float (RFC 8949 Appendix D).
+fn decode_half(half: u16) -> f64 {
+    let exponent = (half >> 10) & 0x1F;
+    let mantissa = (half & 0x3FF) as f64;
+    let value = match exponent {
+        0 => mantissa * 2f64.powi(-24),
+        31 if mantissa == 0.0 => f64::INFINITY,
+        31 => f64::NAN,
+        _ => (mantissa + 1024.0) * 2f64.powi(exponent as i32 - 25),
+    };
+    if half & 0x8000 != 0 { -value } else { value }
+}
+
+fn decode_at(buf: &[u8], offset: usize) -> Result<(Value, usize), CborError> {
+    let (major, argument, mut idx) = decode_head(buf, offset)?;
+    match major {
+        0 | 1 if argument > i64::MAX as u64 => Err(CborError::Unsupported { offset }),
+        0 => Ok((Value::Integer(argument as i64), idx)),
+        1 => Ok((Value::Integer(-1 - argument as i64), idx)),
+        2 | 3 => {
+            let end = idx
+                .checked_add(argument as usize)
+                .filter(|&end| end <= buf.len())
+                .ok_or(CborError::Truncated { offset })?;
+            let bytes = buf[idx..end].to_vec();
+            if major == 2 {
+                return Ok((Value::Bytes(bytes), end));
+            }
+            match String::from_utf8(bytes) {
+                Ok(text) => Ok((Value::Text(text), end)),
+                Err(_) => Err(CborError::InvalidText { offset }),
+            }
+        }
+        4 => {
+            let mut items = Vec::new();
+            for _ in 0..argument {
+                let (item, end) = decode_at(buf, idx)?;
+                items.push(item);
+                idx = end;
+            }
+            Ok((Value::Array(items), idx))
+        }
+        5 => {
+            let mut entries = Vec::new();
+            for _ in 0..argument {
+                let (key, end) = decode_at(buf, idx)?;
+                let (value, end) = decode_at(buf, end)?;
+                entries.push((key, value));
+                idx = end;
+            }
+            Ok((Value::Map(entries), idx))
+        }
+        7 => match buf[offset] {
+            0xF4 => Ok((Value::Bool(false), idx)),
+            0xF5 => Ok((Value::Bool(true), idx)),
+            0xF6 => Ok((Value::Null, idx)),
+            0xF9 => Ok((Value::Float(decode_half(argument as u16)), idx)),
+            0xFA => Ok((Value::Float(f32::from_bits(argument as u32) as f64), idx)),
+            0xFB => Ok((Value::Float(f64::from_bits(argument)), idx)),
+            _ => Err(CborError::Unsupported { offset }),
+        },
+        _ => Err(CborError::Unsupported { offset }),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(Value::Integer(0).to_vec(), vec![0x00]);
+        assert_eq!(Value::Integer(-24).to_vec(), vec![0x37]);
+        assert_eq!(Value::Integer(500).to_vec(), vec![0x19, 0x01, 0xF4]);
+        assert_eq!(Value::Bytes(vec![1, 2]).to_vec(), vec![0x42, 0x01, 0x02]);
+        assert_eq!(Value::Text("a".to_string()).to_vec(), vec![0x61, 0x61]);
+        assert_eq!(
+            Value::Map(vec![(Value::Integer(4), Value::Bool(true))]).to_vec(),
+            vec![0xA1, 0x04, 0xF5]
+        );
+    }
+
+    #[test]
+    fn test_decode_roundtrip() {
+        let value = Value::Array(vec![
+            Value::Integer(-100000),
+            Value::Bytes(vec![0; 300]),
+            Value::Map(vec![(Value::Text("k".to_string()), Value::Null)]),
+        ]);
+        let bytes = value.to_vec();
+        assert_eq!(decode(&bytes).unwrap(), (value.clone(), bytes.len()));
+
+        let float = Value::Float(-2.5);
+        assert_eq!(decode(&float.to_vec()).unwrap(), (float, 9));
+        assert_eq!(decode(&[0xF9, 0x3C, 0x00]).unwrap().0, Value::Float(1.0));
+        assert_eq!(decode(&[0xF9, 0xC4, 0x00]).unwrap().0, Value::Float(-4.0));
+
+        let sequence = encode_sequence(&[value.clone(), Value::Integer(3)]);
+        assert_eq!(decode_sequence(&sequence).unwrap(), vec![value, Value::Integer(3)]);
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert_eq!(decode(&[]).unwrap_err(), CborError::Truncated { offset: 0 });
+        assert_eq!(decode(&[0x43, 0x01]).unwrap_err(), CborError::Truncated { offset: 0 });
+        assert_eq!(decode(&[0x5F]).unwrap_err(), CborError::Unsupported { offset: 0 });
+        assert_eq!(decode(&[0x61, 0xFF]).unwrap_err(), CborError::InvalidText { offset: 0 });
+    }
+}
@@ -0,0 +1,331 @@
+use std::io::{ErrorKind, Result};
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use log::*;
+
+const POLL_INTERVAL: u64 = 50; // 50ms
+
+/// How a `ChaosProxy` disturbs the datagrams it relays. Every rate is a probability
+/// between 0 and 1 applied to each datagram independently.
+#[derive(Clone, Debug)]
+pub struct ChaosConfig {
+    pub drop_rate: f64,
+    pub duplicate_rate: f64,
+    pub delay_rate: f64,
+    /// Delayed datagrams are held for a uniformly chosen time up to this bound.
+    pub max_delay: Duration,
+    /// A reordered datagram is sent after the one following it.
+    pub reorder_rate: f64,
+    /// Seed of the random generator, so that a run can be reproduced.
+    pub seed: u64,
+}
+
+impl Default for ChaosConfig {
+    fn default() -> ChaosConfig {
+        ChaosConfig {
+            drop_rate: 0.0,
+            duplicate_rate: 0.0,
+            delay_rate: 0.0,
+            max_delay: Duration::from_millis(0),
+            reorder_rate: 0.0,
+            seed: 0x5EED,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ChaosStats {
+    pub relayed: u64,
+    pub dropped: u64,
+    pub duplicated: u64,
+    pub delayed: u64,
+    pub reordered: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    relayed: AtomicU64,
+    dropped: AtomicU64,
+    duplicated: AtomicU64,
+    delayed: AtomicU64,
+    reordered: AtomicU64,
+}
+
+/// A UDP relay between a client and a server injecting loss, duplication, delay and
+/// reordering, for testing applications under lossy network conditions.
+///
+/// Point the client at `local_addr` instead of the server; responses are relayed back to
+/// the client that sent the latest datagram. Works for plain CoAP and DTLS alike.
+pub struct ChaosProxy {
+    local_addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    counters: Arc<Counters>,
+    threads: Vec<thread::JoinHandle<()>>,
+}
+
+impl ChaosProxy {
+    /// Start relaying datagrams received on `listen_addr` to `server_addr`.
+    pub fn start<A: ToSocketAddrs, B: ToSocketAddrs>(
+        listen_addr: A,
+        server_addr: B,
+        config: ChaosConfig,
+    ) -> Result<ChaosProxy> {
+        let downstream = UdpSocket::bind(listen_addr)?;
+        let server_addr = server_addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| std::io::Error::other("no address"))?;
+        let upstream = UdpSocket::bind(match server_addr {
+            SocketAddr::V4(_) => "0.0.0.0:0",
+            SocketAddr::V6(_) => ":::0",
+        })?;
+        downstream.set_read_timeout(Some(Duration::from_millis(POLL_INTERVAL)))?;
+        upstream.set_read_timeout(Some(Duration::from_millis(POLL_INTERVAL)))?;
+
+        let local_addr = downstream.local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let counters = Arc::new(Counters::default());
+        let client_addr = Arc::new(Mutex::new(None));
+
+        let requests = Direction {
+            from: downstream.try_clone()?,
+            to: upstream.try_clone()?,
+            rng: Rng::new(config.seed),
+            config: config.clone(),
+            counters: counters.clone(),
+            held: None,
+        };
+        let responses = Direction {
+            from: upstream,
+            to: downstream,
+            rng: Rng::new(config.seed ^ 0x9E37_79B9_7F4A_7C15),
+            config,
+            counters: counters.clone(),
+            held: None,
+        };
+
+        let threads = vec![
+            {
+                let stop = stop.clone();
+                let client_addr = client_addr.clone();
+                thread::spawn(move || {
+                    requests.run(&stop, |src| {
+                        *client_addr.lock().unwrap() = Some(src);
+                        Some(server_addr)
+                    })
+                })
+            },
+            {
+                let stop = stop.clone();
+                thread::spawn(move || responses.run(&stop, |_| *client_addr.lock().unwrap()))
+            },
+        ];
+
+        Ok(ChaosProxy {
+            local_addr,
+            stop,
+            counters,
+            threads,
+        })
+    }
+
+    /// The address clients should send to.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn stats(&self) -> ChaosStats {
+        ChaosStats {
+            relayed: self.counters.relayed.load(Ordering::Relaxed),
+            dropped: self.counters.dropped.load(Ordering::Relaxed),
+            duplicated: self.counters.duplicated.load(Ordering::Relaxed),
+            delayed: self.counters.delayed.load(Ordering::Relaxed),
+            reordered: self.counters.reordered.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Drop for ChaosProxy {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Relays datagrams in one direction.
+struct Direction {
+    from: UdpSocket,
+    to: UdpSocket,
+    rng: Rng,
+    config: ChaosConfig,
+    counters: Arc<Counters>,
+    held: Option<(Vec<u8>, SocketAddr)>,
+}
+
+impl Direction {
+    fn run<F: FnMut(SocketAddr) -> Option<SocketAddr>>(mut self, stop: &AtomicBool, mut destination: F) {
+        let mut buf = [0; 2048];
+        while !stop.load(Ordering::Relaxed) {
+            let (nread, src) = match self.from.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                    // a reordered datagram is not held back forever
+                    if let Some((datagram, dest)) = self.held.take() {
+                        self.send(&datagram, dest);
+                    }
+                    continue;
+                }
+                Err(e) => {
+                    warn!("chaos relay receive failed {}", e);
+                    continue;
+                }
+            };
+            if let Some(dest) = destination(src) {
+                self.relay(buf[..nread].to_vec(), dest);
+            }
+        }
+    }
+
+    fn relay(&mut self, datagram: Vec<u8>, dest: SocketAddr) {
+        if self.rng.chance(self.config.drop_rate) {
+            self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        let copies = if self.rng.chance(self.config.duplicate_rate) {
+            self.counters.duplicated.fetch_add(1, Ordering::Relaxed);
+            2
+        } else {
+            1
+        };
+
+        if self.held.is_none() && self.rng.chance(self.config.reorder_rate) {
+            self.counters.reordered.fetch_add(1, Ordering::Relaxed);
+            self.held = Some((datagram, dest));
+            return;
+        }
+
+        for _ in 0..copies {
+            if self.rng.chance(self.config.delay_rate) {
+                self.counters.delayed.fetch_add(1, Ordering::Relaxed);
+                let delay = self.config.max_delay.mul_f64(self.rng.next_f64());
+                self.send_later(datagram.clone(), dest, delay);
+            } else {
+                self.send(&datagram, dest);
+            }
+        }
+        if let Some((held, dest)) = self.held.take() {
+            self.send(&held, dest);
+        }
+    }
+
+    fn send(&self, datagram: &[u8], dest: SocketAddr) {
+        match self.to.send_to(datagram, dest) {
+            Ok(_) => {
+                self.counters.relayed.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(e) => warn!("chaos relay send failed {}", e),
+        }
+    }
+
+    fn send_later(&self, datagram: Vec<u8>, dest: SocketAddr, delay: Duration) {
+        let socket = match self.to.try_clone() {
+            Ok(socket) => socket,
+            Err(e) => return warn!("chaos relay send failed {}", e),
+        };
+        let counters = self.counters.clone();
+        thread::spawn(move || {
+            thread::sleep(delay);
+            if socket.send_to(&datagram, dest).is_ok() {
+                counters.relayed.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+    }
+}
+
+/// A xorshift64* generator; statistical quality is ample for fault injection.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        Rng(if seed == 0 { 0x5EED } else { seed })
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn chance(&mut self, rate: f64) -> bool {
+        rate > 0.0 && self.next_f64() < rate
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::*;
+
+    fn exchange(proxy: &ChaosProxy, count: usize) -> usize {
+        let client = CoAPClient::new(proxy.local_addr()).unwrap();
+        client.set_receive_timeout(Some(Duration::from_millis(200))).unwrap();
+        let mut answered = 0;
+        for i in 0..count {
+            let mut request = CoAPRequest::new();
+            request.set_message_id(i as u16);
+            request.set_path("/chaos");
+            client.send(&request).unwrap();
+            if client.receive().is_ok() {
+                answered += 1;
+            }
+        }
+        answered
+    }
+
+    #[test]
+    fn test_rng_reproducible() {
+        let mut a = Rng::new(42);
+        let mut b = Rng::new(42);
+        let first: Vec<u64> = (0..4).map(|_| a.next_u64()).collect();
+        let second: Vec<u64> = (0..4).map(|_| b.next_u64()).collect();
+        assert_eq!(first, second);
+        assert!(!a.chance(0.0));
+        assert!(a.chance(1.0));
+    }
+
+    #[test]
+    fn test_chaos_proxy() {
+        let server_port = server::test::spawn_server(|req: CoAPRequest| async { req.response }).recv().unwrap();
+
+        let transparent = ChaosProxy::start("127.0.0.1:0", ("127.0.0.1", server_port), ChaosConfig::default()).unwrap();
+        assert_eq!(exchange(&transparent, 3), 3);
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(transparent.stats().relayed, 6);
+
+        let lossy = ChaosConfig { drop_rate: 1.0, ..ChaosConfig::default() };
+        let lossy = ChaosProxy::start("127.0.0.1:0", ("127.0.0.1", server_port), lossy).unwrap();
+        assert_eq!(exchange(&lossy, 2), 0);
+        assert_eq!(lossy.stats().dropped, 2);
+
+        let slow = ChaosConfig {
+            delay_rate: 1.0,
+            max_delay: Duration::from_millis(50),
+            duplicate_rate: 1.0,
+            ..ChaosConfig::default()
+        };
+        let slow = ChaosProxy::start("127.0.0.1:0", ("127.0.0.1", server_port), slow).unwrap();
+        assert_eq!(exchange(&slow, 1), 1);
+        assert!(slow.stats().duplicated >= 1);
+        assert!(slow.stats().delayed >= 2);
+    }
+}
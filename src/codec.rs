@@ -0,0 +1,801 @@
+//! A registry of payload codecs keyed by Content-Format number, converting payloads to and
+//! from the CBOR data model.
+//!
+//! The registry backs `CoAPRequest::decode_payload`, `CoAPResponse::decode_payload` and
+//! their `encode_payload` counterparts, as well as `filter::transcode_with`, so a format
+//! registered once can be read, written and transcoded everywhere. Handlers decoding with
+//! `CoAPRequest::decode_body` answer 4.15 for the formats it lacks. `to_value` brings any
+//! `Serialize` type into the data model and `from_value` back out of it.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str;
+use std::sync::{Arc, Mutex};
+
+use serde::de::{self, DeserializeOwned, DeserializeSeed, IntoDeserializer, Visitor};
+use serde::de::value::{MapDeserializer, SeqDeserializer};
+use serde::ser;
+use serde::Serialize;
+
+use super::cbor::{self, Value};
+use super::json;
+use super::message::packet::ContentFormat;
+use super::multipart;
+use super::message::response::ContentError;
+
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+// SenML labels and their CBOR representation (RFC 8428 §6)
+const SENML_LABELS: &[(&str, i64)] = &[
+    ("bver", -1), ("bn", -2), ("bt", -3), ("bu", -4), ("bv", -5), ("bs", -6),
+    ("n", 0), ("u", 1), ("v", 2), ("vs", 3), ("vb", 4), ("s", 5), ("t", 6), ("ut", 7), ("vd", 8),
+];
+
+/// Converts payloads of one Content-Format to and from the CBOR data model.
+pub trait Codec: Send + Sync {
+    fn encode(&self, value: &Value) -> Result<Vec<u8>, ContentError>;
+    fn decode(&self, payload: &[u8]) -> Result<Value, ContentError>;
+}
+
+struct FnCodec<E, D> {
+    encode: E,
+    decode: D,
+}
+
+impl<E, D> Codec for FnCodec<E, D>
+where
+    E: Fn(&Value) -> Result<Vec<u8>, ContentError> + Send + Sync,
+    D: Fn(&[u8]) -> Result<Value, ContentError> + Send + Sync,
+{
+    fn encode(&self, value: &Value) -> Result<Vec<u8>, ContentError> {
+        (self.encode)(value)
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<Value, ContentError> {
+        (self.decode)(payload)
+    }
+}
+
+/// Codecs by Content-Format number. Clones share the same codecs.
+///
+/// The default registry knows text/plain, application/octet-stream, application/json,
+/// application/cbor, application/multipart-core, application/link-format and the JSON and
+/// CBOR SenML and SenSML formats.
+#[derive(Clone)]
+pub struct CodecRegistry {
+    codecs: Arc<Mutex<HashMap<u32, Arc<dyn Codec>>>>,
+}
+
+impl Default for CodecRegistry {
+    fn default() -> CodecRegistry {
+        let registry = CodecRegistry::empty();
+        registry.register_fn(ContentFormat::TextPlain as u32, encode_text, |payload| {
+            Ok(Value::Text(text(payload)?.to_string()))
+        });
+        registry.register_fn(ContentFormat::ApplicationOctetStream as u32, encode_bytes, |payload| {
+            Ok(Value::Bytes(payload.to_vec()))
+        });
+        registry.register_fn(ContentFormat::ApplicationLinkFormat as u32, encode_links, decode_links);
+        for &format in &[ContentFormat::ApplicationJSON, ContentFormat::ApplicationSenmlJSON,
+                         ContentFormat::ApplicationSensmlJSON] {
+            registry.register_fn(format as u32, |value| Ok(json::to_string(value).into_bytes()), |payload| {
+                json::parse(text(payload)?).map_err(ContentError::InvalidJson)
+            });
+        }
+        registry.register_fn(ContentFormat::ApplicationCBOR as u32, |value| Ok(value.to_vec()), decode_cbor);
+        registry.register_fn(ContentFormat::ApplicationMultipartCore as u32, |value| {
+            let payload = value.to_vec();
+            multipart::decode(&payload)?;
+            Ok(payload)
+        }, |payload| {
+            multipart::decode(payload)?;
+            decode_cbor(payload)
+        });
+        for &format in &[ContentFormat::ApplicationSenmlCBOR, ContentFormat::ApplicationSensmlCBOR] {
+            registry.register_fn(format as u32, |value| Ok(senml_labels(value, true).to_vec()), |payload| {
+                Ok(senml_labels(&decode_cbor(payload)?, false))
+            });
+        }
+        registry
+    }
+}
+
+impl CodecRegistry {
+    /// Creates a registry without any codec.
+    pub fn empty() -> CodecRegistry {
+        CodecRegistry { codecs: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    /// Registers the codec of a Content-Format, replacing any previous one.
+    pub fn register<C: Codec + 'static>(&self, format: u32, codec: C) {
+        self.codecs.lock().unwrap().insert(format, Arc::new(codec));
+    }
+
+    /// Registers a codec made of an encoding and a decoding function.
+    pub fn register_fn<E, D>(&self, format: u32, encode: E, decode: D)
+    where
+        E: Fn(&Value) -> Result<Vec<u8>, ContentError> + Send + Sync + 'static,
+        D: Fn(&[u8]) -> Result<Value, ContentError> + Send + Sync + 'static,
+    {
+        self.register(format, FnCodec { encode, decode });
+    }
+
+    pub fn unregister(&self, format: u32) {
+        self.codecs.lock().unwrap().remove(&format);
+    }
+
+    pub fn supports(&self, format: u32) -> bool {
+        self.codecs.lock().unwrap().contains_key(&format)
+    }
+
+    /// The registered Content-Format numbers, in ascending order.
+    pub fn formats(&self) -> Vec<u32> {
+        let mut formats: Vec<u32> = self.codecs.lock().unwrap().keys().cloned().collect();
+        formats.sort();
+        formats
+    }
+
+    pub fn encode(&self, format: u32, value: &Value) -> Result<Vec<u8>, ContentError> {
+        self.codec(format)?.encode(value)
+    }
+
+    pub fn decode(&self, format: u32, payload: &[u8]) -> Result<Value, ContentError> {
+        self.codec(format)?.decode(payload)
+    }
+
+    /// Converts a payload from one Content-Format to another.
+    pub fn transcode(&self, from: u32, to: u32, payload: &[u8]) -> Result<Vec<u8>, ContentError> {
+        let value = self.decode(from, payload)?;
+        self.encode(to, &value)
+    }
+
+    // the codec is cloned out so user codecs run without the lock held
+    fn codec(&self, format: u32) -> Result<Arc<dyn Codec>, ContentError> {
+        self.codecs
+            .lock()
+            .unwrap()
+            .get(&format)
+            .cloned()
+            .ok_or(ContentError::UnsupportedContentFormat(format))
+    }
+}
+
+/// Converts a value into the CBOR data model, so that any codec encodes it. Structs and maps
+/// become maps, sequences and tuples arrays, `None` and `()` null, and enum variants carrying
+/// data a map from the variant name to the data, as serde_json does.
+pub fn to_value<T: Serialize + ?Sized>(value: &T) -> Result<Value, ContentError> {
+    value.serialize(ValueSerializer)
+}
+
+impl ser::Error for ContentError {
+    fn custom<M: fmt::Display>(msg: M) -> ContentError {
+        ContentError::Invalid(msg.to_string())
+    }
+}
+
+struct ValueSerializer;
+
+impl ser::Serializer for ValueSerializer {
+    type Ok = Value;
+    type Error = ContentError;
+    type SerializeSeq = Collector;
+    type SerializeTuple = Collector;
+    type SerializeTupleStruct = Collector;
+    type SerializeTupleVariant = Collector;
+    type SerializeMap = Collector;
+    type SerializeStruct = Collector;
+    type SerializeStructVariant = Collector;
+
+    fn serialize_bool(self, v: bool) -> Result<Value, ContentError> {
+        Ok(Value::Bool(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Value, ContentError> {
+        Ok(Value::Integer(v.into()))
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Value, ContentError> {
+        Ok(Value::Integer(v.into()))
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Value, ContentError> {
+        Ok(Value::Integer(v.into()))
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Value, ContentError> {
+        Ok(Value::Integer(v))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Value, ContentError> {
+        Ok(Value::Integer(v.into()))
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Value, ContentError> {
+        Ok(Value::Integer(v.into()))
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Value, ContentError> {
+        Ok(Value::Integer(v.into()))
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Value, ContentError> {
+        if v > i64::MAX as u64 {
+            return Err(ContentError::Invalid(format!("integer {} out of range", v)));
+        }
+        Ok(Value::Integer(v as i64))
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<Value, ContentError> {
+        Ok(Value::Float(v.into()))
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<Value, ContentError> {
+        Ok(Value::Float(v))
+    }
+
+    fn serialize_char(self, v: char) -> Result<Value, ContentError> {
+        Ok(Value::Text(v.to_string()))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Value, ContentError> {
+        Ok(Value::Text(v.to_string()))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Value, ContentError> {
+        Ok(Value::Bytes(v.to_vec()))
+    }
+
+    fn serialize_none(self) -> Result<Value, ContentError> {
+        Ok(Value::Null)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Value, ContentError> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Value, ContentError> {
+        Ok(Value::Null)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Value, ContentError> {
+        Ok(Value::Null)
+    }
+
+    fn serialize_unit_variant(self, _name: &'static str, _index: u32, variant: &'static str) -> Result<Value, ContentError> {
+        Ok(Value::Text(variant.to_string()))
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _name: &'static str, value: &T) -> Result<Value, ContentError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Value, ContentError> {
+        Ok(Value::Map(vec![(Value::Text(variant.to_string()), to_value(value)?)]))
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Collector, ContentError> {
+        Ok(Collector::new(None))
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Collector, ContentError> {
+        Ok(Collector::new(None))
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, _len: usize) -> Result<Collector, ContentError> {
+        Ok(Collector::new(None))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Collector, ContentError> {
+        Ok(Collector::new(Some(variant)))
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Collector, ContentError> {
+        Ok(Collector::new(None))
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Collector, ContentError> {
+        Ok(Collector::new(None))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Collector, ContentError> {
+        Ok(Collector::new(Some(variant)))
+    }
+}
+
+/// Collects the items of an array or the entries of a map, wrapped in a map from the variant
+/// name for enum variants.
+struct Collector {
+    variant: Option<&'static str>,
+    items: Vec<Value>,
+    entries: Vec<(Value, Value)>,
+    key: Option<Value>,
+}
+
+impl Collector {
+    fn new(variant: Option<&'static str>) -> Collector {
+        Collector { variant, items: Vec::new(), entries: Vec::new(), key: None }
+    }
+
+    fn tagged(self, value: Value) -> Value {
+        match self.variant {
+            Some(variant) => Value::Map(vec![(Value::Text(variant.to_string()), value)]),
+            None => value,
+        }
+    }
+
+    fn array(mut self) -> Result<Value, ContentError> {
+        let items = std::mem::take(&mut self.items);
+        Ok(self.tagged(Value::Array(items)))
+    }
+
+    fn map(mut self) -> Result<Value, ContentError> {
+        let entries = std::mem::take(&mut self.entries);
+        Ok(self.tagged(Value::Map(entries)))
+    }
+
+    fn field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), ContentError> {
+        self.entries.push((Value::Text(key.to_string()), to_value(value)?));
+        Ok(())
+    }
+}
+
+impl ser::SerializeSeq for Collector {
+    type Ok = Value;
+    type Error = ContentError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), ContentError> {
+        self.items.push(to_value(value)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Value, ContentError> {
+        self.array()
+    }
+}
+
+impl ser::SerializeTuple for Collector {
+    type Ok = Value;
+    type Error = ContentError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), ContentError> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Value, ContentError> {
+        self.array()
+    }
+}
+
+impl ser::SerializeTupleStruct for Collector {
+    type Ok = Value;
+    type Error = ContentError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), ContentError> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Value, ContentError> {
+        self.array()
+    }
+}
+
+impl ser::SerializeTupleVariant for Collector {
+    type Ok = Value;
+    type Error = ContentError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), ContentError> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Value, ContentError> {
+        self.array()
+    }
+}
+
+impl ser::SerializeMap for Collector {
+    type Ok = Value;
+    type Error = ContentError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), ContentError> {
+        self.key = Some(to_value(key)?);
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), ContentError> {
+        let key = self.key.take().ok_or_else(|| ContentError::Invalid("map value without a key".to_string()))?;
+        self.entries.push((key, to_value(value)?));
+        Ok(())
+    }
+
+    fn end(self) -> Result<Value, ContentError> {
+        self.map()
+    }
+}
+
+impl ser::SerializeStruct for Collector {
+    type Ok = Value;
+    type Error = ContentError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), ContentError> {
+        self.field(key, value)
+    }
+
+    fn end(self) -> Result<Value, ContentError> {
+        self.map()
+    }
+}
+
+impl ser::SerializeStructVariant for Collector {
+    type Ok = Value;
+    type Error = ContentError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), ContentError> {
+        self.field(key, value)
+    }
+
+    fn end(self) -> Result<Value, ContentError> {
+        self.map()
+    }
+}
+
+/// Converts a value of the CBOR data model into a type, the inverse of `to_value`: e.g. the
+/// JSON text of a `Serialize` type, decoded with `json::parse`, becomes the type again.
+pub fn from_value<T: DeserializeOwned>(value: Value) -> Result<T, ContentError> {
+    T::deserialize(value)
+}
+
+impl de::Error for ContentError {
+    fn custom<M: fmt::Display>(msg: M) -> ContentError {
+        ContentError::Invalid(msg.to_string())
+    }
+}
+
+impl<'de> IntoDeserializer<'de, ContentError> for Value {
+    type Deserializer = Value;
+
+    fn into_deserializer(self) -> Value {
+        self
+    }
+}
+
+impl<'de> de::Deserializer<'de> for Value {
+    type Error = ContentError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ContentError> {
+        match self {
+            Value::Integer(n) => visitor.visit_i64(n),
+            Value::Bytes(bytes) => visitor.visit_byte_buf(bytes),
+            Value::Text(text) => visitor.visit_string(text),
+            Value::Array(items) => visitor.visit_seq(SeqDeserializer::new(items.into_iter())),
+            Value::Map(entries) => visitor.visit_map(MapDeserializer::new(entries.into_iter())),
+            Value::Float(n) => visitor.visit_f64(n),
+            Value::Bool(b) => visitor.visit_bool(b),
+            Value::Null => visitor.visit_unit(),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ContentError> {
+        match self {
+            Value::Null => visitor.visit_none(),
+            value => visitor.visit_some(value),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, ContentError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    // unit variants are their name, other variants a map from the name to the data
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, ContentError> {
+        match self {
+            Value::Text(variant) => visitor.visit_enum(IntoDeserializer::<ContentError>::into_deserializer(variant)),
+            Value::Map(mut entries) if entries.len() == 1 => {
+                let (variant, data) = entries.remove(0);
+                visitor.visit_enum(Variant { variant, data })
+            }
+            other => Err(ContentError::Invalid(format!("expected an enum variant, found {:?}", other))),
+        }
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf unit
+        unit_struct seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+/// An enum variant carrying data, as `to_value` represents it.
+struct Variant {
+    variant: Value,
+    data: Value,
+}
+
+impl<'de> de::EnumAccess<'de> for Variant {
+    type Error = ContentError;
+    type Variant = Value;
+
+    fn variant_seed<S: DeserializeSeed<'de>>(self, seed: S) -> Result<(S::Value, Value), ContentError> {
+        Ok((seed.deserialize(self.variant)?, self.data))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for Value {
+    type Error = ContentError;
+
+    fn unit_variant(self) -> Result<(), ContentError> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<S: DeserializeSeed<'de>>(self, seed: S) -> Result<S::Value, ContentError> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, ContentError> {
+        de::Deserializer::deserialize_seq(self, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(self, _fields: &'static [&'static str], visitor: V) -> Result<V::Value, ContentError> {
+        de::Deserializer::deserialize_map(self, visitor)
+    }
+}
+
+fn text(payload: &[u8]) -> Result<&str, ContentError> {
+    let payload = if payload.starts_with(UTF8_BOM) { &payload[UTF8_BOM.len()..] } else { payload };
+    str::from_utf8(payload).map_err(ContentError::InvalidText)
+}
+
+fn encode_text(value: &Value) -> Result<Vec<u8>, ContentError> {
+    match value {
+        Value::Text(text) => Ok(text.clone().into_bytes()),
+        _ => Err(ContentError::Invalid("text/plain payloads must be text".to_string())),
+    }
+}
+
+fn encode_bytes(value: &Value) -> Result<Vec<u8>, ContentError> {
+    match value {
+        Value::Bytes(bytes) => Ok(bytes.clone()),
+        _ => Err(ContentError::Invalid("application/octet-stream payloads must be bytes".to_string())),
+    }
+}
+
+fn decode_cbor(payload: &[u8]) -> Result<Value, ContentError> {
+    let (value, used) = cbor::decode(payload).map_err(ContentError::InvalidCbor)?;
+    if used != payload.len() {
+        return Err(ContentError::TrailingBytes);
+    }
+    Ok(value)
+}
+
+/// Maps the labels of the records of a SenML pack between their JSON names and their CBOR
+/// integers, leaving other labels as they are.
+fn senml_labels(pack: &Value, to_cbor: bool) -> Value {
+    let records = match pack {
+        Value::Array(records) => records,
+        _ => return pack.clone(),
+    };
+    let records = records.iter().map(|record| match record {
+        Value::Map(fields) => Value::Map(fields.iter().map(|(label, value)| {
+            let mapped = SENML_LABELS.iter().find_map(|&(name, number)| match label {
+                Value::Text(text) if to_cbor && text == name => Some(Value::Integer(number)),
+                Value::Integer(n) if !to_cbor && *n == number => Some(Value::Text(name.to_string())),
+                _ => None,
+            });
+            (mapped.unwrap_or_else(|| label.clone()), value.clone())
+        }).collect()),
+        other => other.clone(),
+    });
+    Value::Array(records.collect())
+}
+
+/// Parses a link-format document (RFC 6690) into an array of maps, holding the target under
+/// "href" and each attribute under its name; attributes without a value are `true`.
+fn decode_links(payload: &[u8]) -> Result<Value, ContentError> {
+    let text = text(payload)?;
+    let invalid = |reason: &str| ContentError::Invalid(format!("invalid link-format payload: {}", reason));
+    let mut links = Vec::new();
+    for link in split_unquoted(text, ',') {
+        let link = link.trim();
+        if link.is_empty() {
+            continue;
+        }
+        let mut parts = split_unquoted(link, ';').into_iter();
+        let target = parts.next().unwrap_or("").trim();
+        if !target.starts_with('<') || !target.ends_with('>') {
+            return Err(invalid("missing link target"));
+        }
+        let mut fields = vec![(Value::Text("href".to_string()), Value::Text(target[1..target.len() - 1].to_string()))];
+        for attribute in parts {
+            let attribute = attribute.trim();
+            let (name, value) = match attribute.find('=') {
+                Some(idx) => {
+                    let value = attribute[idx + 1..].trim();
+                    let value = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(value);
+                    (&attribute[..idx], Value::Text(value.to_string()))
+                }
+                None => (attribute, Value::Bool(true)),
+            };
+            if name.is_empty() {
+                return Err(invalid("empty attribute name"));
+            }
+            fields.push((Value::Text(name.trim().to_string()), value));
+        }
+        links.push(Value::Map(fields));
+    }
+    Ok(Value::Array(links))
+}
+
+fn encode_links(value: &Value) -> Result<Vec<u8>, ContentError> {
+    let invalid = || ContentError::Invalid("link-format payloads must be arrays of maps with an href".to_string());
+    let links = match value {
+        Value::Array(links) => links,
+        _ => return Err(invalid()),
+    };
+    let mut encoded = Vec::new();
+    for link in links {
+        let fields = match link {
+            Value::Map(fields) => fields,
+            _ => return Err(invalid()),
+        };
+        let href = fields
+            .iter()
+            .find(|(name, _)| name.as_text() == Some("href"))
+            .and_then(|(_, value)| value.as_text())
+            .ok_or_else(invalid)?;
+        let mut text = format!("<{}>", href);
+        for (name, value) in fields {
+            match (name.as_text(), value) {
+                (Some("href"), _) => (),
+                (Some(name), Value::Bool(true)) => text.push_str(&format!(";{}", name)),
+                (Some(name), Value::Integer(n)) => text.push_str(&format!(";{}={}", name, n)),
+                (Some(name), Value::Text(value)) => text.push_str(&format!(";{}=\"{}\"", name, value)),
+                _ => return Err(invalid()),
+            }
+        }
+        encoded.push(text);
+    }
+    Ok(encoded.join(",").into_bytes())
+}
+
+fn split_unquoted(text: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut quoted = false;
+    let mut start = 0;
+    for (idx, c) in text.char_indices() {
+        if c == '"' {
+            quoted = !quoted;
+        } else if c == separator && !quoted {
+            parts.push(&text[start..idx]);
+            start = idx + 1;
+        }
+    }
+    parts.push(&text[start..]);
+    parts
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_builtin_codecs() {
+        let registry = CodecRegistry::default();
+        let json = ContentFormat::ApplicationJSON as u32;
+        let cbor = ContentFormat::ApplicationCBOR as u32;
+        let cbor_payload = registry.transcode(json, cbor, br#"{"t":1}"#).unwrap();
+        assert_eq!(cbor_payload, vec![0xA1, 0x61, b't', 0x01]);
+        assert_eq!(registry.transcode(cbor, json, &cbor_payload).unwrap(), br#"{"t":1}"#.to_vec());
+
+        let senml = registry
+            .transcode(ContentFormat::ApplicationSenmlJSON as u32, ContentFormat::ApplicationSenmlCBOR as u32,
+                       br#"[{"n":"temp","v":21}]"#)
+            .unwrap();
+        assert_eq!(senml, vec![0x81, 0xA2, 0x00, 0x64, b't', b'e', b'm', b'p', 0x02, 0x15]);
+        let decoded = registry.decode(ContentFormat::ApplicationSenmlCBOR as u32, &senml).unwrap();
+        assert_eq!(decoded, json::parse(r#"[{"n":"temp","v":21}]"#).unwrap());
+
+        let links = br#"</sensors/temp>;rt="temperature-c";if="sensor";obs,</fw>;ct=60"#;
+        let format = ContentFormat::ApplicationLinkFormat as u32;
+        let value = registry.decode(format, links).unwrap();
+        match value {
+            Value::Array(ref links) => {
+                assert_eq!(links.len(), 2);
+                assert_eq!(links[0], Value::Map(vec![
+                    (Value::Text("href".to_string()), Value::Text("/sensors/temp".to_string())),
+                    (Value::Text("rt".to_string()), Value::Text("temperature-c".to_string())),
+                    (Value::Text("if".to_string()), Value::Text("sensor".to_string())),
+                    (Value::Text("obs".to_string()), Value::Bool(true)),
+                ]));
+            }
+            _ => panic!("unexpected value {:?}", value),
+        }
+        assert_eq!(registry.encode(format, &value).unwrap(),
+                   br#"</sensors/temp>;rt="temperature-c";if="sensor";obs,</fw>;ct="60""#.to_vec());
+        assert!(registry.decode(format, b"/no-brackets").is_err());
+
+        assert_eq!(registry.decode(ContentFormat::TextPlain as u32, b"\xEF\xBB\xBFhi").unwrap(),
+                   Value::Text("hi".to_string()));
+        assert_eq!(registry.decode(cbor, &[0x01, 0x02]), Err(ContentError::TrailingBytes));
+        assert_eq!(registry.decode(11050, b"x"), Err(ContentError::UnsupportedContentFormat(11050)));
+    }
+
+    #[test]
+    fn test_to_value() {
+        #[derive(Debug, PartialEq, Serialize, serde::Deserialize)]
+        enum Mode {
+            Off,
+            Dimmed(u8),
+            Scene { name: String },
+        }
+
+        #[derive(Debug, PartialEq, Serialize, serde::Deserialize)]
+        struct Lamp {
+            on: bool,
+            modes: Vec<Mode>,
+            label: Option<String>,
+        }
+
+        let lamp = Lamp { on: true, modes: vec![Mode::Off, Mode::Dimmed(40)], label: None };
+        let registry = CodecRegistry::default();
+        let json = registry.encode(ContentFormat::ApplicationJSON as u32, &to_value(&lamp).unwrap()).unwrap();
+        assert_eq!(json, br#"{"on":true,"modes":["Off",{"Dimmed":40}],"label":null}"#.to_vec());
+        assert!(to_value(&u64::MAX).is_err());
+
+        let lamp = Lamp { on: false, modes: vec![Mode::Scene { name: "dusk".to_string() }], label: Some("hall".to_string()) };
+        assert_eq!(from_value::<Lamp>(to_value(&lamp).unwrap()).unwrap(), lamp);
+        assert_eq!(from_value::<Lamp>(json::parse(std::str::from_utf8(&json).unwrap()).unwrap()).unwrap().modes,
+                   vec![Mode::Off, Mode::Dimmed(40)]);
+        assert!(from_value::<Lamp>(Value::Array(Vec::new())).is_err());
+    }
+
+    #[test]
+    fn test_custom_codec() {
+        let registry = CodecRegistry::empty();
+        assert!(registry.formats().is_empty());
+        // a made-up comma separated list of integers
+        registry.register_fn(65000, |value| match value {
+            Value::Array(items) => Ok(items
+                .iter()
+                .map(|item| item.as_integer().map(|n| n.to_string()))
+                .collect::<Option<Vec<String>>>()
+                .ok_or_else(|| ContentError::Invalid("not an integer".to_string()))?
+                .join(",")
+                .into_bytes()),
+            _ => Err(ContentError::Invalid("not an array".to_string())),
+        }, |payload| {
+            text(payload)?
+                .split(',')
+                .map(|n| n.parse().map(Value::Integer).map_err(|_| ContentError::Invalid(n.to_string())))
+                .collect::<Result<Vec<Value>, ContentError>>()
+                .map(Value::Array)
+        });
+        let shared = registry.clone();
+        assert!(shared.supports(65000));
+        assert_eq!(shared.decode(65000, b"1,2,3").unwrap(),
+                   Value::Array(vec![Value::Integer(1), Value::Integer(2), Value::Integer(3)]));
+        assert_eq!(shared.encode(65000, &Value::Array(vec![Value::Integer(4)])).unwrap(), b"4".to_vec());
+        registry.unregister(65000);
+        assert!(!shared.supports(65000));
+    }
+}
@@ -0,0 +1,409 @@
+//! Configuring clients and servers from TOML or JSON files rather than wiring code, e.g. for
+//! gateway deployments. Built with the `config` feature.
+//!
+//! ```text
+//! [server]
+//! bind = ["0.0.0.0:5683", "[::]:5683"]
+//! handler_timeout_ms = 2000
+//!
+//! [server.acls."admin"]
+//! networks = ["10.0.0.0/8"]
+//! loopback = true
+//!
+//! [client]
+//! peer = "gw.example:5684"
+//! transport = "dtls"
+//! cache_entries = 256
+//! dtls = { psk_identity = "gw-1", psk_key_env = "GW_KEY" }
+//! transmission = { ack_timeout_ms = 3000, max_retransmit = 6 }
+//! ```
+//!
+//! Credentials are referenced rather than embedded: a PSK key is read from a file or an
+//! environment variable. Omitted settings keep the defaults of the client and the server.
+
+use std::collections::HashMap;
+use std::fs;
+use std::future::Future;
+use std::io::{Error, ErrorKind, Result};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use super::acl::Acl;
+use super::cache::ResponseCache;
+use super::client::{CoAPClient, TransmissionParameters};
+use super::context::Transport;
+use super::diag::Diagnostics;
+use super::dtls_client::{DTLSClientBuilder, DTLSCoAPClient};
+use super::message::response::CoAPResponse;
+use super::server::{HandlerTimeout, Server};
+
+/// The configuration of a process, with a server, a client or both.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct CoapConfig {
+    pub server: Option<ServerConfig>,
+    pub client: Option<ClientConfig>,
+}
+
+impl CoapConfig {
+    pub fn from_toml(text: &str) -> Result<CoapConfig> {
+        toml::from_str(text).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+
+    pub fn from_json(text: &str) -> Result<CoapConfig> {
+        serde_json::from_str(text).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+
+    /// Reads a configuration file, as JSON if its extension is `.json` and as TOML otherwise.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<CoapConfig> {
+        let text = fs::read_to_string(&path)?;
+        match path.as_ref().extension().and_then(|extension| extension.to_str()) {
+            Some("json") => Self::from_json(&text),
+            _ => Self::from_toml(&text),
+        }
+    }
+}
+
+/// The configuration of a server, see `Server::from_config`.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// The addresses to listen on, the first one being the primary listener.
+    pub bind: Vec<String>,
+    /// How long the handler may take for a request, see `HandlerTimeout`.
+    pub handler_timeout_ms: Option<u64>,
+    /// The handler timeouts of single resources, by path.
+    pub resource_timeouts_ms: HashMap<String, u64>,
+    /// The random delay of the replies to multicast requests.
+    pub multicast_leisure_ms: Option<u64>,
+    /// The path the per-peer statistics are served at.
+    pub peer_stats_path: Option<String>,
+    /// The peers allowed to read the diagnostic resources under `/.diag`, which are only
+    /// served when set.
+    pub diagnostics: Option<AclConfig>,
+    /// The peers allowed to reach the resources at and below each path.
+    pub acls: HashMap<String, AclConfig>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            bind: vec![String::from("0.0.0.0:5683")],
+            handler_timeout_ms: None,
+            resource_timeouts_ms: HashMap::new(),
+            multicast_leisure_ms: None,
+            peer_stats_path: None,
+            diagnostics: None,
+            acls: HashMap::new(),
+        }
+    }
+}
+
+/// An ACL, see `Acl`.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AclConfig {
+    /// The networks allowed, e.g. `10.0.0.0/8` or `fd00::/8`. A bare address allows a host.
+    pub networks: Vec<String>,
+    /// Whether peers on the loopback interface are allowed.
+    pub loopback: bool,
+    /// The identities allowed, e.g. PSK identities.
+    pub identities: Vec<String>,
+}
+
+impl AclConfig {
+    pub fn to_acl(&self) -> Result<Acl> {
+        let mut acl = Acl::new();
+        if self.loopback {
+            acl = acl.allow_loopback();
+        }
+        for network in self.networks.iter() {
+            let (addr, prefix_len) = parse_network(network)?;
+            acl = acl.allow_network(addr, prefix_len);
+        }
+        for identity in self.identities.iter() {
+            acl = acl.allow_identity(identity.as_bytes());
+        }
+        Ok(acl)
+    }
+}
+
+/// The configuration of a client, see `CoAPClient::from_config`.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientConfig {
+    /// The server, e.g. `192.0.2.1:5683`.
+    pub peer: String,
+    /// The source address, by default any address of the peer's family.
+    pub bind: Option<String>,
+    /// `udp`, the default, or `dtls`.
+    pub transport: Transport,
+    /// The credentials of a DTLS client.
+    pub dtls: Option<DtlsConfig>,
+    pub transmission: TransmissionConfig,
+    /// The entries of a cache for the responses to GET requests, which is only kept when set.
+    pub cache_entries: Option<usize>,
+}
+
+/// The DTLS credentials of a client. Without a PSK the server is authenticated by its
+/// certificate.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DtlsConfig {
+    pub psk_identity: Option<String>,
+    /// A file holding the PSK key. A trailing line break, as editors append, is not part of
+    /// the key.
+    pub psk_key_file: Option<PathBuf>,
+    /// An environment variable holding the PSK key.
+    pub psk_key_env: Option<String>,
+    /// A PEM file of CA certificates trusted in addition to the system's.
+    pub ca_file: Option<PathBuf>,
+    /// The name the server's certificate is verified against.
+    pub server_name: Option<String>,
+}
+
+impl DtlsConfig {
+    fn psk(&self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let identity = match self.psk_identity {
+            Some(ref identity) => identity.as_bytes().to_vec(),
+            None => return Ok(None),
+        };
+        let key = match (&self.psk_key_file, &self.psk_key_env) {
+            (Some(path), _) => {
+                let mut key = fs::read(path)?;
+                if key.ends_with(b"\n") {
+                    key.pop();
+                    if key.ends_with(b"\r") {
+                        key.pop();
+                    }
+                }
+                key
+            }
+            (None, Some(name)) => std::env::var(name)
+                .map_err(|_| Error::new(ErrorKind::NotFound, format!("{} is not set", name)))?
+                .into_bytes(),
+            (None, None) => return Err(Error::new(ErrorKind::InvalidInput, "PSK identity without a key")),
+        };
+        Ok(Some((identity, key)))
+    }
+}
+
+/// Transmission parameters, see `TransmissionParameters`.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TransmissionConfig {
+    pub ack_timeout_ms: Option<u64>,
+    pub ack_random_factor: Option<f64>,
+    pub max_retransmit: Option<u32>,
+    pub confirmable: Option<bool>,
+    pub probing_rate: Option<u32>,
+}
+
+impl TransmissionConfig {
+    pub fn to_parameters(&self) -> TransmissionParameters {
+        let mut transmission = TransmissionParameters::default();
+        if let Some(timeout) = self.ack_timeout_ms {
+            transmission = transmission.with_timeout(Duration::from_millis(timeout));
+        }
+        if let Some(factor) = self.ack_random_factor {
+            transmission = transmission.with_ack_random_factor(factor);
+        }
+        if let Some(max_retransmit) = self.max_retransmit {
+            transmission = transmission.with_max_retransmit(max_retransmit);
+        }
+        if let Some(confirmable) = self.confirmable {
+            transmission = transmission.with_confirmable(confirmable);
+        }
+        if let Some(probing_rate) = self.probing_rate {
+            transmission = transmission.with_probing_rate(probing_rate);
+        }
+        transmission
+    }
+}
+
+impl<'a, HandlerRet> Server<'a, HandlerRet>
+where
+    HandlerRet: Future<Output = Option<CoAPResponse>>,
+{
+    /// Creates a server listening on the addresses of the configuration, set up as it says.
+    pub fn from_config(config: &ServerConfig) -> Result<Server<'a, HandlerRet>> {
+        let (primary, others) = match config.bind.split_first() {
+            Some(bind) => bind,
+            None => return Err(Error::new(ErrorKind::InvalidInput, "no address to bind")),
+        };
+        let mut server = Server::new(primary.as_str())?;
+        for addr in others {
+            server.add_listener(addr.as_str())?;
+        }
+        if let Some(timeout) = config.handler_timeout_ms {
+            server.set_handler_timeout(HandlerTimeout::default().with_timeout(Duration::from_millis(timeout)));
+        }
+        for (path, timeout) in config.resource_timeouts_ms.iter() {
+            let timeout = HandlerTimeout::default().with_timeout(Duration::from_millis(*timeout));
+            server.set_resource_handler_timeout(path, timeout);
+        }
+        if let Some(leisure) = config.multicast_leisure_ms {
+            server.set_multicast_leisure(Duration::from_millis(leisure));
+        }
+        if let Some(ref path) = config.peer_stats_path {
+            server.expose_peer_stats(path);
+        }
+        if let Some(ref acl) = config.diagnostics {
+            server.enable_diagnostics(Diagnostics::new(acl.to_acl()?));
+        }
+        for (path, acl) in config.acls.iter() {
+            server.set_resource_acl(path, acl.to_acl()?);
+        }
+        Ok(server)
+    }
+}
+
+impl CoAPClient {
+    /// Creates a UDP client for the peer of the configuration, set up as it says.
+    pub fn from_config(config: &ClientConfig) -> Result<CoAPClient> {
+        if config.transport != Transport::Udp {
+            return Err(Error::new(ErrorKind::InvalidInput, "not a UDP client, see DTLSCoAPClient::from_config"));
+        }
+        let mut client = match config.bind {
+            Some(ref bind) => CoAPClient::new_with_specific_source(bind.as_str(), config.peer.as_str())?,
+            None => CoAPClient::new(config.peer.as_str())?,
+        };
+        client.set_transmission_parameters(config.transmission.to_parameters());
+        if let Some(entries) = config.cache_entries {
+            client.set_response_cache(Some(ResponseCache::new(entries)));
+        }
+        Ok(client)
+    }
+}
+
+impl DTLSCoAPClient {
+    /// Connects a DTLS client to the peer of the configuration with its credentials.
+    /// Transmission parameters and caching do not apply to DTLS clients.
+    pub fn from_config(config: &ClientConfig) -> Result<DTLSCoAPClient> {
+        if config.transport != Transport::Dtls {
+            return Err(Error::new(ErrorKind::InvalidInput, "not a DTLS client, see CoAPClient::from_config"));
+        }
+        let dtls = config.dtls.clone().unwrap_or_default();
+        let mut builder = DTLSClientBuilder::new()?;
+        if let Some((identity, key)) = dtls.psk()? {
+            builder = builder.with_psk(&identity, &key);
+        }
+        if let Some(ref path) = dtls.ca_file {
+            builder = builder.with_ca_file(path)?;
+        }
+        if let Some(ref server_name) = dtls.server_name {
+            builder = builder.with_server_name(server_name);
+        }
+        match config.bind {
+            Some(ref bind) => builder.connect_with_specific_source(bind.as_str(), config.peer.as_str()),
+            None => builder.connect(config.peer.as_str()),
+        }
+    }
+}
+
+fn parse_network(network: &str) -> Result<(IpAddr, u8)> {
+    let invalid = || Error::new(ErrorKind::InvalidInput, format!("invalid network {}", network));
+    let (addr, prefix_len) = match network.find('/') {
+        Some(idx) => (&network[..idx], Some(&network[idx + 1..])),
+        None => (network, None),
+    };
+    let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+    let max_len = if addr.is_ipv4() { 32 } else { 128 };
+    let prefix_len = match prefix_len {
+        Some(prefix_len) => prefix_len.parse().ok().filter(|len| *len <= max_len).ok_or_else(invalid)?,
+        None => max_len,
+    };
+    Ok((addr, prefix_len))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::*;
+    use super::super::context::Transport;
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_config() {
+        let config = CoapConfig::from_toml(
+            r#"
+            [server]
+            bind = ["127.0.0.1:0"]
+            handler_timeout_ms = 500
+
+            [server.acls."admin"]
+            networks = ["10.0.0.0/8"]
+
+            [client]
+            peer = "127.0.0.1:5683"
+            cache_entries = 16
+            transmission = { ack_timeout_ms = 500, max_retransmit = 1 }
+            "#,
+        )
+        .unwrap();
+        let client_config = config.client.clone().unwrap();
+        assert_eq!(client_config.transport, Transport::Udp);
+        assert_eq!(client_config.transmission.to_parameters().timeout, Duration::from_millis(500));
+        assert_eq!(client_config.transmission.to_parameters().max_retransmit, 1);
+
+        let json = CoapConfig::from_json(
+            r#"{"server": {"bind": ["127.0.0.1:0"], "handler_timeout_ms": 500, "acls": {"admin": {"networks": ["10.0.0.0/8"]}}},
+                "client": {"peer": "127.0.0.1:5683", "cache_entries": 16, "transmission": {"ack_timeout_ms": 500, "max_retransmit": 1}}}"#,
+        )
+        .unwrap();
+        assert_eq!(json, config);
+        assert!(CoapConfig::from_toml("[server]\nport = 5683").is_err());
+        assert!(CoapConfig::from_json(r#"{"client": {"transport": "quic"}}"#).is_err());
+
+        let acl = AclConfig { networks: vec!["fd00::/8".to_string(), "192.0.2.1".to_string()], ..Default::default() };
+        assert_eq!(
+            acl.to_acl().unwrap(),
+            Acl::new().allow_network("fd00::".parse().unwrap(), 8).allow_network("192.0.2.1".parse().unwrap(), 32)
+        );
+        let invalid = AclConfig { networks: vec!["10.0.0.0/33".to_string()], ..Default::default() };
+        assert!(invalid.to_acl().is_err());
+
+        let (tx, rx) = mpsc::channel();
+        let server_config = config.server.clone().unwrap();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new().unwrap().block_on(async move {
+                let mut server = Server::from_config(&server_config).unwrap();
+                tx.send(server.socket_addr().unwrap().port()).unwrap();
+                server.run(|request: CoAPRequest| async move { request.response }).await.unwrap();
+            })
+        });
+        let server_port = rx.recv().unwrap();
+
+        let client_config = ClientConfig { peer: format!("127.0.0.1:{}", server_port), ..client_config };
+        let client = CoAPClient::from_config(&client_config).unwrap();
+        let mut request = CoAPRequest::new();
+        request.set_path("/status");
+        assert_eq!(*client.request(&mut request).unwrap().get_status(), Status::Content);
+        assert!(client.cache_stats().is_some());
+        // the loopback peer is not in the ACL of the admin resources
+        let mut request = CoAPRequest::new();
+        request.set_path("/admin/reboot");
+        assert_eq!(*client.request(&mut request).unwrap().get_status(), Status::Forbidden);
+
+        assert!(DTLSCoAPClient::from_config(&client_config).is_err());
+        let psk_without_key = ClientConfig {
+            transport: Transport::Dtls,
+            dtls: Some(DtlsConfig { psk_identity: Some("gw-1".to_string()), ..Default::default() }),
+            ..client_config
+        };
+        assert_eq!(DTLSCoAPClient::from_config(&psk_without_key).err().unwrap().kind(), ErrorKind::InvalidInput);
+
+        let key_file = std::env::temp_dir().join(format!("coap-psk-{}", std::process::id()));
+        fs::write(&key_file, b"secret\n").unwrap();
+        let dtls = DtlsConfig {
+            psk_identity: Some("gw-1".to_string()),
+            psk_key_file: Some(key_file.clone()),
+            ..Default::default()
+        };
+        assert_eq!(dtls.psk().unwrap(), Some((b"gw-1".to_vec(), b"secret".to_vec())));
+        fs::remove_file(key_file).unwrap();
+    }
+}
@@ -0,0 +1,196 @@
+//! Congestion control of confirmable exchanges, selectable per client.
+//!
+//! A `CongestionController` decides how long the client waits for each transmission of a
+//! request and how many exchanges may be outstanding at once, learning from the round-trip
+//! times of acknowledged exchanges. `Rfc7252` is the default binary exponential backoff;
+//! `Cocoa` estimates the retransmission timeout from measured round trips. Either, or an
+//! experimental implementation, is installed with `CoAPClient::set_congestion_controller`.
+
+use std::time::{Duration, Instant};
+
+// a timeout never backs off beyond this (CoCoA §4.2.2)
+const MAX_RTO: Duration = Duration::from_secs(32);
+
+/// The congestion state of a client, shared by its exchanges.
+pub trait CongestionController: Send {
+    /// An exchange was acknowledged `rtt` after its first transmission, which was
+    /// retransmitted `retransmissions` times.
+    fn on_ack(&mut self, rtt: Duration, retransmissions: u32);
+
+    /// A transmission went unacknowledged until its timeout.
+    fn on_timeout(&mut self);
+
+    /// How long to wait for an acknowledgement after the transmission following
+    /// `retransmissions` retransmissions, given the ACK_TIMEOUT of the transmission
+    /// parameters.
+    fn next_rto(&mut self, ack_timeout: Duration, retransmissions: u32) -> Duration;
+
+    /// Whether another exchange may start while `outstanding` ones are in flight (NSTART).
+    /// A client always starts an exchange when none is outstanding.
+    fn can_send(&self, outstanding: usize) -> bool;
+}
+
+/// The fixed timeouts of RFC 7252 §4.2: ACK_TIMEOUT doubled after each retransmission, one
+/// exchange at a time.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Rfc7252;
+
+impl CongestionController for Rfc7252 {
+    fn on_ack(&mut self, _rtt: Duration, _retransmissions: u32) {}
+
+    fn on_timeout(&mut self) {}
+
+    fn next_rto(&mut self, ack_timeout: Duration, retransmissions: u32) -> Duration {
+        ack_timeout * 2u32.saturating_pow(retransmissions)
+    }
+
+    fn can_send(&self, outstanding: usize) -> bool {
+        outstanding < 1
+    }
+}
+
+/// CoAP Simple Congestion Control/Advanced (draft-ietf-core-cocoa).
+///
+/// Round trips of exchanges acknowledged without retransmission update a strong estimator,
+/// those acknowledged after one or two retransmissions a weak one, and both feed the
+/// retransmission timeout, which starts at ACK_TIMEOUT. The timeout backs off by a variable
+/// factor: tripled when below 1s, multiplied by 1.5 above 3s, doubled otherwise. An
+/// estimate that has not been updated for a while ages towards the defaults. The client
+/// dithers the timeout by the ACK_RANDOM_FACTOR of the request, like the default timeouts.
+#[derive(Clone, Debug, Default)]
+pub struct Cocoa {
+    strong: Option<Estimator>,
+    weak: Option<Estimator>,
+    rto: Option<Duration>,
+    updated: Option<Instant>,
+    // the ACK_TIMEOUT of the latest exchange, the timeout before any estimate
+    initial_rto: Option<Duration>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Estimator {
+    srtt: f64,
+    rttvar: f64,
+}
+
+impl Estimator {
+    // RFC 6298 with the given variance factor K
+    fn update(estimator: &mut Option<Estimator>, rtt: f64, k: f64) -> f64 {
+        let estimate = match *estimator {
+            Some(mut estimate) => {
+                estimate.rttvar = 0.75 * estimate.rttvar + 0.25 * (estimate.srtt - rtt).abs();
+                estimate.srtt = 0.875 * estimate.srtt + 0.125 * rtt;
+                estimate
+            }
+            None => Estimator { srtt: rtt, rttvar: rtt / 2.0 },
+        };
+        *estimator = Some(estimate);
+        estimate.srtt + k * estimate.rttvar
+    }
+}
+
+impl Cocoa {
+    pub fn new() -> Cocoa {
+        Cocoa::default()
+    }
+
+    /// The current retransmission timeout, `None` before the first round trip is measured.
+    pub fn rto(&self) -> Option<Duration> {
+        self.rto
+    }
+
+    fn age(&mut self, ack_timeout: Duration) {
+        let (rto, updated) = match (self.rto, self.updated) {
+            (Some(rto), Some(updated)) => (rto, updated),
+            _ => return,
+        };
+        let idle = updated.elapsed();
+        let aged = if rto < Duration::from_secs(1) && idle > rto * 16 {
+            (Duration::from_secs(1) + rto * 2) / 3
+        } else if rto > Duration::from_secs(3) && idle > rto * 4 {
+            (ack_timeout + rto) / 2
+        } else {
+            return;
+        };
+        self.rto = Some(aged);
+        self.updated = Some(Instant::now());
+    }
+}
+
+impl CongestionController for Cocoa {
+    fn on_ack(&mut self, rtt: Duration, retransmissions: u32) {
+        let rtt = rtt.as_secs_f64();
+        let current = self.rto.or(self.initial_rto).map_or(2.0, |rto| rto.as_secs_f64());
+        let rto = match retransmissions {
+            0 => 0.5 * Estimator::update(&mut self.strong, rtt, 4.0) + 0.5 * current,
+            1 | 2 => 0.25 * Estimator::update(&mut self.weak, rtt, 1.0) + 0.75 * current,
+            // the round trip is too ambiguous to measure anything
+            _ => return,
+        };
+        self.rto = Some(Duration::from_secs_f64(rto).min(MAX_RTO));
+        self.updated = Some(Instant::now());
+    }
+
+    fn on_timeout(&mut self) {}
+
+    fn next_rto(&mut self, ack_timeout: Duration, retransmissions: u32) -> Duration {
+        self.initial_rto = Some(ack_timeout);
+        self.age(ack_timeout);
+        let rto = self.rto.unwrap_or(ack_timeout);
+        let factor = if rto < Duration::from_secs(1) {
+            3.0
+        } else if rto > Duration::from_secs(3) {
+            1.5
+        } else {
+            2.0
+        };
+        let backoff = rto.as_secs_f64() * f64::powi(factor, retransmissions.min(16) as i32);
+        Duration::from_secs_f64(backoff).min(MAX_RTO.max(rto))
+    }
+
+    fn can_send(&self, outstanding: usize) -> bool {
+        outstanding < 1
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn assert_secs(duration: Duration, secs: f64) {
+        assert!((duration.as_secs_f64() - secs).abs() < 1e-6, "{:?} != {}s", duration, secs);
+    }
+
+    #[test]
+    fn test_cocoa() {
+        let ack_timeout = Duration::from_secs(2);
+        let mut cocoa = Cocoa::new();
+        assert_eq!(cocoa.next_rto(ack_timeout, 0), ack_timeout);
+        assert_eq!(cocoa.next_rto(ack_timeout, 2), Duration::from_secs(8));
+
+        // strong: 0.5 * (100ms + 4 * 50ms) + 0.5 * 2s
+        cocoa.on_ack(Duration::from_millis(100), 0);
+        assert_secs(cocoa.rto().unwrap(), 1.15);
+        assert_secs(cocoa.next_rto(ack_timeout, 1), 2.3);
+
+        // weak: 0.25 * (800ms + 400ms) + 0.75 * 1150ms
+        cocoa.on_ack(Duration::from_millis(800), 1);
+        assert_secs(cocoa.rto().unwrap(), 1.1625);
+        cocoa.on_ack(Duration::from_secs(20), 3);
+        assert_secs(cocoa.rto().unwrap(), 1.1625);
+
+        for _ in 0..8 {
+            cocoa.on_ack(Duration::from_millis(10), 0);
+        }
+        let rto = cocoa.rto().unwrap();
+        assert!(rto < Duration::from_secs(1));
+        // below a second, the timeout triples
+        assert_secs(cocoa.next_rto(ack_timeout, 1), rto.as_secs_f64() * 3.0);
+        assert_eq!(cocoa.next_rto(ack_timeout, 10), MAX_RTO);
+
+        let mut rfc = Rfc7252;
+        rfc.on_ack(Duration::from_millis(10), 0);
+        assert_eq!(rfc.next_rto(ack_timeout, 3), Duration::from_secs(16));
+        assert!(rfc.can_send(0) && !rfc.can_send(1));
+    }
+}
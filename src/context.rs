@@ -0,0 +1,146 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use serde::Deserialize;
+
+use super::datagram::DatagramInfo;
+
+/// The transport a request arrived on.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    #[default]
+    Udp,
+    Dtls,
+    Tcp,
+}
+
+/// Everything known about a request besides its message: the peer, the transport and its
+/// security identity, the route parameters and values attached by middleware.
+#[derive(Clone)]
+pub struct RequestContext {
+    pub peer: Option<SocketAddr>,
+    pub transport: Transport,
+    /// The authenticated identity of the peer, e.g. its PSK identity or OSCORE Sender ID.
+    pub identity: Option<Vec<u8>>,
+    /// The network metadata of the datagram that carried the request, when received by a
+    /// server.
+    pub datagram: Option<DatagramInfo>,
+    /// The trace ID the request carried, when the server extracts them, see the `trace`
+    /// module.
+    pub trace_id: Option<Vec<u8>>,
+    params: HashMap<String, String>,
+    extensions: HashMap<String, Arc<dyn Any + Send + Sync>>,
+}
+
+impl RequestContext {
+    pub fn new(peer: Option<SocketAddr>, transport: Transport) -> RequestContext {
+        RequestContext {
+            peer,
+            transport,
+            identity: None,
+            datagram: None,
+            trace_id: None,
+            params: HashMap::new(),
+            extensions: HashMap::new(),
+        }
+    }
+
+    /// Matches a path against a route pattern such as `sensors/{id}/value`, recording the
+    /// parameters on success. Leading and trailing slashes are ignored.
+    pub fn match_route(&mut self, pattern: &str, path: &str) -> bool {
+        let pattern: Vec<&str> = pattern.trim_matches('/').split('/').collect();
+        let path: Vec<&str> = path.trim_matches('/').split('/').collect();
+        if pattern.len() != path.len() {
+            return false;
+        }
+
+        let mut params = HashMap::new();
+        for (expected, actual) in pattern.iter().zip(path.iter()) {
+            if expected.starts_with('{') && expected.ends_with('}') && expected.len() > 2 {
+                params.insert(expected[1..expected.len() - 1].to_string(), actual.to_string());
+            } else if expected != actual {
+                return false;
+            }
+        }
+        self.params.extend(params);
+        true
+    }
+
+    /// Returns a route parameter recorded by `match_route` or `set_param`.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(name).map(|value| value.as_str())
+    }
+
+    pub fn set_param(&mut self, name: &str, value: &str) {
+        self.params.insert(name.to_string(), value.to_string());
+    }
+
+    /// Attaches a value for later handlers, replacing any value under the same key.
+    pub fn insert<T: Any + Send + Sync>(&mut self, key: &str, value: T) {
+        self.extensions.insert(key.to_string(), Arc::new(value));
+    }
+
+    /// Returns the value attached under the key if it has the requested type.
+    pub fn get<T: Any + Send + Sync>(&self, key: &str) -> Option<&T> {
+        self.extensions.get(key).and_then(|value| value.downcast_ref())
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        self.extensions.remove(key);
+    }
+}
+
+impl Default for RequestContext {
+    fn default() -> RequestContext {
+        RequestContext::new(None, Transport::Udp)
+    }
+}
+
+impl fmt::Debug for RequestContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut keys: Vec<&String> = self.extensions.keys().collect();
+        keys.sort();
+        f.debug_struct("RequestContext")
+            .field("peer", &self.peer)
+            .field("transport", &self.transport)
+            .field("identity", &self.identity)
+            .field("datagram", &self.datagram)
+            .field("trace_id", &self.trace_id)
+            .field("params", &self.params)
+            .field("extensions", &keys)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_match_route() {
+        let mut context = RequestContext::default();
+        assert!(!context.match_route("sensors/{id}", "sensors/4/value"));
+        assert!(!context.match_route("actuators/{id}", "sensors/4"));
+        assert_eq!(context.param("id"), None);
+
+        assert!(context.match_route("/sensors/{id}/value", "sensors/4/value"));
+        assert_eq!(context.param("id"), Some("4"));
+    }
+
+    #[test]
+    fn test_extensions() {
+        let mut context = RequestContext::default();
+        context.insert("user", String::from("alice"));
+        assert_eq!(context.get::<String>("user").map(|s| s.as_str()), Some("alice"));
+        assert_eq!(context.get::<u32>("user"), None);
+
+        let cloned = context.clone();
+        context.remove("user");
+        assert!(context.get::<String>("user").is_none());
+        assert!(cloned.get::<String>("user").is_some());
+    }
+}
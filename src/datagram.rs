@@ -0,0 +1,447 @@
+use std::io::{self, ErrorKind};
+use std::net::{self, IpAddr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tokio::io::PollEvented;
+
+/// What the network layer reported about the datagram carrying a request.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DatagramInfo {
+    pub received_at: Instant,
+    /// The destination address of the datagram, a group address for multicast requests.
+    pub local_addr: Option<IpAddr>,
+    /// Index of the interface the datagram arrived on.
+    pub interface: Option<u32>,
+    /// The IPv4 TTL or IPv6 hop limit of the datagram.
+    pub hop_limit: Option<u8>,
+    /// The bound address of the socket that received the datagram, telling apart the
+    /// listeners of a server bound to several addresses.
+    pub listener: Option<SocketAddr>,
+}
+
+impl DatagramInfo {
+    pub(crate) fn new() -> DatagramInfo {
+        DatagramInfo {
+            received_at: Instant::now(),
+            local_addr: None,
+            interface: None,
+            hop_limit: None,
+            listener: None,
+        }
+    }
+
+    /// Whether the datagram was sent to a multicast group rather than to this host.
+    pub fn is_multicast(&self) -> bool {
+        self.local_addr.is_some_and(|addr| addr.is_multicast())
+    }
+
+    /// The local address replies to the datagram are sent from: the address it arrived at,
+    /// unless that is a multicast group, in which case the system chooses a unicast one.
+    pub fn reply_addr(&self) -> Option<IpAddr> {
+        self.local_addr.filter(|addr| !addr.is_multicast())
+    }
+
+    /// The name of the interface the datagram arrived on, e.g. `eth0`.
+    pub fn interface_name(&self) -> Option<String> {
+        interface_name(self.interface?)
+    }
+}
+
+/// A non-blocking UDP socket reporting the `DatagramInfo` of received datagrams.
+pub(crate) struct DatagramSocket {
+    io: PollEvented<mio::net::UdpSocket>,
+    local_addr: SocketAddr,
+}
+
+impl DatagramSocket {
+    /// Binds the socket. Must be called within a tokio runtime.
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<DatagramSocket> {
+        let socket = net::UdpSocket::bind(addr)?;
+        enable_packet_info(&socket);
+        let local_addr = socket.local_addr()?;
+        Ok(DatagramSocket {
+            io: PollEvented::new(mio::net::UdpSocket::from_socket(socket)?)?,
+            local_addr,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+
+    pub fn set_traffic_class(&self, traffic_class: u8) -> io::Result<()> {
+        set_traffic_class(self.io.get_ref(), &self.local_addr, traffic_class)
+    }
+
+    pub fn lease_flow_label(&self, label: u32) -> io::Result<()> {
+        lease_flow_label(self.io.get_ref(), label)
+    }
+
+    pub fn poll_recv_from(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<(usize, SocketAddr, DatagramInfo)>> {
+        futures::ready!(self.io.poll_read_ready(cx, mio::Ready::readable()))?;
+        match recv_with_info(self.io.get_ref(), buf) {
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                self.io.clear_read_ready(cx, mio::Ready::readable())?;
+                Poll::Pending
+            }
+            Ok((nread, src, mut info)) => {
+                info.listener = Some(self.local_addr);
+                Poll::Ready(Ok((nread, src, info)))
+            }
+            result => Poll::Ready(result),
+        }
+    }
+
+    /// Sends a reply from the local address of the datagram being answered, so that a server
+    /// bound to a wildcard address on a multi-homed host does not answer from another one.
+    pub fn poll_send_from(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+        target: &SocketAddr,
+        source: Option<&DatagramInfo>,
+    ) -> Poll<io::Result<usize>> {
+        futures::ready!(self.io.poll_write_ready(cx))?;
+        match send_with_info(self.io.get_ref(), buf, target, source) {
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                self.io.clear_write_ready(cx)?;
+                Poll::Pending
+            }
+            result => Poll::Ready(result),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn enable_packet_info(socket: &net::UdpSocket) {
+    use std::os::unix::io::AsRawFd;
+
+    let enable = |level, name| {
+        let on: libc::c_int = 1;
+        unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                level,
+                name,
+                &on as *const _ as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        }
+    };
+    // a dual-stack IPv6 socket accepts the IPv4 options for IPv4-mapped peers
+    enable(libc::IPPROTO_IP, libc::IP_PKTINFO);
+    enable(libc::IPPROTO_IP, libc::IP_RECVTTL);
+    if let Ok(SocketAddr::V6(_)) = socket.local_addr() {
+        enable(libc::IPPROTO_IPV6, libc::IPV6_RECVPKTINFO);
+        enable(libc::IPPROTO_IPV6, libc::IPV6_RECVHOPLIMIT);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn enable_packet_info(_socket: &net::UdpSocket) {}
+
+#[cfg(target_os = "linux")]
+fn recv_with_info(socket: &mio::net::UdpSocket, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, DatagramInfo)> {
+    use std::mem;
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+    use std::os::unix::io::AsRawFd;
+
+    let mut info = DatagramInfo::new();
+    let mut name: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut control = [0u64; 16];
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_name = &mut name as *mut _ as *mut libc::c_void;
+    msg.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = mem::size_of_val(&control) as _;
+
+    let nread = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, 0) };
+    if nread < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            let data = libc::CMSG_DATA(cmsg);
+            match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+                (libc::IPPROTO_IP, libc::IP_PKTINFO) => {
+                    let pktinfo = (data as *const libc::in_pktinfo).read_unaligned();
+                    info.local_addr = Some(IpAddr::V4(Ipv4Addr::from(u32::from_be(pktinfo.ipi_addr.s_addr))));
+                    info.interface = Some(pktinfo.ipi_ifindex as u32);
+                }
+                (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO) => {
+                    let pktinfo = (data as *const libc::in6_pktinfo).read_unaligned();
+                    info.local_addr = Some(IpAddr::V6(Ipv6Addr::from(pktinfo.ipi6_addr.s6_addr)));
+                    info.interface = Some(pktinfo.ipi6_ifindex);
+                }
+                (libc::IPPROTO_IP, libc::IP_TTL) | (libc::IPPROTO_IPV6, libc::IPV6_HOPLIMIT) => {
+                    info.hop_limit = Some((data as *const libc::c_int).read_unaligned() as u8);
+                }
+                _ => {}
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+
+    let src = match name.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let addr = unsafe { *(&name as *const _ as *const libc::sockaddr_in) };
+            SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+                u16::from_be(addr.sin_port),
+            ))
+        }
+        libc::AF_INET6 => {
+            let addr = unsafe { *(&name as *const _ as *const libc::sockaddr_in6) };
+            SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                u16::from_be(addr.sin6_port),
+                addr.sin6_flowinfo,
+                addr.sin6_scope_id,
+            ))
+        }
+        _ => return Err(io::Error::new(ErrorKind::InvalidData, "unsupported address family")),
+    };
+    Ok((nread as usize, src, info))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn recv_with_info(socket: &mio::net::UdpSocket, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, DatagramInfo)> {
+    let (nread, src) = socket.recv_from(buf)?;
+    Ok((nread, src, DatagramInfo::new()))
+}
+
+#[cfg(target_os = "linux")]
+fn send_with_info(
+    socket: &mio::net::UdpSocket,
+    buf: &[u8],
+    target: &SocketAddr,
+    source: Option<&DatagramInfo>,
+) -> io::Result<usize> {
+    use std::mem;
+    use std::os::unix::io::AsRawFd;
+
+    let (source, interface) = match source.and_then(|info| info.reply_addr().map(|addr| (addr, info.interface))) {
+        Some(source) => source,
+        None => return socket.send_to(buf, target),
+    };
+
+    let mut name: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let namelen = match *target {
+        SocketAddr::V4(ref addr) => {
+            let sin = unsafe { &mut *(&mut name as *mut _ as *mut libc::sockaddr_in) };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = addr.port().to_be();
+            sin.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(ref addr) => {
+            let sin6 = unsafe { &mut *(&mut name as *mut _ as *mut libc::sockaddr_in6) };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = addr.port().to_be();
+            sin6.sin6_addr.s6_addr = addr.ip().octets();
+            sin6.sin6_flowinfo = addr.flowinfo();
+            sin6.sin6_scope_id = addr.scope_id();
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+
+    let mut control = [0u64; 8];
+    let mut iov = libc::iovec {
+        iov_base: buf.as_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_name = &mut name as *mut _ as *mut libc::c_void;
+    msg.msg_namelen = namelen as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+
+    unsafe {
+        match source {
+            IpAddr::V4(addr) => {
+                let size = mem::size_of::<libc::in_pktinfo>() as u32;
+                msg.msg_controllen = libc::CMSG_SPACE(size) as _;
+                let cmsg = libc::CMSG_FIRSTHDR(&msg);
+                (*cmsg).cmsg_level = libc::IPPROTO_IP;
+                (*cmsg).cmsg_type = libc::IP_PKTINFO;
+                (*cmsg).cmsg_len = libc::CMSG_LEN(size) as _;
+                // the route to the peer picks the interface, only the source address is fixed
+                let mut pktinfo: libc::in_pktinfo = mem::zeroed();
+                pktinfo.ipi_spec_dst.s_addr = u32::from(addr).to_be();
+                (libc::CMSG_DATA(cmsg) as *mut libc::in_pktinfo).write_unaligned(pktinfo);
+            }
+            IpAddr::V6(addr) => {
+                let size = mem::size_of::<libc::in6_pktinfo>() as u32;
+                msg.msg_controllen = libc::CMSG_SPACE(size) as _;
+                let cmsg = libc::CMSG_FIRSTHDR(&msg);
+                (*cmsg).cmsg_level = libc::IPPROTO_IPV6;
+                (*cmsg).cmsg_type = libc::IPV6_PKTINFO;
+                (*cmsg).cmsg_len = libc::CMSG_LEN(size) as _;
+                // link-local addresses are only unique together with their interface
+                let mut pktinfo: libc::in6_pktinfo = mem::zeroed();
+                pktinfo.ipi6_addr.s6_addr = addr.octets();
+                if addr.segments()[0] & 0xffc0 == 0xfe80 {
+                    pktinfo.ipi6_ifindex = interface.unwrap_or(0);
+                }
+                (libc::CMSG_DATA(cmsg) as *mut libc::in6_pktinfo).write_unaligned(pktinfo);
+            }
+        }
+    }
+
+    let nsent = unsafe { libc::sendmsg(socket.as_raw_fd(), &msg, 0) };
+    if nsent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(nsent as usize)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_with_info(
+    socket: &mio::net::UdpSocket,
+    buf: &[u8],
+    target: &SocketAddr,
+    _source: Option<&DatagramInfo>,
+) -> io::Result<usize> {
+    socket.send_to(buf, target)
+}
+
+/// The largest IPv6 flow label, which has 20 bits.
+pub(crate) const MAX_FLOW_LABEL: u32 = 0xFFFFF;
+
+/// Marks the datagrams the socket sends with the IPv6 traffic class, or for IPv4 peers the
+/// TOS byte, so that border routers and ISPs can classify them. The upper six bits are the
+/// DSCP, e.g. `0xB8` for Expedited Forwarding. Backs `CoAPClient::set_traffic_class` and
+/// `Server::set_traffic_class`.
+#[cfg(unix)]
+pub(crate) fn set_traffic_class<S: AsRawFd>(socket: &S, local_addr: &SocketAddr, traffic_class: u8) -> io::Result<()> {
+    let value = libc::c_int::from(traffic_class);
+    match *local_addr {
+        SocketAddr::V4(_) => setsockopt_int(socket, libc::IPPROTO_IP, libc::IP_TOS, value),
+        SocketAddr::V6(_) => {
+            // a dual-stack socket marks the datagrams to IPv4-mapped peers with the TOS byte,
+            // which sockets bound to an IPv6 address only refuse
+            let _ = setsockopt_int(socket, libc::IPPROTO_IP, libc::IP_TOS, value);
+            setsockopt_int(socket, libc::IPPROTO_IPV6, libc::IPV6_TCLASS, value)
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn set_traffic_class<S>(_socket: &S, _local_addr: &SocketAddr, _traffic_class: u8) -> io::Result<()> {
+    Err(io::Error::new(ErrorKind::Other, "traffic classes are not supported on this platform"))
+}
+
+/// Leases the 20-bit flow label for the socket and lets it send labeled datagrams to IPv6
+/// endpoints, which the kernel refuses for labels not leased. A lease is exclusive and covers
+/// every destination; only Linux supports it. Backs `CoAPClient::set_flow_label` and
+/// `Server::set_flow_label`.
+#[cfg(target_os = "linux")]
+pub(crate) fn lease_flow_label<S: AsRawFd>(socket: &S, label: u32) -> io::Result<()> {
+    // struct in6_flowlabel_req
+    #[repr(C)]
+    struct FlowLabelRequest {
+        dst: [u8; 16],
+        label: u32,
+        action: u8,
+        share: u8,
+        flags: u16,
+        expires: u16,
+        linger: u16,
+        pad: u32,
+    }
+    const IPV6_FL_A_GET: u8 = 0;
+    const IPV6_FL_S_EXCL: u8 = 1;
+    const IPV6_FL_F_CREATE: u16 = 1;
+
+    let request = FlowLabelRequest {
+        // the kernel wants a destination, but only checks the label when sending
+        dst: Ipv6Addr::LOCALHOST.octets(),
+        label: label.to_be(),
+        action: IPV6_FL_A_GET,
+        // sharing a label with other sockets takes privileges
+        share: IPV6_FL_S_EXCL,
+        flags: IPV6_FL_F_CREATE,
+        expires: 0,
+        linger: 0,
+        pad: 0,
+    };
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IPV6,
+            libc::IPV6_FLOWLABEL_MGR,
+            &request as *const _ as *const libc::c_void,
+            std::mem::size_of::<FlowLabelRequest>() as libc::socklen_t,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    setsockopt_int(socket, libc::IPPROTO_IPV6, libc::IPV6_FLOWINFO_SEND, 1)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn lease_flow_label<S>(_socket: &S, _label: u32) -> io::Result<()> {
+    Err(io::Error::new(ErrorKind::Other, "flow labels are not supported on this platform"))
+}
+
+/// The address with the flow label, which a socket sends with once it leased the label.
+pub(crate) fn with_flow_label(addr: &SocketAddr, label: Option<u32>) -> SocketAddr {
+    match (*addr, label) {
+        (SocketAddr::V6(mut addr), Some(label)) => {
+            // the field is copied to sin6_flowinfo as is, which is in network byte order
+            addr.set_flowinfo(label.to_be());
+            SocketAddr::V6(addr)
+        }
+        (addr, _) => addr,
+    }
+}
+
+#[cfg(unix)]
+fn setsockopt_int<S: AsRawFd>(socket: &S, level: libc::c_int, name: libc::c_int, value: libc::c_int) -> io::Result<()> {
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn interface_name(index: u32) -> Option<String> {
+    let mut name = [0 as libc::c_char; libc::IF_NAMESIZE];
+    let name = unsafe {
+        if libc::if_indextoname(index, name.as_mut_ptr()).is_null() {
+            return None;
+        }
+        std::ffi::CStr::from_ptr(name.as_ptr())
+    };
+    Some(name.to_string_lossy().into_owned())
+}
+
+#[cfg(not(unix))]
+fn interface_name(_index: u32) -> Option<String> {
+    None
+}
@@ -0,0 +1,177 @@
+use std::time::Instant;
+
+use super::acl::Acl;
+use super::cache::CacheStats;
+use super::cbor::Value;
+use super::message::packet::ContentFormat;
+use super::message::request::{CoAPRequest, Method};
+use super::message::response::{CoAPResponse, Status};
+use super::observer::ObserveState;
+use super::stats::PeerStatsRegistry;
+
+/// The path the diagnostic resources live under.
+pub const DIAG_PATH: &str = ".diag";
+
+/// Built-in diagnostic resources of a server, answered in CBOR ahead of the handler:
+///
+/// * `/.diag/stats`: uptime and request totals
+/// * `/.diag/peers`: the per-peer statistics
+/// * `/.diag/observations`: the observed resources and their observers
+/// * `/.diag/cache`: the occupancy of a response cache, when a source is set
+///
+/// Only peers allowed by the ACL get an answer; others get 4.03 Forbidden.
+pub struct Diagnostics {
+    acl: Acl,
+    cache_stats: Option<Box<dyn Fn() -> CacheStats + Send>>,
+    started: Instant,
+}
+
+impl Diagnostics {
+    pub fn new(acl: Acl) -> Diagnostics {
+        Diagnostics {
+            acl,
+            cache_stats: None,
+            started: Instant::now(),
+        }
+    }
+
+    /// Sets the source of `/.diag/cache`, e.g. `ForwardProxy::cache_stats` of a proxy shared
+    /// with the handler.
+    pub fn with_cache_stats<F: Fn() -> CacheStats + Send + 'static>(mut self, source: F) -> Diagnostics {
+        self.cache_stats = Some(Box::new(source));
+        self
+    }
+
+    /// Answers a request to a diagnostic resource, or returns None for other paths.
+    pub(crate) fn respond<F: FnOnce() -> ObserveState>(
+        &self,
+        request: &CoAPRequest,
+        peers: &PeerStatsRegistry,
+        observations: F,
+    ) -> Option<CoAPResponse> {
+        let path = request.get_path();
+        let resource = if path == DIAG_PATH {
+            ""
+        } else if path.starts_with(DIAG_PATH) && path[DIAG_PATH.len()..].starts_with('/') {
+            &path[DIAG_PATH.len() + 1..]
+        } else {
+            return None;
+        };
+
+        let mut response = request.response.clone()?;
+        if !self.acl.permits(&request.context) {
+            response.set_error(Status::Forbidden, "not allowed by the diagnostics ACL");
+            return Some(response);
+        }
+        if *request.get_method() != Method::Get {
+            response.set_error(Status::MethodNotAllowed, "diagnostic resources are read-only");
+            return Some(response);
+        }
+
+        let payload = match resource {
+            "stats" => self.stats(peers, &observations()),
+            "peers" => peers.to_cbor(),
+            "observations" => observations().to_cbor().to_vec(),
+            "cache" if self.cache_stats.is_some() => {
+                let stats = (self.cache_stats.as_ref().unwrap())();
+                let text = |text: &str| Value::Text(text.to_string());
+                Value::Map(vec![
+                    (text("entries"), Value::Integer(stats.entries as i64)),
+                    (text("hits"), Value::Integer(stats.hits as i64)),
+                    (text("misses"), Value::Integer(stats.misses as i64)),
+                    (text("revalidations"), Value::Integer(stats.revalidations as i64)),
+                    (text("evictions"), Value::Integer(stats.evictions as i64)),
+                ])
+                .to_vec()
+            }
+            _ => {
+                response.set_error(Status::NotFound, "no such diagnostic resource");
+                return Some(response);
+            }
+        };
+        response.message.set_content_format(ContentFormat::ApplicationCBOR);
+        response.message.payload = payload;
+        Some(response)
+    }
+
+    fn stats(&self, peers: &PeerStatsRegistry, observations: &ObserveState) -> Vec<u8> {
+        let (mut requests, mut errors, mut retransmissions) = (0, 0, 0);
+        let peers = peers.list();
+        for (_, stats) in peers.iter() {
+            requests += stats.requests;
+            errors += stats.errors;
+            retransmissions += stats.retransmissions;
+        }
+        let text = |text: &str| Value::Text(text.to_string());
+        Value::Map(vec![
+            (text("uptime_s"), Value::Integer(self.started.elapsed().as_secs() as i64)),
+            (text("peers"), Value::Integer(peers.len() as i64)),
+            (text("requests"), Value::Integer(requests as i64)),
+            (text("errors"), Value::Integer(errors as i64)),
+            (text("retransmissions"), Value::Integer(retransmissions as i64)),
+            (text("observations"), Value::Integer(observations.registrations() as i64)),
+        ])
+        .to_vec()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::cbor;
+    use super::super::message::header::MessageType;
+    use super::super::message::IsMessage;
+    use std::net::SocketAddr;
+
+    fn request(peer: &str, method: Method, path: &str) -> CoAPRequest {
+        let mut request = CoAPRequest::new();
+        request.set_type(MessageType::Confirmable);
+        request.set_method(method);
+        request.set_path(path);
+        let peer: SocketAddr = peer.parse().unwrap();
+        CoAPRequest::from_packet(request.message, &peer)
+    }
+
+    fn get(diagnostics: &Diagnostics, peers: &PeerStatsRegistry, request: &CoAPRequest) -> Option<CoAPResponse> {
+        diagnostics.respond(request, peers, ObserveState::default)
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let diagnostics = Diagnostics::new(Acl::new().allow_loopback()).with_cache_stats(|| CacheStats {
+            entries: 3,
+            ..CacheStats::default()
+        });
+        let peers = PeerStatsRegistry::new();
+        let stats_request = request("127.0.0.1:5683", Method::Get, "/.diag/stats");
+        peers.received("127.0.0.1:5683".parse().unwrap(), &stats_request.message);
+
+        let response = get(&diagnostics, &peers, &stats_request).unwrap();
+        assert_eq!(*response.get_status(), Status::Content);
+        let (stats, _) = cbor::decode(&response.message.payload).unwrap();
+        match stats {
+            Value::Map(ref entries) => {
+                assert!(entries.contains(&(Value::Text("requests".to_string()), Value::Integer(1))));
+            }
+            _ => panic!("not a map"),
+        }
+
+        let response = get(&diagnostics, &peers, &request("127.0.0.1:5683", Method::Get, "/.diag/cache")).unwrap();
+        let (cache, _) = cbor::decode(&response.message.payload).unwrap();
+        assert_eq!(cache, Value::Map(vec![
+            (Value::Text("entries".to_string()), Value::Integer(3)),
+            (Value::Text("hits".to_string()), Value::Integer(0)),
+            (Value::Text("misses".to_string()), Value::Integer(0)),
+            (Value::Text("revalidations".to_string()), Value::Integer(0)),
+            (Value::Text("evictions".to_string()), Value::Integer(0)),
+        ]));
+
+        let response = get(&diagnostics, &peers, &request("192.0.2.1:5683", Method::Get, "/.diag/stats")).unwrap();
+        assert_eq!(*response.get_status(), Status::Forbidden);
+        let response = get(&diagnostics, &peers, &request("127.0.0.1:5683", Method::Put, "/.diag/stats")).unwrap();
+        assert_eq!(*response.get_status(), Status::MethodNotAllowed);
+        let response = get(&diagnostics, &peers, &request("127.0.0.1:5683", Method::Get, "/.diag/other")).unwrap();
+        assert_eq!(*response.get_status(), Status::NotFound);
+        assert!(get(&diagnostics, &peers, &request("127.0.0.1:5683", Method::Get, "/.diagnose")).is_none());
+    }
+}
@@ -1,30 +1,289 @@
-use super::message::packet::{ObserveOption, Packet};
+use super::client::{
+  ends_observation, CoAPClient, ObservationHandle, Supervision, DEFAULT_MAX_OBSERVE_RESTARTS, MAX_OBSERVE_ERRORS,
+};
+use super::event::{ClientEvent, EventEmitter};
+use super::ids::{IdGenerator, IdState};
+use super::message::header::MessageType;
+use super::message::packet::{CoAPOption, ObserveOption, Packet};
 use super::message::request::CoAPRequest;
 use super::message::response::{CoAPResponse, Status};
 use super::message::IsMessage;
-use crate::ssl_utils::get_ssl_connector;
+use super::resolve::{self, Resolver};
+use crate::ssl_utils::{
+  get_dtls_connector_builder, get_psk_connector, get_psk_selector_connector, get_ssl_connector, set_psk_selector,
+};
 use crate::udp::UDPWrapper;
 use log::*;
-use openssl::ssl::SslStream;
-use regex::Regex;
+use openssl::sha::sha256;
+use openssl::ssl::{
+  ErrorCode, HandshakeError, SslConnector, SslConnectorBuilder, SslSession, SslStream, SslVerifyMode,
+};
+use openssl::x509::{X509Ref, X509StoreContextRef};
+use std::collections::HashMap;
 use std::io::{Error, ErrorKind, Result};
 use std::net::{SocketAddr, ToSocketAddrs};
-use std::sync::mpsc;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::{mpsc, Arc, Mutex, Weak};
 use std::thread;
-use std::time::Duration;
-use url::Url;
+use std::time::{Duration, Instant};
 
 const DEFAULT_RECEIVE_TIMEOUT: u64 = 1; // 1s
+const HANDSHAKE_TIMEOUT: u64 = 5; // 5s
+// how long the reader of a session waits for a datagram before checking whether the session
+// is still in use
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
 
 enum ObserveMessage {
   Terminate,
+  Reconnect,
+}
+
+/// PSK credentials provisioned on a client, chosen by the identity hint the server
+/// advertises, such as its hostname.
+#[derive(Clone, Debug, Default)]
+pub struct PskStore {
+  credentials: HashMap<Vec<u8>, (Vec<u8>, Vec<u8>)>,
+  fallback: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+impl PskStore {
+  pub fn new() -> PskStore {
+    Self::default()
+  }
+
+  /// Adds the identity and key used when the server's identity hint is `hint`.
+  pub fn add(&mut self, hint: &[u8], identity: &[u8], key: &[u8]) {
+    self
+      .credentials
+      .insert(hint.to_vec(), (identity.to_vec(), key.to_vec()));
+  }
+
+  /// Sets the identity and key used when the server sends no hint or an unknown one.
+  pub fn set_fallback(&mut self, identity: &[u8], key: &[u8]) {
+    self.fallback = Some((identity.to_vec(), key.to_vec()));
+  }
+
+  /// Returns the identity and key for a server's identity hint.
+  pub fn select(&self, hint: Option<&[u8]>) -> Option<(&[u8], &[u8])> {
+    hint
+      .filter(|hint| !hint.is_empty())
+      .and_then(|hint| self.credentials.get(hint))
+      .or(self.fallback.as_ref())
+      .map(|(identity, key)| (&identity[..], &key[..]))
+  }
+}
+
+/// Configures the DTLS handshake of a client beyond what the `DTLSCoAPClient` constructors
+/// offer, such as certificate verification.
+pub struct DTLSClientBuilder {
+  builder: SslConnectorBuilder,
+  server_name: String,
+  verify_hostname: bool,
+  session: Option<SslSession>,
+  queued: Vec<Packet>,
+}
+
+impl DTLSClientBuilder {
+  /// A builder authenticating the server with the system's trusted certificates and no PSK.
+  pub fn new() -> Result<DTLSClientBuilder> {
+    Ok(DTLSClientBuilder {
+      builder: get_dtls_connector_builder()?,
+      server_name: String::from("localhost"),
+      verify_hostname: true,
+      session: None,
+      queued: Vec::new(),
+    })
+  }
+
+  /// Authenticate with a PSK.
+  pub fn with_psk(self, identity: &[u8], key: &[u8]) -> DTLSClientBuilder {
+    let (identity, key) = (identity.to_vec(), key.to_vec());
+    self.with_psk_selector(move |_hint| Some((identity.clone(), key.clone())))
+  }
+
+  /// Authenticate with the credential of the store matching the server's PSK identity hint.
+  pub fn with_psk_store(self, store: PskStore) -> DTLSClientBuilder {
+    self.with_psk_selector(move |hint| {
+      store
+        .select(hint)
+        .map(|(identity, key)| (identity.to_vec(), key.to_vec()))
+    })
+  }
+
+  /// Authenticate with the PSK identity and key that `select` returns for the server's
+  /// identity hint.
+  pub fn with_psk_selector<F>(mut self, select: F) -> DTLSClientBuilder
+  where
+    F: Fn(Option<&[u8]>) -> Option<(Vec<u8>, Vec<u8>)> + Send + Sync + 'static,
+  {
+    set_psk_selector(&mut self.builder, select);
+    self
+  }
+
+  /// Trust the CA certificates of a PEM file in addition to the system's.
+  pub fn with_ca_file<P: AsRef<Path>>(mut self, path: P) -> Result<DTLSClientBuilder> {
+    self.builder.set_ca_file(path)?;
+    Ok(self)
+  }
+
+  /// Decide on every certificate of the server's chain, replacing OpenSSL's verdict, which
+  /// is passed as the first argument. The chain's leaf certificate is checked last.
+  ///
+  /// This allows pinning the server by `spki_sha256`, accepting self-signed certificates
+  /// during commissioning or enforcing an extended key usage.
+  pub fn with_verify_callback<F>(mut self, verify: F) -> DTLSClientBuilder
+  where
+    F: Fn(bool, &mut X509StoreContextRef) -> bool + Send + Sync + 'static,
+  {
+    self.builder.set_verify_callback(SslVerifyMode::PEER, verify);
+    self
+  }
+
+  /// Set the name the server's certificate is verified against, `localhost` by default.
+  pub fn with_server_name(mut self, server_name: &str) -> DTLSClientBuilder {
+    self.server_name = server_name.to_string();
+    self
+  }
+
+  /// Set whether the server's certificate must match the server name.
+  pub fn with_hostname_verification(mut self, verify_hostname: bool) -> DTLSClientBuilder {
+    self.verify_hostname = verify_hostname;
+    self
+  }
+
+  /// Resume a session saved by `DTLSCoAPClient::saved_session`, e.g. before the device went
+  /// to deep sleep, with an abbreviated handshake. A full handshake follows if the server no
+  /// longer knows the session.
+  ///
+  /// # Safety
+  ///
+  /// OpenSSL requires a resumed session to belong to the context of the handshake. The
+  /// caller must ensure that `session` was returned by `saved_session` of a client built
+  /// with the same configuration as this builder, e.g. by keeping it in storage that only
+  /// this application writes.
+  pub unsafe fn with_saved_session(mut self, session: &[u8]) -> Result<DTLSClientBuilder> {
+    self.session = Some(SslSession::from_der(session)?);
+    Ok(self)
+  }
+
+  /// Send a request right after the handshake, see `DTLSCoAPClient::queue_request`.
+  pub fn with_early_request(mut self, request: &CoAPRequest) -> DTLSClientBuilder {
+    self.queued.push(request.message.clone());
+    self
+  }
+
+  /// Connect to the peer address.
+  pub fn connect<A: ToSocketAddrs>(self, addr: A) -> Result<DTLSCoAPClient> {
+    match addr.to_socket_addrs()?.next() {
+      Some(SocketAddr::V4(_)) => self.connect_with_specific_source("0.0.0.0:0", addr),
+      Some(SocketAddr::V6(_)) => self.connect_with_specific_source(":::0", addr),
+      None => Err(Error::new(ErrorKind::Other, "no address")),
+    }
+  }
+
+  /// Connect to the peer host resolved by the resolver rather than the system's DNS, at
+  /// the first address resolved. The certificate is still verified against the server
+  /// name, not the host.
+  pub fn connect_with_resolver<R: Resolver + ?Sized>(self, host: &str, port: u16, resolver: &R) -> Result<DTLSCoAPClient> {
+    let addrs = resolve::resolve(resolver, host, port)?;
+    self.connect(addrs[0])
+  }
+
+  /// Connect to the peer address from a specific source address.
+  pub fn connect_with_specific_source<A: ToSocketAddrs, B: ToSocketAddrs>(
+    self,
+    bind_addr: A,
+    peer_addr: B,
+  ) -> Result<DTLSCoAPClient> {
+    let connector = Connector {
+      connector: self.builder.build(),
+      server_name: self.server_name,
+      verify_hostname: self.verify_hostname,
+      session: Arc::new(Mutex::new(self.session)),
+      queued: Arc::new(Mutex::new(self.queued)),
+    };
+    DTLSCoAPClient::connect(bind_addr, peer_addr, connector)
+  }
+}
+
+/// Returns the SHA-256 hash of a certificate's DER-encoded SubjectPublicKeyInfo, the value
+/// a server is pinned by.
+pub fn spki_sha256(cert: &X509Ref) -> Result<Vec<u8>> {
+  let spki = cert.public_key()?.public_key_to_der()?;
+  Ok(sha256(&spki).to_vec())
+}
+
+/// The DTLS settings a client connects and reconnects with, shared with its observation.
+#[derive(Clone)]
+struct Connector {
+  connector: SslConnector,
+  server_name: String,
+  verify_hostname: bool,
+  // the session of the last handshake, offered for resumption by the next one
+  session: Arc<Mutex<Option<SslSession>>>,
+  // the requests sent right after the next handshake
+  queued: Arc<Mutex<Vec<Packet>>>,
+}
+
+impl Connector {
+  fn new(connector: SslConnector) -> Connector {
+    Connector {
+      connector,
+      server_name: String::from("localhost"),
+      verify_hostname: true,
+      session: Arc::new(Mutex::new(None)),
+      queued: Arc::new(Mutex::new(Vec::new())),
+    }
+  }
+
+  /// Performs a handshake, abbreviated if the server resumes the last session, and sends the
+  /// queued requests right behind the client's last flight.
+  fn handshake(&self, socket: UDPWrapper) -> Result<SslStream<UDPWrapper>> {
+    let mut configuration = self.connector.configure()?.verify_hostname(self.verify_hostname);
+    if let Some(ref session) = *self.session.lock().unwrap() {
+      // SAFETY: the session comes from a handshake of this connector, or from an encoding
+      // the caller of `with_saved_session` vouched for being saved with this configuration
+      unsafe { configuration.set_session(session)? };
+    }
+    // the socket times out while the server is busy, which the handshake is continued after
+    let deadline = Instant::now() + Duration::new(HANDSHAKE_TIMEOUT, 0);
+    let mut handshake = configuration.connect(&self.server_name, socket);
+    let mut stream = loop {
+      match handshake {
+        Ok(stream) => break stream,
+        Err(HandshakeError::WouldBlock(mid)) if Instant::now() < deadline => handshake = mid.handshake(),
+        Err(HandshakeError::WouldBlock(_)) => {
+          return Err(Error::new(ErrorKind::TimedOut, "DTLS handshake timed out"));
+        }
+        // e.g. the ICMP port unreachable of a host without a DTLS endpoint
+        Err(HandshakeError::Failure(ref mid)) if mid.error().io_error().is_some() => {
+          let e = mid.error().io_error().unwrap();
+          return Err(Error::new(e.kind(), e.to_string()));
+        }
+        // the server's credentials were rejected or the server rejected the client's
+        Err(e) => return Err(Error::new(ErrorKind::PermissionDenied, e.to_string())),
+      }
+    };
+    if let Some(session) = stream.ssl().session() {
+      *self.session.lock().unwrap() = Some(session.to_owned());
+    }
+    for message in self.queued.lock().unwrap().drain(..) {
+      DTLSCoAPClient::send_with_socket(&mut stream, &message)?;
+    }
+    Ok(stream)
+  }
 }
 
 pub struct DTLSCoAPClient {
-  socket: SslStream<UDPWrapper>,
+  session: Arc<Session>,
+  connector: Connector,
   peer_addr: SocketAddr,
   observe_sender: Option<mpsc::Sender<ObserveMessage>>,
-  observe_thread: Option<thread::JoinHandle<()>>,
+  events: Arc<EventEmitter>,
+  ids: Arc<Mutex<IdGenerator>>,
+  defaults: Packet,
+  max_observe_restarts: u32,
 }
 
 impl DTLSCoAPClient {
@@ -32,6 +291,49 @@ impl DTLSCoAPClient {
   pub fn new_with_specific_source<A: ToSocketAddrs, B: ToSocketAddrs>(
     bind_addr: A,
     peer_addr: B,
+  ) -> Result<DTLSCoAPClient> {
+    Self::connect(bind_addr, peer_addr, Connector::new(get_ssl_connector()?))
+  }
+
+  /// Create a CoAP client with the peer address, authenticating with a specific PSK
+  /// instead of the one configured in the environment.
+  pub fn new_with_psk<A: ToSocketAddrs>(addr: A, identity: &[u8], key: &[u8]) -> Result<DTLSCoAPClient> {
+    Self::connect_any(addr, get_psk_connector(identity.to_vec(), key.to_vec())?)
+  }
+
+  /// Create a CoAP client with the peer address, authenticating with the credential of the
+  /// store matching the PSK identity hint the server advertises.
+  pub fn new_with_psk_store<A: ToSocketAddrs>(addr: A, store: PskStore) -> Result<DTLSCoAPClient> {
+    Self::new_with_psk_selector(addr, move |hint| {
+      store
+        .select(hint)
+        .map(|(identity, key)| (identity.to_vec(), key.to_vec()))
+    })
+  }
+
+  /// Create a CoAP client with the peer address, authenticating with the PSK identity and
+  /// key that `select` returns for the server's identity hint. The handshake fails if it
+  /// returns none.
+  pub fn new_with_psk_selector<A, F>(addr: A, select: F) -> Result<DTLSCoAPClient>
+  where
+    A: ToSocketAddrs,
+    F: Fn(Option<&[u8]>) -> Option<(Vec<u8>, Vec<u8>)> + Send + Sync + 'static,
+  {
+    Self::connect_any(addr, get_psk_selector_connector(select)?)
+  }
+
+  fn connect_any<A: ToSocketAddrs>(addr: A, connector: SslConnector) -> Result<DTLSCoAPClient> {
+    match addr.to_socket_addrs()?.next() {
+      Some(SocketAddr::V4(_)) => Self::connect("0.0.0.0:0", addr, Connector::new(connector)),
+      Some(SocketAddr::V6(_)) => Self::connect(":::0", addr, Connector::new(connector)),
+      None => Err(Error::other("no address")),
+    }
+  }
+
+  fn connect<A: ToSocketAddrs, B: ToSocketAddrs>(
+    bind_addr: A,
+    peer_addr: B,
+    connector: Connector,
   ) -> Result<DTLSCoAPClient> {
     let addr = peer_addr
       .to_socket_addrs()?
@@ -43,19 +345,26 @@ impl DTLSCoAPClient {
       .next()
       .ok_or(Error::new(ErrorKind::Other, "no address"))?;
 
-    let socket: UDPWrapper = UDPWrapper::connect(&addr, &bind_addr)?;
+    let events = Arc::new(EventEmitter::default());
+    events.emit(ClientEvent::Resolved(addr));
 
-    socket.set_read_timeout(Some(Duration::new(DEFAULT_RECEIVE_TIMEOUT, 0)))?;
+    let socket: UDPWrapper = UDPWrapper::connect(&addr, &bind_addr)?;
+    events.emit(ClientEvent::Connected { local_addr: socket.local_addr()? });
 
-    let connector = get_ssl_connector()?;
+    socket.set_read_timeout(Some(POLL_INTERVAL))?;
 
-    let stream = connector.connect("localhost", socket).unwrap();
+    let stream = connector.handshake(socket)?;
+    Self::emit_handshake(&events, &stream);
 
     Ok(DTLSCoAPClient {
-      socket: stream,
+      session: Session::new(stream)?,
+      connector,
       peer_addr: addr,
       observe_sender: None,
-      observe_thread: None,
+      events,
+      ids: Arc::new(Mutex::new(IdGenerator::new(IdState::seeded()))),
+      defaults: Packet::new(),
+      max_observe_restarts: DEFAULT_MAX_OBSERVE_RESTARTS,
     })
   }
 
@@ -82,7 +391,9 @@ impl DTLSCoAPClient {
     let mut packet = CoAPRequest::new();
     packet.set_path(path.as_str());
 
-    let mut client = Self::new((domain.as_str(), port))?;
+    let client = Self::new((domain.as_str(), port))?;
+    packet.set_message_id(client.next_message_id());
+    packet.set_token(client.next_token());
     client.send(&packet)?;
 
     client.set_receive_timeout(Some(timeout))?;
@@ -93,192 +404,598 @@ impl DTLSCoAPClient {
   }
 
 
+  /// Perform a new DTLS handshake with the server, e.g. after it restarted and lost the
+  /// session. An active observation is registered again over a new session as well.
+  pub fn reconnect(&mut self) -> Result<()> {
+    self.session.reconnect(&self.connector, &self.events)?;
+    if let Some(ref sender) = self.observe_sender {
+      let _ = sender.send(ObserveMessage::Reconnect);
+    }
+    Ok(())
+  }
+
+  fn emit_handshake(events: &EventEmitter, stream: &SslStream<UDPWrapper>) {
+    if let Some(cipher) = stream.ssl().current_cipher() {
+      events.emit(ClientEvent::HandshakeCompleted { cipher: cipher.name().to_string() });
+    }
+    if stream.ssl().session_reused() {
+      events.emit(ClientEvent::SessionResumed);
+    }
+  }
+
+  /// Queue a request to be sent right after the next handshake, e.g. the actuation a device
+  /// performs when it wakes up and calls `reconnect`. When the server resumes the session,
+  /// the request follows the client's Finished in the same flight, so that its response
+  /// arrives one round trip after the handshake started rather than two. Early data of
+  /// DTLS 1.3 is not used, as OpenSSL does not implement DTLS 1.3.
+  pub fn queue_request(&self, request: &CoAPRequest) {
+    let mut message = request.message.clone();
+    message.merge_options(&self.defaults);
+    self.connector.queued.lock().unwrap().push(message);
+  }
+
+  /// The encoding of the current session, to be resumed by a client built with
+  /// `DTLSClientBuilder::with_saved_session`, e.g. after the device slept.
+  pub fn saved_session(&self) -> Option<Vec<u8>> {
+    let session = self.connector.session.lock().unwrap().clone();
+    session.and_then(|session| session.to_der().ok())
+  }
+
+  /// Add an option sent with every request that does not carry the option itself.
+  pub fn add_default_option(&mut self, tp: CoAPOption, value: Vec<u8>) {
+    self.defaults.add_option(tp, value);
+  }
+
+  /// Stop sending an option by default.
+  pub fn clear_default_option(&mut self, tp: CoAPOption) {
+    self.defaults.clear_option(tp);
+  }
+
   /// Execute a request.
-  pub fn send(&mut self, request: &CoAPRequest) -> Result<()> {
-    Self::send_with_socket(&mut self.socket, &self.peer_addr, &request.message)
+  pub fn send(&self, request: &CoAPRequest) -> Result<()> {
+    let mut message = request.message.clone();
+    message.merge_options(&self.defaults);
+    self.session.send(&message)
+  }
+
+  /// Receive a response, or a notification of an observation registered with `send` rather
+  /// than `observe`.
+  pub fn receive(&self) -> Result<CoAPResponse> {
+    let packet = self.session.receive()?;
+    Ok(CoAPResponse::received(packet))
   }
 
-  /// Receive a response.
-  pub fn receive(&mut self) -> Result<CoAPResponse> {
-    let packet = Self::receive_from_socket(&mut self.socket)?;
-    Ok(CoAPResponse { message: packet })
+  /// Set a handler receiving the connection lifecycle events of the client. The events that
+  /// already established the connection, including the handshake, are delivered to it right away.
+  pub fn set_event_handler<F: FnMut(&ClientEvent) + Send + 'static>(&self, handler: F) {
+    self.events.set_handler(Box::new(handler));
   }
 
   /// Set the receive timeout.
   pub fn set_receive_timeout(&self, dur: Option<Duration>) -> Result<()> {
-    self.socket.get_ref().set_read_timeout(dur)
+    *self.session.receive_timeout.lock().unwrap() = dur;
+    Ok(())
+  }
+
+  /// Set how many times the background thread of an observation is restarted after failing
+  /// before the observation is given up, see `CoAPClient::set_max_observe_restarts`.
+  pub fn set_max_observe_restarts(&mut self, max_observe_restarts: u32) {
+    self.max_observe_restarts = max_observe_restarts;
   }
 
-  fn send_with_socket(
-    socket: &mut SslStream<UDPWrapper>,
-    peer_addr: &SocketAddr,
-    message: &Packet,
-  ) -> Result<()> {
+  fn send_with_socket(socket: &mut SslStream<UDPWrapper>, message: &Packet) -> Result<()> {
     match message.to_bytes() {
       Ok(bytes) => {
-        let size = socket.ssl_write(&bytes[..]).unwrap();
+        let size = socket
+          .ssl_write(&bytes[..])
+          .map_err(|e| e.into_io_error().unwrap_or_else(Error::other))?;
         if size == bytes.len() {
           Ok(())
         } else {
           Err(Error::new(ErrorKind::Other, "send length error"))
         }
       }
-      Err(_) => Err(Error::new(ErrorKind::InvalidInput, "packet error")),
+      Err(e) => Err(Error::new(ErrorKind::InvalidInput, e)),
     }
   }
 
   fn receive_from_socket(socket: &mut SslStream<UDPWrapper>) -> Result<Packet> {
     let mut buf = [0; 1500];
 
-    let nread = socket.ssl_read(&mut buf);
-    if nread.is_err() {
-      return Err(Error::new(ErrorKind::InvalidInput, "packet error"));
-    }
-    let nread = nread.unwrap();
+    let nread = match socket.ssl_read(&mut buf) {
+      Ok(nread) => nread,
+      Err(ref e) if e.code() == ErrorCode::WANT_READ => {
+        return Err(Error::new(ErrorKind::WouldBlock, "receive timed out"))
+      }
+      Err(ref e) if e.code() == ErrorCode::ZERO_RETURN => {
+        return Err(Error::new(ErrorKind::ConnectionAborted, "session closed by the server"))
+      }
+      Err(ref e) if e.code() == ErrorCode::SSL => return Err(Error::new(ErrorKind::ConnectionReset, e.to_string())),
+      Err(_) => return Err(Error::new(ErrorKind::InvalidInput, "packet error")),
+    };
     match Packet::from_bytes(&buf[..nread]) {
       Ok(packet) => Ok(packet),
-      Err(_) => Err(Error::new(ErrorKind::InvalidInput, "packet error")),
+      Err(e) => Err(Error::new(ErrorKind::InvalidInput, e)),
     }
   }
 
   fn parse_coap_url(url: &str) -> Result<(String, u16, String)> {
-    let url_params = match Url::parse(url) {
-      Ok(url_params) => url_params,
-      Err(_) => return Err(Error::new(ErrorKind::InvalidInput, "url error")),
-    };
+    Ok(CoAPClient::split_url(&CoAPClient::parse_client_url(url, &["coaps", "coap"])?))
+  }
 
-    let host = match url_params.host_str() {
-      Some("") => return Err(Error::new(ErrorKind::InvalidInput, "host error")),
-      Some(h) => h,
-      None => return Err(Error::new(ErrorKind::InvalidInput, "host error")),
-    };
-    let host = Regex::new(r"^\[(.*?)]$")
-      .unwrap()
-      .replace(&host, "$1")
-      .to_string();
+  /// A message ID not used by the client within EXCHANGE_LIFETIME, see
+  /// `CoAPClient::next_message_id`.
+  pub fn next_message_id(&self) -> u16 {
+    self.ids.lock().unwrap().next_message_id()
+  }
 
-    let port = match url_params.port() {
-      Some(p) => p,
-      None => 5683,
-    };
+  /// A random token, which a peer cannot guess from the earlier ones.
+  pub fn next_token(&self) -> Vec<u8> {
+    self.ids.lock().unwrap().next_token()
+  }
 
-    let path = url_params.path().to_string();
+  /// The state of the message ID generator to store, see `CoAPClient::id_state`.
+  pub fn id_state(&self) -> IdState {
+    self.ids.lock().unwrap().state()
+  }
 
-    return Ok((host.to_string(), port, path));
+  /// Continues the message IDs from a state stored before a reboot.
+  pub fn restore_id_state(&mut self, state: IdState) {
+    self.ids.lock().unwrap().restore(state);
   }
 
-  fn gen_message_id(message_id: &mut u16) -> u16 {
-    (*message_id) += 1;
-    return *message_id;
+  /// Sets a hook receiving the state to store whenever the generator reserves further
+  /// message IDs, see the `ids` module.
+  pub fn set_id_state_hook<F: FnMut(IdState) + Send + 'static>(&mut self, hook: F) {
+    self.ids.lock().unwrap().set_hook(hook);
   }
-  /// Observe a resource with the handler
+
+  /// Observe a resource with the handler. Notifications arrive over the session of the
+  /// client, which the observation shares rather than establishing one of its own; they are
+  /// told from the responses `receive` returns by the token of the registration.
   pub fn observe<H: FnMut(Packet) + Send + 'static>(
     &mut self,
     resource_path: &str,
     mut handler: H,
-  ) -> Result<()> {
-    // TODO: support observe multi resources at the same time
-    let mut message_id: u16 = 0;
-    let mut register_packet = CoAPRequest::new();
-    register_packet.set_observe(vec![ObserveOption::Register as u8]);
-    register_packet.set_message_id(Self::gen_message_id(&mut message_id));
-    register_packet.set_path(resource_path);
+  ) -> Result<ObservationHandle> {
+    let token = self.next_token();
+    let mut observation = Observation {
+      session: self.session.clone(),
+      connector: self.connector.clone(),
+      peer_addr: self.peer_addr,
+      defaults: self.defaults.clone(),
+      path: String::from(resource_path),
+      ids: self.ids.clone(),
+      subscription: self.session.subscribe(token.clone()),
+      token,
+      last_heard: Instant::now(),
+      events: self.events.clone(),
+    };
+    let response = observation.register(false);
+    handler(response?);
+    let (observe_sender, observe_receiver) = mpsc::channel();
 
-    self.send(&register_packet)?;
+    let max_restarts = self.max_observe_restarts;
+    let supervision = Supervision::new();
+    let thread_supervision = supervision.clone();
 
-    self.set_receive_timeout(Some(Duration::new(DEFAULT_RECEIVE_TIMEOUT, 0)))?;
-    let response = self.receive()?;
-    if *response.get_status() != Status::Content {
-      return Err(Error::new(ErrorKind::NotFound, "the resource not found"));
-    }
+    let observe_thread = thread::spawn(move || {
+      let mut connected = true;
+      loop {
+        let run = panic::catch_unwind(AssertUnwindSafe(|| {
+          let mut errors = 0;
+          loop {
+            let received = if connected {
+              observation.receive()
+            } else {
+              Err(Error::new(ErrorKind::NotConnected, "no session"))
+            };
+            match received {
+              Ok(packet) => {
+                errors = 0;
+                observation.last_heard = Instant::now();
+                let ended = ends_observation(&packet);
+                let receive_packet = CoAPRequest::from_packet(packet, &observation.peer_addr);
 
-    handler(response.message);
+                handler(receive_packet.message);
 
-    let socket;
-    match self.socket.get_ref().try_clone() {
-      Ok(good_socket) => socket = good_socket,
-      Err(_) => return Err(Error::new(ErrorKind::Other, "network error")),
-    }
+                if let Some(response) = receive_packet.response {
+                  let mut packet = Packet::new();
+                  packet.header.set_type(response.message.header.get_type());
+                  packet
+                    .header
+                    .set_message_id(response.message.header.get_message_id());
+                  packet.set_token(response.message.get_token().clone());
 
-    let connector = get_ssl_connector()?;
+                  match observation.session.send(&packet) {
+                    Ok(_) => (),
+                    Err(e) => warn!("reply ack failed {}", e),
+                  }
+                }
+                if ended {
+                  debug!("observation of {} ended by the server", observation.path);
+                  return Ok(());
+                }
+              }
+              Err(e) => match e.kind() {
+                ErrorKind::WouldBlock => (), // timeout
+                ErrorKind::ConnectionAborted | ErrorKind::ConnectionReset | ErrorKind::NotConnected => {
+                  connected = observation.resume(&mut handler, true);
+                  if !connected {
+                    thread::sleep(Duration::new(DEFAULT_RECEIVE_TIMEOUT, 0));
+                  }
+                }
+                _ => {
+                  warn!("observe failed {:?}", e);
+                  errors += 1;
+                  if errors >= MAX_OBSERVE_ERRORS {
+                    return Err(e.to_string());
+                  }
+                }
+              },
+            };
 
-    let mut stream = connector.connect("localhost", socket).unwrap();
-    let peer_addr = self.peer_addr.clone();
-    let (observe_sender, observe_receiver) = mpsc::channel();
-    let observe_path = String::from(resource_path);
-
-    let observe_thread = thread::spawn(move || loop {
-      match Self::receive_from_socket(&mut stream) {
-        Ok(packet) => {
-          let receive_packet = CoAPRequest::from_packet(packet, &peer_addr);
-
-          handler(receive_packet.message);
-
-          if let Some(response) = receive_packet.response {
-            let mut packet = Packet::new();
-            packet.header.set_type(response.message.header.get_type());
-            packet
-              .header
-              .set_message_id(response.message.header.get_message_id());
-            packet.set_token(response.message.get_token().clone());
-
-            match Self::send_with_socket(&mut stream, &peer_addr, &packet) {
-              Ok(_) => (),
-              Err(e) => warn!("reply ack failed {}", e),
+            match observe_receiver.try_recv() {
+              Ok(ObserveMessage::Terminate) => {
+                if connected {
+                  observation.deregister();
+                }
+                return Ok(());
+              }
+              // the client already performed the handshake
+              Ok(ObserveMessage::Reconnect) => connected = observation.resume(&mut handler, false),
+              _ => continue,
             }
           }
-        }
-        Err(e) => {
-          match e.kind() {
-            ErrorKind::WouldBlock => (), // timeout
-            _ => warn!("observe failed {:?}", e),
+        }));
+        let reason = match run {
+          Ok(Ok(())) => {
+            Supervision::end(&thread_supervision);
+            break;
           }
+          Ok(Err(reason)) => reason,
+          Err(_) => "the observation handler panicked".to_string(),
+        };
+        if !Supervision::restart(&thread_supervision, &observation.path, reason, max_restarts) {
+          break;
         }
-      };
+        // the server answers the new registration with the current representation
+        connected = observation.resume(&mut handler, !connected);
+      }
+    });
+    // the client keeps a sender to have the observation follow its reconnects
+    self.observe_sender = Some(observe_sender.clone());
 
-      match observe_receiver.try_recv() {
-        Ok(ObserveMessage::Terminate) => {
-          let mut deregister_packet = CoAPRequest::new();
-          deregister_packet.set_message_id(Self::gen_message_id(&mut message_id));
-          deregister_packet.set_observe(vec![ObserveOption::Deregister as u8]);
-          deregister_packet.set_path(observe_path.as_str());
+    Ok(ObservationHandle::supervised(resource_path, observe_thread, move || {
+      let _ = observe_sender.send(ObserveMessage::Terminate);
+    }, supervision))
+  }
 
-          Self::send_with_socket(&mut stream, &peer_addr, &deregister_packet.message).unwrap();
-          Self::receive_from_socket(&mut stream).unwrap();
-          break;
+  /// Splits the client into halves for sending requests and receiving their responses and
+  /// notifications over the same DTLS session, which can be moved to different threads.
+  pub fn split(self) -> (DTLSClientSender, DTLSClientReceiver) {
+    let client = Arc::new(self);
+    (DTLSClientSender { client: client.clone() }, DTLSClientReceiver { client })
+  }
+}
+
+/// The sending half of a split `DTLSCoAPClient`, which can be cloned to send from several
+/// threads.
+#[derive(Clone)]
+pub struct DTLSClientSender {
+  client: Arc<DTLSCoAPClient>,
+}
+
+impl DTLSClientSender {
+  /// Sends a request without waiting for its response, which the receiver gets.
+  pub fn send(&self, request: &CoAPRequest) -> Result<()> {
+    self.client.send(request)
+  }
+}
+
+/// The receiving half of a split `DTLSCoAPClient`.
+pub struct DTLSClientReceiver {
+  client: Arc<DTLSCoAPClient>,
+}
+
+impl DTLSClientReceiver {
+  /// Receives the next response or notification, acknowledging it if confirmable.
+  pub fn receive(&self) -> Result<CoAPResponse> {
+    let packet = self.client.session.receive()?;
+    if packet.header.get_type() == MessageType::Confirmable {
+      let mut ack = Packet::new();
+      ack.header.set_type(MessageType::Acknowledgement);
+      ack.header.set_message_id(packet.header.get_message_id());
+      self.client.session.send(&ack)?;
+    }
+    Ok(CoAPResponse::received(packet))
+  }
+
+  pub fn set_receive_timeout(&self, dur: Option<Duration>) -> Result<()> {
+    self.client.set_receive_timeout(dur)
+  }
+}
+
+/// A DTLS session shared by a client, its halves and its observations.
+///
+/// A reader thread takes the records off the session and routes them by token: those of an
+/// observation to its subscription and all others to the inbox of the client, so that
+/// threads receiving at the same time do not take each other's messages. It waits for a
+/// datagram on a clone of the socket before taking the stream, so that it does not block
+/// sending.
+struct Session {
+  stream: Mutex<SslStream<UDPWrapper>>,
+  socket: UDPWrapper,
+  // why the session broke off, until a handshake replaces it
+  broken: Mutex<Option<(ErrorKind, String)>>,
+  // the receivers are woken up with `None` when the session breaks off
+  subscriptions: Mutex<HashMap<Vec<u8>, mpsc::Sender<Option<Packet>>>>,
+  inbox_sender: Mutex<mpsc::Sender<Option<Packet>>>,
+  inbox: Mutex<mpsc::Receiver<Option<Packet>>>,
+  receive_timeout: Mutex<Option<Duration>>,
+  reader: Mutex<Option<thread::JoinHandle<()>>>,
+}
+
+impl Session {
+  fn new(stream: SslStream<UDPWrapper>) -> Result<Arc<Session>> {
+    let socket = stream.get_ref().try_clone()?;
+    let reader_socket = socket.try_clone()?;
+    let (inbox_sender, inbox) = mpsc::channel();
+    let session = Arc::new(Session {
+      stream: Mutex::new(stream),
+      socket,
+      broken: Mutex::new(None),
+      subscriptions: Mutex::new(HashMap::new()),
+      inbox_sender: Mutex::new(inbox_sender),
+      inbox: Mutex::new(inbox),
+      receive_timeout: Mutex::new(Some(Duration::new(DEFAULT_RECEIVE_TIMEOUT, 0))),
+      reader: Mutex::new(None),
+    });
+    let weak = Arc::downgrade(&session);
+    let reader = thread::Builder::new()
+      .name(String::from("dtls-reader"))
+      .spawn(move || Self::read(weak, reader_socket))?;
+    *session.reader.lock().unwrap() = Some(reader);
+    Ok(session)
+  }
+
+  fn read(session: Weak<Session>, socket: UDPWrapper) {
+    loop {
+      let peeked = socket.peek(&mut [0; 1]);
+      let session = match session.upgrade() {
+        Some(session) => session,
+        None => return,
+      };
+      match peeked {
+        Ok(_) => session.take_records(),
+        Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => (),
+        Err(e) => debug!("receive failed {}", e),
+      }
+    }
+  }
+
+  fn take_records(&self) {
+    if self.broken.lock().unwrap().is_some() {
+      // the datagrams are left to the handshake replacing the session
+      thread::sleep(POLL_INTERVAL);
+      return;
+    }
+    let mut stream = self.stream.lock().unwrap();
+    loop {
+      match DTLSCoAPClient::receive_from_socket(&mut stream) {
+        Ok(packet) => self.route(packet),
+        Err(ref e) if e.kind() == ErrorKind::WouldBlock => (),
+        Err(ref e) if e.kind() == ErrorKind::ConnectionAborted || e.kind() == ErrorKind::ConnectionReset => {
+          self.break_off(e);
+          return;
         }
-        _ => continue,
+        Err(e) => debug!("dropping a record {}", e),
       }
-    });
-    self.observe_sender = Some(observe_sender);
-    self.observe_thread = Some(observe_thread);
+      if stream.ssl().pending() == 0 {
+        return;
+      }
+    }
+  }
 
-    return Ok(());
+  fn route(&self, packet: Packet) {
+    let mut subscriptions = self.subscriptions.lock().unwrap();
+    if let Some(subscription) = subscriptions.get(packet.get_token()) {
+      if let Err(mpsc::SendError(packet)) = subscription.send(Some(packet)) {
+        let token = packet.as_ref().unwrap().get_token().clone();
+        subscriptions.remove(&token);
+        let _ = self.inbox_sender.lock().unwrap().send(packet);
+      }
+    } else {
+      let _ = self.inbox_sender.lock().unwrap().send(Some(packet));
+    }
+  }
+
+  fn break_off(&self, error: &Error) {
+    *self.broken.lock().unwrap() = Some((error.kind(), error.to_string()));
+    let _ = self.inbox_sender.lock().unwrap().send(None);
+    for subscription in self.subscriptions.lock().unwrap().values() {
+      let _ = subscription.send(None);
+    }
+  }
+
+  /// Continues over a new session established on the same socket.
+  fn reconnect(&self, connector: &Connector, events: &EventEmitter) -> Result<()> {
+    let mut stream = self.stream.lock().unwrap();
+    let new_stream = connector.handshake(self.socket.try_clone()?)?;
+    DTLSCoAPClient::emit_handshake(events, &new_stream);
+    *stream = new_stream;
+    *self.broken.lock().unwrap() = None;
+    Ok(())
+  }
+
+  fn send(&self, message: &Packet) -> Result<()> {
+    DTLSCoAPClient::send_with_socket(&mut self.stream.lock().unwrap(), message)
   }
 
-  /// Stop observing
-  pub fn unobserve(&mut self) {
-    match self.observe_sender.take() {
-      Some(ref sender) => {
-        sender.send(ObserveMessage::Terminate).unwrap();
+  /// Receives the next message not routed to a subscription.
+  fn receive(&self) -> Result<Packet> {
+    let timeout = *self.receive_timeout.lock().unwrap();
+    self.take(&self.inbox.lock().unwrap(), timeout)
+  }
+
+  /// Routes the messages with the token to the returned receiver rather than the inbox.
+  fn subscribe(&self, token: Vec<u8>) -> mpsc::Receiver<Option<Packet>> {
+    let (sender, receiver) = mpsc::channel();
+    self.subscriptions.lock().unwrap().insert(token, sender);
+    receiver
+  }
+
+  fn unsubscribe(&self, token: &[u8]) {
+    self.subscriptions.lock().unwrap().remove(token);
+  }
 
-        self.observe_thread.take().map(|g| g.join().unwrap());
+  fn take(&self, receiver: &mpsc::Receiver<Option<Packet>>, timeout: Option<Duration>) -> Result<Packet> {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    loop {
+      // the messages that arrived before the session broke off are still delivered
+      match receiver.try_recv() {
+        Ok(Some(packet)) => return Ok(packet),
+        Ok(None) | Err(mpsc::TryRecvError::Empty) => (),
+        Err(mpsc::TryRecvError::Disconnected) => return Err(Error::new(ErrorKind::NotConnected, "no session")),
+      }
+      if let Some((kind, ref reason)) = *self.broken.lock().unwrap() {
+        return Err(Error::new(kind, reason.clone()));
+      }
+      let received = match deadline {
+        Some(deadline) => receiver
+          .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+          .map_err(|e| match e {
+            mpsc::RecvTimeoutError::Timeout => Error::new(ErrorKind::WouldBlock, "receive timed out"),
+            mpsc::RecvTimeoutError::Disconnected => Error::new(ErrorKind::NotConnected, "no session"),
+          }),
+        None => receiver.recv().map_err(|_| Error::new(ErrorKind::NotConnected, "no session")),
+      };
+      // a wake-up of a session that has been replaced since is skipped
+      if let Some(packet) = received? {
+        return Ok(packet);
       }
-      _ => {}
     }
   }
 }
 
+impl Drop for Session {
+  fn drop(&mut self) {
+    // the reader closes its clone of the socket, so that the port can be bound again
+    if let Some(reader) = self.reader.lock().unwrap().take() {
+      if reader.thread().id() != thread::current().id() {
+        let _ = reader.join();
+      }
+    }
+  }
+}
+
+/// The state of an observation kept by the observe thread, which registers it again over
+/// a new DTLS session when the current one breaks off.
+struct Observation {
+  session: Arc<Session>,
+  connector: Connector,
+  peer_addr: SocketAddr,
+  defaults: Packet,
+  path: String,
+  ids: Arc<Mutex<IdGenerator>>,
+  // the token of the registration, whose notifications arrive on the subscription
+  token: Vec<u8>,
+  subscription: mpsc::Receiver<Option<Packet>>,
+  last_heard: Instant,
+  events: Arc<EventEmitter>,
+}
+
+impl Observation {
+  fn receive(&self) -> Result<Packet> {
+    self
+      .session
+      .take(&self.subscription, Some(Duration::new(DEFAULT_RECEIVE_TIMEOUT, 0)))
+  }
+
+  /// Registers again with a fresh token, after a handshake if `handshake` is set, handing
+  /// the current representation to the handler. Returns whether it succeeded.
+  fn resume<H: FnMut(Packet)>(&mut self, handler: &mut H, handshake: bool) -> bool {
+    match self.register(handshake) {
+      Ok(response) => {
+        let gap = self.last_heard.elapsed();
+        self.last_heard = Instant::now();
+        handler(response);
+        self.events.emit(ClientEvent::ObservationResumed { path: self.path.clone(), gap });
+        true
+      }
+      Err(e) => {
+        warn!("resuming the observation of {} failed {}", self.path, e);
+        false
+      }
+    }
+  }
+
+  /// Registers with the token of the observation, or after a handshake with a fresh one.
+  fn register(&mut self, handshake: bool) -> Result<Packet> {
+    if handshake {
+      self.session.reconnect(&self.connector, &self.events)?;
+      self.session.unsubscribe(&self.token);
+      self.token = self.ids.lock().unwrap().next_token();
+      self.subscription = self.session.subscribe(self.token.clone());
+    }
+    let mut register_packet = CoAPRequest::new();
+    register_packet.set_observe(vec![ObserveOption::Register as u8]);
+    register_packet.set_message_id(self.ids.lock().unwrap().next_message_id());
+    register_packet.set_token(self.token.clone());
+    register_packet.set_path(self.path.as_str());
+    register_packet.message.merge_options(&self.defaults);
+    let registered = self.session.send(&register_packet.message).and_then(|_| self.receive());
+
+    let response = CoAPResponse::received(registered?);
+    if *response.get_status() != Status::Content {
+      return Err(Error::new(ErrorKind::NotFound, "the resource not found"));
+    }
+    Ok(response.message)
+  }
+
+  fn deregister(&mut self) {
+    let mut deregister_packet = CoAPRequest::new();
+    deregister_packet.set_message_id(self.ids.lock().unwrap().next_message_id());
+    deregister_packet.set_observe(vec![ObserveOption::Deregister as u8]);
+    deregister_packet.set_token(self.token.clone());
+    deregister_packet.set_path(self.path.as_str());
+    deregister_packet.message.merge_options(&self.defaults);
+
+    let deregistered = self
+      .session
+      .send(&deregister_packet.message)
+      .and_then(|_| self.receive());
+    if let Err(e) = deregistered {
+      warn!("deregistering from {} failed {}", self.path, e);
+    }
+  }
+}
+
+impl Drop for Observation {
+  fn drop(&mut self) {
+    self.session.unsubscribe(&self.token);
+  }
+}
+
 impl Drop for DTLSCoAPClient {
   fn drop(&mut self) {
-    self.unobserve();
+    self.events.emit(ClientEvent::Disconnected);
   }
 }
 
 #[cfg(test)]
-mod test {
+pub mod test {
   use super::super::*;
   use super::*;
+  use std::sync::atomic::{AtomicU32, Ordering};
+  use openssl::asn1::Asn1Time;
+  use openssl::ec::{EcGroup, EcKey};
+  use openssl::hash::MessageDigest;
+  use openssl::nid::Nid;
+  use openssl::pkey::{PKey, Private};
+  use openssl::ssl::{Ssl, SslContext, SslMethod};
+  use super::super::message::header::MessageClass;
+  use openssl::x509::{X509NameBuilder, X509};
   use std::io::ErrorKind;
   use std::time::Duration;
 
@@ -290,6 +1007,7 @@ mod test {
     assert!(DTLSCoAPClient::parse_coap_url("coap://[::1]:5683").is_ok());
     assert!(DTLSCoAPClient::parse_coap_url("coap://[bbbb::9329:f033:f558:7418]").is_ok());
     assert!(DTLSCoAPClient::parse_coap_url("coap://[bbbb::9329:f033:f558:7418]:5683").is_ok());
+    assert_eq!(DTLSCoAPClient::parse_coap_url("127.0.0.1/a").unwrap(), ("127.0.0.1".to_string(), 5684, "/a".to_string()));
   }
 
   #[test]
@@ -297,7 +1015,376 @@ mod test {
     assert!(DTLSCoAPClient::parse_coap_url("coap://127.0.0.1:65536").is_err());
     assert!(DTLSCoAPClient::parse_coap_url("coap://").is_err());
     assert!(DTLSCoAPClient::parse_coap_url("coap://:5683").is_err());
-    assert!(DTLSCoAPClient::parse_coap_url("127.0.0.1").is_err());
+    assert!(DTLSCoAPClient::parse_coap_url("coaps+tcp://127.0.0.1").unwrap_err().to_string().contains("CoAP over TLS"));
+    assert!(DTLSCoAPClient::parse_coap_url("coaps://user@127.0.0.1").is_err());
+  }
+
+  #[test]
+  fn test_psk_store() {
+    let mut store = PskStore::new();
+    assert_eq!(store.select(Some(b"gw1.example")), None);
+
+    store.add(b"gw1.example", b"client-a", b"key-a");
+    store.add(b"gw2.example", b"client-b", b"key-b");
+    assert_eq!(store.select(Some(b"gw2.example")), Some((&b"client-b"[..], &b"key-b"[..])));
+    assert_eq!(store.select(None), None);
+
+    store.set_fallback(b"client-c", b"key-c");
+    assert_eq!(store.select(Some(b"gw3.example")), Some((&b"client-c"[..], &b"key-c"[..])));
+    assert_eq!(store.select(Some(b"")), Some((&b"client-c"[..], &b"key-c"[..])));
+    assert_eq!(store.select(Some(b"gw1.example")), Some((&b"client-a"[..], &b"key-a"[..])));
+  }
+
+  pub fn self_signed() -> (PKey<Private>, X509) {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+    let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("CN", "device.local").unwrap();
+    let name = name.build();
+
+    let mut builder = X509::builder().unwrap();
+    builder.set_version(2).unwrap();
+    builder.set_subject_name(&name).unwrap();
+    builder.set_issuer_name(&name).unwrap();
+    builder.set_pubkey(&key).unwrap();
+    builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+    builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+    builder.sign(&key, MessageDigest::sha256()).unwrap();
+    (key, builder.build())
+  }
+
+  /// Connects the server's socket to the client whose datagram arrives first.
+  pub fn accept_peer(socket: &std::net::UdpSocket) {
+    let (_, peer) = socket.peek_from(&mut [0; 1500]).unwrap();
+    socket.connect(peer).unwrap();
+  }
+
+  /// Connects a client built by `builder` to a server presenting the certificate.
+  fn handshake(builder: DTLSClientBuilder, key: &PKey<Private>, cert: &X509) -> Result<DTLSCoAPClient> {
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let server_port = socket.local_addr().unwrap().port();
+    socket.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+
+    let mut context = SslContext::builder(SslMethod::dtls()).unwrap();
+    context.set_private_key(key).unwrap();
+    context.set_certificate(cert).unwrap();
+    context.set_cipher_list("ECDHE-ECDSA-AES128-CCM").unwrap();
+    let ssl = Ssl::new(&context.build()).unwrap();
+    let server = thread::spawn(move || {
+      accept_peer(&socket);
+      ssl.accept(UDPWrapper::new(socket)).is_ok()
+    });
+
+    let client = builder.connect_with_specific_source("127.0.0.1:0", ("127.0.0.1", server_port));
+    server.join().unwrap();
+    client
+  }
+
+  #[test]
+  fn test_verify_callback() {
+    let (key, cert) = self_signed();
+    let untrusted = handshake(DTLSClientBuilder::new().unwrap(), &key, &cert);
+    assert_eq!(untrusted.err().unwrap().kind(), ErrorKind::PermissionDenied);
+
+    let pin = spki_sha256(&cert).unwrap();
+    let pinned = DTLSClientBuilder::new().unwrap().with_verify_callback(move |_, context| {
+      context
+        .current_cert()
+        .map_or(false, |cert| spki_sha256(cert).unwrap() == pin)
+    });
+    assert!(handshake(pinned, &key, &cert).is_ok());
+
+    let (other_key, other_cert) = self_signed();
+    let pin = spki_sha256(&cert).unwrap();
+    let mismatched = DTLSClientBuilder::new().unwrap().with_verify_callback(move |_, context| {
+      context
+        .current_cert()
+        .map_or(false, |cert| spki_sha256(cert).unwrap() == pin)
+    });
+    assert!(handshake(mismatched, &other_key, &other_cert).is_err());
+  }
+
+  #[test]
+  fn test_observation_resumed() {
+    let (key, cert) = self_signed();
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let server_port = socket.local_addr().unwrap().port();
+    socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+    let mut context = SslContext::builder(SslMethod::dtls()).unwrap();
+    context.set_private_key(&key).unwrap();
+    context.set_certificate(&cert).unwrap();
+    context.set_cipher_list("ECDHE-ECDSA-AES128-CCM").unwrap();
+    let context = context.build();
+    let server = thread::spawn(move || {
+      let accept = || Ssl::new(&context).unwrap().accept(UDPWrapper::new(socket.try_clone().unwrap())).unwrap();
+      let reply = |stream: &mut SslStream<UDPWrapper>, payload: &[u8]| {
+        let request = DTLSCoAPClient::receive_from_socket(stream).unwrap();
+        let mut response = CoAPResponse::new(&request).unwrap();
+        response.message.payload = payload.to_vec();
+        stream.ssl_write(&response.message.to_bytes().unwrap()).unwrap();
+        request
+      };
+
+      // notifications follow the registration over the same session
+      accept_peer(&socket);
+      let mut session = accept();
+      let registration = reply(&mut session, b"0");
+      let mut notification = Packet::new();
+      notification.header.set_type(MessageType::Confirmable);
+      notification.header.code = MessageClass::Response(Status::Content);
+      notification.set_token(registration.get_token().clone());
+      notification.set_observe(vec![1]);
+      notification.payload = b"1".to_vec();
+      session.ssl_write(&notification.to_bytes().unwrap()).unwrap();
+      let ack = DTLSCoAPClient::receive_from_socket(&mut session).unwrap();
+      assert_eq!(ack.header.get_type(), MessageType::Acknowledgement);
+      // the server restarts and forgets the session
+      session.shutdown().unwrap();
+
+      let mut resumed = accept();
+      let register = reply(&mut resumed, b"2");
+      reply(&mut resumed, b"");
+      register
+    });
+
+    let verify_any = DTLSClientBuilder::new().unwrap().with_verify_callback(|_, _| true);
+    let mut client = verify_any
+      .connect_with_specific_source("127.0.0.1:0", ("127.0.0.1", server_port))
+      .unwrap();
+    let (event_tx, event_rx) = mpsc::channel();
+    let event_tx = std::sync::Mutex::new(event_tx);
+    client.set_event_handler(move |event| {
+      if let ClientEvent::ObservationResumed { .. } = *event {
+        event_tx.lock().unwrap().send(event.clone()).unwrap();
+      }
+    });
+    let (tx, rx) = mpsc::channel();
+    let mut observation = client.observe("/state", move |packet| tx.send(packet.payload).unwrap()).unwrap();
+
+    match event_rx.recv_timeout(Duration::from_secs(5)).unwrap() {
+      ClientEvent::ObservationResumed { path, .. } => assert_eq!(path, "/state"),
+      _ => unreachable!(),
+    }
+    observation.cancel().unwrap();
+    let payloads: Vec<Vec<u8>> = rx.try_iter().collect();
+    assert_eq!(payloads, vec![b"0".to_vec(), b"1".to_vec(), b"2".to_vec()]);
+
+    let register = server.join().unwrap();
+    assert_eq!(register.get_observe(), Some(&vec![ObserveOption::Register as u8]));
+    assert_eq!(register.get_token().len(), 8);
+  }
+
+  #[test]
+  fn test_observe_shares_session() {
+    let (key, cert) = self_signed();
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let server_port = socket.local_addr().unwrap().port();
+    socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+    let mut context = SslContext::builder(SslMethod::dtls()).unwrap();
+    context.set_private_key(&key).unwrap();
+    context.set_certificate(&cert).unwrap();
+    context.set_cipher_list("ECDHE-ECDSA-AES128-CCM").unwrap();
+    let context = context.build();
+    let server = thread::spawn(move || {
+      accept_peer(&socket);
+      let mut session = Ssl::new(&context).unwrap().accept(UDPWrapper::new(socket.try_clone().unwrap())).unwrap();
+      let mut reply = |payload: &[u8]| {
+        let request = DTLSCoAPClient::receive_from_socket(&mut session).unwrap();
+        let mut response = CoAPResponse::new(&request).unwrap();
+        response.message.payload = payload.to_vec();
+        session.ssl_write(&response.message.to_bytes().unwrap()).unwrap();
+        request
+      };
+      let registration = reply(b"0");
+      // a notification arrives while the client waits for the response to another request
+      let request = DTLSCoAPClient::receive_from_socket(&mut session).unwrap();
+      let mut notification = Packet::new();
+      notification.header.set_type(MessageType::Confirmable);
+      notification.header.code = MessageClass::Response(Status::Content);
+      notification.set_token(registration.get_token().clone());
+      notification.set_observe(vec![1]);
+      notification.payload = b"1".to_vec();
+      session.ssl_write(&notification.to_bytes().unwrap()).unwrap();
+      let ack = DTLSCoAPClient::receive_from_socket(&mut session).unwrap();
+      assert_eq!(ack.header.get_type(), MessageType::Acknowledgement);
+      let mut response = CoAPResponse::new(&request).unwrap();
+      response.message.payload = b"other".to_vec();
+      session.ssl_write(&response.message.to_bytes().unwrap()).unwrap();
+
+      let deregister = DTLSCoAPClient::receive_from_socket(&mut session).unwrap();
+      let response = CoAPResponse::new(&deregister).unwrap();
+      session.ssl_write(&response.message.to_bytes().unwrap()).unwrap();
+      (registration, deregister)
+    });
+
+    let verify_any = DTLSClientBuilder::new().unwrap().with_verify_callback(|_, _| true);
+    let mut client = verify_any
+      .connect_with_specific_source("127.0.0.1:0", ("127.0.0.1", server_port))
+      .unwrap();
+    let handshakes = Arc::new(AtomicU32::new(0));
+    let counted = handshakes.clone();
+    client.set_event_handler(move |event| {
+      if let ClientEvent::HandshakeCompleted { .. } = *event {
+        counted.fetch_add(1, Ordering::SeqCst);
+      }
+    });
+    let (tx, rx) = mpsc::channel();
+    let tx = std::sync::Mutex::new(tx);
+    let mut observation = client
+      .observe("/state", move |packet| tx.lock().unwrap().send(packet.payload).unwrap())
+      .unwrap();
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), b"0".to_vec());
+    let mut request = CoAPRequest::new();
+    request.set_path("/other");
+    request.set_token(vec![0x99]);
+    client.send(&request).unwrap();
+    client.set_receive_timeout(Some(Duration::from_secs(5))).unwrap();
+    assert_eq!(client.receive().unwrap().message.payload, b"other".to_vec());
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), b"1".to_vec());
+    observation.cancel().unwrap();
+
+    let (registration, deregister) = server.join().unwrap();
+    assert_eq!(deregister.get_observe(), Some(&vec![ObserveOption::Deregister as u8]));
+    assert_eq!(deregister.get_token(), registration.get_token());
+    assert_eq!(handshakes.load(Ordering::SeqCst), 1);
+  }
+
+  #[test]
+  fn test_observation_restarted_after_panic() {
+    let (key, cert) = self_signed();
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let server_port = socket.local_addr().unwrap().port();
+    socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+    let mut context = SslContext::builder(SslMethod::dtls()).unwrap();
+    context.set_private_key(&key).unwrap();
+    context.set_certificate(&cert).unwrap();
+    context.set_cipher_list("ECDHE-ECDSA-AES128-CCM").unwrap();
+    let context = context.build();
+    let server = thread::spawn(move || {
+      accept_peer(&socket);
+      let mut session = Ssl::new(&context).unwrap().accept(UDPWrapper::new(socket.try_clone().unwrap())).unwrap();
+      let reply = |session: &mut SslStream<UDPWrapper>, payload: &[u8]| {
+        let request = DTLSCoAPClient::receive_from_socket(session).unwrap();
+        let mut response = CoAPResponse::new(&request).unwrap();
+        response.message.payload = payload.to_vec();
+        session.ssl_write(&response.message.to_bytes().unwrap()).unwrap();
+        request
+      };
+      let registration = reply(&mut session, b"0");
+      let mut notification = Packet::new();
+      notification.header.set_type(MessageType::NonConfirmable);
+      notification.header.code = MessageClass::Response(Status::Content);
+      notification.set_token(registration.get_token().clone());
+      notification.set_observe(vec![1]);
+      notification.payload = b"panic".to_vec();
+      session.ssl_write(&notification.to_bytes().unwrap()).unwrap();
+      // the restarted observation registers again
+      let registration = reply(&mut session, b"1");
+      reply(&mut session, b"");
+      registration
+    });
+
+    let verify_any = DTLSClientBuilder::new().unwrap().with_verify_callback(|_, _| true);
+    let mut client = verify_any
+      .connect_with_specific_source("127.0.0.1:0", ("127.0.0.1", server_port))
+      .unwrap();
+    let (tx, rx) = mpsc::channel();
+    let tx = std::sync::Mutex::new(tx);
+    let mut observation = client
+      .observe("/state", move |packet| {
+        if packet.payload == b"panic" {
+          panic!("handler failed");
+        }
+        tx.lock().unwrap().send(packet.payload).unwrap();
+      })
+      .unwrap();
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), b"0".to_vec());
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), b"1".to_vec());
+    assert_eq!(observation.restarts(), 1);
+    assert_eq!(observation.state(), ObservationState::Active);
+    observation.cancel().unwrap();
+
+    let registration = server.join().unwrap();
+    assert_eq!(registration.get_observe(), Some(&vec![ObserveOption::Register as u8]));
+  }
+
+  #[test]
+  fn test_resumption_with_queued_request() {
+    let (key, cert) = self_signed();
+    // the woken up client has another address, which the server reaches on its own socket
+    let sockets: Vec<std::net::UdpSocket> = (0..2).map(|_| std::net::UdpSocket::bind("127.0.0.1:0").unwrap()).collect();
+    let server_ports: Vec<u16> = sockets.iter().map(|socket| socket.local_addr().unwrap().port()).collect();
+    for socket in &sockets {
+      socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    }
+
+    let mut context = SslContext::builder(SslMethod::dtls()).unwrap();
+    context.set_private_key(&key).unwrap();
+    context.set_certificate(&cert).unwrap();
+    context.set_cipher_list("ECDHE-ECDSA-AES128-CCM").unwrap();
+    let context = context.build();
+    let server = thread::spawn(move || {
+      let accept = |socket: &std::net::UdpSocket| Ssl::new(&context).unwrap().accept(UDPWrapper::new(socket.try_clone().unwrap())).unwrap();
+      let reply = |stream: &mut SslStream<UDPWrapper>| {
+        let request = DTLSCoAPClient::receive_from_socket(stream).unwrap();
+        let response = CoAPResponse::new(&request).unwrap();
+        stream.ssl_write(&response.message.to_bytes().unwrap()).unwrap();
+        request.payload
+      };
+
+      let mut resumed = Vec::new();
+      for socket in [&sockets[0], &sockets[0], &sockets[1]].iter() {
+        accept_peer(socket);
+        let mut session = accept(socket);
+        resumed.push(session.ssl().session_reused());
+        // the first message of the session is the request
+        let payload = reply(&mut session);
+        session.shutdown().unwrap();
+        resumed.push(payload == b"queued");
+      }
+      resumed
+    });
+
+    let verify_any = || DTLSClientBuilder::new().unwrap().with_verify_callback(|_, _| true);
+    let mut client = verify_any()
+      .connect_with_specific_source("127.0.0.1:0", ("127.0.0.1", server_ports[0]))
+      .unwrap();
+    let resumptions = Arc::new(AtomicU32::new(0));
+    let counted = resumptions.clone();
+    client.set_event_handler(move |event| {
+      if let ClientEvent::SessionResumed = *event {
+        counted.fetch_add(1, Ordering::SeqCst);
+      }
+    });
+    let mut request = CoAPRequest::new();
+    request.set_path("/light");
+    request.set_payload(b"sent".to_vec());
+    client.send(&request).unwrap();
+    client.receive().unwrap();
+
+    // the request is on its way as soon as the abbreviated handshake completes
+    request.set_payload(b"queued".to_vec());
+    client.queue_request(&request);
+    client.reconnect().unwrap();
+    assert_eq!(*client.receive().unwrap().get_status(), Status::Content);
+    assert_eq!(resumptions.load(Ordering::SeqCst), 1);
+
+    // a client of a woken up device resumes the session it saved
+    let saved = client.saved_session().unwrap();
+    drop(client);
+    // saved by a client of the same configuration
+    let client = unsafe { verify_any().with_saved_session(&saved) }
+      .unwrap()
+      .with_early_request(&request)
+      .connect_with_specific_source("127.0.0.1:0", ("127.0.0.1", server_ports[1]))
+      .unwrap();
+    assert_eq!(*client.receive().unwrap().get_status(), Status::Content);
+
+    assert_eq!(server.join().unwrap(), vec![false, false, true, true, true, true]);
+    assert!(unsafe { DTLSClientBuilder::new().unwrap().with_saved_session(b"garbage") }.is_err());
   }
 
   async fn request_handler(_: CoAPRequest) -> Option<CoAPResponse> {
@@ -0,0 +1,471 @@
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::debug;
+use openssl::error::ErrorStack;
+use openssl::ex_data::Index;
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkey::{PKey, Private};
+use openssl::sign::Signer;
+use openssl::ssl::{
+    ErrorCode, HandshakeError, MidHandshakeSslStream, Ssl, SslContext, SslContextBuilder, SslOptions, SslRef,
+    SslStream,
+};
+
+use super::rng::OsRandom;
+use super::session::{EvictionReason, SessionPolicy, SessionTable};
+
+// the largest datagram a session decrypts, like the plain listeners read
+const MAX_DATAGRAM_SIZE: usize = 65536;
+
+/// The datagrams exchanged with one peer. The listener's socket is shared by all peers, so
+/// the session reads the datagrams the listener hands it and leaves the ones it writes for the
+/// listener to send.
+#[derive(Default)]
+struct PeerChannel {
+    incoming: VecDeque<Vec<u8>>,
+    outgoing: Vec<Vec<u8>>,
+}
+
+impl Read for PeerChannel {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.incoming.pop_front() {
+            Some(datagram) => {
+                // like a datagram socket, the part that does not fit is discarded
+                let len = datagram.len().min(buf.len());
+                buf[..len].copy_from_slice(&datagram[..len]);
+                Ok(len)
+            }
+            None => Err(io::Error::new(ErrorKind::WouldBlock, "no datagram from the peer")),
+        }
+    }
+}
+
+impl Write for PeerChannel {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outgoing.push(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Called with the address and PSK identity of every peer whose established session is
+/// evicted or closed, shared by the DTLS listeners of a server.
+pub(crate) type EvictionHook = Arc<Mutex<Box<dyn FnMut(SocketAddr, Option<&[u8]>, EvictionReason) + Send>>>;
+
+/// How long a handshake may stall before it is dropped.
+pub(crate) const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// the handshakes in progress at once, the least recently active one dropped beyond
+const MAX_HANDSHAKES: usize = 256;
+
+struct Session {
+    stream: SslStream<PeerChannel>,
+    // the PSK identity the session was authenticated with, if any
+    identity: Option<Vec<u8>>,
+}
+
+/// What a datagram from a peer came to.
+#[derive(Default)]
+pub(crate) struct Received {
+    /// The CoAP messages the datagram carried, decrypted.
+    pub messages: Vec<Vec<u8>>,
+    /// The datagrams to send back, e.g. the next flight of the handshake.
+    pub replies: Vec<Vec<u8>>,
+}
+
+/// The DTLS sessions of a server listener, one per peer, accepted with the listener's
+/// context and kept as the session policy says.
+///
+/// A ClientHello is answered with a HelloVerifyRequest carrying a cookie bound to the
+/// address it came from, and nothing is kept until the peer returns the cookie (RFC 6347,
+/// section 4.2.1), so hellos with spoofed addresses neither hold state nor draw the larger
+/// flights of the handshake to their victims. Handshakes in progress are kept apart from
+/// the established sessions, with a short timeout and a cap of their own, so that a burst
+/// of new handshakes drops other handshakes rather than the sessions of devices.
+pub(crate) struct DtlsListener {
+    context: SslContext,
+    // where the cookie callbacks find the address of the peer
+    peer_index: Index<Ssl, SocketAddr>,
+    handshakes: SessionTable<MidHandshakeSslStream<PeerChannel>>,
+    sessions: SessionTable<Session>,
+}
+
+impl DtlsListener {
+    /// Builds the context with the cookie exchange, keyed with a secret of the listener.
+    pub fn new(mut context: SslContextBuilder, policy: SessionPolicy) -> io::Result<DtlsListener> {
+        let mut secret = [0; 32];
+        OsRandom.try_fill_bytes(&mut secret)?;
+        let key = PKey::hmac(&secret).map_err(io::Error::other)?;
+        let peer_index = Ssl::new_ex_index().map_err(io::Error::other)?;
+
+        let generate_key = key.clone();
+        context.set_options(SslOptions::COOKIE_EXCHANGE);
+        context.set_cookie_generate_cb(move |ssl, buf| {
+            let cookie = cookie(&generate_key, ssl, peer_index)?;
+            buf[..cookie.len()].copy_from_slice(&cookie);
+            Ok(cookie.len())
+        });
+        context.set_cookie_verify_cb(move |ssl, received| {
+            match cookie(&key, ssl, peer_index) {
+                Ok(cookie) => cookie.len() == received.len() && memcmp::eq(&cookie, received),
+                Err(_) => false,
+            }
+        });
+
+        let handshake_policy = SessionPolicy::default()
+            .with_max_sessions(MAX_HANDSHAKES)
+            .with_idle_timeout(HANDSHAKE_TIMEOUT);
+        Ok(DtlsListener {
+            context: context.build(),
+            peer_index,
+            handshakes: SessionTable::new(handshake_policy),
+            sessions: SessionTable::new(policy),
+        })
+    }
+
+    pub fn set_policy(&mut self, policy: SessionPolicy) {
+        self.sessions.set_policy(policy);
+    }
+
+    /// Reports the established sessions leaving the listener to the hook.
+    pub fn set_eviction_hook(&mut self, hook: EvictionHook) {
+        self.sessions.set_eviction_hook(move |peer, session: &Session, reason| {
+            (*hook.lock().unwrap())(peer, session.identity.as_deref(), reason);
+        });
+    }
+
+    /// Evicts the sessions idle for longer than the idle timeout of the policy and the
+    /// handshakes stalled for longer than `HANDSHAKE_TIMEOUT`.
+    pub fn evict_idle(&mut self) -> usize {
+        self.handshakes.evict_idle() + self.sessions.evict_idle()
+    }
+
+    /// Hands a datagram to the session of the peer, accepting a new session from unknown
+    /// peers. Sessions failing their handshake or closed by the peer are dropped.
+    pub fn receive(&mut self, peer: SocketAddr, datagram: &[u8]) -> Received {
+        let mut received = Received::default();
+        if let Some(session) = self.sessions.get_mut(&peer) {
+            session.stream.get_mut().incoming.push_back(datagram.to_vec());
+            if !read_messages(peer, &mut session.stream, &mut received) {
+                self.sessions.remove(&peer);
+            }
+            return received;
+        }
+
+        let handshake = match self.handshakes.remove(&peer) {
+            Some(mut stream) => {
+                stream.get_mut().incoming.push_back(datagram.to_vec());
+                stream.handshake()
+            }
+            None => match self.accept(peer, datagram, &mut received) {
+                Some(handshake) => handshake,
+                None => return received,
+            },
+        };
+        match handshake {
+            Ok(mut stream) => {
+                let identity = stream.ssl().psk_identity().map(|identity| identity.to_vec());
+                if read_messages(peer, &mut stream, &mut received) {
+                    self.sessions.insert(peer, Session { stream, identity });
+                }
+            }
+            Err(HandshakeError::WouldBlock(mut stream)) => {
+                received.replies = std::mem::take(&mut stream.get_mut().outgoing);
+                self.handshakes.insert(peer, stream);
+            }
+            Err(HandshakeError::Failure(mut stream)) => {
+                debug!("DTLS handshake with {} failed: {}", peer, stream.error());
+                // the alert telling the peer why
+                received.replies = std::mem::take(&mut stream.get_mut().outgoing);
+            }
+            Err(HandshakeError::SetupFailure(e)) => debug!("cannot accept a DTLS session from {}: {}", peer, e),
+        }
+        received
+    }
+
+    /// Starts a handshake with an unknown peer if the datagram is a ClientHello returning a
+    /// valid cookie. A ClientHello without one is answered with a HelloVerifyRequest, left
+    /// in `received`, and forgotten.
+    ///
+    /// OpenSSL only takes the ClientHello with the cookie after the one it answered, so the
+    /// session is first handed that one, rebuilt from the cookie's, and its answer dropped.
+    fn accept(
+        &self,
+        peer: SocketAddr,
+        datagram: &[u8],
+        received: &mut Received,
+    ) -> Option<Result<SslStream<PeerChannel>, HandshakeError<PeerChannel>>> {
+        let mut ssl = match Ssl::new(&self.context) {
+            Ok(ssl) => ssl,
+            Err(e) => {
+                debug!("cannot accept a DTLS session from {}: {}", peer, e);
+                return None;
+            }
+        };
+        ssl.set_ex_data(self.peer_index, peer);
+        let mut channel = PeerChannel::default();
+        let hello = match without_cookie(datagram) {
+            Some(hello) => hello,
+            None => {
+                channel.incoming.push_back(datagram.to_vec());
+                if let Err(HandshakeError::WouldBlock(mut stream)) | Err(HandshakeError::Failure(mut stream)) =
+                    ssl.accept(channel)
+                {
+                    received.replies = std::mem::take(&mut stream.get_mut().outgoing);
+                }
+                return None;
+            }
+        };
+        channel.incoming.push_back(hello);
+        let mut stream = match ssl.accept(channel) {
+            Err(HandshakeError::WouldBlock(stream)) => stream,
+            _ => return None,
+        };
+        stream.get_mut().outgoing.clear();
+        stream.get_mut().incoming.push_back(datagram.to_vec());
+        Some(stream.handshake())
+    }
+
+    /// Encrypts a message to the peer, returning the datagrams to send. Fails unless the
+    /// peer has an established session.
+    pub fn send(&mut self, peer: &SocketAddr, message: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+        let session = self
+            .sessions
+            .get_mut(peer)
+            .ok_or_else(|| io::Error::new(ErrorKind::NotConnected, "no DTLS session with the peer"))?;
+        session
+            .stream
+            .ssl_write(message)
+            .map_err(|e| io::Error::new(ErrorKind::ConnectionAborted, e.to_string()))?;
+        Ok(std::mem::take(&mut session.stream.get_mut().outgoing))
+    }
+
+    /// The PSK identity the peer authenticated its session with.
+    pub fn psk_identity(&self, peer: &SocketAddr) -> Option<Vec<u8>> {
+        self.sessions.get(peer)?.identity.clone()
+    }
+}
+
+/// The cookie of the peer of the session, a MAC of its address.
+fn cookie(key: &PKey<Private>, ssl: &SslRef, peer_index: Index<Ssl, SocketAddr>) -> Result<Vec<u8>, ErrorStack> {
+    let peer = ssl.ex_data(peer_index).ok_or_else(ErrorStack::get)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), key)?;
+    signer.update(peer.to_string().as_bytes())?;
+    signer.sign_to_vec()
+}
+
+// the offsets in a datagram holding one DTLS record with one handshake message (RFC 6347,
+// section 4.1 and 4.2.2)
+const RECORD_SEQUENCE: std::ops::Range<usize> = 5..11;
+const RECORD_LENGTH: usize = 11;
+const HANDSHAKE: usize = 13;
+const MESSAGE_LENGTH: usize = 14;
+const MESSAGE_SEQ: usize = 17;
+const FRAGMENT_OFFSET: usize = 19;
+const FRAGMENT_LENGTH: usize = 22;
+// past the client version and random of the ClientHello
+const SESSION_ID: usize = 25 + 2 + 32;
+
+/// Rebuilds the ClientHello the peer sent before a ClientHello returning a cookie: the
+/// first message and record of the handshake, without the cookie. `None` unless the
+/// datagram is a whole ClientHello with a cookie.
+fn without_cookie(datagram: &[u8]) -> Option<Vec<u8>> {
+    let uint = |at: usize, len: usize| {
+        let bytes = datagram.get(at..at + len)?;
+        Some(bytes.iter().fold(0, |value, &byte| value << 8 | byte as usize))
+    };
+    let uint24 = |at: usize| uint(at, 3);
+    let record_length = uint(RECORD_LENGTH, 2)?;
+    let message_length = uint24(MESSAGE_LENGTH)?;
+    if datagram[0] != 22
+        || datagram[HANDSHAKE] != 1
+        || record_length != datagram.len() - HANDSHAKE
+        || message_length + 12 != record_length
+        || uint24(FRAGMENT_OFFSET)? != 0
+        || uint24(FRAGMENT_LENGTH)? != message_length
+    {
+        return None;
+    }
+    let cookie = SESSION_ID + 1 + *datagram.get(SESSION_ID)? as usize;
+    let cookie_length = *datagram.get(cookie)? as usize;
+    if cookie_length == 0 || cookie + 1 + cookie_length > datagram.len() {
+        return None;
+    }
+
+    let mut hello = [&datagram[..=cookie], &datagram[cookie + 1 + cookie_length..]].concat();
+    hello[cookie] = 0;
+    hello[RECORD_SEQUENCE].iter_mut().for_each(|byte| *byte = 0);
+    hello[RECORD_LENGTH..RECORD_LENGTH + 2].copy_from_slice(&((record_length - cookie_length) as u16).to_be_bytes());
+    hello[MESSAGE_SEQ..MESSAGE_SEQ + 2].copy_from_slice(&[0, 0]);
+    let length = ((message_length - cookie_length) as u32).to_be_bytes();
+    hello[MESSAGE_LENGTH..MESSAGE_LENGTH + 3].copy_from_slice(&length[1..]);
+    hello[FRAGMENT_LENGTH..FRAGMENT_LENGTH + 3].copy_from_slice(&length[1..]);
+    Some(hello)
+}
+
+/// Decrypts the datagrams handed to an established session, leaving its replies, e.g. the
+/// close_notify answering the peer's, in `received`. Returns whether the session is open.
+fn read_messages(peer: SocketAddr, stream: &mut SslStream<PeerChannel>, received: &mut Received) -> bool {
+    let mut open = true;
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+    loop {
+        match stream.ssl_read(&mut buf) {
+            Ok(len) => received.messages.push(buf[..len].to_vec()),
+            Err(ref e) if e.code() == ErrorCode::WANT_READ => break,
+            Err(ref e) if e.code() == ErrorCode::ZERO_RETURN => {
+                debug!("{} closed its DTLS session", peer);
+                let _ = stream.shutdown();
+                open = false;
+                break;
+            }
+            Err(e) => {
+                debug!("DTLS session with {} failed: {}", peer, e);
+                open = false;
+                break;
+            }
+        }
+    }
+    received.replies.extend(std::mem::take(&mut stream.get_mut().outgoing));
+    open
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ssl_utils::get_psk_connector;
+    use openssl::ssl::SslMethod;
+
+    // a context accepting the PSK identities `sensor-*` with the key `secret`
+    fn psk_context() -> SslContextBuilder {
+        let mut context = SslContextBuilder::new(SslMethod::dtls()).unwrap();
+        context.set_cipher_list("ECDHE-PSK-AES128-CBC-SHA256").unwrap();
+        context.set_psk_server_callback(|_ssl, identity, psk| {
+            if !matches!(identity, Some(identity) if identity.starts_with(b"sensor-")) {
+                return Ok(0);
+            }
+            psk[..6].copy_from_slice(b"secret");
+            Ok(6)
+        });
+        context
+    }
+
+    fn peer(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn client_hello() -> MidHandshakeSslStream<PeerChannel> {
+        let connector = get_psk_connector(b"sensor-1".to_vec(), b"secret".to_vec()).unwrap();
+        let ssl = connector.configure().unwrap().into_ssl("localhost").unwrap();
+        match ssl.connect(PeerChannel::default()) {
+            Err(HandshakeError::WouldBlock(stream)) => stream,
+            _ => panic!("the client sends its ClientHello and waits"),
+        }
+    }
+
+    // sends the ClientHello of a client and the one returning the cookie it was sent,
+    // leaving the client waiting for the server's flight
+    fn verified_hello(listener: &mut DtlsListener, peer: SocketAddr) -> MidHandshakeSslStream<PeerChannel> {
+        let mut stream = client_hello();
+        for datagram in std::mem::take(&mut stream.get_mut().outgoing) {
+            let replies = listener.receive(peer, &datagram).replies;
+            stream.get_mut().incoming.extend(replies);
+        }
+        let mut stream = match stream.handshake() {
+            Err(HandshakeError::WouldBlock(stream)) => stream,
+            _ => panic!("the client returns the cookie and waits"),
+        };
+        for datagram in std::mem::take(&mut stream.get_mut().outgoing) {
+            listener.receive(peer, &datagram);
+        }
+        stream
+    }
+
+    // runs the handshake of a client with the listener in memory
+    fn connect(listener: &mut DtlsListener, peer: SocketAddr) -> SslStream<PeerChannel> {
+        let mut stream = client_hello();
+        loop {
+            for datagram in std::mem::take(&mut stream.get_mut().outgoing) {
+                let replies = listener.receive(peer, &datagram).replies;
+                stream.get_mut().incoming.extend(replies);
+            }
+            stream = match stream.handshake() {
+                Ok(stream) => return stream,
+                Err(HandshakeError::WouldBlock(stream)) => stream,
+                Err(_) => panic!("the handshake failed"),
+            };
+        }
+    }
+
+    #[test]
+    fn test_handshakes_do_not_evict_sessions() {
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        let mut listener = DtlsListener::new(psk_context(), SessionPolicy::default().with_max_sessions(1)).unwrap();
+        let hook_evicted = evicted.clone();
+        let hook: EvictionHook = Arc::new(Mutex::new(Box::new(move |peer, _identity: Option<&[u8]>, reason| {
+            hook_evicted.lock().unwrap().push((peer, reason));
+        })));
+        listener.set_eviction_hook(hook);
+
+        let mut device = connect(&mut listener, peer(1));
+        assert_eq!(listener.psk_identity(&peer(1)), Some(b"sensor-1".to_vec()));
+
+        // a burst of handshakes that are never completed
+        for port in 2..2 + MAX_HANDSHAKES as u16 + 10 {
+            verified_hello(&mut listener, peer(port));
+        }
+        assert_eq!(listener.handshakes.len(), MAX_HANDSHAKES);
+        assert!(evicted.lock().unwrap().is_empty());
+
+        device.ssl_write(b"reading").unwrap();
+        let mut received = Received::default();
+        for datagram in std::mem::take(&mut device.get_mut().outgoing) {
+            let mut datagram = listener.receive(peer(1), &datagram);
+            received.messages.append(&mut datagram.messages);
+        }
+        assert_eq!(received.messages, vec![b"reading".to_vec()]);
+
+        // a second device does take the only session
+        connect(&mut listener, peer(2));
+        assert_eq!(*evicted.lock().unwrap(), vec![(peer(1), EvictionReason::Capacity)]);
+    }
+
+    #[test]
+    fn test_handshakes_need_a_cookie() {
+        let mut listener = DtlsListener::new(psk_context(), SessionPolicy::default()).unwrap();
+
+        // a hello, spoofed or not, only draws the HelloVerifyRequest
+        let mut stream = client_hello();
+        for datagram in std::mem::take(&mut stream.get_mut().outgoing) {
+            let replies = listener.receive(peer(1), &datagram).replies;
+            assert_eq!(replies.len(), 1);
+            assert!(replies[0].len() <= datagram.len());
+            stream.get_mut().incoming.extend(replies);
+        }
+        assert_eq!(listener.handshakes.len(), 0);
+
+        // the cookie only holds for the address it was sent to
+        let mut stream = match stream.handshake() {
+            Err(HandshakeError::WouldBlock(stream)) => stream,
+            _ => panic!("the client returns the cookie and waits"),
+        };
+        let hello = std::mem::take(&mut stream.get_mut().outgoing);
+        for datagram in &hello {
+            listener.receive(peer(2), datagram);
+        }
+        assert_eq!(listener.handshakes.len(), 0);
+        for datagram in &hello {
+            assert!(!listener.receive(peer(1), datagram).replies.is_empty());
+        }
+        assert_eq!(listener.handshakes.len(), 1);
+
+        connect(&mut listener, peer(3));
+        assert_eq!(listener.sessions.len(), 1);
+    }
+}
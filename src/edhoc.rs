@@ -0,0 +1,833 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::io;
+use std::time::{Duration, Instant};
+
+use openssl::derive::Deriver;
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkey::{Id, PKey, Private};
+use openssl::sha::sha256;
+use openssl::sign::Signer;
+use openssl::cipher::Cipher;
+use openssl::cipher_ctx::CipherCtx;
+
+use super::cbor::{self, encode_sequence, Value};
+use super::client::CoAPClient;
+use super::message::packet::ContentFormat;
+use super::message::request::{CoAPRequest, Method};
+use super::message::response::{CoAPResponse, Status};
+use super::message::IsMessage;
+use super::oscore::SecurityContext;
+
+/// The resource EDHOC messages are exchanged on (RFC 9528 Appendix A.2).
+pub const EDHOC_PATH: &str = ".well-known/edhoc";
+
+// Method 3: both parties authenticate with static Diffie-Hellman keys.
+const METHOD_STATIC_DH: i64 = 3;
+// Cipher suite 0: AES-CCM-16-64-128, SHA-256, MAC length 8, X25519.
+const SUITE: i64 = 0;
+const HASH_LENGTH: usize = 32;
+const KEY_LENGTH: usize = 16;
+const IV_LENGTH: usize = 13;
+const MAC_LENGTH: usize = 8;
+const X25519_LENGTH: usize = 32;
+const ERR_CODE_UNSPECIFIED: i64 = 1;
+// C_R is allocated among the one-byte and then the two-byte identifiers.
+const CONNECTION_ID_SPACE: u32 = 0x100 + 0x10000;
+const DEFAULT_MAX_SESSIONS: usize = 64;
+const DEFAULT_SESSION_TIMEOUT: Duration = Duration::from_secs(60);
+const DEFAULT_MAX_CONTEXTS: usize = 1024;
+
+#[derive(Debug, PartialEq)]
+pub enum EdhocError {
+    /// A message could not be decoded.
+    Malformed,
+    UnsupportedMethod(i64),
+    UnsupportedSuite,
+    /// The peer authenticated with a credential that is not trusted.
+    UnknownCredential(Vec<u8>),
+    /// The MAC of the peer did not verify.
+    MacMismatch,
+    /// message_3 failed to decrypt.
+    IntegrityFailure,
+    /// A message arrived out of order or for an unknown session.
+    UnexpectedMessage,
+    /// The responder has no room for another session, as too many are pending or all
+    /// connection identifiers are in use.
+    Busy,
+    /// The peer answered with an EDHOC error message.
+    Peer(String),
+    Crypto(String),
+}
+
+impl fmt::Display for EdhocError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EdhocError::Malformed => write!(f, "malformed EDHOC message"),
+            EdhocError::UnsupportedMethod(method) => write!(f, "unsupported EDHOC method {}", method),
+            EdhocError::UnsupportedSuite => write!(f, "unsupported EDHOC cipher suite"),
+            EdhocError::UnknownCredential(ref kid) => write!(f, "unknown credential {:02x?}", kid),
+            EdhocError::MacMismatch => write!(f, "EDHOC MAC verification failed"),
+            EdhocError::IntegrityFailure => write!(f, "EDHOC message_3 integrity check failed"),
+            EdhocError::UnexpectedMessage => write!(f, "unexpected EDHOC message"),
+            EdhocError::Busy => write!(f, "no room for another EDHOC session"),
+            EdhocError::Peer(ref diagnostic) => write!(f, "EDHOC error from peer: {}", diagnostic),
+            EdhocError::Crypto(ref e) => write!(f, "EDHOC crypto error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for EdhocError {}
+
+impl From<ErrorStack> for EdhocError {
+    fn from(e: ErrorStack) -> EdhocError {
+        EdhocError::Crypto(e.to_string())
+    }
+}
+
+impl From<cbor::CborError> for EdhocError {
+    fn from(_: cbor::CborError) -> EdhocError {
+        EdhocError::Malformed
+    }
+}
+
+impl From<EdhocError> for io::Error {
+    fn from(e: EdhocError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+type Result<T> = std::result::Result<T, EdhocError>;
+
+/// A CCS credential carrying an X25519 public key, identified by its kid.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Credential {
+    kid: Vec<u8>,
+    subject: String,
+    public_key: Vec<u8>,
+}
+
+impl Credential {
+    pub fn new(kid: &[u8], subject: &str, public_key: &[u8]) -> Credential {
+        Credential {
+            kid: kid.to_vec(),
+            subject: subject.to_string(),
+            public_key: public_key.to_vec(),
+        }
+    }
+
+    pub fn kid(&self) -> &[u8] {
+        &self.kid
+    }
+
+    pub fn subject(&self) -> &str {
+        &self.subject
+    }
+
+    /// Encodes CRED_x as a CWT Claims Set holding an OKP COSE_Key.
+    fn encode(&self) -> Vec<u8> {
+        let cose_key = Value::Map(vec![
+            (Value::Integer(1), Value::Integer(1)),
+            (Value::Integer(2), Value::Bytes(self.kid.clone())),
+            (Value::Integer(-1), Value::Integer(4)),
+            (Value::Integer(-2), Value::Bytes(self.public_key.clone())),
+        ]);
+        Value::Map(vec![
+            (Value::Integer(2), Value::Text(self.subject.clone())),
+            (Value::Integer(8), Value::Map(vec![(Value::Integer(1), cose_key)])),
+        ])
+        .to_vec()
+    }
+
+    /// Encodes ID_CRED_x as a kid header map.
+    fn id_cred(&self) -> Vec<u8> {
+        Value::Map(vec![(Value::Integer(4), Value::Bytes(self.kid.clone()))]).to_vec()
+    }
+}
+
+/// A static X25519 key pair together with its credential.
+pub struct Identity {
+    key: PKey<Private>,
+    credential: Credential,
+}
+
+impl Identity {
+    /// Generate a fresh key pair.
+    pub fn generate(kid: &[u8], subject: &str) -> Result<Identity> {
+        Self::with_key(PKey::generate_x25519()?, kid, subject)
+    }
+
+    /// Load a key pair from the raw 32-byte X25519 private key.
+    pub fn from_private_key(private_key: &[u8], kid: &[u8], subject: &str) -> Result<Identity> {
+        Self::with_key(PKey::private_key_from_raw_bytes(private_key, Id::X25519)?, kid, subject)
+    }
+
+    fn with_key(key: PKey<Private>, kid: &[u8], subject: &str) -> Result<Identity> {
+        let public_key = key.raw_public_key()?;
+        Ok(Identity {
+            credential: Credential::new(kid, subject, &public_key),
+            key,
+        })
+    }
+
+    pub fn credential(&self) -> &Credential {
+        &self.credential
+    }
+}
+
+struct InitiatorSession {
+    ephemeral: PKey<Private>,
+    message_1_hash: [u8; HASH_LENGTH],
+}
+
+/// The party starting an EDHOC exchange, usually the CoAP client.
+pub struct Initiator {
+    identity: Identity,
+    connection_id: Vec<u8>,
+    peers: HashMap<Vec<u8>, Credential>,
+    session: Option<InitiatorSession>,
+}
+
+impl Initiator {
+    /// Creates an initiator using `connection_id` as C_I, which becomes its OSCORE Recipient ID.
+    pub fn new(identity: Identity, connection_id: &[u8]) -> Initiator {
+        Initiator {
+            identity,
+            connection_id: connection_id.to_vec(),
+            peers: HashMap::new(),
+            session: None,
+        }
+    }
+
+    /// Trust a responder credential.
+    pub fn add_peer(&mut self, credential: Credential) {
+        self.peers.insert(credential.kid.clone(), credential);
+    }
+
+    /// Builds message_1, starting a new exchange.
+    pub fn message_1(&mut self) -> Result<Vec<u8>> {
+        let ephemeral = PKey::generate_x25519()?;
+        let mut message_1 = encode_sequence(&[
+            Value::Integer(METHOD_STATIC_DH),
+            Value::Integer(SUITE),
+            Value::Bytes(ephemeral.raw_public_key()?),
+        ]);
+        message_1.extend(encode_id(&self.connection_id));
+
+        self.session = Some(InitiatorSession {
+            ephemeral,
+            message_1_hash: sha256(&message_1),
+        });
+        Ok(message_1)
+    }
+
+    /// Verifies message_2 and builds message_3, returning it with the derived OSCORE context.
+    pub fn process_message_2(&mut self, message_2: &[u8]) -> Result<(Vec<u8>, SecurityContext)> {
+        let session = self.session.take().ok_or(EdhocError::UnexpectedMessage)?;
+
+        let (value, used) = cbor::decode(message_2)?;
+        let payload = match value {
+            Value::Bytes(ref payload) if used == message_2.len() && payload.len() > X25519_LENGTH => payload,
+            _ => return Err(EdhocError::Malformed),
+        };
+        let (g_y, ciphertext_2) = payload.split_at(X25519_LENGTH);
+
+        let g_xy = ecdh(&session.ephemeral, g_y)?;
+        let th_2 = transcript(&[&bstr(g_y), &bstr(&session.message_1_hash)]);
+        let prk_2e = hkdf_extract(&th_2, &g_xy)?;
+        let keystream_2 = edhoc_kdf(&prk_2e, 0, &th_2, ciphertext_2.len())?;
+        let plaintext_2 = xor(ciphertext_2, &keystream_2);
+
+        let items = cbor::decode_sequence(&plaintext_2)?;
+        if items.len() < 3 {
+            return Err(EdhocError::Malformed);
+        }
+        let c_r = decode_id(&items[0]).ok_or(EdhocError::Malformed)?;
+        let kid_r = decode_id(&items[1]).ok_or(EdhocError::Malformed)?;
+        let mac_2 = items[2].as_bytes().ok_or(EdhocError::Malformed)?;
+        let peer = self.peers.get(&kid_r).ok_or(EdhocError::UnknownCredential(kid_r))?;
+
+        let salt_3e2m = edhoc_kdf(&prk_2e, 1, &th_2, HASH_LENGTH)?;
+        let prk_3e2m = hkdf_extract(&salt_3e2m, &ecdh(&session.ephemeral, &peer.public_key)?)?;
+        let cred_r = peer.encode();
+        let context_2 = [encode_id(&c_r), peer.id_cred(), bstr(&th_2), cred_r.clone()].concat();
+        verify_mac(&edhoc_kdf(&prk_3e2m, 2, &context_2, MAC_LENGTH)?, mac_2)?;
+
+        let th_3 = transcript(&[&bstr(&th_2), &plaintext_2, &cred_r]);
+        let salt_4e3m = edhoc_kdf(&prk_3e2m, 5, &th_3, HASH_LENGTH)?;
+        let prk_4e3m = hkdf_extract(&salt_4e3m, &ecdh(&self.identity.key, g_y)?)?;
+
+        let own = &self.identity.credential;
+        let cred_i = own.encode();
+        let context_3 = [own.id_cred(), bstr(&th_3), cred_i.clone()].concat();
+        let mac_3 = edhoc_kdf(&prk_4e3m, 6, &context_3, MAC_LENGTH)?;
+        let plaintext_3 = [encode_id(&own.kid), bstr(&mac_3)].concat();
+
+        let (key, iv) = message_3_key(&prk_3e2m, &th_3)?;
+        let ciphertext_3 = aead_encrypt(&key, &iv, &enc_structure(&th_3), &plaintext_3)?;
+
+        let th_4 = transcript(&[&bstr(&th_3), &plaintext_3, &cred_i]);
+        let prk_out = edhoc_kdf(&prk_4e3m, 7, &th_4, HASH_LENGTH)?;
+        let context = security_context(&prk_out, c_r, self.connection_id.clone())?;
+        Ok((bstr(&ciphertext_3), context))
+    }
+
+    /// Runs the exchange against the EDHOC resource of a server.
+    pub fn establish(&mut self, client: &CoAPClient) -> io::Result<SecurityContext> {
+        let mut request = CoAPRequest::new();
+        request.set_method(Method::Post);
+        request.set_path(EDHOC_PATH);
+        request.message.set_content_format(ContentFormat::ApplicationCidEdhocCborSeq);
+
+        request.set_payload([Value::Bool(true).to_vec(), self.message_1()?].concat());
+        client.send(&request)?;
+        let message_2 = Self::check_response(client.receive()?)?;
+
+        let (message_3, context) = self.process_message_2(&message_2)?;
+        request.set_payload([encode_id(&context.sender_id), message_3].concat());
+        client.send(&request)?;
+        Self::check_response(client.receive()?)?;
+
+        Ok(context)
+    }
+
+    fn check_response(response: CoAPResponse) -> Result<Vec<u8>> {
+        if *response.get_status() == Status::Changed {
+            return Ok(response.message.payload);
+        }
+
+        let diagnostic = cbor::decode_sequence(&response.message.payload)
+            .ok()
+            .and_then(|items| items.get(1).and_then(|item| item.as_text().map(String::from)))
+            .unwrap_or_else(|| format!("{:?}", response.get_status()));
+        Err(EdhocError::Peer(diagnostic))
+    }
+}
+
+struct ResponderSession {
+    ephemeral: PKey<Private>,
+    connection_id: Vec<u8>,
+    prk_3e2m: Vec<u8>,
+    th_3: Vec<u8>,
+    started: Instant,
+}
+
+/// The party answering EDHOC exchanges, usually serving `/.well-known/edhoc`.
+pub struct Responder {
+    identity: Identity,
+    peers: HashMap<Vec<u8>, Credential>,
+    sessions: HashMap<Vec<u8>, ResponderSession>,
+    contexts: HashMap<Vec<u8>, SecurityContext>,
+    // the C_R of the established contexts, oldest first
+    context_order: VecDeque<Vec<u8>>,
+    next_connection_id: u32,
+    max_sessions: usize,
+    session_timeout: Duration,
+    max_contexts: usize,
+}
+
+impl Responder {
+    pub fn new(identity: Identity) -> Responder {
+        Responder {
+            identity,
+            peers: HashMap::new(),
+            sessions: HashMap::new(),
+            contexts: HashMap::new(),
+            context_order: VecDeque::new(),
+            next_connection_id: 0,
+            max_sessions: DEFAULT_MAX_SESSIONS,
+            session_timeout: DEFAULT_SESSION_TIMEOUT,
+            max_contexts: DEFAULT_MAX_CONTEXTS,
+        }
+    }
+
+    /// Bounds the sessions awaiting message_3, 64 by default. Further message_1s are answered
+    /// with an error until sessions complete or expire.
+    pub fn set_max_sessions(&mut self, max_sessions: usize) {
+        self.max_sessions = max_sessions;
+    }
+
+    /// How long a session awaits message_3 before it is dropped, 60 seconds by default.
+    pub fn set_session_timeout(&mut self, timeout: Duration) {
+        self.session_timeout = timeout;
+    }
+
+    /// Bounds the established contexts kept, 1024 by default. Beyond it the oldest context
+    /// is dropped, freeing its C_R for new sessions.
+    pub fn set_max_contexts(&mut self, max_contexts: usize) {
+        self.max_contexts = max_contexts;
+        while self.contexts.len() > max_contexts {
+            self.drop_oldest_context();
+        }
+    }
+
+    /// Trust an initiator credential.
+    pub fn add_peer(&mut self, credential: Credential) {
+        self.peers.insert(credential.kid.clone(), credential);
+    }
+
+    /// Returns the OSCORE context established with the peer whose requests carry `recipient_id`.
+    pub fn security_context(&self, recipient_id: &[u8]) -> Option<&SecurityContext> {
+        self.contexts.get(recipient_id)
+    }
+
+    /// Removes the context established with the peer, e.g. once it is installed in the
+    /// OSCORE layer or the peer is gone, so that its C_R can be allocated again.
+    pub fn remove_context(&mut self, recipient_id: &[u8]) -> Option<SecurityContext> {
+        let context = self.contexts.remove(recipient_id)?;
+        self.context_order.retain(|c_r| c_r[..] != *recipient_id);
+        Some(context)
+    }
+
+    fn drop_oldest_context(&mut self) {
+        if let Some(c_r) = self.context_order.pop_front() {
+            self.contexts.remove(&c_r);
+        }
+    }
+
+    /// Processes message_1 and builds message_2.
+    pub fn process_message_1(&mut self, message_1: &[u8]) -> Result<Vec<u8>> {
+        let items = cbor::decode_sequence(message_1)?;
+        if items.len() < 4 {
+            return Err(EdhocError::Malformed);
+        }
+        match items[0].as_integer() {
+            Some(METHOD_STATIC_DH) => (),
+            Some(method) => return Err(EdhocError::UnsupportedMethod(method)),
+            None => return Err(EdhocError::Malformed),
+        }
+        let selected_suite = match items[1] {
+            Value::Array(ref suites) => suites.last().and_then(Value::as_integer),
+            ref suite => suite.as_integer(),
+        };
+        if selected_suite != Some(SUITE) {
+            return Err(EdhocError::UnsupportedSuite);
+        }
+        let g_x = match items[2].as_bytes() {
+            Some(g_x) if g_x.len() == X25519_LENGTH => g_x,
+            _ => return Err(EdhocError::Malformed),
+        };
+        let c_i = decode_id(&items[3]).ok_or(EdhocError::Malformed)?;
+
+        let timeout = self.session_timeout;
+        self.sessions.retain(|_, session| session.started.elapsed() < timeout);
+        if self.sessions.len() >= self.max_sessions {
+            return Err(EdhocError::Busy);
+        }
+        let c_r = self.allocate_connection_id(&c_i)?;
+
+        let ephemeral = PKey::generate_x25519()?;
+        let g_y = ephemeral.raw_public_key()?;
+        let th_2 = transcript(&[&bstr(&g_y), &bstr(&sha256(message_1))]);
+        let prk_2e = hkdf_extract(&th_2, &ecdh(&ephemeral, g_x)?)?;
+        let salt_3e2m = edhoc_kdf(&prk_2e, 1, &th_2, HASH_LENGTH)?;
+        let prk_3e2m = hkdf_extract(&salt_3e2m, &ecdh(&self.identity.key, g_x)?)?;
+
+        let own = &self.identity.credential;
+        let cred_r = own.encode();
+        let context_2 = [encode_id(&c_r), own.id_cred(), bstr(&th_2), cred_r.clone()].concat();
+        let mac_2 = edhoc_kdf(&prk_3e2m, 2, &context_2, MAC_LENGTH)?;
+        let plaintext_2 = [encode_id(&c_r), encode_id(&own.kid), bstr(&mac_2)].concat();
+        let keystream_2 = edhoc_kdf(&prk_2e, 0, &th_2, plaintext_2.len())?;
+
+        let th_3 = transcript(&[&bstr(&th_2), &plaintext_2, &cred_r]);
+        self.sessions.insert(
+            c_r,
+            ResponderSession {
+                ephemeral,
+                connection_id: c_i,
+                prk_3e2m,
+                th_3: th_3.to_vec(),
+                started: Instant::now(),
+            },
+        );
+
+        Ok(bstr(&[g_y, xor(&plaintext_2, &keystream_2)].concat()))
+    }
+
+    /// Verifies message_3 of the session identified by `c_r` and derives the OSCORE context.
+    pub fn process_message_3(&mut self, c_r: &[u8], message_3: &[u8]) -> Result<SecurityContext> {
+        let session = self.sessions.remove(c_r).ok_or(EdhocError::UnexpectedMessage)?;
+
+        let (value, used) = cbor::decode(message_3)?;
+        let ciphertext_3 = match value {
+            Value::Bytes(ref ciphertext) if used == message_3.len() && ciphertext.len() >= MAC_LENGTH => ciphertext,
+            _ => return Err(EdhocError::Malformed),
+        };
+        let (key, iv) = message_3_key(&session.prk_3e2m, &session.th_3)?;
+        let plaintext_3 = aead_decrypt(&key, &iv, &enc_structure(&session.th_3), ciphertext_3)
+            .map_err(|_| EdhocError::IntegrityFailure)?;
+
+        let items = cbor::decode_sequence(&plaintext_3)?;
+        if items.len() < 2 {
+            return Err(EdhocError::Malformed);
+        }
+        let kid_i = decode_id(&items[0]).ok_or(EdhocError::Malformed)?;
+        let mac_3 = items[1].as_bytes().ok_or(EdhocError::Malformed)?;
+        let peer = self.peers.get(&kid_i).ok_or(EdhocError::UnknownCredential(kid_i))?;
+
+        let salt_4e3m = edhoc_kdf(&session.prk_3e2m, 5, &session.th_3, HASH_LENGTH)?;
+        let prk_4e3m = hkdf_extract(&salt_4e3m, &ecdh(&session.ephemeral, &peer.public_key)?)?;
+        let cred_i = peer.encode();
+        let context_3 = [peer.id_cred(), bstr(&session.th_3), cred_i.clone()].concat();
+        verify_mac(&edhoc_kdf(&prk_4e3m, 6, &context_3, MAC_LENGTH)?, mac_3)?;
+
+        let th_4 = transcript(&[&bstr(&session.th_3), &plaintext_3, &cred_i]);
+        let prk_out = edhoc_kdf(&prk_4e3m, 7, &th_4, HASH_LENGTH)?;
+        let context = security_context(&prk_out, session.connection_id, c_r.to_vec())?;
+        if self.contexts.len() >= self.max_contexts {
+            self.drop_oldest_context();
+        }
+        self.contexts.insert(c_r.to_vec(), context.clone());
+        self.context_order.push_back(c_r.to_vec());
+        Ok(context)
+    }
+
+    /// Handles a POST to the EDHOC resource, answering message_1 with message_2 and
+    /// message_3 with an empty 2.04 Changed. Failures are answered with 4.00 Bad Request
+    /// carrying an EDHOC error message.
+    pub fn handle(&mut self, request: &CoAPRequest) -> Option<CoAPResponse> {
+        let mut response = request.response.clone()?;
+        if *request.get_method() != Method::Post {
+            response.set_status(Status::MethodNotAllowed);
+            response.set_payload(Vec::new());
+            return Some(response);
+        }
+
+        let payload = &request.message.payload;
+        let result = cbor::decode(payload)
+            .map_err(EdhocError::from)
+            .and_then(|(first, used)| match first {
+                Value::Bool(true) => self.process_message_1(&payload[used..]).map(Some),
+                ref c_r => match decode_id(c_r) {
+                    Some(c_r) => self.process_message_3(&c_r, &payload[used..]).map(|_| None),
+                    None => Err(EdhocError::Malformed),
+                },
+            });
+
+        match result {
+            Ok(Some(message_2)) => {
+                response.set_status(Status::Changed);
+                response.message.set_content_format(ContentFormat::ApplicationEdhocCborSeq);
+                response.set_payload(message_2);
+            }
+            Ok(None) => {
+                response.set_status(Status::Changed);
+                response.set_payload(Vec::new());
+            }
+            Err(e) => {
+                response.set_status(Status::BadRequest);
+                response.message.set_content_format(ContentFormat::ApplicationEdhocCborSeq);
+                response.set_payload(encode_sequence(&[
+                    Value::Integer(ERR_CODE_UNSPECIFIED),
+                    Value::Text(e.to_string()),
+                ]));
+            }
+        }
+        Some(response)
+    }
+
+    // a C_R distinct from C_I and from the identifiers of pending sessions and established
+    // contexts, which is one byte long while such are free
+    fn allocate_connection_id(&mut self, c_i: &[u8]) -> Result<Vec<u8>> {
+        for _ in 0..CONNECTION_ID_SPACE {
+            let n = self.next_connection_id;
+            self.next_connection_id = (n + 1) % CONNECTION_ID_SPACE;
+            let c_r = if n < 0x100 {
+                vec![n as u8]
+            } else {
+                ((n - 0x100) as u16).to_be_bytes().to_vec()
+            };
+            if c_r[..] != *c_i && !self.sessions.contains_key(&c_r) && !self.contexts.contains_key(&c_r) {
+                return Ok(c_r);
+            }
+        }
+        Err(EdhocError::Busy)
+    }
+}
+
+/// Encodes a connection identifier or kid, using the one-byte integer form when the
+/// identifier is itself a valid one-byte CBOR integer (RFC 9528 §3.3.2).
+fn encode_id(id: &[u8]) -> Vec<u8> {
+    match id {
+        [byte] if *byte <= 0x17 || (0x20..=0x37).contains(byte) => vec![*byte],
+        _ => bstr(id),
+    }
+}
+
+fn decode_id(value: &Value) -> Option<Vec<u8>> {
+    match *value {
+        Value::Integer(n) if (-24..=23).contains(&n) => Some(value.to_vec()),
+        Value::Bytes(ref id) => Some(id.clone()),
+        _ => None,
+    }
+}
+
+fn bstr(bytes: &[u8]) -> Vec<u8> {
+    Value::Bytes(bytes.to_vec()).to_vec()
+}
+
+fn transcript(parts: &[&[u8]]) -> [u8; HASH_LENGTH] {
+    sha256(&parts.concat())
+}
+
+fn xor(data: &[u8], keystream: &[u8]) -> Vec<u8> {
+    data.iter().zip(keystream).map(|(a, b)| a ^ b).collect()
+}
+
+fn ecdh(private_key: &PKey<Private>, public_key: &[u8]) -> Result<Vec<u8>> {
+    let peer = PKey::public_key_from_raw_bytes(public_key, Id::X25519)?;
+    let mut deriver = Deriver::new(private_key)?;
+    deriver.set_peer(&peer)?;
+    Ok(deriver.derive_to_vec()?)
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    let key = PKey::hmac(key)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(data)?;
+    Ok(signer.sign_to_vec()?)
+}
+
+fn hkdf_extract(salt: &[u8], ikm: &[u8]) -> Result<Vec<u8>> {
+    hmac(salt, ikm)
+}
+
+fn hkdf_expand(prk: &[u8], info: &[u8], length: usize) -> Result<Vec<u8>> {
+    let mut okm = Vec::with_capacity(length);
+    let mut block = Vec::new();
+    let mut counter = 1u8;
+    while okm.len() < length {
+        block = hmac(prk, &[&block[..], info, &[counter]].concat())?;
+        okm.extend_from_slice(&block);
+        counter += 1;
+    }
+    okm.truncate(length);
+    Ok(okm)
+}
+
+/// EDHOC_KDF (RFC 9528 §4.1.2).
+fn edhoc_kdf(prk: &[u8], label: i64, context: &[u8], length: usize) -> Result<Vec<u8>> {
+    let info = encode_sequence(&[
+        Value::Integer(label),
+        Value::Bytes(context.to_vec()),
+        Value::Integer(length as i64),
+    ]);
+    hkdf_expand(prk, &info, length)
+}
+
+fn message_3_key(prk_3e2m: &[u8], th_3: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+    Ok((
+        edhoc_kdf(prk_3e2m, 3, th_3, KEY_LENGTH)?,
+        edhoc_kdf(prk_3e2m, 4, th_3, IV_LENGTH)?,
+    ))
+}
+
+/// Encrypts with AES-CCM-16-64-128, appending the tag to the ciphertext.
+fn aead_encrypt(key: &[u8], iv: &[u8], aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+    let mut ctx = CipherCtx::new()?;
+    ctx.encrypt_init(Some(Cipher::aes_128_ccm()), None, None)?;
+    ctx.set_iv_length(iv.len())?;
+    ctx.set_tag_length(MAC_LENGTH)?;
+    ctx.encrypt_init(None, Some(key), Some(iv))?;
+    ctx.set_data_len(plaintext.len())?;
+    ctx.cipher_update(aad, None)?;
+
+    let mut ciphertext = Vec::new();
+    ctx.cipher_update_vec(plaintext, &mut ciphertext)?;
+    ctx.cipher_final_vec(&mut ciphertext)?;
+    let mut tag = [0; MAC_LENGTH];
+    ctx.tag(&mut tag)?;
+    ciphertext.extend_from_slice(&tag);
+    Ok(ciphertext)
+}
+
+fn aead_decrypt(key: &[u8], iv: &[u8], aad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>> {
+    let (ciphertext, tag) = ciphertext.split_at(ciphertext.len() - MAC_LENGTH);
+    let mut ctx = CipherCtx::new()?;
+    ctx.decrypt_init(Some(Cipher::aes_128_ccm()), None, None)?;
+    ctx.set_iv_length(iv.len())?;
+    ctx.set_tag(tag)?;
+    ctx.decrypt_init(None, Some(key), Some(iv))?;
+    ctx.set_data_len(ciphertext.len())?;
+    ctx.cipher_update(aad, None)?;
+
+    let mut plaintext = Vec::new();
+    ctx.cipher_update_vec(ciphertext, &mut plaintext)?;
+    Ok(plaintext)
+}
+
+fn enc_structure(th_3: &[u8]) -> Vec<u8> {
+    Value::Array(vec![
+        Value::Text("Encrypt0".to_string()),
+        Value::Bytes(Vec::new()),
+        Value::Bytes(th_3.to_vec()),
+    ])
+    .to_vec()
+}
+
+fn verify_mac(expected: &[u8], received: &[u8]) -> Result<()> {
+    if expected.len() == received.len() && memcmp::eq(expected, received) {
+        Ok(())
+    } else {
+        Err(EdhocError::MacMismatch)
+    }
+}
+
+/// Derives the OSCORE Master Secret and Master Salt with the EDHOC exporter (RFC 9528 Appendix A.1).
+fn security_context(prk_out: &[u8], sender_id: Vec<u8>, recipient_id: Vec<u8>) -> Result<SecurityContext> {
+    let prk_exporter = edhoc_kdf(prk_out, 10, &[], HASH_LENGTH)?;
+    Ok(SecurityContext {
+        master_secret: edhoc_kdf(&prk_exporter, 0, &[], 16)?,
+        master_salt: edhoc_kdf(&prk_exporter, 1, &[], 8)?,
+        sender_id,
+        recipient_id,
+        id_context: None,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::*;
+    use std::sync::{Arc, Mutex};
+
+    fn parties() -> (Initiator, Responder) {
+        let initiator_identity = Identity::generate(&[0x2B], "initiator").unwrap();
+        let responder_identity = Identity::generate(&[0x32], "responder").unwrap();
+        let initiator_credential = initiator_identity.credential().clone();
+        let responder_credential = responder_identity.credential().clone();
+
+        let mut initiator = Initiator::new(initiator_identity, &[0x37]);
+        initiator.add_peer(responder_credential);
+        let mut responder = Responder::new(responder_identity);
+        responder.add_peer(initiator_credential);
+        (initiator, responder)
+    }
+
+    #[test]
+    fn test_handshake() {
+        let (mut initiator, mut responder) = parties();
+
+        let message_1 = initiator.message_1().unwrap();
+        let message_2 = responder.process_message_1(&message_1).unwrap();
+        let (message_3, initiator_context) = initiator.process_message_2(&message_2).unwrap();
+        let responder_context = responder
+            .process_message_3(&initiator_context.sender_id, &message_3)
+            .unwrap();
+
+        assert_eq!(initiator_context.master_secret.len(), 16);
+        assert_eq!(initiator_context.master_salt.len(), 8);
+        assert_eq!(initiator_context.master_secret, responder_context.master_secret);
+        assert_eq!(initiator_context.master_salt, responder_context.master_salt);
+        assert_eq!(initiator_context.sender_id, responder_context.recipient_id);
+        assert_eq!(initiator_context.recipient_id, vec![0x37]);
+        assert_eq!(responder_context.sender_id, vec![0x37]);
+        assert_eq!(responder.security_context(&responder_context.recipient_id), Some(&responder_context));
+    }
+
+    #[test]
+    fn test_unknown_initiator() {
+        let (mut initiator, mut responder) = parties();
+        responder.peers.clear();
+
+        let message_2 = responder.process_message_1(&initiator.message_1().unwrap()).unwrap();
+        let (message_3, context) = initiator.process_message_2(&message_2).unwrap();
+        assert_eq!(
+            responder.process_message_3(&context.sender_id, &message_3).unwrap_err(),
+            EdhocError::UnknownCredential(vec![0x2B])
+        );
+    }
+
+    #[test]
+    fn test_tampered_messages() {
+        let (mut initiator, mut responder) = parties();
+        let mut message_2 = responder.process_message_1(&initiator.message_1().unwrap()).unwrap();
+        let last = message_2.len() - 1;
+        message_2[last] ^= 0x01;
+        assert_eq!(initiator.process_message_2(&message_2).unwrap_err(), EdhocError::MacMismatch);
+
+        let (mut initiator, mut responder) = parties();
+        let message_2 = responder.process_message_1(&initiator.message_1().unwrap()).unwrap();
+        let (mut message_3, context) = initiator.process_message_2(&message_2).unwrap();
+        message_3[1] ^= 0x01;
+        assert_eq!(
+            responder.process_message_3(&context.sender_id, &message_3).unwrap_err(),
+            EdhocError::IntegrityFailure
+        );
+    }
+
+    #[test]
+    fn test_session_limits() {
+        let (mut initiator, mut responder) = parties();
+        responder.set_max_sessions(1);
+        responder.process_message_1(&initiator.message_1().unwrap()).unwrap();
+        assert_eq!(responder.process_message_1(&initiator.message_1().unwrap()).unwrap_err(), EdhocError::Busy);
+        // half-open sessions expire
+        responder.set_session_timeout(Duration::from_millis(0));
+        responder.process_message_1(&initiator.message_1().unwrap()).unwrap();
+        assert_eq!(responder.sessions.len(), 1);
+
+        // two-byte identifiers follow the one-byte ones, skipping those in use
+        responder.next_connection_id = 0xFF;
+        let context = SecurityContext {
+            master_secret: Vec::new(),
+            master_salt: Vec::new(),
+            sender_id: Vec::new(),
+            recipient_id: vec![0xFF],
+            id_context: None,
+        };
+        responder.contexts.insert(vec![0xFF], context);
+        assert_eq!(responder.allocate_connection_id(&[0x37]).unwrap(), vec![0x00, 0x00]);
+        responder.next_connection_id = CONNECTION_ID_SPACE - 1;
+        assert_eq!(responder.allocate_connection_id(&[0x00]).unwrap(), vec![0xFF, 0xFF]);
+        // 0x00 is C_I and 0x01 is pending
+        assert_eq!(responder.allocate_connection_id(&[0x00]).unwrap(), vec![0x02]);
+    }
+
+    #[test]
+    fn test_context_limits() {
+        let (mut initiator, mut responder) = parties();
+        responder.set_max_contexts(2);
+        let mut handshake = |responder: &mut Responder| {
+            let message_2 = responder.process_message_1(&initiator.message_1().unwrap()).unwrap();
+            let (message_3, context) = initiator.process_message_2(&message_2).unwrap();
+            responder.process_message_3(&context.sender_id, &message_3).unwrap();
+            context.sender_id
+        };
+        let first = handshake(&mut responder);
+        let second = handshake(&mut responder);
+        let third = handshake(&mut responder);
+        // the oldest context made room for the third
+        assert!(responder.security_context(&first).is_none());
+        assert!(responder.security_context(&second).is_some());
+
+        assert!(responder.remove_context(&third).is_some());
+        assert!(responder.security_context(&third).is_none());
+        // the C_R of a removed context is free again
+        responder.next_connection_id = u32::from(third[0]);
+        assert_eq!(responder.allocate_connection_id(&[0x37]).unwrap(), third);
+    }
+
+    #[test]
+    fn test_establish() {
+        let (mut initiator, responder) = parties();
+        let responder = Arc::new(Mutex::new(responder));
+
+        let server_responder = responder.clone();
+        let server_port = server::test::spawn_server(move |req: CoAPRequest| {
+            let responder = server_responder.clone();
+            async move { responder.lock().unwrap().handle(&req) }
+        }).recv().unwrap();
+
+        let client = CoAPClient::new(format!("127.0.0.1:{}", server_port)).unwrap();
+        let context = initiator.establish(&client).unwrap();
+
+        let responder = responder.lock().unwrap();
+        let responder_context = responder.security_context(&context.sender_id).unwrap();
+        assert_eq!(context.master_secret, responder_context.master_secret);
+        assert_eq!(context.recipient_id, responder_context.sender_id);
+    }
+}
@@ -0,0 +1,70 @@
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// A change in the connection state of a client, reported to the handler set with
+/// `set_event_handler`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ClientEvent {
+    /// The peer address was resolved.
+    Resolved(SocketAddr),
+    /// The local socket is ready to exchange datagrams with the peer.
+    Connected { local_addr: SocketAddr },
+    /// The DTLS handshake completed with the negotiated cipher suite.
+    HandshakeCompleted { cipher: String },
+    /// The DTLS handshake resumed an earlier session.
+    SessionResumed,
+    /// A confirmable request is being retransmitted.
+    Retransmitting { message_id: u16, retransmission: u32 },
+    /// A request was not answered in time.
+    TimedOut { message_id: u16 },
+    /// The client switched to another of its endpoints.
+    FailedOver { from: SocketAddr, to: SocketAddr },
+    /// An observation was registered again after the DTLS session carrying it broke off.
+    /// Notifications sent during the gap, measured from the last message heard on the
+    /// observation, were missed.
+    ObservationResumed { path: String, gap: Duration },
+    /// The client was dropped.
+    Disconnected,
+}
+
+impl ClientEvent {
+    /// Events describing the current connection, replayed to a newly set handler.
+    fn is_state(&self) -> bool {
+        matches!(
+            *self,
+            ClientEvent::Resolved(_)
+                | ClientEvent::Connected { .. }
+                | ClientEvent::HandshakeCompleted { .. }
+                | ClientEvent::SessionResumed
+        )
+    }
+}
+
+type EventHandler = Box<dyn FnMut(&ClientEvent) + Send>;
+
+/// Delivers client events to the application's handler.
+#[derive(Default)]
+pub(crate) struct EventEmitter {
+    handler: Mutex<Option<EventHandler>>,
+    state: Mutex<Vec<ClientEvent>>,
+}
+
+impl EventEmitter {
+    /// Sets the handler, first handing it the events that established the connection.
+    pub fn set_handler(&self, mut handler: EventHandler) {
+        for event in self.state.lock().unwrap().iter() {
+            handler(event);
+        }
+        *self.handler.lock().unwrap() = Some(handler);
+    }
+
+    pub fn emit(&self, event: ClientEvent) {
+        if event.is_state() {
+            self.state.lock().unwrap().push(event.clone());
+        }
+        if let Some(ref mut handler) = *self.handler.lock().unwrap() {
+            handler(&event);
+        }
+    }
+}
@@ -0,0 +1,226 @@
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::budget::{BudgetExceeded, BudgetResource, MemoryBudget};
+
+// an aborted exchange is remembered until a late response is unlikely (EXCHANGE_LIFETIME of
+// RFC 7252 §4.8.2), and at most this many of them
+const ABORTED_LIFETIME: Duration = Duration::from_secs(247);
+const MAX_ABORTED: usize = 64;
+
+/// An exchange waiting for its response or acknowledgement.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExchangeInfo {
+    pub peer: SocketAddr,
+    pub message_id: u16,
+    pub token: Vec<u8>,
+    pub started_at: Instant,
+    pub retransmissions: u32,
+}
+
+impl ExchangeInfo {
+    /// How long the exchange has been outstanding.
+    pub fn age(&self) -> Duration {
+        self.started_at.elapsed()
+    }
+}
+
+/// A shared view of the outstanding exchanges of a client or server.
+///
+/// Handles are cheap to clone, so a debugging dashboard can list the exchanges or abort a
+/// stuck one from another thread while the endpoint keeps running.
+#[derive(Clone, Default)]
+pub struct ExchangeRegistry {
+    inner: Arc<Mutex<Registry>>,
+}
+
+/// What a received message is to the exchanges of an endpoint.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Completion {
+    /// It answers the outstanding exchange with the request of the message ID, which is now
+    /// completed.
+    Completed(u16),
+    /// It answers an aborted exchange and is to be dropped.
+    Aborted,
+    /// It answers no known exchange.
+    Unsolicited,
+}
+
+#[derive(Default)]
+struct Registry {
+    exchanges: Vec<ExchangeInfo>,
+    aborted: Vec<(SocketAddr, u16, Vec<u8>, Instant)>,
+    budget: MemoryBudget,
+}
+
+impl ExchangeRegistry {
+    pub fn new() -> ExchangeRegistry {
+        Self::default()
+    }
+
+    /// Lists the outstanding exchanges, oldest first.
+    pub fn list(&self) -> Vec<ExchangeInfo> {
+        self.inner.lock().unwrap().exchanges.clone()
+    }
+
+    /// Aborts an exchange: it is no longer retransmitted and a late response to it is dropped.
+    /// Returns false if no such exchange is outstanding.
+    pub fn abort(&self, peer: &SocketAddr, message_id: u16) -> bool {
+        let mut registry = self.inner.lock().unwrap();
+        match registry.position(peer, message_id) {
+            Some(idx) => {
+                let exchange = registry.remove(idx);
+                registry.aborted.retain(|aborted| aborted.3.elapsed() < ABORTED_LIFETIME);
+                if registry.aborted.len() >= MAX_ABORTED {
+                    registry.aborted.remove(0);
+                }
+                registry.aborted.push((exchange.peer, exchange.message_id, exchange.token, Instant::now()));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Counts the outstanding exchanges against a budget, see `CoAPClient::set_memory_budget`.
+    pub(crate) fn set_budget(&self, budget: MemoryBudget) {
+        let mut registry = self.inner.lock().unwrap();
+        let outstanding = registry.exchanges.len();
+        registry.budget.release(BudgetResource::Exchanges, outstanding);
+        // the exchanges already outstanding are counted even beyond the limit
+        let _ = budget.reserve(BudgetResource::Exchanges, outstanding);
+        registry.budget = budget;
+    }
+
+    /// Records a new exchange, replacing one with the same peer and message ID, unless the
+    /// budget allows no further exchange.
+    pub(crate) fn start(&self, peer: SocketAddr, message_id: u16, token: Vec<u8>) -> Result<(), BudgetExceeded> {
+        let mut registry = self.inner.lock().unwrap();
+        match registry.position(&peer, message_id) {
+            Some(idx) => {
+                registry.exchanges.remove(idx);
+            }
+            None => registry.budget.reserve(BudgetResource::Exchanges, 1)?,
+        }
+        registry.exchanges.push(ExchangeInfo {
+            peer,
+            message_id,
+            token,
+            started_at: Instant::now(),
+            retransmissions: 0,
+        });
+        Ok(())
+    }
+
+    pub(crate) fn retransmitted(&self, peer: &SocketAddr, message_id: u16) {
+        let mut registry = self.inner.lock().unwrap();
+        if let Some(idx) = registry.position(peer, message_id) {
+            registry.exchanges[idx].retransmissions += 1;
+        }
+    }
+
+    /// Completes the exchange a received message belongs to, matched by message ID for
+    /// acknowledgements and resets and by token otherwise.
+    pub(crate) fn complete(&self, peer: &SocketAddr, message_id: u16, token: &[u8], by_message_id: bool) -> Completion {
+        let mut registry = self.inner.lock().unwrap();
+        let matches = |exchange_peer: &SocketAddr, exchange_id: u16, exchange_token: &[u8]| {
+            exchange_peer == peer && if by_message_id { exchange_id == message_id } else { exchange_token == token }
+        };
+
+        if let Some(idx) = registry.aborted.iter().position(|(p, id, t, _)| matches(p, *id, t)) {
+            registry.aborted.remove(idx);
+            return Completion::Aborted;
+        }
+        match registry.exchanges.iter().position(|e| matches(&e.peer, e.message_id, &e.token)) {
+            Some(idx) => Completion::Completed(registry.remove(idx).message_id),
+            None => Completion::Unsolicited,
+        }
+    }
+
+    /// Checks whether an exchange is outstanding, e.g. when an empty acknowledgement
+    /// announces its separate response.
+    pub(crate) fn is_outstanding(&self, peer: &SocketAddr, message_id: u16) -> bool {
+        self.inner.lock().unwrap().position(peer, message_id).is_some()
+    }
+
+    /// Removes an exchange that ended without a response.
+    pub(crate) fn finish(&self, peer: &SocketAddr, message_id: u16) {
+        let mut registry = self.inner.lock().unwrap();
+        if let Some(idx) = registry.position(peer, message_id) {
+            registry.remove(idx);
+        }
+    }
+
+    /// Returns the exchanges aborted since the last call, for endpoints that retransmit.
+    pub(crate) fn take_aborted(&self) -> Vec<(SocketAddr, u16)> {
+        let mut registry = self.inner.lock().unwrap();
+        registry.aborted.drain(..).map(|(peer, message_id, _, _)| (peer, message_id)).collect()
+    }
+}
+
+impl Registry {
+    fn remove(&mut self, idx: usize) -> ExchangeInfo {
+        self.budget.release(BudgetResource::Exchanges, 1);
+        self.exchanges.remove(idx)
+    }
+
+    fn position(&self, peer: &SocketAddr, message_id: u16) -> Option<usize> {
+        self.exchanges
+            .iter()
+            .position(|exchange| exchange.peer == *peer && exchange.message_id == message_id)
+    }
+}
+
+impl Drop for Registry {
+    fn drop(&mut self) {
+        self.budget.release(BudgetResource::Exchanges, self.exchanges.len());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_registry() {
+        let peer: SocketAddr = "127.0.0.1:5683".parse().unwrap();
+        let registry = ExchangeRegistry::new();
+        registry.start(peer, 1, vec![0xA]).unwrap();
+        registry.start(peer, 2, vec![0xB]).unwrap();
+        registry.retransmitted(&peer, 2);
+
+        let exchanges = registry.clone().list();
+        assert_eq!(exchanges.len(), 2);
+        assert_eq!(exchanges[1].retransmissions, 1);
+
+        assert!(registry.is_outstanding(&peer, 1));
+        assert_eq!(registry.complete(&peer, 7, &[0xA], false), Completion::Completed(1));
+        assert_eq!(registry.list().len(), 1);
+        assert!(!registry.is_outstanding(&peer, 1));
+
+        assert!(registry.abort(&peer, 2));
+        assert!(!registry.abort(&peer, 2));
+        assert!(registry.list().is_empty());
+        assert_eq!(registry.complete(&peer, 2, &[], true), Completion::Aborted);
+        assert_eq!(registry.complete(&peer, 2, &[], true), Completion::Unsolicited);
+        // only the latest aborted exchanges are remembered
+        for message_id in 100..100 + MAX_ABORTED as u16 + 1 {
+            registry.start(peer, message_id, vec![]).unwrap();
+            registry.abort(&peer, message_id);
+        }
+        assert_eq!(registry.complete(&peer, 100, &[], true), Completion::Unsolicited);
+        assert_eq!(registry.complete(&peer, 101, &[], true), Completion::Aborted);
+
+        let budget = MemoryBudget::new().with_max_exchanges(1);
+        registry.set_budget(budget.clone());
+        registry.start(peer, 3, vec![0xC]).unwrap();
+        registry.start(peer, 3, vec![0xC]).unwrap();
+        assert_eq!(registry.start(peer, 4, vec![0xD]).unwrap_err().resource, BudgetResource::Exchanges);
+        registry.finish(&peer, 3);
+        assert_eq!(budget.usage().exchanges, 0);
+        registry.start(peer, 4, vec![0xD]).unwrap();
+        // the exchanges of a dropped registry are given back
+        drop(registry);
+        assert_eq!(budget.usage().exchanges, 0);
+    }
+}
@@ -0,0 +1,116 @@
+//! Filters applied by the server to handler responses before they are sent.
+
+use log::debug;
+
+use super::codec::CodecRegistry;
+use super::message::packet::{decode_uint, encode_uint, CoAPOption};
+use super::message::request::CoAPRequest;
+use super::message::response::CoAPResponse;
+use super::message::IsMessage;
+
+/// Transcodes the payload between the formats of the default `CodecRegistry`, e.g. between
+/// application/json and application/cbor, when the request's Accept option asks for another
+/// format than the response's, so handlers can produce a single format.
+///
+/// Payloads that fail to convert are left untouched.
+pub fn transcode(request: &CoAPRequest, response: &mut CoAPResponse) {
+    transcode_with(&CodecRegistry::default(), request, response)
+}
+
+/// Transcodes the payload like `transcode`, with the codecs of the registry.
+pub fn transcode_with(codecs: &CodecRegistry, request: &CoAPRequest, response: &mut CoAPResponse) {
+    let accept = match first_uint(request, CoAPOption::Accept) {
+        Some(accept) => accept,
+        None => return,
+    };
+    let content_format = match first_uint(response, CoAPOption::ContentFormat) {
+        Some(content_format) => content_format,
+        None => return,
+    };
+    if accept == content_format || !codecs.supports(accept) {
+        return;
+    }
+
+    match codecs.transcode(content_format, accept, &response.message.payload) {
+        Ok(payload) => {
+            response.clear_option(CoAPOption::ContentFormat);
+            response.add_option(CoAPOption::ContentFormat, encode_uint(accept));
+            response.set_payload(payload);
+        }
+        Err(e) => debug!("payload not transcoded: {}", e),
+    }
+}
+
+fn first_uint<M: IsMessage>(message: &M, option: CoAPOption) -> Option<u32> {
+    message
+        .get_option(option)
+        .and_then(|list| list.front())
+        .and_then(|value| decode_uint(value))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::cbor::Value;
+    use super::super::message::response::ContentError;
+    use super::super::message::packet::{ContentFormat, Packet};
+
+    fn exchange(accept: ContentFormat, content_format: ContentFormat, payload: &[u8]) -> CoAPResponse {
+        let mut packet = Packet::new();
+        packet.add_option(CoAPOption::Accept, encode_uint(accept as u32));
+        let request = CoAPRequest::from_packet(packet, &"127.0.0.1:5683".parse().unwrap());
+
+        let mut response = request.response.clone().unwrap();
+        response.message.set_content_format(content_format);
+        response.set_payload(payload.to_vec());
+        transcode(&request, &mut response);
+        response
+    }
+
+    #[test]
+    fn test_transcode() {
+        let response = exchange(ContentFormat::ApplicationCBOR, ContentFormat::ApplicationJSON, br#"{"t":1}"#);
+        assert_eq!(response.message.get_content_format(), Some(ContentFormat::ApplicationCBOR));
+        assert_eq!(response.message.payload, vec![0xA1, 0x61, b't', 0x01]);
+
+        let response = exchange(ContentFormat::ApplicationJSON, ContentFormat::ApplicationCBOR, &[0xA1, 0x61, b't', 0x01]);
+        assert_eq!(response.message.get_content_format(), Some(ContentFormat::ApplicationJSON));
+        assert_eq!(response.message.payload, br#"{"t":1}"#.to_vec());
+    }
+
+    #[test]
+    fn test_transcode_untouched() {
+        let response = exchange(ContentFormat::ApplicationCBOR, ContentFormat::ApplicationJSON, b"{bad");
+        assert_eq!(response.message.get_content_format(), Some(ContentFormat::ApplicationJSON));
+        assert_eq!(response.message.payload, b"{bad".to_vec());
+
+        let response = exchange(ContentFormat::TextPlain, ContentFormat::ApplicationJSON, b"1");
+        assert_eq!(response.message.get_content_format(), Some(ContentFormat::ApplicationJSON));
+    }
+
+    #[test]
+    fn test_transcode_custom_format() {
+        let codecs = CodecRegistry::default();
+        codecs.register_fn(65000, |value| match value {
+            Value::Integer(n) => Ok(format!("#{}", n).into_bytes()),
+            _ => Err(ContentError::Invalid("not an integer".to_string())),
+        }, |_| Err(ContentError::Invalid("write only".to_string())));
+
+        let mut packet = Packet::new();
+        packet.add_option(CoAPOption::Accept, encode_uint(65000));
+        let request = CoAPRequest::from_packet(packet, &"127.0.0.1:5683".parse().unwrap());
+        let mut response = request.response.clone().unwrap();
+        response.message.set_content_format(ContentFormat::ApplicationJSON);
+        response.set_payload(b"42".to_vec());
+        transcode_with(&codecs, &request, &mut response);
+        assert_eq!(response.message.payload, b"#42".to_vec());
+        assert_eq!(response.decode_payload(&codecs), Err(ContentError::Invalid("write only".to_string())));
+
+        let mut reply = request.response.clone().unwrap();
+        reply.encode_payload(&codecs, ContentFormat::ApplicationCBOR as u32, &Value::Integer(42)).unwrap();
+        assert_eq!(reply.message.get_content_format(), Some(ContentFormat::ApplicationCBOR));
+        assert_eq!(reply.decode_payload(&codecs), Ok(Value::Integer(42)));
+        reply.clear_option(CoAPOption::ContentFormat);
+        assert_eq!(reply.decode_payload(&codecs), Err(ContentError::MissingContentFormat));
+    }
+}
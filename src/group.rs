@@ -0,0 +1,484 @@
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+use log::*;
+
+use super::ids::{IdGenerator, IdState};
+use super::message::header::{class_to_code, MessageType};
+use super::message::packet::{decode_uint, CoAPOption, Packet};
+use super::message::request::CoAPRequest;
+use super::message::response::CoAPResponse;
+use super::message::IsMessage;
+
+/// The All-CoAP-Nodes IPv4 multicast address (RFC 7252 §12.8).
+pub const ALL_COAP_NODES_V4: Ipv4Addr = Ipv4Addr::new(224, 0, 1, 187);
+/// The link-local All-CoAP-Nodes IPv6 multicast address (RFC 7252 §12.8).
+pub const ALL_COAP_NODES_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfd);
+
+const DEFAULT_LEISURE: u64 = 5; // 5s
+const DEFAULT_MULTICAST_TTL: u32 = 1;
+const NO_RESPONSE_ALL: u32 = 2 | 8 | 16;
+
+/// A network interface multicast requests leave on.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Interface {
+    Index(u32),
+    Name(String),
+}
+
+impl Interface {
+    fn index(&self) -> Result<u32> {
+        match *self {
+            Interface::Index(index) => Ok(index),
+            Interface::Name(ref name) => interface_index(name),
+        }
+    }
+}
+
+/// Per-request multicast settings for `GroupClient::send_with`, built with the `with_*`
+/// methods.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MulticastOptions {
+    /// The interfaces the request is sent on, each receiving a copy. When empty the system
+    /// chooses one.
+    pub interfaces: Vec<Interface>,
+    /// The hop limit, overriding the client's.
+    pub ttl: Option<u32>,
+}
+
+impl MulticastOptions {
+    pub fn with_interface(mut self, interface: Interface) -> MulticastOptions {
+        self.interfaces.push(interface);
+        self
+    }
+
+    pub fn with_ttl(mut self, ttl: u32) -> MulticastOptions {
+        self.ttl = Some(ttl);
+        self
+    }
+}
+
+/// A client sending requests to a group of CoAP servers (RFC 7390).
+///
+/// Requests are sent as NON to the group address and every response arriving within the
+/// leisure period is collected. When the network refuses multicast and members are
+/// configured, the request is sent to each member in turn instead.
+pub struct GroupClient {
+    socket: UdpSocket,
+    group_addr: SocketAddr,
+    members: Vec<SocketAddr>,
+    leisure: Duration,
+    multicast_ttl: u32,
+    ids: IdGenerator,
+}
+
+impl GroupClient {
+    /// Create a group client sending to the given group address.
+    pub fn new<A: ToSocketAddrs>(group_addr: A) -> Result<GroupClient> {
+        let group_addr = group_addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| Error::other("no address"))?;
+        let bind_addr = match group_addr.ip() {
+            IpAddr::V4(_) => "0.0.0.0:0",
+            IpAddr::V6(_) => ":::0",
+        };
+
+        let socket = UdpSocket::bind(bind_addr)?;
+        if group_addr.is_ipv4() {
+            socket.set_broadcast(true)?;
+        }
+
+        Ok(GroupClient {
+            socket,
+            group_addr,
+            members: Vec::new(),
+            leisure: Duration::new(DEFAULT_LEISURE, 0),
+            multicast_ttl: DEFAULT_MULTICAST_TTL,
+            ids: IdGenerator::new(IdState::seeded()),
+        })
+    }
+
+    /// Add a member used by the serial unicast fallback.
+    pub fn add_member<A: ToSocketAddrs>(&mut self, addr: A) -> Result<()> {
+        for addr in addr.to_socket_addrs()? {
+            if !self.members.contains(&addr) {
+                self.members.push(addr);
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove a member.
+    pub fn remove_member(&mut self, addr: &SocketAddr) {
+        self.members.retain(|member| member != addr);
+    }
+
+    pub fn members(&self) -> &[SocketAddr] {
+        &self.members
+    }
+
+    pub fn group_addr(&self) -> SocketAddr {
+        self.group_addr
+    }
+
+    /// Set how long responses to a group request are collected.
+    pub fn set_leisure(&mut self, leisure: Duration) {
+        self.leisure = leisure;
+    }
+
+    /// Set the hop limit of multicast requests.
+    pub fn set_multicast_ttl(&mut self, ttl: u32) -> Result<()> {
+        self.apply_ttl(ttl)?;
+        self.multicast_ttl = ttl;
+        Ok(())
+    }
+
+    /// The state of the message ID generator to store, see `CoAPClient::id_state`.
+    pub fn id_state(&self) -> IdState {
+        self.ids.state()
+    }
+
+    /// Continues the message IDs from a state stored before a reboot.
+    pub fn restore_id_state(&mut self, state: IdState) {
+        self.ids.restore(state);
+    }
+
+    /// Sets a hook receiving the state to store whenever the generator reserves further
+    /// message IDs, see the `ids` module.
+    pub fn set_id_state_hook<F: FnMut(IdState) + Send + 'static>(&mut self, hook: F) {
+        self.ids.set_hook(hook);
+    }
+
+    /// Send a request to the group and collect the responses.
+    ///
+    /// Every call uses a fresh token, as a token must not be reused while responses to an
+    /// earlier group request may still arrive. Requests whose No-Response option suppresses
+    /// every response class return immediately without waiting.
+    pub fn send(&mut self, request: &mut CoAPRequest) -> Result<Vec<(SocketAddr, CoAPResponse)>> {
+        self.send_with(request, &MulticastOptions::default())
+    }
+
+    /// Send a request to the group with per-request multicast settings and collect the
+    /// responses arriving on any of the interfaces, e.g. on a multi-homed border router.
+    ///
+    /// The request falls back to serial unicast only when it could not be sent on any
+    /// interface.
+    pub fn send_with(
+        &mut self,
+        request: &mut CoAPRequest,
+        options: &MulticastOptions,
+    ) -> Result<Vec<(SocketAddr, CoAPResponse)>> {
+        let interfaces = options
+            .interfaces
+            .iter()
+            .map(|interface| interface.index().map(Some))
+            .collect::<Result<Vec<Option<u32>>>>()?;
+        self.prepare(request);
+
+        let bytes = Self::encode(&request.message)?;
+        if let Err(e) = self.multicast(&bytes, &interfaces, options.ttl) {
+            if self.members.is_empty() {
+                return Err(e);
+            }
+            warn!("multicast to {} failed, falling back to unicast: {}", self.group_addr, e);
+            return self.send_serial(request);
+        }
+
+        if !Self::expects_responses(request) {
+            return Ok(Vec::new());
+        }
+        self.collect(request.get_token(), None)
+    }
+
+    /// Send a request to each member in turn, for networks without multicast.
+    pub fn send_serial(&mut self, request: &mut CoAPRequest) -> Result<Vec<(SocketAddr, CoAPResponse)>> {
+        if request.get_token().is_empty() {
+            self.prepare(request);
+        }
+
+        let mut responses = Vec::new();
+        for member in self.members.clone() {
+            request.set_message_id(self.ids.next_message_id());
+            let bytes = Self::encode(&request.message)?;
+            if let Err(e) = self.socket.send_to(&bytes[..], member) {
+                warn!("unicast to {} failed: {}", member, e);
+                continue;
+            }
+
+            if Self::expects_responses(request) {
+                responses.extend(self.collect(request.get_token(), Some(member))?);
+            }
+        }
+        Ok(responses)
+    }
+
+    /// Sends a datagram to the group on each interface, succeeding if any send succeeded.
+    /// The socket's multicast settings are restored afterwards.
+    fn multicast(&self, bytes: &[u8], interfaces: &[Option<u32>], ttl: Option<u32>) -> Result<()> {
+        let interfaces = if interfaces.is_empty() { &[None][..] } else { interfaces };
+        let mut result = Err(Error::other("no interface"));
+        if let Some(ttl) = ttl {
+            self.apply_ttl(ttl)?;
+        }
+
+        for interface in interfaces.iter() {
+            let sent = match *interface {
+                Some(index) => self.apply_interface(index),
+                None => Ok(()),
+            }
+            .and_then(|_| self.socket.send_to(bytes, self.group_addr));
+            match sent {
+                Ok(_) => result = Ok(()),
+                Err(e) => {
+                    warn!("multicast on interface {:?} failed: {}", interface, e);
+                    if result.is_err() {
+                        result = Err(e);
+                    }
+                }
+            }
+        }
+
+        if interfaces.iter().any(Option::is_some) {
+            self.apply_interface(0)?;
+        }
+        if ttl.is_some() {
+            self.apply_ttl(self.multicast_ttl)?;
+        }
+        result
+    }
+
+    fn apply_ttl(&self, ttl: u32) -> Result<()> {
+        match self.group_addr.ip() {
+            IpAddr::V4(_) => self.socket.set_multicast_ttl_v4(ttl),
+            IpAddr::V6(_) => set_multicast_hops_v6(&self.socket, ttl),
+        }
+    }
+
+    /// Selects the outgoing multicast interface by index, 0 restoring the system's choice.
+    fn apply_interface(&self, index: u32) -> Result<()> {
+        match self.group_addr.ip() {
+            IpAddr::V4(_) => set_multicast_if_v4(&self.socket, index),
+            IpAddr::V6(_) => set_multicast_if_v6(&self.socket, index),
+        }
+    }
+
+    fn prepare(&mut self, request: &mut CoAPRequest) {
+        request.set_type(MessageType::NonConfirmable);
+        request.set_message_id(self.ids.next_message_id());
+        request.set_token(self.ids.next_token());
+    }
+
+    /// Collects responses carrying the token until the leisure period ends, or until the
+    /// member answered when one is given.
+    fn collect(&self, token: &[u8], member: Option<SocketAddr>) -> Result<Vec<(SocketAddr, CoAPResponse)>> {
+        let deadline = Instant::now() + self.leisure;
+        let mut responses = Vec::new();
+        let mut buf = [0; 1500];
+
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            self.socket.set_read_timeout(Some(deadline - now))?;
+
+            let (nread, src) = match self.socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => break,
+                Err(e) => return Err(e),
+            };
+            let packet = match Packet::from_bytes(&buf[..nread]) {
+                Ok(packet) => packet,
+                Err(e) => {
+                    debug!("dropping malformed response from {}: {}", src, e);
+                    continue;
+                }
+            };
+            if packet.get_token()[..] != *token || member.is_some_and(|member| member != src) {
+                continue;
+            }
+
+            responses.push((src, CoAPResponse::received(packet)));
+            if member.is_some() {
+                break;
+            }
+        }
+        Ok(responses)
+    }
+
+    /// Checks the No-Response option (RFC 7967) of a request.
+    fn expects_responses(request: &CoAPRequest) -> bool {
+        match request
+            .get_option(CoAPOption::NoResponse)
+            .and_then(|list| list.front())
+            .and_then(|value| decode_uint(value))
+        {
+            Some(suppressed) => suppressed & NO_RESPONSE_ALL != NO_RESPONSE_ALL,
+            None => true,
+        }
+    }
+
+    fn encode(packet: &Packet) -> Result<Vec<u8>> {
+        packet
+            .to_bytes()
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn interface_index(name: &str) -> Result<u32> {
+    let name = std::ffi::CString::new(name).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => Err(Error::new(ErrorKind::NotFound, "no such interface")),
+        index => Ok(index),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_socket_option<T>(socket: &UdpSocket, level: libc::c_int, name: libc::c_int, value: T) -> Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const T as *const libc::c_void,
+            std::mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn set_multicast_if_v4(socket: &UdpSocket, index: u32) -> Result<()> {
+    let mreqn = libc::ip_mreqn {
+        imr_multiaddr: libc::in_addr { s_addr: 0 },
+        imr_address: libc::in_addr { s_addr: 0 },
+        imr_ifindex: index as libc::c_int,
+    };
+    set_socket_option(socket, libc::IPPROTO_IP, libc::IP_MULTICAST_IF, mreqn)
+}
+
+#[cfg(target_os = "linux")]
+fn set_multicast_if_v6(socket: &UdpSocket, index: u32) -> Result<()> {
+    set_socket_option(socket, libc::IPPROTO_IPV6, libc::IPV6_MULTICAST_IF, index as libc::c_int)
+}
+
+#[cfg(target_os = "linux")]
+fn set_multicast_hops_v6(socket: &UdpSocket, hops: u32) -> Result<()> {
+    set_socket_option(socket, libc::IPPROTO_IPV6, libc::IPV6_MULTICAST_HOPS, hops as libc::c_int)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn interface_index(_name: &str) -> Result<u32> {
+    Err(Error::other("interface selection is not supported on this platform"))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_multicast_if_v4(_socket: &UdpSocket, _index: u32) -> Result<()> {
+    Err(Error::other("interface selection is not supported on this platform"))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_multicast_if_v6(_socket: &UdpSocket, _index: u32) -> Result<()> {
+    Err(Error::other("interface selection is not supported on this platform"))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_multicast_hops_v6(_socket: &UdpSocket, _hops: u32) -> Result<()> {
+    Ok(())
+}
+
+/// Checks whether a No-Response value suppresses responses of the given code.
+pub fn is_response_suppressed(no_response: u32, response: &CoAPResponse) -> bool {
+    let class = class_to_code(&response.message.header.code) >> 5;
+    match class {
+        2 => no_response & 2 != 0,
+        4 => no_response & 8 != 0,
+        5 => no_response & 16 != 0,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::*;
+
+    #[test]
+    fn test_group_send() {
+        let server_port = server::test::spawn_server(|req: CoAPRequest| async { req.response }).recv().unwrap();
+
+        let mut client = GroupClient::new(format!("127.0.0.1:{}", server_port)).unwrap();
+        client.set_leisure(Duration::from_millis(500));
+
+        let mut request = CoAPRequest::new();
+        request.set_path("/lights");
+        let responses = client.send(&mut request).unwrap();
+        assert_eq!(request.get_type(), MessageType::NonConfirmable);
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].0.port(), server_port);
+
+        let first_token = request.get_token().clone();
+        request.add_option(CoAPOption::NoResponse, vec![26]);
+        assert!(client.send(&mut request).unwrap().is_empty());
+        assert_ne!(*request.get_token(), first_token);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_send_on_interfaces() {
+        let server_port = server::test::spawn_server(|req: CoAPRequest| async { req.response }).recv().unwrap();
+
+        let mut client = GroupClient::new(format!("127.0.0.1:{}", server_port)).unwrap();
+        client.set_leisure(Duration::from_millis(500));
+        let loopback = Interface::Name("lo".to_string()).index().unwrap();
+
+        let options = MulticastOptions::default()
+            .with_interface(Interface::Name("lo".to_string()))
+            .with_interface(Interface::Index(loopback))
+            .with_ttl(4);
+        let mut request = CoAPRequest::new();
+        request.set_path("/lights");
+        let responses = client.send_with(&mut request, &options).unwrap();
+        assert_eq!(responses.len(), 2);
+        assert_eq!(client.socket.multicast_ttl_v4().unwrap(), DEFAULT_MULTICAST_TTL);
+
+        let missing = MulticastOptions::default().with_interface(Interface::Name("missing0".to_string()));
+        let error = client.send_with(&mut request, &missing).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_serial_unicast() {
+        let mut client = GroupClient::new((ALL_COAP_NODES_V4, 5683)).unwrap();
+        client.set_leisure(Duration::from_millis(500));
+        for _ in 0..2 {
+            let port = server::test::spawn_server(|req: CoAPRequest| async { req.response }).recv().unwrap();
+            client.add_member(("127.0.0.1", port)).unwrap();
+        }
+
+        let mut request = CoAPRequest::new();
+        request.set_path("/lights");
+        let responses = client.send_serial(&mut request).unwrap();
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[0].0, client.members()[0]);
+        assert_eq!(responses[1].0, client.members()[1]);
+    }
+
+    #[test]
+    fn test_response_suppressed() {
+        let mut packet = Packet::new();
+        packet.header.set_type(MessageType::Confirmable);
+        let mut response = CoAPResponse::new(&packet).unwrap();
+        assert!(is_response_suppressed(2, &response));
+        assert!(!is_response_suppressed(8, &response));
+        response.set_status(Status::NotFound);
+        assert!(is_response_suppressed(8, &response));
+    }
+}
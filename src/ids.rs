@@ -0,0 +1,146 @@
+//! Message ID and token generation that survives reboots.
+//!
+//! A device must not reuse a message ID within EXCHANGE_LIFETIME, or a peer may take a new
+//! request for a duplicate. A generator hands out message IDs from blocks reserved ahead: the
+//! hook set with `set_id_state_hook` receives an `IdState` whenever a new block is reserved,
+//! which the application writes to persistent storage, e.g. flash, and restores on start.
+//! Since the state lies beyond every message ID handed out, none is reused after a restore, at
+//! the cost of skipping the rest of a block.
+//!
+//! Tokens are drawn from the random source instead, so that an off-path attacker cannot
+//! guess the token of a request from an earlier one and spoof its response (RFC 7252
+//! §5.3.1). Nothing about them needs storing.
+
+use std::io::{Error, ErrorKind, Result};
+use serde::{Deserialize, Serialize};
+
+use super::rng;
+
+// message IDs reserved at once, bounding the writes to storage
+const RESERVATION: u16 = 256;
+const TOKEN_LENGTH: usize = 8;
+
+/// The next message ID a generator hands out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IdState {
+    message_id: u16,
+}
+
+impl IdState {
+    pub fn new(message_id: u16) -> IdState {
+        IdState { message_id }
+    }
+
+    /// A random state, for a device without stored state.
+    pub fn seeded() -> IdState {
+        IdState::new(rng::next_u32() as u16)
+    }
+
+    pub fn message_id(&self) -> u16 {
+        self.message_id
+    }
+
+    /// Encodes the state for storage.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    /// Decodes a state produced by `to_bytes`.
+    pub fn from_bytes(buf: &[u8]) -> Result<IdState> {
+        bincode::deserialize(buf).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+}
+
+pub(crate) struct IdGenerator {
+    next: IdState,
+    // the message IDs up to this one are covered by the last state handed to the hook
+    reserved: IdState,
+    hook: Option<Box<dyn FnMut(IdState) + Send>>,
+}
+
+impl IdGenerator {
+    pub fn new(state: IdState) -> IdGenerator {
+        IdGenerator {
+            next: state,
+            reserved: state,
+            hook: None,
+        }
+    }
+
+    /// The state to store: beyond every message ID handed out so far.
+    pub fn state(&self) -> IdState {
+        self.reserved
+    }
+
+    /// Continues from a stored state.
+    pub fn restore(&mut self, state: IdState) {
+        self.next = state;
+        self.reserved = state;
+    }
+
+    /// Sets the hook receiving each reservation, and reserves a block at once so that the
+    /// storage is current.
+    pub fn set_hook<F: FnMut(IdState) + Send + 'static>(&mut self, hook: F) {
+        self.hook = Some(Box::new(hook));
+        self.reserve();
+    }
+
+    pub fn next_message_id(&mut self) -> u16 {
+        if self.next.message_id == self.reserved.message_id {
+            self.reserve();
+        }
+        let message_id = self.next.message_id;
+        self.next.message_id = message_id.wrapping_add(1);
+        message_id
+    }
+
+    pub fn next_token(&mut self) -> Vec<u8> {
+        let mut token = vec![0; TOKEN_LENGTH];
+        rng::fill_bytes(&mut token);
+        token
+    }
+
+    fn reserve(&mut self) {
+        self.reserved = IdState::new(self.next.message_id.wrapping_add(RESERVATION));
+        if let Some(ref mut hook) = self.hook {
+            hook(self.reserved);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_reservations() {
+        let stored = Arc::new(Mutex::new(Vec::new()));
+        let mut ids = IdGenerator::new(IdState::new(65500));
+        let hook_stored = stored.clone();
+        ids.set_hook(move |state| hook_stored.lock().unwrap().push(state));
+        assert_eq!(*stored.lock().unwrap(), vec![IdState::new(65500u16.wrapping_add(256))]);
+
+        let mut message_ids = Vec::new();
+        for _ in 0..300 {
+            message_ids.push(ids.next_message_id());
+        }
+        assert_eq!(message_ids[0], 65500);
+        assert_eq!(message_ids[36], 0);
+        assert_eq!(stored.lock().unwrap().len(), 2);
+
+        // tokens do not follow one another
+        let token = ids.next_token();
+        assert_eq!(token.len(), TOKEN_LENGTH);
+        assert_ne!(ids.next_token(), token);
+        assert_eq!(stored.lock().unwrap().len(), 2);
+
+        // after a reboot nothing handed out before is handed out again
+        let state = IdState::from_bytes(&ids.state().to_bytes()).unwrap();
+        assert_eq!(state, *stored.lock().unwrap().last().unwrap());
+        let mut restored = IdGenerator::new(IdState::seeded());
+        restored.restore(state);
+        let next = restored.next_message_id();
+        assert!(!message_ids.contains(&next));
+    }
+}
@@ -0,0 +1,310 @@
+//! Conversion between JSON text and the CBOR data model (RFC 8949 §6), used to transcode
+//! payloads between application/json and application/cbor.
+
+use std::fmt;
+
+use super::cbor::Value;
+
+#[derive(Debug, PartialEq)]
+pub enum JsonError {
+    /// The text ended inside a value.
+    Truncated,
+    /// An unexpected character was found at the offset.
+    Unexpected { offset: usize },
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            JsonError::Truncated => write!(f, "truncated JSON text"),
+            JsonError::Unexpected { offset } => write!(f, "unexpected JSON character at offset {}", offset),
+        }
+    }
+}
+
+impl std::error::Error for JsonError {}
+
+/// Parses a JSON text. Integers become CBOR integers, other numbers floats.
+pub fn parse(text: &str) -> Result<Value, JsonError> {
+    let mut parser = Parser { buf: text.as_bytes(), idx: 0 };
+    let value = parser.value()?;
+    parser.skip_whitespace();
+    if parser.idx < parser.buf.len() {
+        return Err(JsonError::Unexpected { offset: parser.idx });
+    }
+    Ok(value)
+}
+
+/// Converts a CBOR value to JSON text. Byte strings become base64url text and map keys
+/// that are not text are converted to their JSON text.
+pub fn to_string(value: &Value) -> String {
+    let mut out = String::new();
+    write_value(&mut out, value);
+    out
+}
+
+fn write_value(out: &mut String, value: &Value) {
+    match *value {
+        Value::Integer(n) => out.push_str(&n.to_string()),
+        Value::Float(n) if n.is_finite() => out.push_str(&format!("{:?}", n)),
+        Value::Float(_) | Value::Null => out.push_str("null"),
+        Value::Bool(b) => out.push_str(if b { "true" } else { "false" }),
+        Value::Text(ref text) => write_string(out, text),
+        Value::Bytes(ref bytes) => write_string(out, &base64url(bytes)),
+        Value::Array(ref items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_value(out, item);
+            }
+            out.push(']');
+        }
+        Value::Map(ref entries) => {
+            out.push('{');
+            for (i, (key, item)) in entries.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                match *key {
+                    Value::Text(ref text) => write_string(out, text),
+                    _ => write_string(out, &to_string(key)),
+                }
+                out.push(':');
+                write_value(out, item);
+            }
+            out.push('}');
+        }
+    }
+}
+
+fn write_string(out: &mut String, text: &str) {
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn base64url(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+    let mut out = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk.iter().fold(0u32, |acc, &b| acc << 8 | b as u32) << (8 * (3 - chunk.len()));
+        for i in 0..=chunk.len() {
+            out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+        }
+    }
+    out
+}
+
+struct Parser<'a> {
+    buf: &'a [u8],
+    idx: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn skip_whitespace(&mut self) {
+        while self.idx < self.buf.len() && b" \t\r\n".contains(&self.buf[self.idx]) {
+            self.idx += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Result<u8, JsonError> {
+        self.skip_whitespace();
+        self.buf.get(self.idx).cloned().ok_or(JsonError::Truncated)
+    }
+
+    fn expect(&mut self, c: u8) -> Result<(), JsonError> {
+        if self.peek()? != c {
+            return Err(JsonError::Unexpected { offset: self.idx });
+        }
+        self.idx += 1;
+        Ok(())
+    }
+
+    fn literal(&mut self, text: &str, value: Value) -> Result<Value, JsonError> {
+        let end = self.idx + text.len();
+        if end > self.buf.len() {
+            return Err(JsonError::Truncated);
+        }
+        if &self.buf[self.idx..end] != text.as_bytes() {
+            return Err(JsonError::Unexpected { offset: self.idx });
+        }
+        self.idx = end;
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<Value, JsonError> {
+        match self.peek()? {
+            b'{' => self.object(),
+            b'[' => self.array(),
+            b'"' => self.string().map(Value::Text),
+            b't' => self.literal("true", Value::Bool(true)),
+            b'f' => self.literal("false", Value::Bool(false)),
+            b'n' => self.literal("null", Value::Null),
+            b'-' | b'0'..=b'9' => self.number(),
+            _ => Err(JsonError::Unexpected { offset: self.idx }),
+        }
+    }
+
+    fn object(&mut self) -> Result<Value, JsonError> {
+        self.expect(b'{')?;
+        let mut entries = Vec::new();
+        if self.peek()? == b'}' {
+            self.idx += 1;
+            return Ok(Value::Map(entries));
+        }
+        loop {
+            if self.peek()? != b'"' {
+                return Err(JsonError::Unexpected { offset: self.idx });
+            }
+            let key = self.string()?;
+            self.expect(b':')?;
+            entries.push((Value::Text(key), self.value()?));
+            match self.peek()? {
+                b',' => self.idx += 1,
+                b'}' => {
+                    self.idx += 1;
+                    return Ok(Value::Map(entries));
+                }
+                _ => return Err(JsonError::Unexpected { offset: self.idx }),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, JsonError> {
+        self.expect(b'[')?;
+        let mut items = Vec::new();
+        if self.peek()? == b']' {
+            self.idx += 1;
+            return Ok(Value::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            match self.peek()? {
+                b',' => self.idx += 1,
+                b']' => {
+                    self.idx += 1;
+                    return Ok(Value::Array(items));
+                }
+                _ => return Err(JsonError::Unexpected { offset: self.idx }),
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, JsonError> {
+        self.expect(b'"')?;
+        let mut bytes = Vec::new();
+        loop {
+            let c = *self.buf.get(self.idx).ok_or(JsonError::Truncated)?;
+            self.idx += 1;
+            match c {
+                b'"' => break,
+                b'\\' => {
+                    let escape = *self.buf.get(self.idx).ok_or(JsonError::Truncated)?;
+                    self.idx += 1;
+                    match escape {
+                        b'"' | b'\\' | b'/' => bytes.push(escape),
+                        b'b' => bytes.push(0x08),
+                        b'f' => bytes.push(0x0C),
+                        b'n' => bytes.push(b'\n'),
+                        b'r' => bytes.push(b'\r'),
+                        b't' => bytes.push(b'\t'),
+                        b'u' => {
+                            let c = self.unicode_escape()?;
+                            let mut utf8 = [0; 4];
+                            bytes.extend_from_slice(c.encode_utf8(&mut utf8).as_bytes());
+                        }
+                        _ => return Err(JsonError::Unexpected { offset: self.idx - 1 }),
+                    }
+                }
+                c if c < 0x20 => return Err(JsonError::Unexpected { offset: self.idx - 1 }),
+                c => bytes.push(c),
+            }
+        }
+        // the input is a str, so unescaped bytes are valid UTF-8
+        Ok(String::from_utf8(bytes).unwrap())
+    }
+
+    fn hex4(&mut self) -> Result<u32, JsonError> {
+        let end = self.idx + 4;
+        if end > self.buf.len() {
+            return Err(JsonError::Truncated);
+        }
+        let digits = std::str::from_utf8(&self.buf[self.idx..end]).ok();
+        let code = digits
+            .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+            .ok_or(JsonError::Unexpected { offset: self.idx })?;
+        self.idx = end;
+        Ok(code)
+    }
+
+    fn unicode_escape(&mut self) -> Result<char, JsonError> {
+        let offset = self.idx;
+        let mut code = self.hex4()?;
+        if (0xD800..0xDC00).contains(&code) && self.buf[self.idx..].starts_with(b"\\u") {
+            self.idx += 2;
+            let low = self.hex4()?;
+            code = 0x10000 + ((code - 0xD800) << 10) + (low.wrapping_sub(0xDC00) & 0x3FF);
+        }
+        std::char::from_u32(code).ok_or(JsonError::Unexpected { offset })
+    }
+
+    fn number(&mut self) -> Result<Value, JsonError> {
+        let start = self.idx;
+        while self.idx < self.buf.len() && b"+-0123456789.eE".contains(&self.buf[self.idx]) {
+            self.idx += 1;
+        }
+        let text = std::str::from_utf8(&self.buf[start..self.idx]).unwrap();
+        if let Ok(n) = text.parse::<i64>() {
+            return Ok(Value::Integer(n));
+        }
+        text.parse::<f64>()
+            .map(Value::Float)
+            .map_err(|_| JsonError::Unexpected { offset: start })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let value = parse(r#" {"temp": 21.5, "ids": [1, -2], "ok": true, "name": "a\"é", "x": null} "#).unwrap();
+        assert_eq!(
+            value,
+            Value::Map(vec![
+                (Value::Text("temp".to_string()), Value::Float(21.5)),
+                (Value::Text("ids".to_string()), Value::Array(vec![Value::Integer(1), Value::Integer(-2)])),
+                (Value::Text("ok".to_string()), Value::Bool(true)),
+                (Value::Text("name".to_string()), Value::Text("a\"é".to_string())),
+                (Value::Text("x".to_string()), Value::Null),
+            ])
+        );
+
+        assert_eq!(parse("[1,").unwrap_err(), JsonError::Truncated);
+        assert_eq!(parse("[1] x").unwrap_err(), JsonError::Unexpected { offset: 4 });
+        assert_eq!(parse("{1: 2}").unwrap_err(), JsonError::Unexpected { offset: 1 });
+    }
+
+    #[test]
+    fn test_to_string() {
+        let value = Value::Map(vec![
+            (Value::Text("v".to_string()), Value::Array(vec![Value::Float(1.5), Value::Integer(3)])),
+            (Value::Integer(1), Value::Bytes(vec![0xFB, 0xFF])),
+        ]);
+        assert_eq!(to_string(&value), r#"{"v":[1.5,3],"1":"-_8"}"#);
+        assert_eq!(parse(&to_string(&Value::Text("a\n".to_string()))).unwrap(), Value::Text("a\n".to_string()));
+    }
+}
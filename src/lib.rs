@@ -79,20 +79,80 @@
 #[cfg(test)]
 extern crate quickcheck;
 
-pub use self::client::CoAPClient;
+pub use self::budget::{BudgetExceeded, MemoryBudget};
+pub use self::capability::Capabilities;
+pub use self::client::{AuthRecovery, BlockReader, ClientReceiver, ClientSender, CoAPClient, ObservationHandle, ObservationState, Priority, Recovery, UnsolicitedReply};
+pub use self::codec::{Codec, CodecRegistry};
+pub use self::congestion::CongestionController;
+pub use self::context::{RequestContext, Transport};
+pub use self::datagram::DatagramInfo;
+pub use self::group::GroupClient;
+pub use self::ids::IdState;
 pub use self::message::header::MessageType;
 pub use self::message::IsMessage;
 pub use self::message::packet::CoAPOption;
 pub use self::message::request::CoAPRequest;
 pub use self::message::request::Method;
 pub use self::message::response::CoAPResponse;
-pub use self::message::response::Status;
-pub use self::observer::Observer;
-pub use self::server::{Server, CoAPServer};
+pub use self::message::response::{Outcome, ResponseError, Status};
+pub use self::observer::{NotificationPacing, ObserveDecision, ObserveState, Observer, ResourcePublisher};
+pub use self::proxy::ForwardProxy;
+pub use self::record::{Recorder, SessionLog};
+pub use self::resolve::Resolver;
+pub use self::resource::VersionedResource;
+pub use self::rng::RandomSource;
+pub use self::server::{CoAPServer, HandlerTimeout, Server};
+pub use self::session::{EvictionReason, SessionPolicy};
+pub use self::trace::Tracing;
 pub mod message;
+pub mod ace;
+pub mod acl;
+pub mod budget;
+#[cfg(feature = "capi")]
+pub mod capi;
+pub mod oscore;
+pub mod cache;
+pub mod capability;
+pub mod cbor;
+pub mod chaos;
 pub mod client;
+pub mod congestion;
+pub mod codec;
+#[cfg(feature = "config")]
+pub mod config;
+pub mod context;
+pub mod datagram;
+pub mod diag;
 pub mod dtls_client;
+pub mod edhoc;
+pub mod event;
+pub mod exchange;
+pub mod filter;
+pub mod group;
+pub mod ids;
+pub mod json;
+pub mod mdns;
+pub mod multipart;
+pub mod negotiate;
+pub mod poll;
+pub mod proxy;
+#[cfg(feature = "python")]
+pub mod python;
+pub mod record;
+pub mod resolve;
+pub mod resource;
+pub mod rng;
+pub mod schedule;
 pub mod server;
+pub mod session;
+#[cfg(feature = "tower")]
+pub mod service;
+pub mod stats;
+pub mod tcp;
+pub mod trace;
 pub mod udp;
+pub mod uri;
+mod dtls_server;
 mod observer;
 mod ssl_utils;
+mod transfer;
@@ -0,0 +1,397 @@
+use std::collections::HashMap;
+use std::io::{ErrorKind, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+use log::*;
+
+/// The mDNS IPv4 multicast address (RFC 6762 §3).
+pub const MDNS_V4: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+/// The mDNS port.
+pub const MDNS_PORT: u16 = 5353;
+
+const DEFAULT_BROWSE_TIMEOUT: u64 = 2; // 2s
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+const MAX_POINTERS: usize = 16;
+
+/// A DNS-SD service type CoAP servers advertise.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ServiceType {
+    /// `_coap._udp`
+    Coap,
+    /// `_coaps._udp`, CoAP over DTLS
+    Coaps,
+}
+
+impl ServiceType {
+    /// The fully qualified name browsed for instances of the service.
+    pub fn domain(self) -> &'static str {
+        match self {
+            ServiceType::Coap => "_coap._udp.local",
+            ServiceType::Coaps => "_coaps._udp.local",
+        }
+    }
+
+    fn scheme(self) -> &'static str {
+        match self {
+            ServiceType::Coap => "coap",
+            ServiceType::Coaps => "coaps",
+        }
+    }
+}
+
+/// A CoAP server found by browsing.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Endpoint {
+    /// The service instance name, e.g. `Kitchen Light._coap._udp.local`.
+    pub instance: String,
+    pub service: ServiceType,
+    /// The host name the instance runs on.
+    pub host: String,
+    pub addr: SocketAddr,
+    /// The key/value strings of the TXT record.
+    pub txt: Vec<String>,
+}
+
+impl Endpoint {
+    /// The URI of the server, e.g. `coap://192.0.2.1:5683`.
+    pub fn uri(&self) -> String {
+        format!("{}://{}", self.service.scheme(), self.addr)
+    }
+}
+
+/// Discovers CoAP servers with mDNS/DNS-SD (RFC 6762, RFC 6763), complementing a multicast
+/// GET of `/.well-known/core` on networks where mDNS is the norm.
+///
+/// Queries are one-shot and sent from an ephemeral port, so responders answer directly. The
+/// endpoints found are only candidates; nothing checks that they actually speak CoAP.
+pub struct MdnsDiscovery {
+    socket: UdpSocket,
+    target: SocketAddr,
+    timeout: Duration,
+}
+
+impl MdnsDiscovery {
+    /// Create a browser querying the mDNS group.
+    pub fn new() -> Result<MdnsDiscovery> {
+        Self::with_target(SocketAddr::new(IpAddr::V4(MDNS_V4), MDNS_PORT))
+    }
+
+    /// Create a browser querying the given address, e.g. a unicast DNS-SD responder.
+    pub fn with_target<A: ToSocketAddrs>(target: A) -> Result<MdnsDiscovery> {
+        let target = target
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| std::io::Error::other("no address"))?;
+        let bind_addr = match target.ip() {
+            IpAddr::V4(_) => "0.0.0.0:0",
+            IpAddr::V6(_) => ":::0",
+        };
+        Ok(MdnsDiscovery {
+            socket: UdpSocket::bind(bind_addr)?,
+            target,
+            timeout: Duration::new(DEFAULT_BROWSE_TIMEOUT, 0),
+        })
+    }
+
+    /// Set how long answers are collected.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Browse for instances of the service types, returning those whose address was resolved.
+    pub fn browse(&self, services: &[ServiceType]) -> Result<Vec<Endpoint>> {
+        let questions: Vec<(&str, u16)> = services.iter().map(|service| (service.domain(), TYPE_PTR)).collect();
+        let records = self.collect(&questions, |_| false)?;
+        Ok(records.endpoints(services))
+    }
+
+    /// Resolve a host name, e.g. `lamp.local`, to its addresses, returning once a responder
+    /// answers or with none when the timeout passes.
+    pub fn resolve_host(&self, host: &str) -> Result<Vec<IpAddr>> {
+        let key = host.trim_end_matches('.').to_lowercase();
+        let records = self.collect(&[(host, TYPE_A), (host, TYPE_AAAA)], |records| {
+            records.addrs.contains_key(&key)
+        })?;
+        Ok(records.addrs.get(&key).cloned().unwrap_or_default())
+    }
+
+    /// Sends the questions and collects the answers until the timeout or until `done`.
+    fn collect<F: Fn(&Records) -> bool>(&self, questions: &[(&str, u16)], done: F) -> Result<Records> {
+        self.socket.send_to(&query(questions), self.target)?;
+
+        let mut records = Records::default();
+        let deadline = Instant::now() + self.timeout;
+        let mut buf = [0; 9000];
+        loop {
+            let now = Instant::now();
+            if now >= deadline || done(&records) {
+                break;
+            }
+            self.socket.set_read_timeout(Some(deadline - now))?;
+            match self.socket.recv_from(&mut buf) {
+                Ok((nread, src)) => {
+                    if records.parse(&buf[..nread]).is_none() {
+                        debug!("ignoring malformed mDNS response from {}", src);
+                    }
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(records)
+    }
+}
+
+fn query(questions: &[(&str, u16)]) -> Vec<u8> {
+    let mut buf = vec![0; 12];
+    buf[4..6].copy_from_slice(&(questions.len() as u16).to_be_bytes());
+    for (name, record_type) in questions {
+        encode_name(name, &mut buf);
+        buf.extend_from_slice(&record_type.to_be_bytes());
+        buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+    }
+    buf
+}
+
+fn encode_name(name: &str, buf: &mut Vec<u8>) {
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.push(0);
+}
+
+/// Decodes a possibly compressed name at `offset`, returning it and the offset past it.
+fn decode_name(message: &[u8], mut offset: usize) -> Option<(String, usize)> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut end = None;
+    let mut pointers = 0;
+    loop {
+        let len = *message.get(offset)? as usize;
+        if len == 0 {
+            return Some((labels.join("."), end.unwrap_or(offset + 1)));
+        }
+        if len & 0xC0 == 0xC0 {
+            pointers += 1;
+            if pointers > MAX_POINTERS {
+                return None;
+            }
+            let target = (len & 0x3F) << 8 | *message.get(offset + 1)? as usize;
+            end.get_or_insert(offset + 2);
+            offset = target;
+            continue;
+        }
+        let label = message.get(offset + 1..offset + 1 + len)?;
+        labels.push(String::from_utf8_lossy(label).to_string());
+        offset += 1 + len;
+    }
+}
+
+fn read_u16(message: &[u8], offset: usize) -> Option<u16> {
+    message.get(offset..offset + 2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+}
+
+/// The records collected from the responses, keyed by lower-case owner name.
+#[derive(Default)]
+struct Records {
+    instances: Vec<(String, String)>,
+    services: HashMap<String, (String, u16)>,
+    txt: HashMap<String, Vec<String>>,
+    addrs: HashMap<String, Vec<IpAddr>>,
+}
+
+impl Records {
+    fn parse(&mut self, message: &[u8]) -> Option<()> {
+        let questions = read_u16(message, 4)?;
+        let records = read_u16(message, 6)? as usize + read_u16(message, 8)? as usize + read_u16(message, 10)? as usize;
+        let mut offset = 12;
+        for _ in 0..questions {
+            offset = decode_name(message, offset)?.1 + 4;
+        }
+        for _ in 0..records {
+            let (name, next) = decode_name(message, offset)?;
+            let record_type = read_u16(message, next)?;
+            let rdlength = read_u16(message, next + 8)? as usize;
+            let rdata_offset = next + 10;
+            let rdata = message.get(rdata_offset..rdata_offset + rdlength)?;
+            let owner = name.to_lowercase();
+            match record_type {
+                TYPE_PTR => {
+                    let (instance, _) = decode_name(message, rdata_offset)?;
+                    if !self.instances.iter().any(|(service, known)| *service == owner && *known == instance) {
+                        self.instances.push((owner, instance));
+                    }
+                }
+                TYPE_SRV => {
+                    let port = read_u16(rdata, 4)?;
+                    let (host, _) = decode_name(message, rdata_offset + 6)?;
+                    self.services.insert(owner, (host, port));
+                }
+                TYPE_TXT => {
+                    let mut strings = Vec::new();
+                    let mut i = 0;
+                    while i < rdata.len() {
+                        let len = rdata[i] as usize;
+                        let string = rdata.get(i + 1..i + 1 + len)?;
+                        if !string.is_empty() {
+                            strings.push(String::from_utf8_lossy(string).to_string());
+                        }
+                        i += 1 + len;
+                    }
+                    self.txt.insert(owner, strings);
+                }
+                TYPE_A if rdlength == 4 => {
+                    let ip = IpAddr::V4(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]));
+                    self.add_addr(owner, ip);
+                }
+                TYPE_AAAA if rdlength == 16 => {
+                    let mut octets = [0; 16];
+                    octets.copy_from_slice(rdata);
+                    self.add_addr(owner, IpAddr::V6(Ipv6Addr::from(octets)));
+                }
+                _ => (),
+            }
+            offset = rdata_offset + rdlength;
+        }
+        Some(())
+    }
+
+    fn add_addr(&mut self, host: String, ip: IpAddr) {
+        let addrs = self.addrs.entry(host).or_default();
+        if !addrs.contains(&ip) {
+            addrs.push(ip);
+        }
+    }
+
+    fn endpoints(&self, services: &[ServiceType]) -> Vec<Endpoint> {
+        let mut endpoints = Vec::new();
+        for (domain, instance) in self.instances.iter() {
+            let service = match services.iter().find(|service| service.domain() == domain) {
+                Some(service) => *service,
+                None => continue,
+            };
+            let key = instance.to_lowercase();
+            let (host, port) = match self.services.get(&key) {
+                Some(srv) => srv,
+                None => {
+                    debug!("no SRV record for {}", instance);
+                    continue;
+                }
+            };
+            let addrs = match self.addrs.get(&host.to_lowercase()) {
+                Some(addrs) => addrs,
+                None => {
+                    debug!("no address for {}", host);
+                    continue;
+                }
+            };
+            for ip in addrs {
+                endpoints.push(Endpoint {
+                    instance: instance.clone(),
+                    service,
+                    host: host.clone(),
+                    addr: SocketAddr::new(*ip, *port),
+                    txt: self.txt.get(&key).cloned().unwrap_or_default(),
+                });
+            }
+        }
+        endpoints
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::resolve::{MdnsResolver, Resolver};
+    use std::thread;
+
+    fn record(buf: &mut Vec<u8>, name: &str, record_type: u16, rdata: &[u8]) {
+        encode_name(name, buf);
+        buf.extend_from_slice(&record_type.to_be_bytes());
+        buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+        buf.extend_from_slice(&120u32.to_be_bytes());
+        buf.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        buf.extend_from_slice(rdata);
+    }
+
+    #[test]
+    fn test_browse() {
+        let responder = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let responder_addr = responder.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; 1500];
+            let (nread, src) = responder.recv_from(&mut buf).unwrap();
+            let mut questions = Records::default();
+            questions.parse(&buf[..nread]).unwrap();
+            assert_eq!(decode_name(&buf, 12).unwrap().0, "_coap._udp.local");
+
+            // repeats the question, so the PTR points back into it
+            let mut response = vec![0, 0, 0x84, 0, 0, 1, 0, 1, 0, 0, 0, 4];
+            encode_name("_coap._udp.local", &mut response);
+            response.extend_from_slice(&[0, 12, 0, 1]);
+            let mut ptr = vec![5];
+            ptr.extend_from_slice(b"lamp1");
+            ptr.extend_from_slice(&[0xC0, 12]);
+            record(&mut response, "_coap._udp.local", TYPE_PTR, &ptr);
+            let mut srv = vec![0, 0, 0, 0, 0x16, 0x33];
+            encode_name("lamp.local", &mut srv);
+            record(&mut response, "lamp1._coap._udp.local", TYPE_SRV, &srv);
+            record(&mut response, "lamp1._coap._udp.local", TYPE_TXT, b"\x06rt=oic");
+            record(&mut response, "lamp.local", TYPE_A, &[127, 0, 0, 1]);
+            record(&mut response, "printer.local", TYPE_A, &[127, 0, 0, 2]);
+            responder.send_to(&response, src).unwrap();
+        });
+
+        let mut discovery = MdnsDiscovery::with_target(responder_addr).unwrap();
+        discovery.set_timeout(Duration::from_millis(200));
+        let endpoints = discovery.browse(&[ServiceType::Coap, ServiceType::Coaps]).unwrap();
+        assert_eq!(endpoints, vec![Endpoint {
+            instance: "lamp1._coap._udp.local".to_string(),
+            service: ServiceType::Coap,
+            host: "lamp.local".to_string(),
+            addr: "127.0.0.1:5683".parse().unwrap(),
+            txt: vec!["rt=oic".to_string()],
+        }]);
+        assert_eq!(endpoints[0].uri(), "coap://127.0.0.1:5683");
+    }
+
+    #[test]
+    fn test_resolve_host() {
+        let responder = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let responder_addr = responder.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; 1500];
+            let (_, src) = responder.recv_from(&mut buf).unwrap();
+            assert_eq!(decode_name(&buf, 12).unwrap().0, "Lamp.local");
+
+            let mut response = vec![0, 0, 0x84, 0, 0, 0, 0, 1, 0, 0, 0, 0];
+            record(&mut response, "lamp.local", TYPE_A, &[127, 0, 0, 1]);
+            responder.send_to(&response, src).unwrap();
+        });
+
+        let mut discovery = MdnsDiscovery::with_target(responder_addr).unwrap();
+        discovery.set_timeout(Duration::from_secs(5));
+        let resolver = MdnsResolver::with_discovery(discovery);
+        // answered before the timeout passes
+        let start = Instant::now();
+        assert_eq!(
+            resolver.resolve("Lamp.local", 5683).unwrap(),
+            vec!["127.0.0.1:5683".parse().unwrap()]
+        );
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(resolver.resolve("localhost", 5683).is_ok());
+    }
+
+    #[test]
+    fn test_malformed() {
+        let mut records = Records::default();
+        // a pointer loop
+        assert!(records.parse(&[0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0xC0, 12]).is_none());
+        assert!(records.parse(&[0, 0, 0, 0]).is_none());
+    }
+}
@@ -1,3 +1,5 @@
+use serde::de::{self, Deserializer};
+use serde::ser::{self, Serializer};
 use serde::{Serialize, Deserialize};
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -29,6 +31,8 @@ pub enum MessageClass {
     Empty,
     Request(RequestType),
     Response(ResponseType),
+    /// A signaling message of a reliable transport (RFC 8323 §5).
+    Signaling(SignalingType),
     Reserved,
 }
 
@@ -38,6 +42,8 @@ pub enum RequestType {
     Post,
     Put,
     Delete,
+    /// FETCH (RFC 8132), a GET with the query in the payload.
+    Fetch,
     UnKnown,
 }
 
@@ -59,6 +65,7 @@ pub enum ResponseType {
     NotFound,
     MethodNotAllowed,
     NotAcceptable,
+    Conflict,
     PreconditionFailed,
     RequestEntityTooLarge,
     UnsupportedContentFormat,
@@ -76,6 +83,15 @@ pub enum ResponseType {
     UnKnown,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SignalingType {
+    Csm,
+    Ping,
+    Pong,
+    Release,
+    Abort,
+}
+
 #[derive(PartialEq, Eq, Debug)]
 pub enum MessageType {
     Confirmable,
@@ -191,6 +207,7 @@ pub fn class_to_code(class: &MessageClass) -> u8 {
         MessageClass::Request(RequestType::Post) => 0x02,
         MessageClass::Request(RequestType::Put) => 0x03,
         MessageClass::Request(RequestType::Delete) => 0x04,
+        MessageClass::Request(RequestType::Fetch) => 0x05,
 
         MessageClass::Response(ResponseType::Created) => 0x41,
         MessageClass::Response(ResponseType::Deleted) => 0x42,
@@ -206,6 +223,7 @@ pub fn class_to_code(class: &MessageClass) -> u8 {
         MessageClass::Response(ResponseType::NotFound) => 0x84,
         MessageClass::Response(ResponseType::MethodNotAllowed) => 0x85,
         MessageClass::Response(ResponseType::NotAcceptable) => 0x86,
+        MessageClass::Response(ResponseType::Conflict) => 0x89,
         MessageClass::Response(ResponseType::PreconditionFailed) => 0x8C,
         MessageClass::Response(ResponseType::RequestEntityTooLarge) => 0x8D,
         MessageClass::Response(ResponseType::UnsupportedContentFormat) => 0x8F,
@@ -219,6 +237,12 @@ pub fn class_to_code(class: &MessageClass) -> u8 {
         MessageClass::Response(ResponseType::GatewayTimeout) => 0x94,
         MessageClass::Response(ResponseType::ProxyingNotSupported) => 0x95,
 
+        MessageClass::Signaling(SignalingType::Csm) => 0xE1,
+        MessageClass::Signaling(SignalingType::Ping) => 0xE2,
+        MessageClass::Signaling(SignalingType::Pong) => 0xE3,
+        MessageClass::Signaling(SignalingType::Release) => 0xE4,
+        MessageClass::Signaling(SignalingType::Abort) => 0xE5,
+
         _ => 0xFF,
     } as u8;
 }
@@ -231,6 +255,7 @@ pub fn code_to_class(code: &u8) -> MessageClass {
         0x02 => MessageClass::Request(RequestType::Post),
         0x03 => MessageClass::Request(RequestType::Put),
         0x04 => MessageClass::Request(RequestType::Delete),
+        0x05 => MessageClass::Request(RequestType::Fetch),
 
         0x41 => MessageClass::Response(ResponseType::Created),
         0x42 => MessageClass::Response(ResponseType::Deleted),
@@ -246,6 +271,7 @@ pub fn code_to_class(code: &u8) -> MessageClass {
         0x84 => MessageClass::Response(ResponseType::NotFound),
         0x85 => MessageClass::Response(ResponseType::MethodNotAllowed),
         0x86 => MessageClass::Response(ResponseType::NotAcceptable),
+        0x89 => MessageClass::Response(ResponseType::Conflict),
         0x8C => MessageClass::Response(ResponseType::PreconditionFailed),
         0x8D => MessageClass::Response(ResponseType::RequestEntityTooLarge),
         0x8F => MessageClass::Response(ResponseType::UnsupportedContentFormat),
@@ -258,10 +284,27 @@ pub fn code_to_class(code: &u8) -> MessageClass {
         0x93 => MessageClass::Response(ResponseType::ServiceUnavailable),
         0x94 => MessageClass::Response(ResponseType::GatewayTimeout),
         0x95 => MessageClass::Response(ResponseType::ProxyingNotSupported),
+
+        0xE1 => MessageClass::Signaling(SignalingType::Csm),
+        0xE2 => MessageClass::Signaling(SignalingType::Ping),
+        0xE3 => MessageClass::Signaling(SignalingType::Pong),
+        0xE4 => MessageClass::Signaling(SignalingType::Release),
+        0xE5 => MessageClass::Signaling(SignalingType::Abort),
         _ => MessageClass::Reserved,
     }
 }
 
+/// Parses a code written as in `code_to_str`, e.g. `2.05`.
+pub fn str_to_code(code: &str) -> Option<u8> {
+    let mut parts = code.splitn(2, '.');
+    let class_code = parts.next()?.parse::<u8>().ok()?;
+    let detail_code = parts.next()?.parse::<u8>().ok()?;
+    if class_code > 7 || detail_code > 31 {
+        return None;
+    }
+    Some(class_code << 5 | detail_code)
+}
+
 pub fn code_to_str(code: &u8) -> String {
     let class_code = (0xE0 & code) >> 5;
     let detail_code = 0x1F & code;
@@ -273,6 +316,47 @@ pub fn class_to_str(class: &MessageClass) -> String {
     return code_to_str(&class_to_code(class));
 }
 
+/// Codes are serialized as written in the RFCs, e.g. `"2.05"`.
+impl Serialize for MessageClass {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&class_to_str(self))
+    }
+}
+
+impl<'de> Deserialize<'de> for MessageClass {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<MessageClass, D::Error> {
+        let code = String::deserialize(deserializer)?;
+        str_to_code(&code)
+            .map(|code| code_to_class(&code))
+            .ok_or_else(|| de::Error::custom(format!("invalid code {}", code)))
+    }
+}
+
+/// Message types are serialized by their abbreviations: `"CON"`, `"NON"`, `"ACK"` and `"RST"`.
+impl Serialize for MessageType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(match *self {
+            MessageType::Confirmable => "CON",
+            MessageType::NonConfirmable => "NON",
+            MessageType::Acknowledgement => "ACK",
+            MessageType::Reset => "RST",
+            MessageType::Invalid => return Err(ser::Error::custom("invalid message type")),
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for MessageType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<MessageType, D::Error> {
+        match String::deserialize(deserializer)?.as_str() {
+            "CON" => Ok(MessageType::Confirmable),
+            "NON" => Ok(MessageType::NonConfirmable),
+            "ACK" => Ok(MessageType::Acknowledgement),
+            "RST" => Ok(MessageType::Reset),
+            other => Err(de::Error::custom(format!("invalid message type {}", other))),
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
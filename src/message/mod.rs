@@ -11,6 +11,10 @@ use tokio_util::codec::{Decoder, Encoder};
 
 use self::packet::Packet;
 use self::header::Header;
+use self::response::ContentError;
+use crate::cbor::Value;
+use crate::codec::CodecRegistry;
+use crate::multipart::{self, Part};
 
 pub trait IsMessage {
     fn get_message(&self) -> &Packet;
@@ -70,6 +74,50 @@ pub trait IsMessage {
     fn set_code(&mut self, code: &str) {
         self.get_mut_message().header.set_code(code);
     }
+
+    /// Decodes the payload with the codec of its Content-Format.
+    fn decode_payload(&self, codecs: &CodecRegistry) -> Result<Value, ContentError> {
+        let format = self
+            .get_option(packet::CoAPOption::ContentFormat)
+            .and_then(|list| list.front())
+            .and_then(|value| packet::decode_uint(value))
+            .ok_or(ContentError::MissingContentFormat)?;
+        codecs.decode(format, &self.get_message().payload)
+    }
+
+    /// Encodes a value as the payload with the codec of a Content-Format, declaring the format.
+    fn encode_payload(&mut self, codecs: &CodecRegistry, format: u32, value: &Value) -> Result<(), ContentError> {
+        let payload = codecs.encode(format, value)?;
+        self.clear_option(packet::CoAPOption::ContentFormat);
+        self.add_option(packet::CoAPOption::ContentFormat, packet::encode_uint(format));
+        self.set_payload(payload);
+        Ok(())
+    }
+
+    /// Bundles representations as an application/multipart-core payload (RFC 8710).
+    fn set_multipart(&mut self, parts: &[Part]) {
+        self.clear_option(packet::CoAPOption::ContentFormat);
+        self.add_option(
+            packet::CoAPOption::ContentFormat,
+            packet::encode_uint(packet::ContentFormat::ApplicationMultipartCore as u32),
+        );
+        self.set_payload(multipart::encode(parts));
+    }
+
+    /// Splits an application/multipart-core payload into its parts, accepting payloads
+    /// without a Content-Format.
+    fn multipart(&self) -> Result<Vec<Part>, ContentError> {
+        let format = self
+            .get_option(packet::CoAPOption::ContentFormat)
+            .and_then(|list| list.front())
+            .and_then(|value| packet::decode_uint(value));
+        match format {
+            Some(format) if format != packet::ContentFormat::ApplicationMultipartCore as u32 => {
+                Err(ContentError::UnexpectedContentFormat(format))
+            }
+            _ => multipart::decode(&self.get_message().payload),
+        }
+    }
 }
 
 pub struct Codec {}
@@ -86,7 +134,7 @@ impl Decoder for Codec {
 
     fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Packet>, io::Error> {
         Ok(Some(Packet::from_bytes(buf)
-        .map_err(|cause| io::Error::new(io::ErrorKind::InvalidData, cause.to_string()))?))
+        .map_err(|cause| io::Error::new(io::ErrorKind::InvalidData, cause))?))
     }
 }
 
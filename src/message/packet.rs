@@ -4,20 +4,17 @@ use std::{
     fmt,
     collections::BTreeMap,
     collections::LinkedList,
+    collections::btree_map,
 };
 
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
+use serde::de::{self, Deserializer};
+use serde::{Deserialize, Serialize, Serializer};
 
 use super::header;
 
-macro_rules! u8_to_unsigned_be {
-    ($src:ident, $start:expr, $end:expr, $t:ty) => ({
-        (0 .. $end - $start + 1).rev().fold(0, |acc, i| acc | $src[$start+i] as $t << i * 8)
-    })
-}
-
-#[derive(PartialEq, Eq, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum CoAPOption {
     IfMatch,
     UriHost,
@@ -26,6 +23,7 @@ pub enum CoAPOption {
     Observe,
     UriPort,
     LocationPath,
+    Oscore,
     UriPath,
     ContentFormat,
     MaxAge,
@@ -38,18 +36,32 @@ pub enum CoAPOption {
     ProxyScheme,
     Size1,
     Size2,
+    Echo,
     NoResponse,
 }
 
-#[derive(PartialEq, Eq, Debug, FromPrimitive)]
+const OPTIONS: [CoAPOption; 22] = [
+    CoAPOption::IfMatch, CoAPOption::UriHost, CoAPOption::ETag, CoAPOption::IfNoneMatch,
+    CoAPOption::Observe, CoAPOption::UriPort, CoAPOption::LocationPath, CoAPOption::Oscore,
+    CoAPOption::UriPath, CoAPOption::ContentFormat, CoAPOption::MaxAge, CoAPOption::UriQuery,
+    CoAPOption::Accept, CoAPOption::LocationQuery, CoAPOption::Block2, CoAPOption::Block1,
+    CoAPOption::ProxyUri, CoAPOption::ProxyScheme, CoAPOption::Size1, CoAPOption::Size2,
+    CoAPOption::Echo, CoAPOption::NoResponse,
+];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, FromPrimitive)]
 pub enum ContentFormat {
     TextPlain = 0,
+    ApplicationAceCbor = 19,
     ApplicationLinkFormat = 40,
     ApplicationXML = 41,
     ApplicationOctetStream = 42,
     ApplicationEXI = 47,
     ApplicationJSON = 50,
     ApplicationCBOR = 60,
+    ApplicationMultipartCore = 62,
+    ApplicationEdhocCborSeq = 64,
+    ApplicationCidEdhocCborSeq = 65,
     ApplicationSenmlJSON = 110,
     ApplicationSensmlJSON = 111,
     ApplicationSenmlCBOR = 112,
@@ -66,32 +78,111 @@ pub enum ObserveOption {
     Deregister = 1,
 }
 
-#[derive(Debug)]
+/// The maximum token length (RFC 7252 §3).
+pub const MAX_TOKEN_LENGTH: usize = 8;
+/// The largest option number, as option numbers are 16-bit (RFC 7252 §5.4).
+pub const MAX_OPTION_NUMBER: usize = 65535;
+/// The Max-Message-Size assumed of a reliable transport peer before its CSM (RFC 8323 §5.3.1).
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 1152;
+
+#[derive(Debug, PartialEq)]
 pub enum PackageError {
     InvalidHeader,
     InvalidPacketLength,
+    /// The token is longer than 8 bytes.
+    TokenTooLong { length: usize },
+    /// An option value is outside the length range allowed for the option number.
+    InvalidOptionLength { number: usize, length: usize, min: usize, max: usize },
 }
 
 impl fmt::Display for PackageError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:?}", self)
+        match *self {
+            PackageError::TokenTooLong { length } => {
+                write!(f, "token of {} bytes exceeds {} bytes", length, MAX_TOKEN_LENGTH)
+            }
+            PackageError::InvalidOptionLength { number, length, min, max } => write!(
+                f,
+                "option {} has {} bytes, must have {} to {}",
+                number, length, min, max
+            ),
+            _ => write!(f, "{:?}", self),
+        }
     }
 }
 
-#[derive(Debug)]
-pub enum ParseError {
-    InvalidHeader,
-    InvalidTokenLength,
-    InvalidOptionDelta,
-    InvalidOptionLength,
+impl std::error::Error for PackageError {}
+
+/// Describes why a byte buffer could not be decoded into a `Packet`.
+///
+/// Offsets are relative to the start of the buffer handed to `Packet::from_bytes`.
+#[derive(Debug, PartialEq)]
+pub enum MessageError {
+    /// The buffer is shorter than the fixed 4-byte header.
+    TruncatedHeader { length: usize },
+    /// The token length is one of the reserved values 9-15, or the token runs past the buffer.
+    InvalidTokenLength { offset: usize, length: u8 },
+    /// An option header uses the reserved delta nibble 15.
+    OptionDeltaReserved { offset: usize },
+    /// An option header uses the reserved length nibble 15.
+    OptionLengthReserved { offset: usize },
+    /// The extended delta or length bytes of an option run past the buffer.
+    TruncatedOption { offset: usize },
+    /// The option value is longer than the bytes remaining in the buffer.
+    OptionTooLong { offset: usize, length: usize },
+    /// A payload marker was found but no payload follows it.
+    UnexpectedPayloadMarker { offset: usize },
+    /// The option deltas add up to an option number above 65535.
+    OptionNumberOverflow { offset: usize, number: usize },
+    /// The length of a framed message exceeds the Max-Message-Size.
+    MessageTooLarge { offset: usize, length: usize, max: usize },
 }
 
-impl fmt::Display for ParseError {
+impl fmt::Display for MessageError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:?}", self)
+        match *self {
+            MessageError::TruncatedHeader { length } => {
+                write!(f, "truncated header: got {} bytes, need 4", length)
+            }
+            MessageError::InvalidTokenLength { offset, length } => {
+                write!(f, "invalid token length {} at offset {}", length, offset)
+            }
+            MessageError::OptionDeltaReserved { offset } => {
+                write!(f, "reserved option delta 15 at offset {}", offset)
+            }
+            MessageError::OptionLengthReserved { offset } => {
+                write!(f, "reserved option length 15 at offset {}", offset)
+            }
+            MessageError::TruncatedOption { offset } => {
+                write!(f, "truncated option header at offset {}", offset)
+            }
+            MessageError::OptionTooLong { offset, length } => write!(
+                f,
+                "option value of {} bytes at offset {} exceeds the packet",
+                length, offset
+            ),
+            MessageError::UnexpectedPayloadMarker { offset } => {
+                write!(f, "payload marker at offset {} without payload", offset)
+            }
+            MessageError::OptionNumberOverflow { offset, number } => write!(
+                f,
+                "option number {} at offset {} exceeds {}",
+                number, offset, MAX_OPTION_NUMBER
+            ),
+            MessageError::MessageTooLarge { offset, length, max } => write!(
+                f,
+                "message of {} bytes at offset {} exceeds the Max-Message-Size {}",
+                length, offset, max
+            ),
+        }
     }
 }
 
+impl std::error::Error for MessageError {}
+
+// option values by number, in the order they appear in the message
+type OptionMap = BTreeMap<usize, LinkedList<Vec<u8>>>;
+
 #[derive(Clone, Debug)]
 pub struct Packet {
     pub header: header::Header,
@@ -115,6 +206,40 @@ impl Packet {
         self.token = token;
     }
 
+    /// Same as `set_token`, rejecting tokens longer than 8 bytes.
+    pub fn try_set_token(&mut self, token: Vec<u8>) -> Result<(), PackageError> {
+        if token.len() > MAX_TOKEN_LENGTH {
+            return Err(PackageError::TokenTooLong { length: token.len() });
+        }
+        self.set_token(token);
+        Ok(())
+    }
+
+    /// Same as `add_option`, rejecting values whose length the option does not allow.
+    pub fn try_add_option(&mut self, tp: CoAPOption, value: Vec<u8>) -> Result<(), PackageError> {
+        check_option_length(Self::get_option_number(tp), value.len())?;
+        self.add_option(tp, value);
+        Ok(())
+    }
+
+    /// Checks the token and option lengths against the limits of RFC 7252, which peers
+    /// would otherwise reject with 4.02 Bad Option.
+    pub fn validate(&self) -> Result<(), PackageError> {
+        if self.token.len() > MAX_TOKEN_LENGTH {
+            return Err(PackageError::TokenTooLong { length: self.token.len() });
+        }
+        // signaling options reuse the option numbers with other meanings
+        if let header::MessageClass::Signaling(_) = self.header.code {
+            return Ok(());
+        }
+        for (number, values) in self.options.iter() {
+            for value in values.iter() {
+                check_option_length(*number, value.len())?;
+            }
+        }
+        Ok(())
+    }
+
     pub fn get_token(&self) -> &Vec<u8> {
         return &self.token;
     }
@@ -154,6 +279,119 @@ impl Packet {
         self.options.get(&num)
     }
 
+    /// Returns the values of an option by number, e.g. of a signaling option (RFC 8323 §5).
+    pub fn get_option_values(&self, number: usize) -> Option<&LinkedList<Vec<u8>>> {
+        self.options.get(&number).filter(|list| !list.is_empty())
+    }
+
+    /// Adds a value of an option given by number.
+    pub fn add_option_value(&mut self, number: usize, value: Vec<u8>) {
+        self.options.entry(number).or_default().push_back(value);
+    }
+
+    /// Iterates over all options in ascending option number order.
+    pub fn options(&self) -> btree_map::Iter<'_, usize, LinkedList<Vec<u8>>> {
+        self.options.iter()
+    }
+
+    /// Adds the options of `defaults` whose number this packet carries no value for.
+    pub fn merge_options(&mut self, defaults: &Packet) {
+        for (number, values) in defaults.options.iter() {
+            let present = self.options.get(number).is_some_and(|list| !list.is_empty());
+            if !present && !values.is_empty() {
+                self.options.insert(*number, values.clone());
+            }
+        }
+    }
+
+    /// Keeps only the options whose number satisfies the predicate.
+    pub fn retain_options<F: FnMut(usize) -> bool>(&mut self, mut keep: F) {
+        self.options.retain(|number, _| keep(*number));
+    }
+
+    /// Re-encodes the options canonically, so that packets of different stacks meaning the
+    /// same compare equal: unsigned integer values lose their leading zero bytes and options
+    /// without values or with their default value, a Max-Age of 60, a No-Response of 0 or a
+    /// lone empty Uri-Path, are removed. Uri-Host and Uri-Port are kept, since their default
+    /// depends on the destination. Options are always encoded in ascending order with minimal
+    /// deltas.
+    pub fn normalize(&mut self) {
+        // signaling options reuse the option numbers with other meanings
+        if let header::MessageClass::Signaling(_) = self.header.code {
+            return;
+        }
+        for (number, values) in self.options.iter_mut() {
+            if !is_uint_option(*number) {
+                continue;
+            }
+            for value in values.iter_mut() {
+                if let Some(uint) = decode_uint(value) {
+                    *value = encode_uint(uint);
+                }
+            }
+        }
+        self.options.retain(|number, values| {
+            let default = match (*number, values.front()) {
+                (14, Some(value)) => decode_uint(value) == Some(60), // Max-Age
+                (258, Some(value)) => value.is_empty(),              // No-Response
+                (11, Some(value)) => values.len() == 1 && value.is_empty(), // Uri-Path
+                _ => false,
+            };
+            !values.is_empty() && !default
+        });
+    }
+
+    /// Describes how the packet differs from another in meaning, empty if they are equal as
+    /// `==` compares them: by type, code, token, payload and options after `normalize`. The
+    /// values of an option are compared in order for paths and queries and as a multiset
+    /// otherwise. The message ID only counts for ACK and Reset messages, which it matches to
+    /// the message they answer, since a retransmission or another stack picks its own.
+    pub fn diff(&self, other: &Packet) -> Vec<String> {
+        let mut differences = Vec::new();
+        if self == other {
+            return differences;
+        }
+        if self.header.get_type() != other.header.get_type() {
+            differences.push(format!("type {:?} != {:?}", self.header.get_type(), other.header.get_type()));
+        }
+        if self.is_matched_by_id() && self.header.get_message_id() != other.header.get_message_id() {
+            differences.push(format!(
+                "message ID {} != {}",
+                self.header.get_message_id(),
+                other.header.get_message_id()
+            ));
+        }
+        if self.header.code != other.header.code {
+            differences.push(format!("code {:?} != {:?}", self.header.code, other.header.code));
+        }
+        if self.token != other.token {
+            differences.push(format!("token {:02x?} != {:02x?}", self.token, other.token));
+        }
+
+        let normalized = self.is_normalizable();
+        let numbers: std::collections::BTreeSet<usize> = self.options.keys().chain(other.options.keys()).cloned().collect();
+        for number in numbers {
+            let values = self.significant_option(number, normalized);
+            let other_values = other.significant_option(number, normalized);
+            let equal = match (values, other_values) {
+                (Some(values), Some(other_values)) => option_values_eq(number, values, other_values, normalized),
+                (values, other_values) => values.is_none() && other_values.is_none(),
+            };
+            if !equal {
+                let values: Vec<&Vec<u8>> = values.into_iter().flatten().collect();
+                let other_values: Vec<&Vec<u8>> = other_values.into_iter().flatten().collect();
+                differences.push(format!("option {} {:02x?} != {:02x?}", number, values, other_values));
+            }
+        }
+
+        if self.payload.len() != other.payload.len() {
+            differences.push(format!("payload of {} bytes != {} bytes", self.payload.len(), other.payload.len()));
+        } else if let Some(offset) = self.payload.iter().zip(other.payload.iter()).position(|(a, b)| a != b) {
+            differences.push(format!("payload differs at byte {}", offset));
+        }
+        differences
+    }
+
     pub fn clear_option(&mut self, tp: CoAPOption) {
         let num = Self::get_option_number(tp);
         if let Some(list) = self.options.get_mut(&num) {
@@ -163,18 +401,64 @@ impl Packet {
 
     pub fn get_content_format(&self) -> Option<ContentFormat> {
         if let Some(list) = self.get_option(CoAPOption::ContentFormat) {
-            if let Some(vector) = list.front() {
-                let msb = vector[0] as u16;
-                let lsb = vector[1] as u16;
-                let number = (msb << 8) + lsb;
+            if let Some(number) = list.front().and_then(|value| decode_uint(value)) {
+                return ContentFormat::from_u32(number);
+            }
+        }
+
+        None
+    }
+
+    pub fn set_etag(&mut self, etag: Vec<u8>) {
+        self.clear_option(CoAPOption::ETag);
+        self.add_option(CoAPOption::ETag, etag);
+    }
+
+    pub fn get_etag(&self) -> Option<&Vec<u8>> {
+        if let Some(list) = self.get_option(CoAPOption::ETag) {
+            return list.front();
+        }
+
+        None
+    }
+
+    pub fn set_max_age(&mut self, seconds: u32) {
+        self.clear_option(CoAPOption::MaxAge);
+        self.add_option(CoAPOption::MaxAge, encode_uint(seconds));
+    }
 
-                return ContentFormat::from_u16(number);
+    pub fn get_max_age(&self) -> Option<u32> {
+        if let Some(list) = self.get_option(CoAPOption::MaxAge) {
+            if let Some(value) = list.front() {
+                return decode_uint(value);
             }
         }
 
         None
     }
 
+    pub fn set_block1(&mut self, block: BlockValue) {
+        self.clear_option(CoAPOption::Block1);
+        self.add_option(CoAPOption::Block1, block.to_bytes());
+    }
+
+    pub fn get_block1(&self) -> Option<BlockValue> {
+        self.get_option(CoAPOption::Block1)
+            .and_then(|list| list.front())
+            .and_then(|value| BlockValue::from_bytes(value))
+    }
+
+    pub fn set_block2(&mut self, block: BlockValue) {
+        self.clear_option(CoAPOption::Block2);
+        self.add_option(CoAPOption::Block2, block.to_bytes());
+    }
+
+    pub fn get_block2(&self) -> Option<BlockValue> {
+        self.get_option(CoAPOption::Block2)
+            .and_then(|list| list.front())
+            .and_then(|value| BlockValue::from_bytes(value))
+    }
+
     pub fn set_observe(&mut self, value: Vec<u8>) {
         self.clear_option(CoAPOption::Observe);
         self.add_option(CoAPOption::Observe, value);
@@ -191,7 +475,7 @@ impl Packet {
     }
 
     /// Decodes a byte slice and construct the equivalent Packet.
-    pub fn from_bytes(buf: &[u8]) -> Result<Packet, ParseError> {
+    pub fn from_bytes(buf: &[u8]) -> Result<Packet, MessageError> {
 
         let header_result: bincode::Result<header::HeaderRaw> = bincode::config().big_endian().deserialize(buf);
         match header_result {
@@ -201,104 +485,15 @@ impl Packet {
                 let options_start: usize = 4 + token_length as usize;
 
                 if token_length > 8 {
-                    return Err(ParseError::InvalidTokenLength);
+                    return Err(MessageError::InvalidTokenLength { offset: 0, length: token_length });
                 }
 
                 if options_start > buf.len() {
-                    return Err(ParseError::InvalidTokenLength);
+                    return Err(MessageError::InvalidTokenLength { offset: 4, length: token_length });
                 }
 
                 let token = buf[4..options_start].to_vec();
-
-                let mut idx = options_start;
-                let mut options_number = 0;
-                let mut options: BTreeMap<usize, LinkedList<Vec<u8>>> = BTreeMap::new();
-                while idx < buf.len() {
-                    let byte = buf[idx];
-
-                    if byte == 255 || idx > buf.len() {
-                        break;
-                    }
-
-                    let mut delta = (byte >> 4) as usize;
-                    let mut length = (byte & 0xF) as usize;
-
-                    idx += 1;
-
-                    // Check for special delta characters
-                    match delta {
-                        13 => {
-                            if idx >= buf.len() {
-                                return Err(ParseError::InvalidOptionLength);
-                            }
-                            delta = buf[idx] as usize + 13;
-                            idx += 1;
-                        }
-                        14 => {
-                            if idx + 1 >= buf.len() {
-                                return Err(ParseError::InvalidOptionLength);
-                            }
-
-                            delta = (u16::from_be(u8_to_unsigned_be!(buf, idx, idx + 1, u16)) +
-                                     269) as usize;
-                            idx += 2;
-                        }
-                        15 => {
-                            return Err(ParseError::InvalidOptionDelta);
-                        }
-                        _ => {}
-                    };
-
-                    // Check for special length characters
-                    match length {
-                        13 => {
-                            if idx >= buf.len() {
-                                return Err(ParseError::InvalidOptionLength);
-                            }
-
-                            length = buf[idx] as usize + 13;
-                            idx += 1;
-                        }
-                        14 => {
-                            if idx + 1 >= buf.len() {
-                                return Err(ParseError::InvalidOptionLength);
-                            }
-
-                            length = (u16::from_be(u8_to_unsigned_be!(buf, idx, idx + 1, u16)) +
-                                      269) as usize;
-                            idx += 2;
-                        }
-                        15 => {
-                            return Err(ParseError::InvalidOptionLength);
-                        }
-                        _ => {}
-                    };
-
-                    options_number += delta;
-
-                    let end = idx + length;
-                    if end > buf.len() {
-                        return Err(ParseError::InvalidOptionLength);
-                    }
-                    let options_value = buf[idx..end].to_vec();
-
-                    if options.contains_key(&options_number) {
-                        let options_list = options.get_mut(&options_number).unwrap();
-                        options_list.push_back(options_value);
-                    } else {
-                        let mut list = LinkedList::new();
-                        list.push_back(options_value);
-                        options.insert(options_number, list);
-                    }
-
-                    idx += length;
-                }
-
-                let mut payload = Vec::new();
-                if idx < buf.len() {
-                    payload = buf[(idx + 1)..buf.len()].to_vec();
-                }
-
+                let (options, payload) = Self::decode_body(buf, options_start)?;
 
                 Ok(Packet {
                     header: header,
@@ -307,114 +502,144 @@ impl Packet {
                     payload: payload,
                 })
             }
-            Err(_) => Err(ParseError::InvalidHeader),
+            Err(_) => Err(MessageError::TruncatedHeader { length: buf.len() }),
         }
     }
 
-    /// Returns a vector of bytes representing the Packet.
-    pub fn to_bytes(&self) -> Result<Vec<u8>, PackageError> {
-        let mut options_delta_length = 0;
-        let mut options_bytes: Vec<u8> = Vec::new();
-        for (number, value_list) in self.options.iter() {
-            for value in value_list.iter() {
-                let mut header: Vec<u8> = Vec::with_capacity(1 + 2 + 2);
-                let delta = number - options_delta_length;
-
-                let mut byte: u8 = 0;
-                if delta <= 12 {
-                    byte |= (delta << 4) as u8;
-                } else if delta < 269 {
-                    byte |= 13 << 4;
-                } else {
-                    byte |= 14 << 4;
-                }
-                if value.len() <= 12 {
-                    byte |= value.len() as u8;
-                } else if value.len() < 269 {
-                    byte |= 13;
-                } else {
-                    byte |= 14;
-                }
-                header.push(byte);
-
-                if delta > 12 && delta < 269 {
-                    header.push((delta - 13) as u8);
-                } else if delta >= 269 {
-                    let fix = (delta - 269) as u16;
-                    header.push((fix >> 8) as u8);
-                    header.push((fix & 0xFF) as u8);
-                }
-
-                if value.len() > 12 && value.len() < 269 {
-                    header.push((value.len() - 13) as u8);
-                } else if value.len() >= 269 {
-                    let fix = (value.len() - 269) as u16;
-                    header.push((fix >> 8) as u8);
-                    header.push((fix & 0xFF) as u8);
-                }
+    /// Decodes the messages of a reliable transport byte stream, framed as in RFC 8323 §3.2.
+    ///
+    /// Iteration stops at the first malformed message or at an incomplete one, whose bytes
+    /// start at `Frames::offset` and should be kept until more data arrives. Decoded messages
+    /// carry no type or message ID.
+    ///
+    /// A message announcing a length above `DEFAULT_MAX_MESSAGE_SIZE`, or the size set with
+    /// `Frames::with_max_message_size`, is malformed rather than incomplete, so that a peer
+    /// cannot have the reader buffer without bound.
+    pub fn decode_all(buf: &[u8]) -> Frames<'_> {
+        Frames {
+            buf,
+            offset: 0,
+            failed: false,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+        }
+    }
 
-                options_delta_length += delta;
-
-                options_bytes.reserve(header.len() + value.len());
-                unsafe {
-                    use std::ptr;
-                    let buf_len = options_bytes.len();
-                    ptr::copy(header.as_ptr(),
-                              options_bytes.as_mut_ptr().offset(buf_len as isize),
-                              header.len());
-                    ptr::copy(value.as_ptr(),
-                              options_bytes.as_mut_ptr().offset((buf_len + header.len()) as isize),
-                              value.len());
-                    options_bytes.set_len(buf_len + header.len() + value.len());
-                }
+    /// Decodes the options and payload starting at `idx`; offsets in errors are relative to
+    /// the start of `buf`.
+    fn decode_body(buf: &[u8], mut idx: usize) -> Result<(OptionMap, Vec<u8>), MessageError> {
+        let mut options_number = 0;
+        let mut options = OptionMap::new();
+        while idx < buf.len() && buf[idx] != 0xFF {
+            let option_offset = idx;
+            let byte = buf[idx];
+            idx += 1;
+
+            let delta = match byte >> 4 {
+                15 => return Err(MessageError::OptionDeltaReserved { offset: option_offset }),
+                nibble => read_extended(buf, &mut idx, nibble)
+                    .ok_or(MessageError::TruncatedOption { offset: option_offset })?,
+            };
+            let length = match byte & 0xF {
+                15 => return Err(MessageError::OptionLengthReserved { offset: option_offset }),
+                nibble => read_extended(buf, &mut idx, nibble)
+                    .ok_or(MessageError::TruncatedOption { offset: option_offset })?,
+            };
+
+            options_number += delta;
+            if options_number > MAX_OPTION_NUMBER {
+                return Err(MessageError::OptionNumberOverflow { offset: option_offset, number: options_number });
             }
-        }
 
-        let mut buf_length = 4 + self.payload.len() + self.token.len();
-        if self.header.code != header::MessageClass::Empty && self.payload.len() != 0 {
-            buf_length += 1;
+            let end = match idx.checked_add(length) {
+                Some(end) if end <= buf.len() => end,
+                _ => return Err(MessageError::OptionTooLong { offset: option_offset, length }),
+            };
+            options
+                .entry(options_number)
+                .or_default()
+                .push_back(buf[idx..end].to_vec());
+            idx = end;
         }
-        buf_length += options_bytes.len();
 
-        if buf_length > 1280 {
-            return Err(PackageError::InvalidPacketLength);
+        let mut payload = Vec::new();
+        if idx < buf.len() {
+            if idx + 1 == buf.len() {
+                return Err(MessageError::UnexpectedPayloadMarker { offset: idx });
+            }
+            payload = buf[(idx + 1)..buf.len()].to_vec();
         }
+        Ok((options, payload))
+    }
+
+    /// Returns a vector of bytes representing the Packet.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, PackageError> {
+        let mut buf = Vec::new();
+        self.encode_into(&mut buf)?;
+        Ok(buf)
+    }
 
-        let mut buf: Vec<u8> = Vec::with_capacity(buf_length);
-        let header_result: bincode::Result<()> =
-            bincode::config().big_endian().serialize_into(&mut buf, &self.header.to_raw());
+    fn has_payload(&self) -> bool {
+        self.header.code != header::MessageClass::Empty && !self.payload.is_empty()
+    }
 
+    /// Appends the datagram encoding of the packet to `buf`.
+    fn encode_into(&self, buf: &mut Vec<u8>) -> Result<(), PackageError> {
+        self.validate()?;
 
-        match header_result {
-            Ok(_) => {
-                buf.reserve(self.token.len() + options_bytes.len());
-                unsafe {
-                    use std::ptr;
-                    let buf_len = buf.len();
-                    ptr::copy(self.token.as_ptr(),
-                              buf.as_mut_ptr().offset(buf_len as isize),
-                              self.token.len());
-                    ptr::copy(options_bytes.as_ptr(),
-                              buf.as_mut_ptr().offset((buf_len + self.token.len()) as isize),
-                              options_bytes.len());
-                    buf.set_len(buf_len + self.token.len() + options_bytes.len());
-                }
+        let start = buf.len();
+        bincode::config()
+            .big_endian()
+            .serialize_into(&mut *buf, &self.header.to_raw())
+            .map_err(|_| PackageError::InvalidHeader)?;
+        buf.extend_from_slice(&self.token);
+        self.encode_body(buf);
 
-                if self.header.code != header::MessageClass::Empty && self.payload.len() != 0 {
-                    buf.push(0xFF);
-                    buf.reserve(self.payload.len());
-                    unsafe {
-                        use std::ptr;
-                        let buf_len = buf.len();
-                        ptr::copy(self.payload.as_ptr(),
-                                  buf.as_mut_ptr().offset(buf.len() as isize),
-                                  self.payload.len());
-                        buf.set_len(buf_len + self.payload.len());
-                    }
-                }
-                Ok(buf)
+        if buf.len() - start > 1280 {
+            buf.truncate(start);
+            return Err(PackageError::InvalidPacketLength);
+        }
+        Ok(())
+    }
+
+    /// Appends the reliable transport encoding of the packet to `buf` (RFC 8323 §3.2).
+    fn encode_framed_into(&self, buf: &mut Vec<u8>) -> Result<(), PackageError> {
+        self.validate()?;
+
+        // the body is encoded first as the frame header starts with its length
+        let start = buf.len();
+        self.encode_body(buf);
+        let length = buf.len() - start;
+
+        let mut frame_header = Vec::with_capacity(7 + self.token.len());
+        let (nibble, extended) = extended_length(length, &[13, 269, 65805]);
+        frame_header.push(nibble << 4 | self.token.len() as u8);
+        frame_header.extend_from_slice(&extended);
+        frame_header.push(header::class_to_code(&self.header.code));
+        frame_header.extend_from_slice(&self.token);
+        buf.splice(start..start, frame_header);
+        Ok(())
+    }
+
+    /// Appends the options and the payload.
+    fn encode_body(&self, buf: &mut Vec<u8>) {
+        let mut last_number = 0;
+        for (number, value_list) in self.options.iter() {
+            for value in value_list.iter() {
+                let delta = number - last_number;
+                last_number = *number;
+
+                let (delta_nibble, delta_extended) = extended_length(delta, &[13, 269]);
+                let (length_nibble, length_extended) = extended_length(value.len(), &[13, 269]);
+                buf.push(delta_nibble << 4 | length_nibble);
+                buf.extend_from_slice(&delta_extended);
+                buf.extend_from_slice(&length_extended);
+                buf.extend_from_slice(value);
             }
-            Err(_) => Err(PackageError::InvalidHeader),
+        }
+
+        if self.has_payload() {
+            buf.push(0xFF);
+            buf.extend_from_slice(&self.payload);
         }
     }
 
@@ -427,6 +652,7 @@ impl Packet {
             CoAPOption::Observe => 6,
             CoAPOption::UriPort => 7,
             CoAPOption::LocationPath => 8,
+            CoAPOption::Oscore => 9,
             CoAPOption::UriPath => 11,
             CoAPOption::ContentFormat => 12,
             CoAPOption::MaxAge => 14,
@@ -439,11 +665,449 @@ impl Packet {
             CoAPOption::ProxyScheme => 39,
             CoAPOption::Size1 => 60,
             CoAPOption::Size2 => 28,
+            CoAPOption::Echo => 252,
             CoAPOption::NoResponse => 258
         }
     }
 }
 
+impl Packet {
+    /// Whether the message ID matches the packet to the message it answers.
+    fn is_matched_by_id(&self) -> bool {
+        matches!(self.header.get_type(), header::MessageType::Acknowledgement | header::MessageType::Reset)
+    }
+
+    /// Whether `normalize` re-encodes the options; signaling options are left as they are.
+    fn is_normalizable(&self) -> bool {
+        !matches!(self.header.code, header::MessageClass::Signaling(_))
+    }
+
+    /// The values of an option that `normalize` keeps, None if it would remove the option.
+    fn significant_option(&self, number: usize, normalized: bool) -> Option<&LinkedList<Vec<u8>>> {
+        let values = self.options.get(&number).filter(|values| !values.is_empty())?;
+        if !normalized {
+            return Some(values);
+        }
+        let front = values.front()?;
+        let default = match number {
+            14 => decode_uint(front) == Some(60),            // Max-Age
+            258 => decode_uint(front) == Some(0),            // No-Response
+            11 => values.len() == 1 && front.is_empty(),     // Uri-Path
+            _ => false,
+        };
+        if default {
+            None
+        } else {
+            Some(values)
+        }
+    }
+
+    /// Compares the options of two packets as `normalize` would leave them, without copying.
+    fn options_eq(&self, other: &Packet) -> bool {
+        let normalized = self.is_normalizable();
+        let mut left = self.options.keys().filter_map(|&number| Some((number, self.significant_option(number, normalized)?)));
+        let mut right = other.options.keys().filter_map(|&number| Some((number, other.significant_option(number, normalized)?)));
+        loop {
+            match (left.next(), right.next()) {
+                (None, None) => return true,
+                (Some((number, values)), Some((other_number, other_values))) => {
+                    if number != other_number || !option_values_eq(number, values, other_values, normalized) {
+                        return false;
+                    }
+                }
+                _ => return false,
+            }
+        }
+    }
+}
+
+impl PartialEq for Packet {
+    fn eq(&self, other: &Packet) -> bool {
+        self.header.get_type() == other.header.get_type()
+            && (!self.is_matched_by_id() || self.header.get_message_id() == other.header.get_message_id())
+            && self.header.code == other.header.code
+            && self.token == other.token
+            && self.payload == other.payload
+            && self.options_eq(other)
+    }
+}
+
+/// Compares the values of an option, in order for paths and queries and as a multiset
+/// otherwise. Normalized unsigned integers are compared by value.
+fn option_values_eq(number: usize, values: &LinkedList<Vec<u8>>, other: &LinkedList<Vec<u8>>, normalized: bool) -> bool {
+    let uint = normalized && is_uint_option(number);
+    let value_eq = |a: &Vec<u8>, b: &Vec<u8>| match (uint, decode_uint(a), decode_uint(b)) {
+        (true, Some(a), Some(b)) => a == b,
+        _ => a == b,
+    };
+    if values.len() != other.len() {
+        return false;
+    }
+    if is_ordered_option(number) {
+        return values.iter().zip(other.iter()).all(|(a, b)| value_eq(a, b));
+    }
+    values.iter().all(|value| {
+        let count = |list: &LinkedList<Vec<u8>>| list.iter().filter(|other| value_eq(value, other)).count();
+        count(values) == count(other)
+    })
+}
+
+/// Returns the allowed value lengths of an option number (RFC 7252 §5.10, RFC 7641,
+/// RFC 7959, RFC 7967, RFC 9175), or `None` for options without a known limit.
+pub fn option_length_range(number: usize) -> Option<(usize, usize)> {
+    match number {
+        1 => Some((0, 8)),           // If-Match
+        3 => Some((1, 255)),         // Uri-Host
+        4 => Some((1, 8)),           // ETag
+        5 => Some((0, 0)),           // If-None-Match
+        6 | 23 | 27 => Some((0, 3)), // Observe, Block2, Block1
+        7 | 12 | 17 => Some((0, 2)), // Uri-Port, Content-Format, Accept
+        8 | 11 | 15 | 20 => Some((0, 255)), // Location-Path, Uri-Path, Uri-Query, Location-Query
+        9 => Some((0, 255)),         // OSCORE
+        14 | 28 | 60 => Some((0, 4)), // Max-Age, Size2, Size1
+        35 => Some((1, 1034)),       // Proxy-Uri
+        39 => Some((1, 255)),        // Proxy-Scheme
+        252 => Some((1, 40)),        // Echo
+        258 => Some((0, 1)),         // No-Response
+        _ => None,
+    }
+}
+
+fn check_option_length(number: usize, length: usize) -> Result<(), PackageError> {
+    match option_length_range(number) {
+        Some((min, max)) if length < min || length > max => {
+            Err(PackageError::InvalidOptionLength { number, length, min, max })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// The value of a Block1 or Block2 option (RFC 7959 §2.2): the number of a block, whether
+/// more blocks follow and the block size.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlockValue {
+    pub num: u32,
+    pub more: bool,
+    /// The size exponent, the block size being `2^(size_exponent + 4)` bytes.
+    pub size_exponent: u8,
+}
+
+impl BlockValue {
+    /// Creates a block value with the largest block size not above `size`, from 16 to 1024.
+    pub fn new(num: u32, more: bool, size: usize) -> BlockValue {
+        let size_exponent = (0..=6u8).rev().find(|exponent| 16 << exponent <= size).unwrap_or(0);
+        BlockValue { num, more, size_exponent }
+    }
+
+    /// The block size in bytes.
+    pub fn size(&self) -> usize {
+        16 << self.size_exponent
+    }
+
+    /// The offset of the block in the whole body.
+    pub fn offset(&self) -> usize {
+        self.num as usize * self.size()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        encode_uint(self.num << 4 | (self.more as u32) << 3 | self.size_exponent as u32)
+    }
+
+    /// Decodes an option value, rejecting the reserved size exponent 7.
+    pub fn from_bytes(value: &[u8]) -> Option<BlockValue> {
+        let value = decode_uint(value).filter(|_| value.len() <= 3)?;
+        let size_exponent = (value & 0x7) as u8;
+        if size_exponent == 7 {
+            return None;
+        }
+        Some(BlockValue {
+            num: value >> 4,
+            more: value & 0x8 != 0,
+            size_exponent,
+        })
+    }
+}
+
+/// Encodes an unsigned integer option value with the minimal number of bytes.
+pub(crate) fn encode_uint(value: u32) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let start = bytes.iter().position(|&x| x > 0).unwrap_or(bytes.len());
+    bytes[start..].to_vec()
+}
+
+/// Whether the order of the values of an option number carries meaning, as for the segments
+/// of a path.
+fn is_ordered_option(number: usize) -> bool {
+    match number {
+        8 | 11 | 15 | 20 => true, // Location-Path, Uri-Path, Uri-Query, Location-Query
+        _ => false,
+    }
+}
+
+/// Whether the values of an option number are unsigned integers (RFC 7252 §3.2).
+fn is_uint_option(number: usize) -> bool {
+    matches!(number, 6 | 7 | 12 | 14 | 17 | 23 | 27 | 28 | 60 | 258)
+}
+
+/// Decodes an unsigned integer option value, rejecting values wider than 4 bytes.
+pub(crate) fn decode_uint(value: &[u8]) -> Option<u32> {
+    if value.len() > 4 {
+        return None;
+    }
+    Some(value.iter().fold(0, |acc, &b| acc << 8 | b as u32))
+}
+
+/// Iterator over the messages of a reliable transport byte stream, see `Packet::decode_all`.
+pub struct Frames<'a> {
+    buf: &'a [u8],
+    offset: usize,
+    failed: bool,
+    max_message_size: usize,
+}
+
+impl<'a> Frames<'a> {
+    /// Sets the Max-Message-Size messages may not exceed, e.g. the one announced in the CSM.
+    pub fn with_max_message_size(mut self, max_message_size: usize) -> Frames<'a> {
+        self.max_message_size = max_message_size;
+        self
+    }
+
+    /// The number of bytes taken by the messages decoded so far.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+}
+
+impl<'a> Iterator for Frames<'a> {
+    type Item = Result<Packet, MessageError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || self.offset >= self.buf.len() {
+            return None;
+        }
+
+        let start = self.offset;
+        let first = self.buf[start];
+        let token_length = first & 0xF;
+        let mut idx = start + 1;
+        let length = match first >> 4 {
+            13 => read_be(self.buf, &mut idx, 1)? + 13,
+            14 => read_be(self.buf, &mut idx, 2)? + 269,
+            15 => read_be(self.buf, &mut idx, 4)? + 65805,
+            nibble => nibble as usize,
+        };
+        let code_idx = idx;
+        let options_start = code_idx + 1 + token_length as usize;
+        let end = options_start.checked_add(length)?;
+        if token_length > 8 {
+            self.failed = true;
+            return Some(Err(MessageError::InvalidTokenLength { offset: start, length: token_length }));
+        }
+        if length > self.max_message_size {
+            self.failed = true;
+            let max = self.max_message_size;
+            return Some(Err(MessageError::MessageTooLarge { offset: start, length, max }));
+        }
+        if end > self.buf.len() {
+            return None;
+        }
+
+        let frame = &self.buf[..end];
+        let mut packet = Packet::new();
+        packet.header.code = header::code_to_class(&frame[code_idx]);
+        packet.set_token(frame[code_idx + 1..options_start].to_vec());
+        match Packet::decode_body(frame, options_start) {
+            Ok((options, payload)) => {
+                packet.options = options;
+                packet.payload = payload;
+                self.offset = end;
+                Some(Ok(packet))
+            }
+            Err(e) => {
+                self.failed = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Encodes packets into a buffer reused across packets, sparing an allocation per packet
+/// on hot paths such as a reliable transport or a capture replayer.
+#[derive(Debug, Default)]
+pub struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    pub fn new() -> Encoder {
+        Self::default()
+    }
+
+    /// Encodes a packet for a datagram transport, as `Packet::to_bytes` does.
+    pub fn encode(&mut self, packet: &Packet) -> Result<&[u8], PackageError> {
+        self.buf.clear();
+        packet.encode_into(&mut self.buf)?;
+        Ok(&self.buf)
+    }
+
+    /// Encodes a packet for a reliable transport (RFC 8323 §3.2), omitting its type and
+    /// message ID.
+    pub fn encode_framed(&mut self, packet: &Packet) -> Result<&[u8], PackageError> {
+        self.buf.clear();
+        packet.encode_framed_into(&mut self.buf)?;
+        Ok(&self.buf)
+    }
+}
+
+/// Splits a length into its nibble and extended bytes given the thresholds of the 13, 14
+/// and 15 nibbles.
+fn extended_length(value: usize, thresholds: &[usize]) -> (u8, Vec<u8>) {
+    match thresholds.iter().rposition(|&threshold| value >= threshold) {
+        None => (value as u8, Vec::new()),
+        Some(idx) => {
+            let extended = (value - thresholds[idx]) as u32;
+            let width = [1, 2, 4][idx];
+            (13 + idx as u8, extended.to_be_bytes()[4 - width..].to_vec())
+        }
+    }
+}
+
+/// Reads a big-endian unsigned integer of `width` bytes, None if it runs past the buffer.
+fn read_be(buf: &[u8], idx: &mut usize, width: usize) -> Option<usize> {
+    let bytes = buf.get(*idx..*idx + width)?;
+    *idx += width;
+    Some(bytes.iter().fold(0, |acc, &b| acc << 8 | b as usize))
+}
+
+/// Reads the value of an option delta or length nibble below 15, consuming its extended
+/// bytes (RFC 7252 §3.1). Returns None if they run past the buffer.
+fn read_extended(buf: &[u8], idx: &mut usize, nibble: u8) -> Option<usize> {
+    match nibble {
+        13 => {
+            let value = *buf.get(*idx)? as usize + 13;
+            *idx += 1;
+            Some(value)
+        }
+        14 => {
+            let extended = buf.get(*idx..*idx + 2)?;
+            *idx += 2;
+            Some(((extended[0] as usize) << 8 | extended[1] as usize) + 269)
+        }
+        nibble => Some(nibble as usize),
+    }
+}
+
+/// Options are serialized by their number.
+impl Serialize for CoAPOption {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u16(Packet::get_option_number(*self) as u16)
+    }
+}
+
+impl<'de> Deserialize<'de> for CoAPOption {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<CoAPOption, D::Error> {
+        let number = u16::deserialize(deserializer)? as usize;
+        OPTIONS
+            .iter()
+            .find(|&&option| Packet::get_option_number(option) == number)
+            .cloned()
+            .ok_or_else(|| de::Error::custom(format!("unknown option {}", number)))
+    }
+}
+
+/// Bytes serialized as a lowercase hex string.
+struct Hex(Vec<u8>);
+
+impl Serialize for Hex {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let hex: String = self.0.iter().map(|b| format!("{:02x}", b)).collect();
+        serializer.serialize_str(&hex)
+    }
+}
+
+impl<'de> Deserialize<'de> for Hex {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Hex, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        if hex.len() % 2 != 0 || !hex.is_ascii() {
+            return Err(de::Error::custom("invalid hex string"));
+        }
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map(Hex)
+            .map_err(|_| de::Error::custom("invalid hex string"))
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct OptionRepr {
+    number: u16,
+    value: Hex,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PacketRepr {
+    version: u8,
+    #[serde(rename = "type")]
+    message_type: header::MessageType,
+    code: header::MessageClass,
+    message_id: u16,
+    token: Hex,
+    options: Vec<OptionRepr>,
+    payload: Hex,
+}
+
+/// Packets are serialized field by field, with the token, the option values and the payload
+/// as hex strings and every option value, including unknown ones, as a separate entry in
+/// wire order, e.g. as JSON:
+///
+/// ```text
+/// {"version":1,"type":"CON","code":"0.01","message_id":7,"token":"71",
+///  "options":[{"number":11,"value":"74656d70"}],"payload":""}
+/// ```
+impl Serialize for Packet {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let options = self.options
+            .iter()
+            .flat_map(|(&number, values)| values.iter().map(move |value| OptionRepr {
+                number: number as u16,
+                value: Hex(value.clone()),
+            }))
+            .collect();
+        PacketRepr {
+            version: self.header.get_version(),
+            message_type: self.header.get_type(),
+            code: self.header.code.clone(),
+            message_id: self.header.get_message_id(),
+            token: Hex(self.token.clone()),
+            options,
+            payload: Hex(self.payload.clone()),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Packet {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Packet, D::Error> {
+        let repr = PacketRepr::deserialize(deserializer)?;
+        if repr.version > 3 {
+            return Err(de::Error::custom(format!("invalid version {}", repr.version)));
+        }
+        let mut packet = Packet::new();
+        packet.header.set_version(repr.version);
+        packet.header.set_type(repr.message_type);
+        packet.header.code = repr.code;
+        packet.header.set_message_id(repr.message_id);
+        packet.try_set_token(repr.token.0).map_err(|e| de::Error::custom(format!("{:?}", e)))?;
+        for option in repr.options {
+            packet.add_option_value(option.number as usize, option.value.0);
+        }
+        packet.payload = repr.payload.0;
+        Ok(packet)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -545,6 +1209,141 @@ mod test {
         assert!(packet.get_content_format().is_none());
     }
 
+    #[test]
+    fn test_encode_decode_max_age() {
+        let mut packet = Packet::new();
+        assert!(packet.get_max_age().is_none());
+        packet.set_max_age(0);
+        assert_eq!(*packet.get_option(CoAPOption::MaxAge).unwrap().front().unwrap(), Vec::<u8>::new());
+        assert_eq!(Some(0), packet.get_max_age());
+        packet.set_max_age(3600);
+        assert_eq!(*packet.get_option(CoAPOption::MaxAge).unwrap().front().unwrap(), vec![0x0E, 0x10]);
+        assert_eq!(Some(3600), packet.get_max_age());
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert_eq!(Packet::from_bytes(&[0x40, 0x01]).unwrap_err(),
+                   MessageError::TruncatedHeader { length: 2 });
+        assert_eq!(Packet::from_bytes(&[0x49, 0x01, 0x00, 0x00]).unwrap_err(),
+                   MessageError::InvalidTokenLength { offset: 0, length: 9 });
+        assert_eq!(Packet::from_bytes(&[0x42, 0x01, 0x00, 0x00, 0x01]).unwrap_err(),
+                   MessageError::InvalidTokenLength { offset: 4, length: 2 });
+        assert_eq!(Packet::from_bytes(&[0x40, 0x01, 0x00, 0x00, 0xF1, 0x00]).unwrap_err(),
+                   MessageError::OptionDeltaReserved { offset: 4 });
+        assert_eq!(Packet::from_bytes(&[0x40, 0x01, 0x00, 0x00, 0xB1, 0x61, 0x1F]).unwrap_err(),
+                   MessageError::OptionLengthReserved { offset: 6 });
+        assert_eq!(Packet::from_bytes(&[0x40, 0x01, 0x00, 0x00, 0xD0]).unwrap_err(),
+                   MessageError::TruncatedOption { offset: 4 });
+        assert_eq!(Packet::from_bytes(&[0x40, 0x01, 0x00, 0x00, 0xB3, 0x61]).unwrap_err(),
+                   MessageError::OptionTooLong { offset: 4, length: 3 });
+        assert_eq!(Packet::from_bytes(&[0x40, 0x01, 0x00, 0x00, 0xFF]).unwrap_err(),
+                   MessageError::UnexpectedPayloadMarker { offset: 4 });
+    }
+
+    #[test]
+    fn test_decode_crafted_options() {
+        // extended deltas of 0xFFFF + 269 used to overflow u16 arithmetic
+        assert_eq!(Packet::from_bytes(&[0x40, 0x01, 0x00, 0x00, 0xE0, 0xFF, 0xFF]).unwrap_err(),
+                   MessageError::OptionNumberOverflow { offset: 4, number: 65804 });
+        assert_eq!(Packet::from_bytes(&[0x40, 0x01, 0x00, 0x00, 0xE0, 0xFE, 0xF2, 0xE0, 0x00, 0x00]).unwrap_err(),
+                   MessageError::OptionNumberOverflow { offset: 7, number: 65804 });
+        assert_eq!(Packet::from_bytes(&[0x40, 0x01, 0x00, 0x00, 0x1E, 0xFF, 0xFF]).unwrap_err(),
+                   MessageError::OptionTooLong { offset: 4, length: 65804 });
+        assert_eq!(Packet::from_bytes(&[0x40, 0x01, 0x00, 0x00, 0x1E, 0xFF]).unwrap_err(),
+                   MessageError::TruncatedOption { offset: 4 });
+        assert_eq!(Packet::from_bytes(&[0x40, 0x01, 0x00, 0x00, 0xFE]).unwrap_err(),
+                   MessageError::OptionDeltaReserved { offset: 4 });
+
+        let packet = Packet::from_bytes(&[0x40, 0x01, 0x00, 0x00, 0xE0, 0xFE, 0xF2, 0xFF, 0x01]).unwrap();
+        let (number, _) = packet.options().next().unwrap();
+        assert_eq!(*number, MAX_OPTION_NUMBER);
+        assert_eq!(packet.payload, vec![0x01]);
+    }
+
+    #[test]
+    fn test_framed_encoding() {
+        let mut packet = Packet::new();
+        packet.header.set_code("2.05");
+        packet.set_token(vec![0x71]);
+        packet.add_option(CoAPOption::ContentFormat, vec![0, 50]);
+        packet.payload = vec![b'x'; 300];
+
+        let mut encoder = Encoder::new();
+        assert_eq!(encoder.encode(&packet).unwrap(), &packet.to_bytes().unwrap()[..]);
+
+        let mut stream = encoder.encode_framed(&packet).unwrap().to_vec();
+        // a body of 3 + 1 + 300 bytes takes two extended length bytes
+        assert_eq!(stream[..5], [0xE1, 0x00, 0x23, 0x45, 0x71]);
+        packet.payload = b"ok".to_vec();
+        stream.extend_from_slice(encoder.encode_framed(&packet).unwrap());
+        assert_eq!(encoder.encode_framed(&packet).unwrap(), [0x61, 0x45, 0x71, 0xC2, 0, 50, 0xFF, b'o', b'k']);
+        let complete = stream.len();
+        stream.extend_from_slice(&[0x51, 0x45]);
+
+        let mut frames = Packet::decode_all(&stream);
+        let first = frames.next().unwrap().unwrap();
+        assert_eq!(first.header.code, header::MessageClass::Response(header::ResponseType::Content));
+        assert_eq!(first.payload.len(), 300);
+        let second = frames.next().unwrap().unwrap();
+        assert_eq!(*second.get_token(), vec![0x71]);
+        assert_eq!(second.get_content_format(), Some(ContentFormat::ApplicationJSON));
+        assert_eq!(second.payload, b"ok".to_vec());
+        assert!(frames.next().is_none());
+        assert_eq!(frames.offset(), complete);
+
+        let mut frames = Packet::decode_all(&[0x29, 0x45]);
+        assert_eq!(frames.next().unwrap().unwrap_err(), MessageError::InvalidTokenLength { offset: 0, length: 9 });
+        assert!(frames.next().is_none());
+
+        // a frame announcing about 4 GiB is rejected before its body arrives
+        let mut frames = Packet::decode_all(&[0xF0, 0xFF, 0xFF, 0xFF, 0xFF, 0x45]);
+        let length = 0xFFFF_FFFF + 65805;
+        let too_large = MessageError::MessageTooLarge { offset: 0, length, max: DEFAULT_MAX_MESSAGE_SIZE };
+        assert_eq!(frames.next().unwrap().unwrap_err(), too_large);
+        assert!(frames.next().is_none());
+        let mut frames = Packet::decode_all(&stream).with_max_message_size(100);
+        assert_eq!(frames.next().unwrap().unwrap_err(), MessageError::MessageTooLarge { offset: 0, length: 304, max: 100 });
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut packet = Packet::new();
+        assert_eq!(packet.try_set_token(vec![0; 9]), Err(PackageError::TokenTooLong { length: 9 }));
+        assert!(packet.try_set_token(vec![0; 8]).is_ok());
+
+        assert_eq!(
+            packet.try_add_option(CoAPOption::UriPath, vec![b'a'; 256]),
+            Err(PackageError::InvalidOptionLength { number: 11, length: 256, min: 0, max: 255 })
+        );
+        assert!(packet.try_add_option(CoAPOption::ProxyUri, vec![b'a'; 1034]).is_ok());
+        assert!(packet.try_add_option(CoAPOption::ETag, Vec::new()).is_err());
+        assert!(packet.to_bytes().is_ok());
+
+        packet.add_option(CoAPOption::ContentFormat, vec![0, 0, 50]);
+        assert_eq!(
+            packet.to_bytes().unwrap_err().to_string(),
+            "option 12 has 3 bytes, must have 0 to 2"
+        );
+    }
+
+    #[test]
+    fn test_block_value() {
+        let block = BlockValue::new(5, true, 1000);
+        assert_eq!(block, BlockValue { num: 5, more: true, size_exponent: 5 });
+        assert_eq!(block.size(), 512);
+        assert_eq!(block.offset(), 2560);
+        assert_eq!(block.to_bytes(), vec![0x5D]);
+        assert_eq!(BlockValue::from_bytes(&[0x5D]), Some(block));
+        assert_eq!(BlockValue::from_bytes(&[]), Some(BlockValue::new(0, false, 16)));
+        assert_eq!(BlockValue::from_bytes(&[0x0F]), None);
+
+        let mut packet = Packet::new();
+        packet.set_block2(block);
+        assert_eq!(packet.get_block2(), Some(block));
+        assert_eq!(packet.get_block1(), None);
+    }
+
     #[test]
     fn test_malicious_packet() {
         use quickcheck::{QuickCheck, StdThreadGen, TestResult};
@@ -563,4 +1362,131 @@ mod test {
             .gen(StdThreadGen::new(1500))
             .quickcheck(run as fn(Vec<u8>) -> TestResult)
     }
+
+    #[test]
+    fn test_normalize() {
+        let mut packet = Packet::new();
+        packet.add_option(CoAPOption::UriPath, b"".to_vec());
+        packet.add_option(CoAPOption::ContentFormat, vec![0, 0]);
+        packet.add_option(CoAPOption::MaxAge, vec![0, 0, 0, 60]);
+        packet.add_option(CoAPOption::UriPort, vec![0x16, 0x33]);
+        packet.add_option(CoAPOption::Size1, vec![0, 1, 0]);
+        packet.add_option(CoAPOption::ETag, vec![0, 1]);
+        packet.set_option(CoAPOption::UriQuery, LinkedList::new());
+        packet.normalize();
+
+        let mut expected = Packet::new();
+        expected.add_option(CoAPOption::ContentFormat, vec![]);
+        expected.add_option(CoAPOption::UriPort, vec![0x16, 0x33]);
+        expected.add_option(CoAPOption::Size1, vec![1, 0]);
+        expected.add_option(CoAPOption::ETag, vec![0, 1]);
+        assert_eq!(packet.to_bytes().unwrap(), expected.to_bytes().unwrap());
+
+        // a path of empty segments is not the root
+        let mut packet = Packet::new();
+        packet.add_option(CoAPOption::UriPath, b"".to_vec());
+        packet.add_option(CoAPOption::UriPath, b"".to_vec());
+        packet.normalize();
+        assert_eq!(packet.get_option(CoAPOption::UriPath).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_semantic_equality() {
+        let mut packet = Packet::new();
+        packet.header.set_message_id(1);
+        packet.set_token(vec![1, 2]);
+        packet.add_option(CoAPOption::UriPath, b"a".to_vec());
+        packet.add_option(CoAPOption::UriPath, b"b".to_vec());
+        packet.add_option(CoAPOption::ETag, vec![1]);
+        packet.add_option(CoAPOption::ETag, vec![2]);
+        packet.add_option(CoAPOption::Accept, vec![0, 50]);
+        packet.payload = b"x".to_vec();
+
+        let mut other = Packet::from_bytes(&packet.to_bytes().unwrap()).unwrap();
+        other.header.set_message_id(2);
+        other.clear_option(CoAPOption::ETag);
+        other.add_option(CoAPOption::ETag, vec![2]);
+        other.add_option(CoAPOption::ETag, vec![1]);
+        other.clear_option(CoAPOption::Accept);
+        other.add_option(CoAPOption::Accept, vec![50]);
+        assert!(packet == other);
+
+        // options with their default value equal absent ones
+        other.add_option(CoAPOption::MaxAge, vec![60]);
+        other.add_option(CoAPOption::NoResponse, vec![0]);
+        assert!(packet == other && other == packet);
+        other.clear_option(CoAPOption::MaxAge);
+        other.add_option(CoAPOption::MaxAge, vec![0, 61]);
+        assert!(packet != other);
+        assert_eq!(packet.diff(&other), vec!["option 14 [] != [[00, 3d]]".to_string()]);
+        other.clear_option(CoAPOption::MaxAge);
+
+        other.clear_option(CoAPOption::UriPath);
+        other.add_option(CoAPOption::UriPath, b"b".to_vec());
+        other.add_option(CoAPOption::UriPath, b"a".to_vec());
+        other.payload = b"y".to_vec();
+        assert_eq!(packet.diff(&other), vec![
+            "option 11 [[61], [62]] != [[62], [61]]".to_string(),
+            "payload differs at byte 0".to_string(),
+        ]);
+
+        // an ACK answers the message of its ID
+        packet.header.set_type(header::MessageType::Acknowledgement);
+        let mut ack = packet.clone();
+        ack.header.set_message_id(2);
+        assert_eq!(packet.diff(&ack), vec!["message ID 1 != 2".to_string()]);
+    }
+
+    #[test]
+    fn test_serde() {
+        use serde::de::value::{Error as ValueError, StrDeserializer, U16Deserializer};
+        use serde::de::IntoDeserializer;
+
+        let mut packet = Packet::new();
+        packet.header.set_type(header::MessageType::NonConfirmable);
+        packet.header.set_code("2.05");
+        packet.header.set_message_id(7);
+        packet.set_token(vec![0x71, 0xA0]);
+        packet.add_option(CoAPOption::UriPath, b"temp".to_vec());
+        packet.add_option(CoAPOption::UriPath, b"c".to_vec());
+        packet.add_option_value(65000, vec![1]);
+        packet.payload = b"21".to_vec();
+
+        let bytes = bincode::serialize(&packet).unwrap();
+        let restored: Packet = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(restored.to_bytes().unwrap(), packet.to_bytes().unwrap());
+
+        let code: StrDeserializer<ValueError> = "4.04".into_deserializer();
+        assert_eq!(header::MessageClass::deserialize(code).unwrap(),
+                   header::MessageClass::Response(header::ResponseType::NotFound));
+        let code: StrDeserializer<ValueError> = "9.99".into_deserializer();
+        assert!(header::MessageClass::deserialize(code).is_err());
+        let token: StrDeserializer<ValueError> = "71a0".into_deserializer();
+        assert_eq!(Hex::deserialize(token).unwrap().0, vec![0x71, 0xA0]);
+        let token: StrDeserializer<ValueError> = "7g".into_deserializer();
+        assert!(Hex::deserialize(token).is_err());
+        let option: U16Deserializer<ValueError> = 12u16.into_deserializer();
+        assert_eq!(CoAPOption::deserialize(option).unwrap(), CoAPOption::ContentFormat);
+    }
+
+    #[test]
+    fn test_serde_json() {
+        let mut packet = Packet::new();
+        packet.header.set_type(header::MessageType::Confirmable);
+        packet.header.set_code("2.05");
+        packet.header.set_message_id(7);
+        packet.set_token(vec![0x71, 0xA0]);
+        packet.add_option(CoAPOption::UriPath, b"temp".to_vec());
+        packet.add_option_value(65000, vec![1]);
+        packet.payload = b"21".to_vec();
+
+        let json = serde_json::to_string(&packet).unwrap();
+        assert_eq!(json, concat!(
+            r#"{"version":1,"type":"CON","code":"2.05","message_id":7,"token":"71a0","#,
+            r#""options":[{"number":11,"value":"74656d70"},{"number":65000,"value":"01"}],"payload":"3231"}"#,
+        ));
+        let restored: Packet = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.to_bytes().unwrap(), packet.to_bytes().unwrap());
+        assert!(serde_json::from_str::<Packet>(&json.replace("\"CON\"", "\"XYZ\"")).is_err());
+    }
 }
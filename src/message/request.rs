@@ -1,9 +1,12 @@
 use super::IsMessage;
-use super::response::CoAPResponse;
-use super::packet::{CoAPOption, Packet};
+use super::response::{CoAPResponse, ContentError, Status};
+use super::packet::{decode_uint, CoAPOption, PackageError, Packet};
 use super::header::{Header, MessageClass};
 use std::net::SocketAddr;
 use std::str;
+use crate::cbor::Value;
+use crate::codec::CodecRegistry;
+use crate::context::{RequestContext, Transport};
 
 pub use super::header::RequestType as Method;
 
@@ -12,6 +15,7 @@ pub struct CoAPRequest {
     pub message: Packet,
     pub response: Option<CoAPResponse>,
     pub source: Option<SocketAddr>,
+    pub context: RequestContext,
 }
 
 impl CoAPRequest {
@@ -20,6 +24,7 @@ impl CoAPRequest {
             response: None,
             message: Packet::new(),
             source: None,
+            context: RequestContext::default(),
         }
     }
 
@@ -28,6 +33,7 @@ impl CoAPRequest {
             response: CoAPResponse::new(&packet),
             message: packet,
             source: Some(source.clone()),
+            context: RequestContext::new(Some(*source), Transport::Udp),
         }
     }
 
@@ -41,6 +47,7 @@ impl CoAPRequest {
             MessageClass::Request(Method::Post) => &Method::Post,
             MessageClass::Request(Method::Put) => &Method::Put,
             MessageClass::Request(Method::Delete) => &Method::Delete,
+            MessageClass::Request(Method::Fetch) => &Method::Fetch,
             _ => &Method::UnKnown,
         }
     }
@@ -58,6 +65,30 @@ impl CoAPRequest {
         }
     }
 
+    /// Same as `set_path`, rejecting segments longer than the 255 bytes a Uri-Path allows.
+    /// The path is left unchanged on error.
+    pub fn try_set_path(&mut self, path: &str) -> Result<(), PackageError> {
+        let mut message = self.message.clone();
+        message.clear_option(CoAPOption::UriPath);
+        for (i, s) in path.split("/").enumerate() {
+            if i == 0 && s.is_empty() {
+                continue;
+            }
+
+            message.try_add_option(CoAPOption::UriPath, s.as_bytes().to_vec())?;
+        }
+        self.message = message;
+        Ok(())
+    }
+
+    /// Sets the Uri-Query options from a query string such as `gt=30&st=0.5`.
+    pub fn set_query(&mut self, query: &str) {
+        self.clear_option(CoAPOption::UriQuery);
+        for arg in query.split('&').filter(|arg| !arg.is_empty()) {
+            self.add_option(CoAPOption::UriQuery, arg.as_bytes().to_vec());
+        }
+    }
+
     pub fn get_path(&self) -> String {
         match self.get_option(CoAPOption::UriPath) {
             Some(options) => {
@@ -72,6 +103,77 @@ impl CoAPRequest {
             _ => "".to_string(),
         }
     }
+
+    /// Checks the If-Match preconditions of the request against the current
+    /// ETag of the target resource. Requests without If-Match always match,
+    /// as does an empty If-Match value.
+    pub fn if_match(&self, etag: &[u8]) -> bool {
+        match self.get_option(CoAPOption::IfMatch) {
+            Some(list) if !list.is_empty() => {
+                list.iter().any(|value| value.is_empty() || value[..] == *etag)
+            }
+            _ => true,
+        }
+    }
+
+    /// Returns a 4.09 Conflict response if the request was made against a
+    /// representation other than the one identified by `etag`.
+    pub fn detect_conflict(&self, etag: &[u8]) -> Option<CoAPResponse> {
+        if self.if_match(etag) {
+            return None;
+        }
+
+        let mut response = self.response.clone()?;
+        response.set_status(Status::Conflict);
+        response.set_payload(Vec::new());
+        Some(response)
+    }
+
+    /// Same as `detect_conflict` for resources tracking a version counter,
+    /// whose ETag is the big-endian encoding of the version.
+    pub fn detect_version_conflict(&self, version: u32) -> Option<CoAPResponse> {
+        self.detect_conflict(&version.to_be_bytes())
+    }
+
+    /// Decodes the payload with the codec of its Content-Format, like `decode_payload`, or
+    /// returns the reply to send instead: 4.15 Unsupported Content-Format listing the formats
+    /// of the registry if the request declares none of them, 4.00 Bad Request if the payload
+    /// does not decode. The reply is `None` for messages that are not answered, so a handler
+    /// can return it as is.
+    pub fn decode_body(&self, codecs: &CodecRegistry) -> Result<Value, Option<CoAPResponse>> {
+        self.decode_body_in(codecs, &codecs.formats())
+    }
+
+    /// Same as `decode_body`, accepting only the given Content-Formats, e.g. those a
+    /// resource understands.
+    pub fn decode_body_in(&self, codecs: &CodecRegistry, formats: &[u32]) -> Result<Value, Option<CoAPResponse>> {
+        let format = self
+            .get_option(CoAPOption::ContentFormat)
+            .and_then(|list| list.front())
+            .and_then(|value| decode_uint(value));
+        let error = match format {
+            Some(format) if formats.contains(&format) => match codecs.decode(format, &self.message.payload) {
+                Ok(value) => return Ok(value),
+                Err(e) => e,
+            },
+            Some(format) => ContentError::UnsupportedContentFormat(format),
+            None => ContentError::MissingContentFormat,
+        };
+
+        let mut response = match self.response.clone() {
+            Some(response) => response,
+            None => return Err(None),
+        };
+        match error {
+            ContentError::UnsupportedContentFormat(_) | ContentError::MissingContentFormat => {
+                let supported: Vec<String> = formats.iter().map(|format| format.to_string()).collect();
+                let diagnostic = format!("{}, supported: {}", error, supported.join(", "));
+                response.set_error(Status::UnsupportedContentFormat, &diagnostic);
+            }
+            _ => response.set_error(Status::BadRequest, &error.to_string()),
+        }
+        Err(Some(response))
+    }
 }
 
 impl IsMessage for CoAPRequest {
@@ -160,6 +262,60 @@ mod test {
         assert_eq!("0.04", request.get_code());
     }
 
+    #[test]
+    fn test_detect_conflict() {
+        let mut packet = Packet::new();
+        packet.header.set_type(MessageType::Confirmable);
+        let request = CoAPRequest::from_packet(packet.clone(), &SocketAddr::from_str("127.0.0.1:1234").unwrap());
+        assert!(request.detect_conflict(b"v1").is_none());
+
+        packet.add_option(CoAPOption::IfMatch, b"v1".to_vec());
+        let request = CoAPRequest::from_packet(packet.clone(), &SocketAddr::from_str("127.0.0.1:1234").unwrap());
+        assert!(request.detect_conflict(b"v1").is_none());
+        let response = request.detect_conflict(b"v2").unwrap();
+        assert_eq!(*response.get_status(), Status::Conflict);
+
+        let mut packet = Packet::new();
+        packet.header.set_type(MessageType::Confirmable);
+        packet.add_option(CoAPOption::IfMatch, 7u32.to_be_bytes().to_vec());
+        let request = CoAPRequest::from_packet(packet, &SocketAddr::from_str("127.0.0.1:1234").unwrap());
+        assert!(request.detect_version_conflict(7).is_none());
+        assert!(request.detect_version_conflict(8).is_some());
+    }
+
+    #[test]
+    fn test_decode_body() {
+        let codecs = CodecRegistry::default();
+        let source = SocketAddr::from_str("127.0.0.1:1234").unwrap();
+        let mut packet = Packet::new();
+        packet.header.set_type(MessageType::Confirmable);
+        packet.payload = b"{\"on\": true}".to_vec();
+
+        let request = CoAPRequest::from_packet(packet.clone(), &source);
+        let reply = request.decode_body(&codecs).unwrap_err().unwrap();
+        assert_eq!(*reply.get_status(), Status::UnsupportedContentFormat);
+        assert!(reply.diagnostic().unwrap().starts_with("missing content format, supported: 0, 40"));
+
+        packet.add_option(CoAPOption::ContentFormat, vec![50]);
+        let request = CoAPRequest::from_packet(packet.clone(), &source);
+        assert_eq!(
+            request.decode_body(&codecs).unwrap(),
+            Value::Map(vec![(Value::Text("on".to_string()), Value::Bool(true))])
+        );
+        let reply = request.decode_body_in(&codecs, &[60]).unwrap_err().unwrap();
+        assert_eq!(reply.diagnostic().unwrap(), "unsupported content format 50, supported: 60");
+
+        packet.payload = b"{".to_vec();
+        let request = CoAPRequest::from_packet(packet.clone(), &source);
+        let reply = request.decode_body(&codecs).unwrap_err().unwrap();
+        assert_eq!(*reply.get_status(), Status::BadRequest);
+
+        // an ACK is not answered
+        packet.header.set_type(MessageType::Acknowledgement);
+        let request = CoAPRequest::from_packet(packet, &source);
+        assert!(request.decode_body(&codecs).unwrap_err().is_none());
+    }
+
     #[test]
     fn test_path() {
         let mut request = CoAPRequest::new();
@@ -189,6 +345,12 @@ mod test {
                 .unwrap()
         );
 
+        let long_segment = "a".repeat(256);
+        assert!(request.try_set_path(&format!("/ok/{}", long_segment)).is_err());
+        assert_eq!(path2, request.get_path());
+        assert!(request.try_set_path("/a/b").is_ok());
+        assert_eq!("a/b", request.get_path());
+
         let path3 = "test-interface2/";
         request.set_path(path3);
         assert_eq!(path3, request.get_path());
@@ -1,12 +1,121 @@
 use super::IsMessage;
-use super::packet::Packet;
-use super::header::{Header, MessageClass, MessageType};
+use super::packet::{decode_uint, BlockValue, CoAPOption, ContentFormat, Packet};
+use super::header::{class_to_code, code_to_str, Header, MessageClass, MessageType};
+use crate::cbor::{self, CborError, Value};
+use crate::json::{self, JsonError};
+use futures::Stream;
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::str::{self, Utf8Error};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 pub use super::header::ResponseType as Status;
 
+/// The Max-Age assumed when a response carries no Max-Age option (RFC 7252 §5.10.5).
+pub const DEFAULT_MAX_AGE: u32 = 60;
+
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// Describes why a response payload could not be decoded.
+#[derive(Debug, PartialEq)]
+pub enum ContentError {
+    /// The response declares a Content-Format other than the requested one.
+    UnexpectedContentFormat(u32),
+    InvalidText(Utf8Error),
+    InvalidJson(JsonError),
+    InvalidCbor(CborError),
+    /// The payload holds more than one CBOR item.
+    TrailingBytes,
+    /// No codec is registered for the Content-Format.
+    UnsupportedContentFormat(u32),
+    /// The message declares no Content-Format to pick a codec with.
+    MissingContentFormat,
+    /// A codec rejected the payload or the value to encode.
+    Invalid(String),
+}
+
+impl fmt::Display for ContentError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ContentError::UnexpectedContentFormat(format) => write!(f, "unexpected content format {}", format),
+            ContentError::InvalidText(ref e) => write!(f, "invalid text payload: {}", e),
+            ContentError::InvalidJson(ref e) => write!(f, "invalid JSON payload: {}", e),
+            ContentError::InvalidCbor(ref e) => write!(f, "invalid CBOR payload: {}", e),
+            ContentError::TrailingBytes => write!(f, "trailing bytes after CBOR payload"),
+            ContentError::UnsupportedContentFormat(format) => write!(f, "unsupported content format {}", format),
+            ContentError::MissingContentFormat => write!(f, "missing content format"),
+            ContentError::Invalid(ref reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ContentError {}
+
+/// A 4.xx or 5.xx response, with the diagnostic payload the server explained it with.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResponseError {
+    pub status: Status,
+    /// The code of the response, e.g. `4.04`.
+    pub code: String,
+    pub diagnostic: Option<String>,
+}
+
+impl fmt::Display for ResponseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.diagnostic {
+            Some(ref diagnostic) => write!(f, "{} {:?}: {}", self.code, self.status, diagnostic),
+            None => write!(f, "{} {:?}", self.code, self.status),
+        }
+    }
+}
+
+impl std::error::Error for ResponseError {}
+
+impl From<ResponseError> for io::Error {
+    fn from(e: ResponseError) -> io::Error {
+        io::Error::other(e)
+    }
+}
+
+/// What a response means to the caller, so that high-level calls need not match raw codes
+/// and options.
+#[derive(Debug)]
+pub enum Outcome {
+    /// 2.05 Content, with the representation.
+    Content(CoAPResponse),
+    /// 2.03 Valid: the representation with the ETag, if any, is still current.
+    Valid(Option<Vec<u8>>),
+    /// 2.31 Continue: the server awaits the next block after the one in the Block1 option.
+    Continue(Option<BlockValue>),
+    /// 2.01 Created, with the location of the new resource if the server named one.
+    Created(Option<String>),
+    /// 2.04 Changed, 2.02 Deleted or another success, with the response.
+    Success(CoAPResponse),
+    /// A 4.xx or 5.xx response.
+    Error(ResponseError),
+}
+
+/// The chunks of a body served block by block, see `CoAPResponse::set_body_stream`.
+pub type BodyStream = Pin<Box<dyn Stream<Item = io::Result<Vec<u8>>> + Send>>;
+
 #[derive(Clone, Debug)]
 pub struct CoAPResponse {
     pub message: Packet,
+    /// When the response was received, `None` for locally built responses.
+    pub received_at: Option<Instant>,
+    body: Option<SharedBody>,
+}
+
+// shared by the clones of a response, the first to take the stream serving it
+#[derive(Clone)]
+struct SharedBody(Arc<Mutex<Option<BodyStream>>>);
+
+impl fmt::Debug for SharedBody {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SharedBody")
+    }
 }
 
 impl CoAPResponse {
@@ -28,13 +137,133 @@ impl CoAPResponse {
 
         packet.payload = request.payload.clone();
 
-        Some(CoAPResponse { message: packet })
+        Some(CoAPResponse { message: packet, received_at: None, body: None })
+    }
+
+    /// Wraps a packet that has just been received from the network.
+    pub fn received(packet: Packet) -> CoAPResponse {
+        CoAPResponse {
+            message: packet,
+            received_at: Some(Instant::now()),
+            body: None,
+        }
+    }
+
+    /// Returns the freshness lifetime of the response, falling back to the
+    /// protocol default when no Max-Age option is present.
+    pub fn get_max_age(&self) -> Duration {
+        let seconds = self.message.get_max_age().unwrap_or(DEFAULT_MAX_AGE);
+        Duration::from_secs(seconds as u64)
+    }
+
+    /// Returns the instant after which the response is stale.
+    pub fn expires_at(&self) -> Option<Instant> {
+        self.received_at.map(|at| at + self.get_max_age())
+    }
+
+    /// Checks whether the response is still fresh.
+    pub fn is_fresh(&self) -> bool {
+        match self.expires_at() {
+            Some(expires_at) => Instant::now() < expires_at,
+            None => false,
+        }
+    }
+
+    /// Makes the body of the response the chunks of the stream, e.g. the lines of a large log
+    /// file, in place of the payload. A server sends the body in Block2 blocks (RFC 7959),
+    /// pulling chunks from the stream only as the client asks for the next block, so that the
+    /// body is never held in memory as a whole and the transfer follows the client's pace.
+    /// The response's code and options are repeated in every block; response filters are
+    /// not applied. A failing stream ends the transfer with 5.00.
+    pub fn set_body_stream<S>(&mut self, stream: S)
+    where
+        S: Stream<Item = io::Result<Vec<u8>>> + Send + 'static,
+    {
+        self.message.payload.clear();
+        self.body = Some(SharedBody(Arc::new(Mutex::new(Some(Box::pin(stream))))));
+    }
+
+    /// Takes the stream set by `set_body_stream`.
+    pub(crate) fn take_body_stream(&mut self) -> Option<BodyStream> {
+        self.body.take().and_then(|body| body.0.lock().unwrap().take())
     }
 
     pub fn set_status(&mut self, status: Status) {
         self.message.header.code = MessageClass::Response(status);
     }
 
+    /// Turns the response into an error response carrying a diagnostic payload: UTF-8 text
+    /// without a Content-Format (RFC 7252 §5.5.2). An empty diagnostic leaves no payload.
+    pub fn set_error(&mut self, status: Status, diagnostic: &str) {
+        self.set_status(status);
+        self.message.clear_option(CoAPOption::ContentFormat);
+        self.message.payload = diagnostic.as_bytes().to_vec();
+    }
+
+    /// Checks whether the response has a 4.xx or 5.xx code.
+    pub fn is_error(&self) -> bool {
+        match self.message.header.code {
+            MessageClass::Response(_) => class_to_code(&self.message.header.code) >> 5 >= 4,
+            _ => false,
+        }
+    }
+
+    /// Returns the diagnostic payload of an error response. Payloads declaring a
+    /// Content-Format are not diagnostics; invalid UTF-8 is replaced.
+    pub fn diagnostic(&self) -> Option<String> {
+        if !self.is_error() || self.message.payload.is_empty() || self.content_format().is_some() {
+            return None;
+        }
+        Some(String::from_utf8_lossy(&self.message.payload).to_string())
+    }
+
+    /// Returns the response, or an error carrying its diagnostic if it is an error
+    /// response, so that `?` surfaces the server's explanation.
+    pub fn error_for_status(self) -> Result<CoAPResponse, ResponseError> {
+        if !self.is_error() {
+            return Ok(self);
+        }
+        Err(ResponseError {
+            status: self.get_status().clone(),
+            code: code_to_str(&class_to_code(&self.message.header.code)),
+            diagnostic: self.diagnostic(),
+        })
+    }
+
+    /// The location named by the Location-Path and Location-Query options, e.g. `/items/7?v=1`.
+    pub fn location(&self) -> Option<String> {
+        let path = self.message.get_option(CoAPOption::LocationPath);
+        let query = self.message.get_option(CoAPOption::LocationQuery);
+        if path.is_none() && query.is_none() {
+            return None;
+        }
+        let segments = path.into_iter().flatten().map(|segment| String::from_utf8_lossy(segment));
+        let mut location: String = segments.map(|segment| format!("/{}", segment)).collect();
+        if location.is_empty() {
+            location.push('/');
+        }
+        let queries: Vec<_> = query.into_iter().flatten().map(|query| String::from_utf8_lossy(query)).collect();
+        if !queries.is_empty() {
+            location.push('?');
+            location.push_str(&queries.join("&"));
+        }
+        Some(location)
+    }
+
+    /// Classifies the response by its code, taking the option each code is read with.
+    pub fn into_outcome(self) -> Outcome {
+        match self.error_for_status() {
+            Err(e) => Outcome::Error(e),
+            Ok(response) => match *response.get_status() {
+                Status::Content => Outcome::Content(response),
+                Status::Valid => Outcome::Valid(response.message.get_etag().cloned()),
+                Status::Continue => Outcome::Continue(response.message.get_block1()),
+                Status::Created => Outcome::Created(response.location()),
+                _ => Outcome::Success(response),
+            },
+        }
+    }
+
     pub fn get_status(&self) -> &Status {
         match self.message.header.code {
             MessageClass::Response(Status::Created) => &Status::Created,
@@ -42,6 +271,7 @@ impl CoAPResponse {
             MessageClass::Response(Status::Valid) => &Status::Valid,
             MessageClass::Response(Status::Changed) => &Status::Changed,
             MessageClass::Response(Status::Content) => &Status::Content,
+            MessageClass::Response(Status::Continue) => &Status::Continue,
 
             MessageClass::Response(Status::BadRequest) => &Status::BadRequest,
             MessageClass::Response(Status::Unauthorized) => &Status::Unauthorized,
@@ -50,6 +280,7 @@ impl CoAPResponse {
             MessageClass::Response(Status::NotFound) => &Status::NotFound,
             MessageClass::Response(Status::MethodNotAllowed) => &Status::MethodNotAllowed,
             MessageClass::Response(Status::NotAcceptable) => &Status::NotAcceptable,
+            MessageClass::Response(Status::Conflict) => &Status::Conflict,
             MessageClass::Response(Status::PreconditionFailed) => &Status::PreconditionFailed,
             MessageClass::Response(Status::RequestEntityTooLarge) => &Status::RequestEntityTooLarge,
             MessageClass::Response(Status::UnsupportedContentFormat) => &Status::UnsupportedContentFormat,
@@ -65,6 +296,62 @@ impl CoAPResponse {
     }
 }
 
+impl CoAPResponse {
+    /// Returns the Content-Format number of the payload, if declared.
+    pub fn content_format(&self) -> Option<u32> {
+        self.get_option(CoAPOption::ContentFormat)
+            .and_then(|list| list.front())
+            .and_then(|value| decode_uint(value))
+    }
+
+    /// Decodes a text payload as UTF-8, skipping a leading byte order mark.
+    pub fn payload_as_str(&self) -> Result<&str, ContentError> {
+        self.expect_format(&[ContentFormat::TextPlain, ContentFormat::ApplicationLinkFormat,
+                             ContentFormat::ApplicationXML, ContentFormat::ApplicationJSON])?;
+        Self::text(&self.message.payload)
+    }
+
+    /// Decodes a text payload, replacing invalid UTF-8 sequences instead of failing.
+    pub fn payload_to_string_lossy(&self) -> String {
+        let payload = &self.message.payload;
+        let payload = if payload.starts_with(UTF8_BOM) { &payload[UTF8_BOM.len()..] } else { &payload[..] };
+        String::from_utf8_lossy(payload).to_string()
+    }
+
+    /// Parses a JSON payload into the CBOR data model.
+    pub fn payload_as_json(&self) -> Result<Value, ContentError> {
+        self.expect_format(&[ContentFormat::ApplicationJSON, ContentFormat::ApplicationSenmlJSON,
+                             ContentFormat::ApplicationSensmlJSON])?;
+        json::parse(Self::text(&self.message.payload)?).map_err(ContentError::InvalidJson)
+    }
+
+    /// Decodes a payload holding a single CBOR item.
+    pub fn payload_as_cbor(&self) -> Result<Value, ContentError> {
+        self.expect_format(&[ContentFormat::ApplicationCBOR, ContentFormat::ApplicationSenmlCBOR,
+                             ContentFormat::ApplicationSensmlCBOR])?;
+        let (value, used) = cbor::decode(&self.message.payload).map_err(ContentError::InvalidCbor)?;
+        if used != self.message.payload.len() {
+            return Err(ContentError::TrailingBytes);
+        }
+        Ok(value)
+    }
+
+    /// Accepts payloads without a Content-Format or with one of the given formats.
+    fn expect_format(&self, formats: &[ContentFormat]) -> Result<(), ContentError> {
+        match self.content_format() {
+            Some(format) if !formats.iter().any(|&f| f as u32 == format) => {
+                Err(ContentError::UnexpectedContentFormat(format))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn text(payload: &[u8]) -> Result<&str, ContentError> {
+        let payload = if payload.starts_with(UTF8_BOM) { &payload[UTF8_BOM.len()..] } else { payload };
+        str::from_utf8(payload).map_err(ContentError::InvalidText)
+    }
+}
+
 impl IsMessage for CoAPResponse {
     fn get_message(&self) -> &Packet {
         &self.message
@@ -99,6 +386,139 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_outcome() {
+        let response = |status: Status| {
+            let mut response = CoAPResponse::received(Packet::new());
+            response.set_status(status);
+            response
+        };
+        assert!(match response(Status::Content).into_outcome() {
+            Outcome::Content(content) => *content.get_status() == Status::Content,
+            _ => false,
+        });
+
+        let mut valid = response(Status::Valid);
+        valid.message.set_etag(vec![0x1F]);
+        assert!(match valid.into_outcome() {
+            Outcome::Valid(etag) => etag == Some(vec![0x1F]),
+            _ => false,
+        });
+
+        let mut next = response(Status::Continue);
+        next.message.set_block1(BlockValue::new(2, true, 64));
+        assert!(match next.into_outcome() {
+            Outcome::Continue(block) => block == Some(BlockValue::new(2, true, 64)),
+            _ => false,
+        });
+
+        let mut created = response(Status::Created);
+        assert!(match created.clone().into_outcome() {
+            Outcome::Created(location) => location.is_none(),
+            _ => false,
+        });
+        created.message.add_option(CoAPOption::LocationPath, b"items".to_vec());
+        created.message.add_option(CoAPOption::LocationPath, b"7".to_vec());
+        created.message.add_option(CoAPOption::LocationQuery, b"v=1".to_vec());
+        assert!(match created.into_outcome() {
+            Outcome::Created(location) => location == Some("/items/7?v=1".to_string()),
+            _ => false,
+        });
+
+        assert!(match response(Status::Changed).into_outcome() {
+            Outcome::Success(changed) => *changed.get_status() == Status::Changed,
+            _ => false,
+        });
+
+        let mut not_found = response(Status::NotFound);
+        not_found.set_error(Status::NotFound, "no such item");
+        assert!(match not_found.into_outcome() {
+            Outcome::Error(e) => e.code == "4.04" && e.diagnostic == Some("no such item".to_string()),
+            _ => false,
+        });
+    }
+
+    #[test]
+    fn test_freshness() {
+        let mut packet = Packet::new();
+        packet.header.set_type(MessageType::Confirmable);
+        let response = CoAPResponse::new(&packet).unwrap();
+        assert_eq!(response.get_max_age(), Duration::from_secs(DEFAULT_MAX_AGE as u64));
+        assert!(response.expires_at().is_none());
+        assert!(!response.is_fresh());
+
+        packet.set_max_age(30);
+        let response = CoAPResponse::received(packet);
+        assert_eq!(response.get_max_age(), Duration::from_secs(30));
+        assert_eq!(response.expires_at().unwrap(),
+                   response.received_at.unwrap() + Duration::from_secs(30));
+        assert!(response.is_fresh());
+
+        let mut packet = Packet::new();
+        packet.set_max_age(0);
+        assert!(!CoAPResponse::received(packet).is_fresh());
+    }
+
+    fn response_with(content_format: ContentFormat, payload: &[u8]) -> CoAPResponse {
+        let mut packet = Packet::new();
+        packet.set_content_format(content_format);
+        packet.payload = payload.to_vec();
+        CoAPResponse::received(packet)
+    }
+
+    #[test]
+    fn test_payload_decoding() {
+        let response = response_with(ContentFormat::TextPlain, b"\xEF\xBB\xBF21.5");
+        assert_eq!(response.payload_as_str(), Ok("21.5"));
+        assert_eq!(response.payload_as_cbor(), Err(ContentError::UnexpectedContentFormat(0)));
+
+        let response = response_with(ContentFormat::ApplicationJSON, br#"{"t": 1}"#);
+        assert_eq!(response.payload_as_json(),
+                   Ok(Value::Map(vec![(Value::Text("t".to_string()), Value::Integer(1))])));
+
+        let response = response_with(ContentFormat::ApplicationCBOR, &[0x82, 0x01, 0xF5]);
+        assert_eq!(response.payload_as_cbor(), Ok(Value::Array(vec![Value::Integer(1), Value::Bool(true)])));
+
+        let mut packet = Packet::new();
+        packet.payload = vec![0x01, 0x02];
+        assert_eq!(CoAPResponse::received(packet).payload_as_cbor(), Err(ContentError::TrailingBytes));
+    }
+
+    #[test]
+    fn test_payload_decoding_errors() {
+        let response = response_with(ContentFormat::TextPlain, b"a\xFFb");
+        assert!(match response.payload_as_str() {
+            Err(ContentError::InvalidText(_)) => true,
+            _ => false,
+        });
+        assert_eq!(response.payload_to_string_lossy(), "a\u{FFFD}b");
+
+        let response = response_with(ContentFormat::ApplicationJSON, b"{");
+        assert_eq!(response.payload_as_json(), Err(ContentError::InvalidJson(JsonError::Truncated)));
+    }
+
+    #[test]
+    fn test_error_diagnostic() {
+        let mut response = response_with(ContentFormat::ApplicationJSON, b"{}");
+        assert_eq!(response.diagnostic(), None);
+        response.set_error(Status::NotFound, "no sensor 7");
+        assert!(response.get_option(CoAPOption::ContentFormat).map_or(true, |list| list.is_empty()));
+        assert_eq!(response.diagnostic(), Some("no sensor 7".to_string()));
+
+        let error = response.error_for_status().unwrap_err();
+        assert_eq!(error, ResponseError {
+            status: Status::NotFound,
+            code: "4.04".to_string(),
+            diagnostic: Some("no sensor 7".to_string()),
+        });
+        assert_eq!(error.to_string(), "4.04 NotFound: no sensor 7");
+        assert!(io::Error::from(error).to_string().ends_with("no sensor 7"));
+
+        let content = response_with(ContentFormat::TextPlain, b"ok");
+        assert_eq!(content.diagnostic(), None);
+        assert!(content.error_for_status().is_ok());
+    }
+
     #[test]
     fn test_new_response_invalid() {
         let mut packet = Packet::new();
@@ -0,0 +1,110 @@
+//! The application/multipart-core content format (RFC 8710), bundling several
+//! representations in one payload, e.g. a reading and its metadata.
+//!
+//! The payload is a CBOR array of Content-Format and representation pairs. A part may be
+//! omitted, e.g. when the server cannot produce it, which is encoded as null and an empty
+//! byte string. Messages are bundled with `IsMessage::set_multipart` and split with
+//! `IsMessage::multipart`.
+
+use super::cbor::{self, Value};
+use super::codec::CodecRegistry;
+use super::message::response::ContentError;
+
+/// One representation of a multipart-core payload.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Part {
+    /// The Content-Format of the representation, `None` if the part is omitted.
+    pub format: Option<u32>,
+    pub payload: Vec<u8>,
+}
+
+impl Part {
+    pub fn new(format: u32, payload: Vec<u8>) -> Part {
+        Part { format: Some(format), payload }
+    }
+
+    /// A part standing for a representation left out.
+    pub fn omitted() -> Part {
+        Part { format: None, payload: Vec::new() }
+    }
+
+    pub fn is_omitted(&self) -> bool {
+        self.format.is_none()
+    }
+
+    /// Decodes the representation with the codec of its Content-Format.
+    pub fn decode(&self, codecs: &CodecRegistry) -> Result<Value, ContentError> {
+        let format = self.format.ok_or(ContentError::MissingContentFormat)?;
+        codecs.decode(format, &self.payload)
+    }
+}
+
+/// Encodes the parts as a multipart-core payload.
+pub fn encode(parts: &[Part]) -> Vec<u8> {
+    let mut items = Vec::with_capacity(parts.len() * 2);
+    for part in parts {
+        items.push(part.format.map_or(Value::Null, |format| Value::Integer(format as i64)));
+        items.push(Value::Bytes(part.payload.clone()));
+    }
+    Value::Array(items).to_vec()
+}
+
+/// Decodes a multipart-core payload into its parts.
+pub fn decode(payload: &[u8]) -> Result<Vec<Part>, ContentError> {
+    let (value, used) = cbor::decode(payload).map_err(ContentError::InvalidCbor)?;
+    if used != payload.len() {
+        return Err(ContentError::TrailingBytes);
+    }
+    let items = match value {
+        Value::Array(items) if items.len() % 2 == 0 => items,
+        _ => return Err(ContentError::Invalid("multipart-core payload is not an array of pairs".to_string())),
+    };
+    items
+        .chunks(2)
+        .map(|pair| match (&pair[0], &pair[1]) {
+            (Value::Integer(format), Value::Bytes(payload)) if *format >= 0 && *format <= 0xFFFF => {
+                Ok(Part::new(*format as u32, payload.clone()))
+            }
+            (Value::Null, Value::Bytes(payload)) if payload.is_empty() => Ok(Part::omitted()),
+            _ => Err(ContentError::Invalid("invalid multipart-core part".to_string())),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::message::packet::{CoAPOption, ContentFormat, Packet};
+    use super::super::message::response::CoAPResponse;
+    use super::super::message::IsMessage;
+
+    #[test]
+    fn test_multipart() {
+        let parts = vec![
+            Part::new(ContentFormat::TextPlain as u32, b"21.5".to_vec()),
+            Part::omitted(),
+            Part::new(ContentFormat::ApplicationCBOR as u32, Value::Text("Cel".to_string()).to_vec()),
+        ];
+        // [0, h'32312E35', null, h'', 60, h'6343656C']
+        assert_eq!(encode(&parts), vec![
+            0x86, 0x00, 0x44, 0x32, 0x31, 0x2E, 0x35, 0xF6, 0x40, 0x18, 0x3C, 0x44, 0x63, 0x43, 0x65, 0x6C,
+        ]);
+
+        let mut response = CoAPResponse::received(Packet::new());
+        response.set_multipart(&parts);
+        assert_eq!(response.message.get_content_format(), Some(ContentFormat::ApplicationMultipartCore));
+        let decoded = response.multipart().unwrap();
+        assert_eq!(decoded, parts);
+        assert!(decoded[1].is_omitted());
+        let codecs = CodecRegistry::default();
+        assert_eq!(decoded[2].decode(&codecs).unwrap(), Value::Text("Cel".to_string()));
+        assert_eq!(decoded[1].decode(&codecs), Err(ContentError::MissingContentFormat));
+        assert_eq!(response.decode_payload(&codecs).unwrap(), cbor::decode(&encode(&parts)).unwrap().0);
+
+        assert!(decode(&Value::Array(vec![Value::Integer(0)]).to_vec()).is_err());
+        assert!(decode(&Value::Array(vec![Value::Null, Value::Bytes(vec![1])]).to_vec()).is_err());
+        response.message.clear_option(CoAPOption::ContentFormat);
+        response.message.set_content_format(ContentFormat::ApplicationCBOR);
+        assert_eq!(response.multipart(), Err(ContentError::UnexpectedContentFormat(60)));
+    }
+}
@@ -0,0 +1,295 @@
+//! A client negotiating between coaps and coap, for device commissioning before credentials
+//! are provisioned.
+
+use std::io::{Error, ErrorKind, Result};
+use std::time::Duration;
+use log::*;
+
+use super::client::CoAPClient;
+use super::context::Transport;
+use super::dtls_client::{DTLSClientBuilder, DTLSCoAPClient};
+use super::message::request::CoAPRequest;
+use super::message::response::CoAPResponse;
+
+const DEFAULT_SECURE_PORT: u16 = 5684;
+const DEFAULT_PLAIN_PORT: u16 = 5683;
+const DEFAULT_PROBE_TIMEOUT: u64 = 2; // 2s
+
+/// Which transport `NegotiatedClient::connect` tries first.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SecurityPreference {
+    /// Try coaps, falling back to coap, e.g. for devices that may already be provisioned.
+    SecureFirst,
+    /// Try coap, falling back to coaps, e.g. for a factory-fresh device that only switches to
+    /// DTLS once provisioned.
+    PlaintextFirst,
+}
+
+/// How a `NegotiatedClient` chooses its transport, built from the defaults with the `with_*`
+/// methods.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NegotiationPolicy {
+    pub preference: SecurityPreference,
+    /// Whether coap may be used at all; when false only coaps is tried.
+    pub allow_plaintext: bool,
+    pub secure_port: u16,
+    pub plain_port: u16,
+    /// How long the coap endpoint has to answer the CoAP ping probing it.
+    pub probe_timeout: Duration,
+}
+
+impl Default for NegotiationPolicy {
+    fn default() -> NegotiationPolicy {
+        NegotiationPolicy {
+            preference: SecurityPreference::SecureFirst,
+            allow_plaintext: true,
+            secure_port: DEFAULT_SECURE_PORT,
+            plain_port: DEFAULT_PLAIN_PORT,
+            probe_timeout: Duration::new(DEFAULT_PROBE_TIMEOUT, 0),
+        }
+    }
+}
+
+impl NegotiationPolicy {
+    pub fn with_preference(mut self, preference: SecurityPreference) -> NegotiationPolicy {
+        self.preference = preference;
+        self
+    }
+
+    pub fn with_allow_plaintext(mut self, allow_plaintext: bool) -> NegotiationPolicy {
+        self.allow_plaintext = allow_plaintext;
+        self
+    }
+
+    pub fn with_secure_port(mut self, secure_port: u16) -> NegotiationPolicy {
+        self.secure_port = secure_port;
+        self
+    }
+
+    pub fn with_plain_port(mut self, plain_port: u16) -> NegotiationPolicy {
+        self.plain_port = plain_port;
+        self
+    }
+
+    pub fn with_probe_timeout(mut self, probe_timeout: Duration) -> NegotiationPolicy {
+        self.probe_timeout = probe_timeout;
+        self
+    }
+}
+
+enum Connection {
+    Secure(Box<DTLSCoAPClient>),
+    Plain(Box<CoAPClient>),
+}
+
+/// A client over coaps or coap, whichever the policy settled on.
+///
+/// coaps is usable once the DTLS handshake completes and coap once the endpoint answers a
+/// CoAP ping. The caller learns the chosen transport from `transport`, e.g. to provision
+/// credentials when the device is only reachable over coap, then connect again over coaps.
+pub struct NegotiatedClient {
+    connection: Connection,
+    fallback_error: Option<Error>,
+}
+
+impl NegotiatedClient {
+    /// Connects to the host with the transports allowed by the policy, in its order. The
+    /// builder configures the DTLS handshake.
+    ///
+    /// Only a secure port that times out or is refused is fallen back from; a handshake that
+    /// fails, e.g. on the server's certificate, is returned as the error.
+    pub fn connect(host: &str, builder: DTLSClientBuilder, policy: NegotiationPolicy) -> Result<NegotiatedClient> {
+        let mut builder = Some(builder);
+        let order: &[Transport] = match (policy.preference, policy.allow_plaintext) {
+            (_, false) => &[Transport::Dtls],
+            (SecurityPreference::SecureFirst, true) => &[Transport::Dtls, Transport::Udp],
+            (SecurityPreference::PlaintextFirst, true) => &[Transport::Udp, Transport::Dtls],
+        };
+
+        let mut errors: Vec<(Transport, Error)> = Vec::new();
+        for &transport in order {
+            let attempt = match transport {
+                Transport::Dtls => builder
+                    .take()
+                    .unwrap()
+                    .connect((host, policy.secure_port))
+                    .map(|client| Connection::Secure(Box::new(client))),
+                _ => Self::probe_plain(host, &policy).map(|client| Connection::Plain(Box::new(client))),
+            };
+            match attempt {
+                Ok(connection) => {
+                    if !errors.is_empty() {
+                        warn!("falling back to {:?} for {}: {}", transport, host, errors[0].1);
+                    }
+                    return Ok(NegotiatedClient {
+                        connection,
+                        fallback_error: errors.into_iter().next().map(|(_, e)| e),
+                    });
+                }
+                // a DTLS endpoint that failed the handshake is there; falling back to coap
+                // would let an attacker downgrade the connection
+                Err(e) if transport == Transport::Dtls && !Self::unreachable(&e) => return Err(e),
+                Err(e) => errors.push((transport, e)),
+            }
+        }
+
+        let reasons: Vec<String> = errors
+            .iter()
+            .map(|(transport, e)| format!("{:?}: {}", transport, e))
+            .collect();
+        Err(Error::new(ErrorKind::ConnectionRefused, reasons.join(", ")))
+    }
+
+    /// Whether the DTLS handshake failed because nothing answered at the secure port.
+    fn unreachable(error: &Error) -> bool {
+        matches!(error.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock | ErrorKind::ConnectionRefused)
+    }
+
+    fn probe_plain(host: &str, policy: &NegotiationPolicy) -> Result<CoAPClient> {
+        let mut client = CoAPClient::new((host, policy.plain_port))?;
+        let transmission = client.transmission_parameters();
+        client.set_transmission_parameters(transmission.with_timeout(policy.probe_timeout));
+        client.check_health()?;
+        client.set_transmission_parameters(transmission);
+        Ok(client)
+    }
+
+    /// The transport the client settled on, `Transport::Dtls` or `Transport::Udp`.
+    pub fn transport(&self) -> Transport {
+        match self.connection {
+            Connection::Secure(_) => Transport::Dtls,
+            Connection::Plain(_) => Transport::Udp,
+        }
+    }
+
+    pub fn is_secure(&self) -> bool {
+        self.transport() == Transport::Dtls
+    }
+
+    /// Why the preferred transport was not used, if the client fell back to the other one.
+    pub fn fallback_error(&self) -> Option<&Error> {
+        self.fallback_error.as_ref()
+    }
+
+    /// Execute a request and wait for its response.
+    pub fn request(&mut self, request: &mut CoAPRequest) -> Result<CoAPResponse> {
+        match self.connection {
+            Connection::Secure(ref mut client) => {
+                client.send(request)?;
+                client.receive()
+            }
+            Connection::Plain(ref client) => client.request(request),
+        }
+    }
+
+    pub fn send(&mut self, request: &CoAPRequest) -> Result<()> {
+        match self.connection {
+            Connection::Secure(ref mut client) => client.send(request),
+            Connection::Plain(ref client) => client.send(request),
+        }
+    }
+
+    pub fn receive(&mut self) -> Result<CoAPResponse> {
+        match self.connection {
+            Connection::Secure(ref mut client) => client.receive(),
+            Connection::Plain(ref client) => client.receive(),
+        }
+    }
+
+    pub fn set_receive_timeout(&self, dur: Option<Duration>) -> Result<()> {
+        match self.connection {
+            Connection::Secure(ref client) => client.set_receive_timeout(dur),
+            Connection::Plain(ref client) => client.set_receive_timeout(dur),
+        }
+    }
+
+    /// Returns the DTLS client, if the client settled on coaps.
+    pub fn into_secure(self) -> Option<DTLSCoAPClient> {
+        match self.connection {
+            Connection::Secure(client) => Some(*client),
+            Connection::Plain(_) => None,
+        }
+    }
+
+    /// Returns the plaintext client, if the client settled on coap.
+    pub fn into_plain(self) -> Option<CoAPClient> {
+        match self.connection {
+            Connection::Plain(client) => Some(*client),
+            Connection::Secure(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::{dtls_client, server};
+    use super::super::udp::UDPWrapper;
+    use openssl::ssl::{Ssl, SslContext, SslMethod};
+    use std::thread;
+
+    fn unused_port() -> u16 {
+        std::net::UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+    }
+
+    #[test]
+    fn test_plaintext_fallback() {
+        let server_port = server::test::spawn_server(|request: CoAPRequest| async move { request.response })
+            .recv()
+            .unwrap();
+        let policy = NegotiationPolicy::default()
+            .with_secure_port(unused_port())
+            .with_plain_port(server_port)
+            .with_probe_timeout(Duration::from_millis(500));
+
+        let mut client = NegotiatedClient::connect("127.0.0.1", DTLSClientBuilder::new().unwrap(), policy).unwrap();
+        assert_eq!(client.transport(), Transport::Udp);
+        assert!(!client.is_secure());
+        assert!(client.fallback_error().is_some());
+        let mut request = CoAPRequest::new();
+        request.set_path("/commissioning");
+        assert!(client.request(&mut request).is_ok());
+
+        let plaintext_first = policy.with_preference(SecurityPreference::PlaintextFirst);
+        let client = NegotiatedClient::connect("127.0.0.1", DTLSClientBuilder::new().unwrap(), plaintext_first).unwrap();
+        assert_eq!(client.transport(), Transport::Udp);
+        assert!(client.fallback_error().is_none());
+        assert!(client.into_plain().is_some());
+
+        let secure_only = policy.with_allow_plaintext(false);
+        let error = NegotiatedClient::connect("127.0.0.1", DTLSClientBuilder::new().unwrap(), secure_only)
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), ErrorKind::ConnectionRefused);
+        assert!(error.to_string().starts_with("Dtls: "));
+    }
+
+    #[test]
+    fn test_no_fallback_from_failed_handshake() {
+        let plain_port = server::test::spawn_server(|request: CoAPRequest| async move { request.response })
+            .recv()
+            .unwrap();
+        let (key, cert) = dtls_client::test::self_signed();
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let secure_port = socket.local_addr().unwrap().port();
+        socket.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let mut context = SslContext::builder(SslMethod::dtls()).unwrap();
+        context.set_private_key(&key).unwrap();
+        context.set_certificate(&cert).unwrap();
+        let ssl = Ssl::new(&context.build()).unwrap();
+        let server = thread::spawn(move || {
+            dtls_client::test::accept_peer(&socket);
+            ssl.accept(UDPWrapper::new(socket)).is_ok()
+        });
+
+        // the client does not trust the server's certificate
+        let policy = NegotiationPolicy::default()
+            .with_secure_port(secure_port)
+            .with_plain_port(plain_port);
+        let error = NegotiatedClient::connect("127.0.0.1", DTLSClientBuilder::new().unwrap(), policy)
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+        assert!(!server.join().unwrap());
+    }
+}
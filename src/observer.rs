@@ -1,21 +1,36 @@
 use std::{
+    io,
     net::SocketAddr,
-    collections::{HashMap, HashSet, hash_map::Entry},
-    time::{Duration},
+    collections::{BTreeMap, HashMap, HashSet, hash_map::Entry},
+    time::{Duration, Instant},
 };
 use log::{debug, warn};
 use bincode;
-use futures::{StreamExt, stream::{Fuse, SelectNextSome}};
+use serde::{Serialize, Deserialize};
+use futures::{StreamExt, select, stream::{Fuse, SelectNextSome}};
+use tokio::sync::mpsc;
 use tokio::time::{Interval, interval};
 
 use super::message::request::{CoAPRequest, Method};
 use super::message::response::Status;
-use super::message::packet::{ObserveOption, Packet};
+use super::message::packet::{CoAPOption, ObserveOption, Packet};
 use super::message::IsMessage;
 use super::message::header::{MessageClass, MessageType, ResponseType};
+use super::budget::{BudgetExceeded, BudgetResource, MemoryBudget};
+use super::cbor::Value;
+use super::context::Transport;
+use super::exchange::ExchangeRegistry;
 use super::server::MessageSender;
 
 const DEFAULT_UNACKNOWLEDGE_MESSAGE_TRY_TIMES: usize = 10;
+const DEFAULT_PACING_RATE: f64 = 100.0;
+const DEFAULT_PACING_BURST: u32 = 10;
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(1);
+const PACING_INTERVAL: Duration = Duration::from_millis(10);
+// the longest pmin or pmax accepted, a week, keeping deadlines far from overflowing Instant
+const MAX_PERIOD_SECS: f64 = 604800.0;
+
+type ObservePolicy = Box<dyn FnMut(&CoAPRequest, usize) -> ObserveDecision + Send>;
 
 pub struct Observer {
     registers: HashMap<String, RegisterItem>,
@@ -25,6 +40,314 @@ pub struct Observer {
     tx_sender: MessageSender,
     current_message_id: u16,
     timer: Fuse<Interval>,
+    state_hook: Option<Box<dyn FnMut(ObserveState) + Send>>,
+    observe_policy: Option<ObservePolicy>,
+    exchanges: ExchangeRegistry,
+    pacer: Option<Pacer>,
+    maintained_at: Instant,
+    publisher: ResourcePublisher,
+    publications: Fuse<mpsc::UnboundedReceiver<Publication>>,
+    budget: MemoryBudget,
+}
+
+/// Publishes representations of resources to their observers, like a PUT to the server
+/// does, for state that lives outside the request handler, e.g. a `VersionedResource`.
+#[derive(Clone)]
+pub struct ResourcePublisher {
+    sender: mpsc::UnboundedSender<Publication>,
+}
+
+impl ResourcePublisher {
+    /// Sets the representation of the resource at the path and notifies its observers. The
+    /// Content-Format and ETag of the representation are sent along with its payload.
+    pub fn publish(&self, path: &str, representation: &Packet) {
+        let options = [CoAPOption::ContentFormat, CoAPOption::ETag]
+            .iter()
+            .filter_map(|&option| {
+                representation
+                    .get_option(option)
+                    .and_then(|list| list.front())
+                    .map(|value| (option, value.clone()))
+            })
+            .collect();
+        let publication = Publication {
+            path: path.trim_matches('/').to_string(),
+            payload: representation.payload.clone(),
+            options,
+        };
+        if self.sender.send(publication).is_err() {
+            warn!("publishing {} failed: the observer is gone", path);
+        }
+    }
+}
+
+pub(crate) struct Publication {
+    path: String,
+    payload: Vec<u8>,
+    options: Vec<(CoAPOption, Vec<u8>)>,
+}
+
+/// How the notifications of a server are paced, so that a change of a resource with many
+/// observers does not hit the network with a synchronized burst. Built from the defaults with
+/// the `with_*` methods.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NotificationPacing {
+    /// The notifications sent per second once the burst is used up.
+    pub rate: f64,
+    /// The notifications that may be sent back to back.
+    pub burst: u32,
+    /// The notifications caused by one change are spread evenly over this window.
+    pub window: Duration,
+}
+
+impl Default for NotificationPacing {
+    fn default() -> NotificationPacing {
+        NotificationPacing {
+            rate: DEFAULT_PACING_RATE,
+            burst: DEFAULT_PACING_BURST,
+            window: Duration::from_secs(0),
+        }
+    }
+}
+
+impl NotificationPacing {
+    pub fn with_rate(mut self, rate: f64) -> NotificationPacing {
+        self.rate = rate;
+        self
+    }
+
+    pub fn with_burst(mut self, burst: u32) -> NotificationPacing {
+        self.burst = burst;
+        self
+    }
+
+    pub fn with_window(mut self, window: Duration) -> NotificationPacing {
+        self.window = window;
+        self
+    }
+}
+
+/// A token bucket releasing the scheduled notifications in the order they are due.
+#[derive(Debug)]
+struct Pacer {
+    pacing: NotificationPacing,
+    tokens: f64,
+    refilled_at: Instant,
+    queue: BTreeMap<(Instant, u64), String>,
+    // the registrations queued, each once however often its resource changes meanwhile
+    queued: HashSet<String>,
+    sequence: u64,
+}
+
+impl Pacer {
+    fn new(pacing: NotificationPacing) -> Pacer {
+        Pacer {
+            pacing,
+            tokens: pacing.burst as f64,
+            refilled_at: Instant::now(),
+            queue: BTreeMap::new(),
+            queued: HashSet::new(),
+            sequence: 0,
+        }
+    }
+
+    fn schedule(&mut self, register_resource_keys: Vec<String>) {
+        let now = Instant::now();
+        let keys: Vec<String> = register_resource_keys
+            .into_iter()
+            .filter(|key| !self.queued.contains(key))
+            .collect();
+        let count = keys.len() as u32;
+        for (index, key) in keys.into_iter().enumerate() {
+            let due = now + self.pacing.window * index as u32 / count;
+            self.sequence += 1;
+            self.queued.insert(key.clone());
+            self.queue.insert((due, self.sequence), key);
+        }
+    }
+
+    /// Takes the notifications that are due and covered by the tokens in the bucket.
+    fn take_due(&mut self) -> Vec<String> {
+        let now = Instant::now();
+        let refill = now.duration_since(self.refilled_at).as_secs_f64() * self.pacing.rate;
+        self.tokens = (self.tokens + refill).min(self.pacing.burst.max(1) as f64);
+        self.refilled_at = now;
+
+        let mut due = Vec::new();
+        while self.tokens >= 1.0 {
+            let next = match self.queue.keys().next() {
+                Some(&next) if next.0 <= now => next,
+                _ => break,
+            };
+            let key = self.queue.remove(&next).unwrap();
+            self.queued.remove(&key);
+            self.tokens -= 1.0;
+            due.push(key);
+        }
+        due
+    }
+
+    fn clear(&mut self) {
+        self.queue.clear();
+        self.queued.clear();
+    }
+}
+
+/// What the server does with a request registering an observation.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ObserveDecision {
+    /// Register the observer.
+    Accept,
+    /// Leave the request to the handler as a plain GET, so the peer gets a single response
+    /// without an Observe option (RFC 7641 §4.1).
+    Ignore,
+    /// Refuse the observation with an error response carrying the status and the diagnostic,
+    /// e.g. 4.01 Unauthorized for anonymous peers. An earlier registration of the peer for the
+    /// resource is removed.
+    Reject(Status, String),
+}
+
+/// A snapshot of the observation registry, which a server restarted for an upgrade can restore
+/// so that observers keep receiving notifications without registering again. A part of the
+/// registry drained with `Observer::drain_state` is handed to another instance likewise.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ObserveState {
+    resources: Vec<ResourceState>,
+    registrations: Vec<RegistrationState>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct ResourceState {
+    path: String,
+    payload: Vec<u8>,
+    sequence: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct RegistrationState {
+    address: SocketAddr,
+    path: String,
+    token: Vec<u8>,
+    filter: ObserveFilter,
+    // registered over DTLS, whose session cannot be handed to another instance
+    dtls: bool,
+}
+
+/// The notification attributes an observer gives as Uri-Query options when registering, as
+/// with the LwM2M write-attributes: `gt` and `lt` notify when the value crosses the threshold
+/// and `st` when it moved by at least the step since the last notification. Any condition
+/// met triggers a notification; payloads that are not a number always notify.
+///
+/// `pmin` holds notifications back until that many seconds passed since the previous one,
+/// then sends the latest state, and `pmax` sends the state again when no notification was
+/// sent for that many seconds.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+struct ObserveFilter {
+    greater_than: Option<f64>,
+    less_than: Option<f64>,
+    step: Option<f64>,
+    min_period: Option<Duration>,
+    max_period: Option<Duration>,
+}
+
+impl ObserveFilter {
+    fn from_request(request: &CoAPRequest) -> Result<ObserveFilter, String> {
+        let mut filter = ObserveFilter::default();
+        let queries = match request.get_option(CoAPOption::UriQuery) {
+            Some(queries) => queries,
+            None => return Ok(filter),
+        };
+        for query in queries {
+            let query = String::from_utf8_lossy(query);
+            let mut parts = query.splitn(2, '=');
+            let name = parts.next().unwrap_or("");
+            if !["gt", "lt", "st", "pmin", "pmax"].contains(&name) {
+                // other arguments select the resource and are left to the application
+                continue;
+            }
+            let value = match parts.next().and_then(|value| value.parse::<f64>().ok()) {
+                Some(value) if value.is_finite() && (value >= 0.0 || !name.starts_with('p')) => value,
+                _ => return Err(format!("invalid observe attribute {}", query)),
+            };
+            if name.starts_with('p') && value > MAX_PERIOD_SECS {
+                return Err(format!("{} exceeds {} seconds", name, MAX_PERIOD_SECS));
+            }
+            match name {
+                "gt" => filter.greater_than = Some(value),
+                "lt" => filter.less_than = Some(value),
+                "st" => filter.step = Some(value),
+                "pmin" => filter.min_period = Some(Duration::from_secs_f64(value)),
+                _ => filter.max_period = Some(Duration::from_secs_f64(value)),
+            }
+        }
+        if let (Some(min_period), Some(max_period)) = (filter.min_period, filter.max_period) {
+            if max_period < min_period {
+                return Err("pmax must not be less than pmin".to_string());
+            }
+        }
+        Ok(filter)
+    }
+
+    fn admits(&self, last: Option<f64>, value: f64) -> bool {
+        let has_conditions = self.greater_than.is_some() || self.less_than.is_some() || self.step.is_some();
+        let last = match last {
+            Some(last) if has_conditions => last,
+            _ => return true,
+        };
+        self.greater_than.is_some_and(|threshold| (last > threshold) != (value > threshold))
+            || self.less_than.is_some_and(|threshold| (last < threshold) != (value < threshold))
+            || self.step.is_some_and(|step| (value - last).abs() >= step)
+    }
+}
+
+fn numeric_value(payload: &[u8]) -> Option<f64> {
+    std::str::from_utf8(payload).ok().and_then(|text| text.trim().parse().ok())
+}
+
+impl ObserveState {
+    /// Returns true if no resource has been recorded.
+    pub fn is_empty(&self) -> bool {
+        self.resources.is_empty()
+    }
+
+    /// The number of recorded observer registrations.
+    pub fn registrations(&self) -> usize {
+        self.registrations.len()
+    }
+
+    /// Encodes the snapshot for storage.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    /// Decodes a snapshot produced by `to_bytes`.
+    pub fn from_bytes(buf: &[u8]) -> io::Result<ObserveState> {
+        bincode::deserialize(buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Describes the observed resources and their observers, leaving out the payloads.
+    pub(crate) fn to_cbor(&self) -> Value {
+        let text = |text: &str| Value::Text(text.to_string());
+        let resources = self.resources
+            .iter()
+            .map(|resource| Value::Map(vec![
+                (text("path"), text(&resource.path)),
+                (text("sequence"), Value::Integer(resource.sequence as i64)),
+            ]))
+            .collect();
+        let registrations = self.registrations
+            .iter()
+            .map(|registration| Value::Map(vec![
+                (text("peer"), Value::Text(registration.address.to_string())),
+                (text("path"), text(&registration.path)),
+                (text("token"), Value::Bytes(registration.token.clone())),
+            ]))
+            .collect();
+        Value::Map(vec![
+            (text("resources"), Value::Array(resources)),
+            (text("registrations"), Value::Array(registrations)),
+        ])
+    }
 }
 
 #[derive(Debug)]
@@ -35,6 +358,8 @@ struct RegisterItem {
 #[derive(Debug)]
 struct ResourceItem {
     payload: Vec<u8>,
+    // the options of a published representation sent along with the payload, not persisted
+    options: Vec<(CoAPOption, Vec<u8>)>,
     register_resources: HashSet<String>,
     sequence: u32,
 }
@@ -45,6 +370,13 @@ struct RegisterResourceItem {
     resource: String,
     token: Vec<u8>,
     unacknowledge_message: Option<u16>,
+    filter: ObserveFilter,
+    dtls: bool,
+    // the value of the last notification, which the filter compares changes with
+    last_value: Option<f64>,
+    last_notified: Instant,
+    // a change held back until the minimum period elapses
+    deferred: bool,
 }
 
 #[derive(Debug)]
@@ -56,6 +388,7 @@ struct UnacknowledgeMessageItem {
 impl Observer {
     /// Creates an observer with channel to send message.
     pub fn new(tx_sender: MessageSender) -> Observer {
+        let (sender, receiver) = mpsc::unbounded_channel();
         Observer {
             registers: HashMap::new(),
             resources: HashMap::new(),
@@ -63,7 +396,232 @@ impl Observer {
             unacknowledge_messages: HashMap::new(),
             tx_sender: tx_sender,
             current_message_id: 0,
-            timer: interval(Duration::from_secs(1)).fuse()
+            timer: interval(Duration::from_secs(1)).fuse(),
+            state_hook: None,
+            observe_policy: None,
+            exchanges: ExchangeRegistry::new(),
+            pacer: None,
+            maintained_at: Instant::now(),
+            publisher: ResourcePublisher { sender },
+            publications: receiver.fuse(),
+            budget: MemoryBudget::new(),
+        }
+    }
+
+    /// Returns a sender for messages to be sent by the server.
+    pub fn message_sender(&self) -> MessageSender {
+        self.tx_sender.clone()
+    }
+
+    /// Returns a handle listing the notifications awaiting acknowledgement.
+    pub fn exchanges(&self) -> ExchangeRegistry {
+        self.exchanges.clone()
+    }
+
+    /// Returns a publisher of resource representations.
+    pub fn resource_publisher(&self) -> ResourcePublisher {
+        self.publisher.clone()
+    }
+
+    /// Takes a snapshot of the observed resources and their registrations.
+    ///
+    /// Notifications awaiting acknowledgement are not included; the next change of a
+    /// resource notifies every restored observer.
+    pub fn export_state(&self) -> ObserveState {
+        let mut resources: Vec<ResourceState> = self.resources
+            .iter()
+            .map(|(path, resource)| ResourceState {
+                path: path.clone(),
+                payload: resource.payload.clone(),
+                sequence: resource.sequence,
+            })
+            .collect();
+        resources.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let mut registrations: Vec<RegistrationState> = self.register_resources
+            .values()
+            .map(|item| RegistrationState {
+                address: item.register.parse().unwrap(),
+                path: item.resource.clone(),
+                token: item.token.clone(),
+                filter: item.filter.clone(),
+                dtls: item.dtls,
+            })
+            .collect();
+        registrations.sort_by(|a, b| (a.address, &a.path).cmp(&(b.address, &b.path)));
+
+        ObserveState { resources, registrations }
+    }
+
+    /// Restores a snapshot taken by `export_state`, replacing the current registry.
+    pub fn restore_state(&mut self, state: ObserveState) {
+        self.budget.release(BudgetResource::Observers, self.register_resources.len());
+        self.registers.clear();
+        self.resources.clear();
+        self.register_resources.clear();
+        self.unacknowledge_messages.clear();
+        if let Some(ref mut pacer) = self.pacer {
+            pacer.clear();
+        }
+
+        for resource in state.resources {
+            self.resources.insert(resource.path, ResourceItem {
+                payload: resource.payload,
+                options: Vec::new(),
+                register_resources: HashSet::new(),
+                sequence: resource.sequence,
+            });
+        }
+        for registration in state.registrations {
+            if !self.resources.contains_key(&registration.path) {
+                warn!("dropping registration for unknown resource {}", registration.path);
+                continue;
+            }
+            let recorded = self.record_register_resource(
+                &registration.address,
+                &registration.path,
+                &registration.token,
+                registration.filter,
+                registration.dtls,
+            );
+            if let Err(e) = recorded {
+                warn!("dropping registration for {}: {}", registration.path, e);
+            }
+        }
+    }
+
+    /// Hands registrations over to another instance, e.g. to drain a gateway before a rolling
+    /// upgrade: the registrations of the peers and paths `select` picks are exported along
+    /// with the resources they observe, and removed here so that only the importing instance
+    /// notifies them. Registrations made over DTLS stay here: the other instance has no
+    /// session with those peers, so it could not notify them.
+    pub fn drain_state<F: FnMut(&SocketAddr, &str) -> bool>(&mut self, mut select: F) -> ObserveState {
+        let mut drained: Vec<RegistrationState> = self.register_resources
+            .values()
+            .map(|item| RegistrationState {
+                address: item.register.parse().unwrap(),
+                path: item.resource.clone(),
+                token: item.token.clone(),
+                filter: item.filter.clone(),
+                dtls: item.dtls,
+            })
+            .filter(|registration| !registration.dtls && select(&registration.address, &registration.path))
+            .collect();
+        drained.sort_by(|a, b| (a.address, &a.path).cmp(&(b.address, &b.path)));
+
+        let mut resources: Vec<ResourceState> = Vec::new();
+        for registration in drained.iter() {
+            if !resources.iter().any(|resource| resource.path == registration.path) {
+                let resource = &self.resources[&registration.path];
+                resources.push(ResourceState {
+                    path: registration.path.clone(),
+                    payload: resource.payload.clone(),
+                    sequence: resource.sequence,
+                });
+            }
+            self.remove_register_resource(&registration.address, &registration.path, &registration.token);
+        }
+        resources.sort_by(|a, b| a.path.cmp(&b.path));
+        if !drained.is_empty() {
+            self.state_changed();
+        }
+
+        ObserveState { resources, registrations: drained }
+    }
+
+    /// Adds the registrations drained from another instance to the registry, unlike
+    /// `restore_state` keeping those already present. Of a resource known to both, the
+    /// representation with the higher sequence number is kept, so that observers do not
+    /// take the next notification for a reordered one. DTLS registrations in a snapshot from
+    /// `export_state` are dropped, as there is no session to notify them over.
+    pub fn import_state(&mut self, state: ObserveState) {
+        for resource in state.resources {
+            match self.resources.entry(resource.path) {
+                Entry::Occupied(mut entry) => {
+                    let item = entry.get_mut();
+                    if resource.sequence > item.sequence {
+                        item.payload = resource.payload;
+                        item.options.clear();
+                        item.sequence = resource.sequence;
+                    }
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(ResourceItem {
+                        payload: resource.payload,
+                        options: Vec::new(),
+                        register_resources: HashSet::new(),
+                        sequence: resource.sequence,
+                    });
+                }
+            }
+        }
+        for registration in state.registrations {
+            if !self.resources.contains_key(&registration.path) {
+                warn!("dropping registration for unknown resource {}", registration.path);
+                continue;
+            }
+            if registration.dtls {
+                warn!("dropping DTLS registration of {} for {}", registration.address, registration.path);
+                continue;
+            }
+            let recorded = self.record_register_resource(
+                &registration.address,
+                &registration.path,
+                &registration.token,
+                registration.filter,
+                false,
+            );
+            if let Err(e) = recorded {
+                warn!("dropping registration for {}: {}", registration.path, e);
+            }
+        }
+        self.state_changed();
+    }
+
+    /// Sets a hook receiving a snapshot whenever a registration or an observed resource changes,
+    /// so that the registry can be persisted as it evolves.
+    pub fn set_state_hook<F: FnMut(ObserveState) + Send + 'static>(&mut self, hook: F) {
+        self.state_hook = Some(Box::new(hook));
+    }
+
+    /// Sets the policy deciding on each registration, given the request and the number of
+    /// resources the peer already observes, e.g. to reject anonymous peers or enforce a
+    /// per-peer quota. Without a policy every registration of a known resource is accepted.
+    pub fn set_observe_policy<F>(&mut self, policy: F)
+    where
+        F: FnMut(&CoAPRequest, usize) -> ObserveDecision + Send + 'static,
+    {
+        self.observe_policy = Some(Box::new(policy));
+    }
+
+    /// Counts the registrations against the observers of a budget. A registration beyond
+    /// the budget is answered with the representation only, like a plain GET.
+    pub fn set_memory_budget(&mut self, budget: MemoryBudget) {
+        let registrations = self.register_resources.len();
+        self.budget.release(BudgetResource::Observers, registrations);
+        // the registrations already recorded are counted even beyond the limit
+        let _ = budget.reserve(BudgetResource::Observers, registrations);
+        self.budget = budget;
+    }
+
+    /// Whether a registration may be accepted at all, i.e. the budget allows any observer.
+    pub(crate) fn accepts_registrations(&self) -> bool {
+        self.budget.limit(BudgetResource::Observers) != Some(0)
+    }
+
+    /// Paces the notifications with a token bucket instead of sending them as soon as a
+    /// resource changes. Retransmissions are not paced.
+    pub fn set_notification_pacing(&mut self, pacing: NotificationPacing) {
+        self.pacer = Some(Pacer::new(pacing));
+        self.timer = interval(PACING_INTERVAL).fuse();
+    }
+
+    fn state_changed(&mut self) {
+        if self.state_hook.is_some() {
+            let state = self.export_state();
+            if let Some(ref mut hook) = self.state_hook {
+                hook(state);
+            }
         }
     }
 
@@ -72,6 +630,21 @@ impl Observer {
         self.timer.select_next_some()
     }
 
+    /// Waits for the timer or a representation published with a `ResourcePublisher`, then
+    /// handles it.
+    pub(crate) async fn next_event(&mut self) {
+        let publication = select! {
+            _ = self.timer.select_next_some() => None,
+            publication = self.publications.select_next_some() => Some(publication),
+        };
+        match publication {
+            Some(publication) => {
+                self.resource_changed(&publication.path, &publication.payload, publication.options).await;
+            }
+            None => self.timer_handler().await,
+        }
+    }
+
     /// filter the requests belong to the observer.
     pub async fn request_handler(&mut self, request: &CoAPRequest) -> bool {
         if request.get_type() == MessageType::Acknowledgement {
@@ -79,12 +652,24 @@ impl Observer {
             return false;
         }
 
+        // requests addressed to a forward proxy are left to the handler
+        if request.get_option(CoAPOption::ProxyUri).is_some_and(|list| !list.is_empty()) {
+            return true;
+        }
+
         match (request.get_method(), request.get_observe()) {
             (&Method::Get, Some(observe_option)) => match observe_option[0] {
-                x if x == ObserveOption::Register as u8 => {
-                    self.register(request).await;
-                    return false;
-                }
+                x if x == ObserveOption::Register as u8 => match self.decide(request) {
+                    ObserveDecision::Accept => {
+                        self.register(request).await;
+                        false
+                    }
+                    ObserveDecision::Ignore => true,
+                    ObserveDecision::Reject(status, diagnostic) => {
+                        self.reject(request, status, &diagnostic).await;
+                        false
+                    }
+                },
                 x if x == ObserveOption::Deregister as u8 => {
                     self.deregister(request);
                     return true;
@@ -92,7 +677,7 @@ impl Observer {
                 _ => return true,
             },
             (&Method::Put, _) => {
-                self.resource_changed(request).await;
+                self.resource_changed(&request.get_path(), &request.message.payload, Vec::new()).await;
                 return true;
             }
             _ => return true,
@@ -101,6 +686,17 @@ impl Observer {
 
     /// trigger send the unacknowledge messages.
     pub async fn timer_handler(&mut self) {
+        self.send_paced().await;
+        // with pacing the timer ticks more often than retransmissions are due
+        if self.pacer.is_some() && self.maintained_at.elapsed() < MAINTENANCE_INTERVAL {
+            return;
+        }
+        self.maintained_at = Instant::now();
+
+        for (address, message_id) in self.exchanges.take_aborted() {
+            self.abort_unacknowledge_message(&address, message_id);
+        }
+
         let register_resource_keys: Vec<String>;
         {
             register_resource_keys = self.unacknowledge_messages
@@ -114,6 +710,60 @@ impl Observer {
                 self.notify_register_with_newest_resource(&register_resource_key).await;
             }
         }
+
+        // send the changes held back by pmin and the periodic notifications of pmax
+        let mut due: Vec<String> = self.register_resources
+            .iter()
+            .filter(|(_, item)| {
+                let elapsed = item.last_notified.elapsed();
+                let filter = &item.filter;
+                (item.deferred && filter.min_period.is_none_or(|min_period| elapsed >= min_period))
+                    || filter.max_period.is_some_and(|max_period| elapsed >= max_period)
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+        due.sort();
+        self.notify_all(due).await;
+    }
+
+    /// Notifies the registrations, or schedules them when the notifications are paced.
+    async fn notify_all(&mut self, register_resource_keys: Vec<String>) {
+        match self.pacer {
+            Some(ref mut pacer) => pacer.schedule(register_resource_keys),
+            None => {
+                for register_resource_key in register_resource_keys {
+                    self.notify(&register_resource_key).await;
+                }
+                return;
+            }
+        }
+        self.send_paced().await;
+    }
+
+    async fn send_paced(&mut self) {
+        let due = match self.pacer {
+            Some(ref mut pacer) => pacer.take_due(),
+            None => return,
+        };
+        for register_resource_key in due {
+            // the observer may have deregistered while its notification was queued
+            if self.register_resources.contains_key(&register_resource_key) {
+                self.notify(&register_resource_key).await;
+            }
+        }
+    }
+
+    async fn notify(&mut self, register_resource_key: &String) {
+        {
+            let register_resource = self.register_resources.get_mut(register_resource_key).unwrap();
+            let resource = self.resources.get(&register_resource.resource).unwrap();
+            register_resource.last_value = numeric_value(&resource.payload);
+            register_resource.last_notified = Instant::now();
+            register_resource.deferred = false;
+        }
+        self.gen_message_id();
+        self.notify_register_with_newest_resource(register_resource_key).await;
+        self.record_unacknowledge_message(register_resource_key);
     }
 
     async fn register(&mut self, request: &CoAPRequest) {
@@ -132,36 +782,87 @@ impl Observer {
             return;
         }
 
-        self.record_register_resource(&register_address, &resource_path, &request.get_token());
+        let filter = match ObserveFilter::from_request(request) {
+            Ok(filter) => filter,
+            Err(diagnostic) => {
+                if let Some(ref response) = request.response {
+                    let mut response2 = response.clone();
+                    response2.set_error(Status::BadRequest, &diagnostic);
+                    self.send_message(&register_address, &response2.message).await;
+                }
+                return;
+            }
+        };
+        let dtls = request.context.transport == Transport::Dtls;
+        let recorded = self.record_register_resource(&register_address, &resource_path, request.get_token(), filter, dtls);
+        if let Err(ref e) = recorded {
+            debug!("not observing {} {}: {}", register_address, resource_path, e);
+        }
 
         let resource = self.resources.get(&resource_path).unwrap();
 
         if let Some(ref response) = request.response {
             let mut response2 = response.clone();
             response2.set_payload(resource.payload.clone());
-            response2.set_observe(vec![ObserveOption::Register as u8]);
+            if recorded.is_ok() {
+                response2.set_observe(vec![ObserveOption::Register as u8]);
+            }
+            for (option, value) in resource.options.iter() {
+                response2.message.add_option(*option, value.clone());
+            }
             self.send_message(&register_address, &response2.message).await;
         }
+        self.state_changed();
     }
 
-    fn deregister(&mut self, request: &CoAPRequest) {
+    fn decide(&mut self, request: &CoAPRequest) -> ObserveDecision {
+        // a registration refreshing one the peer already holds does not add to its count
+        let address = request.source.unwrap();
+        let refreshed = Self::format_register_resource(&address, &request.get_path());
+        let observed = self.registers
+            .get(&Self::format_register(&address))
+            .map_or(0, |register| {
+                register.register_resources.iter().filter(|key| **key != refreshed).count()
+            });
+        match self.observe_policy {
+            Some(ref mut policy) => policy(request, observed),
+            None => ObserveDecision::Accept,
+        }
+    }
+
+    async fn reject(&mut self, request: &CoAPRequest, status: Status, diagnostic: &str) {
         let register_address = request.source.unwrap();
         let resource_path = request.get_path();
 
-        debug!("deregister {} {}", register_address, resource_path);
+        debug!("reject {} {}: {}", register_address, resource_path, diagnostic);
 
-        self.remove_register_resource(&register_address, &resource_path, &request.get_token());
+        if self.remove_register_resource(&register_address, &resource_path, request.get_token()) {
+            self.state_changed();
+        }
+        if let Some(ref response) = request.response {
+            let mut response2 = response.clone();
+            response2.set_error(status, diagnostic);
+            self.send_message(&register_address, &response2.message).await;
+        }
     }
 
-    async fn resource_changed(&mut self, request: &CoAPRequest) {
+    fn deregister(&mut self, request: &CoAPRequest) {
+        let register_address = request.source.unwrap();
         let resource_path = request.get_path();
-        let ref resource_payload = request.message.payload;
 
+        debug!("deregister {} {}", register_address, resource_path);
+
+        if self.remove_register_resource(&register_address, &resource_path, request.get_token()) {
+            self.state_changed();
+        }
+    }
+
+    async fn resource_changed(&mut self, resource_path: &String, resource_payload: &Vec<u8>, options: Vec<(CoAPOption, Vec<u8>)>) {
         debug!("resource_changed {} {:?}", resource_path, resource_payload);
 
         let register_resource_keys: Vec<String>;
         {
-            let resource = self.record_resource(&resource_path, &resource_payload);
+            let resource = self.record_resource(resource_path, resource_payload, options);
             register_resource_keys = resource
                 .register_resources
                 .iter()
@@ -169,30 +870,65 @@ impl Observer {
                 .collect();
         }
 
+        let value = numeric_value(resource_payload);
+        let mut notified = Vec::new();
         for register_resource_key in register_resource_keys {
-            self.gen_message_id();
-            self.notify_register_with_newest_resource(&register_resource_key).await;
-            self.record_unacknowledge_message(&register_resource_key);
+            let register_resource = self.register_resources.get_mut(&register_resource_key).unwrap();
+            if let Some(value) = value {
+                if !register_resource.filter.admits(register_resource.last_value, value) {
+                    continue;
+                }
+            }
+            let held_back = register_resource.filter.min_period
+                .is_some_and(|min_period| register_resource.last_notified.elapsed() < min_period);
+            if held_back {
+                register_resource.deferred = true;
+                continue;
+            }
+            notified.push(register_resource_key);
         }
+        notified.sort();
+        self.notify_all(notified).await;
+        self.state_changed();
     }
 
     fn acknowledge(&mut self, request: &CoAPRequest) {
         self.remove_unacknowledge_message(&request.get_message_id(), &request.get_token());
     }
 
-    fn record_register_resource(&mut self, address: &SocketAddr, path: &String, token: &Vec<u8>) {
-        let resource = self.resources.get_mut(path).unwrap();
+    fn record_register_resource(
+        &mut self,
+        address: &SocketAddr,
+        path: &String,
+        token: &[u8],
+        filter: ObserveFilter,
+        dtls: bool,
+    ) -> Result<(), BudgetExceeded> {
         let register_key = Self::format_register(&address);
         let register_resource_key = Self::format_register_resource(&address, path);
+        if !self.register_resources.contains_key(&register_resource_key) {
+            self.budget.reserve(BudgetResource::Observers, 1)?;
+        }
+        let resource = self.resources.get_mut(path).unwrap();
 
-        self.register_resources
+        let register_resource = self.register_resources
             .entry(register_resource_key.clone())
             .or_insert(RegisterResourceItem {
                 register: register_key.clone(),
                 resource: path.clone(),
-                token: token.clone(),
+                token: token.to_vec(),
                 unacknowledge_message: None,
+                filter: ObserveFilter::default(),
+                dtls,
+                last_value: None,
+                last_notified: Instant::now(),
+                deferred: false,
             });
+        register_resource.filter = filter;
+        register_resource.dtls = dtls;
+        register_resource.last_value = numeric_value(&resource.payload);
+        register_resource.last_notified = Instant::now();
+        register_resource.deferred = false;
         resource
             .register_resources
             .replace(register_resource_key.clone());
@@ -212,6 +948,7 @@ impl Observer {
                 v.insert(register);
             }
         };
+        Ok(())
     }
 
     fn remove_register_resource(
@@ -231,6 +968,7 @@ impl Observer {
                 self.unacknowledge_messages
                     .remove(&unacknowledge_message)
                     .unwrap();
+                self.exchanges.finish(address, unacknowledge_message);
             }
 
             assert_eq!(
@@ -255,23 +993,26 @@ impl Observer {
             if remove_register {
                 self.registers.remove(&register_resource.register);
             }
+            self.budget.release(BudgetResource::Observers, 1);
         }
 
         self.register_resources.remove(&register_resource_key);
         return true;
     }
 
-    fn record_resource(&mut self, path: &String, payload: &Vec<u8>) -> &ResourceItem {
-        match self.resources.entry(path.clone()) {
+    fn record_resource(&mut self, path: &str, payload: &[u8], options: Vec<(CoAPOption, Vec<u8>)>) -> &ResourceItem {
+        match self.resources.entry(path.to_string()) {
             Entry::Occupied(resource) => {
                 let mut r = resource.into_mut();
                 r.sequence += 1;
-                r.payload = payload.clone();
+                r.payload = payload.to_vec();
+                r.options = options;
                 return r;
             }
             Entry::Vacant(v) => {
                 return v.insert(ResourceItem {
-                    payload: payload.clone(),
+                    payload: payload.to_vec(),
+                    options,
                     register_resources: HashSet::new(),
                     sequence: 0,
                 });
@@ -285,11 +1026,15 @@ impl Observer {
         let register_resource = self.register_resources
             .get_mut(register_resource_key)
             .unwrap();
+        let address: SocketAddr = register_resource.register.parse().unwrap();
         if let Some(old_message_id) = register_resource.unacknowledge_message {
             self.unacknowledge_messages.remove(&old_message_id);
+            self.exchanges.finish(&address, old_message_id);
         }
 
         register_resource.unacknowledge_message = Some(message_id);
+        // the notifications are bounded by the observers, the registry has no budget
+        let _ = self.exchanges.start(address, message_id, register_resource.token.clone());
         self.unacknowledge_messages.insert(
             message_id,
             UnacknowledgeMessageItem {
@@ -316,6 +1061,7 @@ impl Observer {
             }
         }
 
+        let address: SocketAddr = register_resource.register.parse().unwrap();
         if !try_again {
             warn!(
                 "unacknowledge_message try times exceeded  {}",
@@ -324,6 +1070,9 @@ impl Observer {
 
             register_resource.unacknowledge_message = None;
             self.unacknowledge_messages.remove(message_id);
+            self.exchanges.finish(&address, *message_id);
+        } else {
+            self.exchanges.retransmitted(&address, *message_id);
         }
 
         return try_again;
@@ -339,11 +1088,28 @@ impl Observer {
             }
 
             register_resource.unacknowledge_message = None;
+            self.exchanges.finish(&register_resource.register.parse().unwrap(), *message_id);
         }
 
         self.unacknowledge_messages.remove(message_id);
     }
 
+    fn abort_unacknowledge_message(&mut self, address: &SocketAddr, message_id: u16) {
+        let register_resource_key = match self.unacknowledge_messages.get(&message_id) {
+            Some(message) => message.register_resource.clone(),
+            None => return,
+        };
+        if let Some(register_resource) = self.register_resources.get_mut(&register_resource_key) {
+            if register_resource.register != Self::format_register(address) {
+                return;
+            }
+            register_resource.unacknowledge_message = None;
+        }
+
+        debug!("abort notification {} {}", register_resource_key, message_id);
+        self.unacknowledge_messages.remove(&message_id);
+    }
+
     async fn notify_register_with_newest_resource(&mut self, register_resource_key: &String) {
         let message_id = self.current_message_id;
 
@@ -367,6 +1133,9 @@ impl Observer {
             message.set_observe(sequence_bin);
             message.header.set_message_id(message_id);
             message.payload = resource.payload.clone();
+            for (option, value) in resource.options.iter() {
+                message.add_option(*option, value.clone());
+            }
 
             address = register_resource.register.parse().unwrap();
         }
@@ -393,6 +1162,12 @@ impl Observer {
     }
 }
 
+impl Drop for Observer {
+    fn drop(&mut self) {
+        self.budget.release(BudgetResource::Observers, self.register_resources.len());
+    }
+}
+
 
 #[cfg(test)]
 mod test {
@@ -436,7 +1211,7 @@ mod test {
 
         let server_address = &format!("127.0.0.1:{}", server_port);
 
-        let mut client = CoAPClient::new(server_address).unwrap();
+        let client = CoAPClient::new(server_address).unwrap();
 
         tx.send(step).unwrap();
         let mut request = CoAPRequest::new();
@@ -450,7 +1225,7 @@ mod test {
         let payload2_clone = payload2.clone();
 
         let mut receive_step = 1;
-        client.observe(path, move |msg| {
+        let _observation = client.observe(path, move |msg| {
             match rx.try_recv() {
                 Ok(n) => receive_step = n,
                 _ => (),
@@ -487,7 +1262,7 @@ mod test {
 
         let server_address = &format!("127.0.0.1:{}", server_port);
 
-        let mut client = CoAPClient::new(server_address).unwrap();
+        let client = CoAPClient::new(server_address).unwrap();
 
         let mut request = CoAPRequest::new();
         request.set_method(Method::Put);
@@ -497,11 +1272,14 @@ mod test {
         client.receive().unwrap();
 
         let payload1_clone = payload1.clone();
-        client.observe(path, move |msg| {
+        let mut observation = client.observe(path, move |msg| {
             assert_eq!(msg.payload, payload1_clone);
         }).unwrap();
+        assert!(observation.is_active());
+        assert_eq!(observation.path(), path);
 
-        client.unobserve();
+        observation.cancel().unwrap();
+        assert!(!observation.is_active());
 
         request.set_payload(payload2.clone());
 
@@ -510,14 +1288,496 @@ mod test {
         client3.receive().unwrap();
     }
 
+    #[test]
+    fn test_observation_handle_drop() {
+        let (port_tx, port_rx) = mpsc::channel();
+        let (state_tx, state_rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new().unwrap().block_on(async move {
+                let mut server = Server::new("127.0.0.1:0").unwrap();
+                server.set_observe_state_hook(move |state| state_tx.send(state.registrations()).unwrap());
+                port_tx.send(server.socket_addr().unwrap().port()).unwrap();
+                server.run(request_handler).await.unwrap();
+            })
+        });
+        let server_address = format!("127.0.0.1:{}", port_rx.recv().unwrap());
+
+        let client = CoAPClient::new(&server_address).unwrap();
+        let mut request = CoAPRequest::new();
+        request.set_method(Method::Put);
+        request.set_path("/test");
+        request.set_payload(b"data".to_vec());
+        client.send(&request).unwrap();
+        client.receive().unwrap();
+        assert_eq!(state_rx.recv_timeout(Duration::new(5, 0)).unwrap(), 0);
+
+        let observation = client.observe("/test", |_msg| {}).unwrap();
+        assert_eq!(state_rx.recv_timeout(Duration::new(5, 0)).unwrap(), 1);
+        drop(observation);
+        assert_eq!(state_rx.recv_timeout(Duration::new(5, 0)).unwrap(), 0);
+
+        let mut observation = client.observe("/test", |_msg| {}).unwrap();
+        assert_eq!(state_rx.recv_timeout(Duration::new(5, 0)).unwrap(), 1);
+        observation.set_cancel_on_drop(false);
+        drop(observation);
+        assert!(state_rx.recv_timeout(Duration::from_millis(1500)).is_err());
+    }
+
+    #[test]
+    fn test_observation_supervision() {
+        let path = "/test";
+        let server_port = server::test::spawn_server(request_handler).recv().unwrap();
+        let server_address = format!("127.0.0.1:{}", server_port);
+
+        let mut client = CoAPClient::new(&server_address).unwrap();
+        client.set_max_observe_restarts(1);
+        let mut request = CoAPRequest::new();
+        request.set_method(Method::Put);
+        request.set_path(path);
+        request.set_payload(b"data1".to_vec());
+        client.send(&request).unwrap();
+        client.receive().unwrap();
+
+        // the handler accepts the first representation and panics on every notification
+        let (tx, rx) = mpsc::channel();
+        let mut observation = client.observe(path, move |msg| {
+            tx.send(msg.payload.clone()).unwrap();
+            assert_eq!(msg.payload, b"data1".to_vec());
+        }).unwrap();
+        assert_eq!(observation.state(), ObservationState::Active);
+
+        let writer = CoAPClient::new(&server_address).unwrap();
+        request.set_payload(b"data2".to_vec());
+        writer.send(&request).unwrap();
+        writer.receive().unwrap();
+
+        // the panic restarts the thread, which registers again and fails on the answer
+        assert_eq!(rx.recv_timeout(Duration::new(5, 0)).unwrap(), b"data1".to_vec());
+        assert_eq!(rx.recv_timeout(Duration::new(5, 0)).unwrap(), b"data2".to_vec());
+        assert_eq!(rx.recv_timeout(Duration::new(5, 0)).unwrap(), b"data2".to_vec());
+        let error = observation.await_terminated().unwrap_err();
+        assert!(error.to_string().ends_with("the observation handler panicked"));
+        assert_eq!(observation.state(), ObservationState::Failed("the observation handler panicked".to_string()));
+        assert_eq!(observation.restarts(), 1);
+    }
+
+    #[test]
+    fn test_observe_state_restore() {
+        let path = "/test";
+        let (port_tx, port_rx) = mpsc::channel();
+        let (state_tx, state_rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new().unwrap().block_on(async move {
+                let mut server = Server::new("127.0.0.1:0").unwrap();
+                server.set_observe_state_hook(move |state| state_tx.send(state).unwrap());
+                port_tx.send(server.socket_addr().unwrap().port()).unwrap();
+                server.run(request_handler).await.unwrap();
+            })
+        });
+        let server_port = port_rx.recv().unwrap();
+
+        let client = CoAPClient::new(format!("127.0.0.1:{}", server_port)).unwrap();
+        let mut request = CoAPRequest::new();
+        request.set_method(Method::Put);
+        request.set_path(path);
+        request.set_payload(b"data1".to_vec());
+        client.send(&request).unwrap();
+        client.receive().unwrap();
+        assert_eq!(state_rx.recv_timeout(Duration::new(5, 0)).unwrap().registrations(), 0);
+
+        let _observation = client.observe(path, |_msg| {}).unwrap();
+        let state = state_rx.recv_timeout(Duration::new(5, 0)).unwrap();
+        assert_eq!(state.registrations(), 1);
+
+        let restored = ObserveState::from_bytes(&state.to_bytes()).unwrap();
+        assert_eq!(restored, state);
+        tokio::runtime::Runtime::new().unwrap().block_on(async move {
+            let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+            let mut observer = Observer::new(tx);
+            observer.restore_state(restored);
+            assert_eq!(observer.export_state(), state);
+        });
+    }
+
+    #[test]
+    fn test_observe_state_handover() {
+        let (port_tx, port_rx) = mpsc::channel();
+        let (state_tx, state_rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new().unwrap().block_on(async move {
+                let mut server = Server::new("127.0.0.1:0").unwrap();
+                server.set_observe_state_hook(move |state| state_tx.send(state).unwrap());
+                port_tx.send(server.socket_addr().unwrap().port()).unwrap();
+                server.run(request_handler).await.unwrap();
+            })
+        });
+        let client = CoAPClient::new(format!("127.0.0.1:{}", port_rx.recv().unwrap())).unwrap();
+        for path in &["/test", "/other"] {
+            let mut request = CoAPRequest::new();
+            request.set_method(Method::Put);
+            request.set_path(path);
+            request.set_payload(b"data".to_vec());
+            client.send(&request).unwrap();
+            client.receive().unwrap();
+        }
+        let _test = client.observe("/test", |_msg| {}).unwrap();
+        let _other = client.observe("/other", |_msg| {}).unwrap();
+        let state = loop {
+            let state = state_rx.recv_timeout(Duration::new(5, 0)).unwrap();
+            if state.registrations() == 2 {
+                break state;
+            }
+        };
+
+        tokio::runtime::Runtime::new().unwrap().block_on(async move {
+            let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+            let mut draining = Observer::new(tx.clone());
+            draining.restore_state(state.clone());
+            let drained = draining.drain_state(|_, path| path.contains("test"));
+            assert_eq!(drained.registrations(), 1);
+            assert_eq!(draining.export_state().registrations(), 1);
+            assert_eq!(draining.drain_state(|_, _| false), ObserveState::default());
+
+            let mut taking_over = Observer::new(tx.clone());
+            taking_over.import_state(draining.drain_state(|_, _| true));
+            taking_over.import_state(ObserveState::from_bytes(&drained.to_bytes()).unwrap());
+            assert_eq!(taking_over.export_state(), state);
+            assert_eq!(draining.export_state().registrations(), 0);
+
+            // a DTLS observer stays with the instance holding its session
+            let mut secured = state.clone();
+            secured.registrations[0].dtls = true;
+            draining.restore_state(secured.clone());
+            assert_eq!(draining.drain_state(|_, _| true).registrations(), 1);
+            assert_eq!(draining.export_state().registrations(), 1);
+            let mut restarted = Observer::new(tx.clone());
+            restarted.import_state(secured);
+            assert_eq!(restarted.export_state().registrations(), 1);
+        });
+    }
+
     #[test]
     fn test_observe_without_resource() {
         let path = "/test";
 
         let server_port = server::test::spawn_server(request_handler).recv().unwrap();
 
-        let mut client = CoAPClient::new(format!("127.0.0.1:{}", server_port)).unwrap();
+        let client = CoAPClient::new(format!("127.0.0.1:{}", server_port)).unwrap();
         let error = client.observe(path, |_msg| {}).unwrap_err();
         assert_eq!(error.kind(), ErrorKind::NotFound);
     }
+
+    #[test]
+    fn test_observe_policy() {
+        let (port_tx, port_rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new().unwrap().block_on(async move {
+                let mut server = Server::new("127.0.0.1:0").unwrap();
+                server.set_observe_policy(|request, observed| {
+                    if request.get_path() == "secret" {
+                        ObserveDecision::Reject(Status::Forbidden, "observing secrets is not allowed".to_string())
+                    } else if observed >= 1 {
+                        ObserveDecision::Reject(Status::ServiceUnavailable, "quota exceeded".to_string())
+                    } else {
+                        ObserveDecision::Accept
+                    }
+                });
+                port_tx.send(server.socket_addr().unwrap().port()).unwrap();
+                server.run(request_handler).await.unwrap();
+            })
+        });
+        let server_address = format!("127.0.0.1:{}", port_rx.recv().unwrap());
+
+        let client = CoAPClient::new(&server_address).unwrap();
+        for path in ["/test", "/secret"].iter() {
+            let mut request = CoAPRequest::new();
+            request.set_method(Method::Put);
+            request.set_path(path);
+            request.set_payload(b"data".to_vec());
+            client.send(&request).unwrap();
+            client.receive().unwrap();
+        }
+
+        let client = CoAPClient::new(&server_address).unwrap();
+        let error = client.observe("/secret", |_msg| {}).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+        assert!(error.to_string().contains("observing secrets is not allowed"));
+
+        let client = CoAPClient::new(&server_address).unwrap();
+        let _observation = client.observe("/test", |_msg| {}).unwrap();
+
+        // refreshing the registration the peer holds stays within its quota
+        let client = CoAPClient::new(&server_address).unwrap();
+        for _ in 0..2 {
+            let mut request = CoAPRequest::new();
+            request.set_path("/test");
+            request.set_observe(vec![ObserveOption::Register as u8]);
+            request.set_message_id(client.next_message_id());
+            request.set_token(vec![0x51]);
+            client.send(&request).unwrap();
+            let response = client.receive().unwrap();
+            assert_eq!(*response.get_status(), Status::Content);
+            assert!(response.message.get_observe().is_some());
+        }
+    }
+
+    #[test]
+    fn test_observe_budget() {
+        let budget = MemoryBudget::new().with_max_observers(1);
+        let server_budget = budget.clone();
+        let (port_tx, port_rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new().unwrap().block_on(async move {
+                let mut server = Server::new("127.0.0.1:0").unwrap();
+                server.set_memory_budget(server_budget);
+                port_tx.send(server.socket_addr().unwrap().port()).unwrap();
+                server.run(request_handler).await.unwrap();
+            })
+        });
+        let server_address = format!("127.0.0.1:{}", port_rx.recv().unwrap());
+
+        let client = CoAPClient::new(&server_address).unwrap();
+        let mut request = CoAPRequest::new();
+        request.set_method(Method::Put);
+        request.set_path("/test");
+        request.set_payload(b"data".to_vec());
+        client.send(&request).unwrap();
+        client.receive().unwrap();
+
+        let mut observe = CoAPRequest::new();
+        observe.set_path("/test");
+        observe.set_token(vec![1]);
+        observe.set_observe(vec![ObserveOption::Register as u8]);
+        client.send(&observe).unwrap();
+        assert!(client.receive().unwrap().message.get_observe().is_some());
+        assert_eq!(budget.usage().observers, 1);
+
+        // beyond the budget the representation is returned without observing
+        let client2 = CoAPClient::new(&server_address).unwrap();
+        client2.send(&observe).unwrap();
+        let response = client2.receive().unwrap();
+        assert_eq!(response.message.payload, b"data".to_vec());
+        assert!(response.message.get_observe().is_none());
+
+        observe.set_observe(vec![ObserveOption::Deregister as u8]);
+        client.send(&observe).unwrap();
+        client.receive().unwrap();
+        assert_eq!(budget.usage().observers, 0);
+    }
+
+    #[test]
+    fn test_wait_for_change() {
+        let server_port = server::test::spawn_server(request_handler).recv().unwrap();
+        let server_address = format!("127.0.0.1:{}", server_port);
+        let update = move |value: &str| {
+            let client = CoAPClient::new(&server_address).unwrap();
+            let mut request = CoAPRequest::new();
+            request.set_method(Method::Put);
+            request.set_path("/valve");
+            request.set_payload(value.as_bytes().to_vec());
+            client.send(&request).unwrap();
+            client.receive().unwrap();
+        };
+        update("moving");
+
+        let url = format!("coap://127.0.0.1:{}/valve", server_port);
+        let error = CoAPClient::wait_for_change(&url, |_| false, Duration::from_millis(300)).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+
+        let updater = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(200));
+            update("stuck");
+            update("closed");
+        });
+        let mut seen = Vec::new();
+        let response = CoAPClient::wait_for_change(&url, |response| {
+            seen.push(response.message.payload.clone());
+            response.message.payload == b"closed".to_vec()
+        }, Duration::from_secs(5)).unwrap();
+        assert_eq!(response.message.payload, b"closed".to_vec());
+        assert_eq!(seen, vec![b"moving".to_vec(), b"stuck".to_vec(), b"closed".to_vec()]);
+        updater.join().unwrap();
+    }
+
+    #[test]
+    fn test_observe_with_query() {
+        let server_port = server::test::spawn_server(request_handler).recv().unwrap();
+        let server_address = format!("127.0.0.1:{}", server_port);
+        let client = CoAPClient::new(&server_address).unwrap();
+        let update = |value: &str| {
+            let mut request = CoAPRequest::new();
+            request.set_method(Method::Put);
+            request.set_path("/temp");
+            request.set_payload(value.as_bytes().to_vec());
+            client.send(&request).unwrap();
+            client.receive().unwrap();
+        };
+        update("20");
+
+        let observer = CoAPClient::new(&server_address).unwrap();
+        assert!(observer.observe("/temp?gt=abc", |_msg| {}).is_err());
+        let (tx, rx) = mpsc::channel();
+        let _observation = observer.observe("/temp?gt=30", move |msg| tx.send(msg.payload).unwrap()).unwrap();
+        assert_eq!(rx.recv_timeout(Duration::new(5, 0)).unwrap(), b"20".to_vec());
+
+        for value in ["25", "35", "36", "28"].iter() {
+            update(value);
+        }
+        assert_eq!(rx.recv_timeout(Duration::new(5, 0)).unwrap(), b"35".to_vec());
+        assert_eq!(rx.recv_timeout(Duration::new(5, 0)).unwrap(), b"28".to_vec());
+        assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+    }
+
+    #[test]
+    fn test_observe_filter() {
+        let filter = ObserveFilter { less_than: Some(10.0), step: Some(5.0), ..ObserveFilter::default() };
+        assert!(!filter.admits(Some(20.0), 16.0));
+        assert!(filter.admits(Some(20.0), 15.0));
+        assert!(filter.admits(Some(11.0), 9.5));
+        assert!(filter.admits(None, 11.0));
+        assert!(ObserveFilter::default().admits(Some(1.0), 1.0));
+    }
+
+    #[test]
+    fn test_notification_periods() {
+        tokio::runtime::Runtime::new().unwrap().block_on(async move {
+            let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+            let mut observer = Observer::new(tx);
+            let peer: SocketAddr = "127.0.0.1:5683".parse().unwrap();
+            let request = |method: Method, payload: &[u8], query: &str| {
+                let mut packet = Packet::new();
+                packet.header.set_type(MessageType::Confirmable);
+                let mut request = CoAPRequest::from_packet(packet, &peer);
+                request.set_method(method);
+                request.set_path("/temp");
+                request.set_query(query);
+                request.set_payload(payload.to_vec());
+                request
+            };
+
+            observer.request_handler(&request(Method::Put, b"20", "")).await;
+            let mut register = request(Method::Get, b"", "pmin=1&pmax=2");
+            register.set_observe(vec![ObserveOption::Register as u8]);
+            register.set_token(vec![0x51]);
+            assert!(!observer.request_handler(&register).await);
+            assert_eq!(rx.try_recv().unwrap().0.payload, b"20".to_vec());
+            for query in ["pmin=3&pmax=2", "pmin=1e20", "pmax=1e300"].iter() {
+                let mut invalid = request(Method::Get, b"", query);
+                invalid.set_observe(vec![ObserveOption::Register as u8]);
+                observer.request_handler(&invalid).await;
+                assert_eq!(rx.try_recv().unwrap().0.header.code, MessageClass::Response(Status::BadRequest));
+            }
+
+            // held back by pmin, then sent with the latest state
+            observer.request_handler(&request(Method::Put, b"21", "")).await;
+            observer.request_handler(&request(Method::Put, b"22", "")).await;
+            assert!(rx.try_recv().is_err());
+            std::thread::sleep(Duration::from_millis(1100));
+            observer.timer_handler().await;
+            let (notification, _) = rx.try_recv().unwrap();
+            assert_eq!(notification.payload, b"22".to_vec());
+            assert!(rx.try_recv().is_err());
+
+            let mut ack = Packet::new();
+            ack.header.set_type(MessageType::Acknowledgement);
+            ack.header.set_message_id(notification.header.get_message_id());
+            ack.set_token(vec![0x51]);
+            observer.request_handler(&CoAPRequest::from_packet(ack, &peer)).await;
+
+            // repeated by pmax without a change
+            std::thread::sleep(Duration::from_millis(2100));
+            observer.timer_handler().await;
+            assert_eq!(rx.try_recv().unwrap().0.payload, b"22".to_vec());
+        });
+    }
+
+    #[test]
+    fn test_notification_pacing() {
+        tokio::runtime::Runtime::new().unwrap().block_on(async move {
+            let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+            let mut observer = Observer::new(tx);
+            observer.set_notification_pacing(NotificationPacing::default().with_rate(10.0).with_burst(2));
+            let request = |method: Method, port: u16| {
+                let mut packet = Packet::new();
+                packet.header.set_type(MessageType::Confirmable);
+                let mut request = CoAPRequest::from_packet(packet, &SocketAddr::from(([127, 0, 0, 1], port)));
+                request.set_method(method);
+                request.set_path("/temp");
+                request.set_payload(port.to_string().into_bytes());
+                request
+            };
+
+            observer.request_handler(&request(Method::Put, 5683)).await;
+            for port in 6000..6005 {
+                let mut register = request(Method::Get, port);
+                register.set_observe(vec![ObserveOption::Register as u8]);
+                observer.request_handler(&register).await;
+                rx.try_recv().unwrap();
+            }
+
+            // the burst goes out at once, the rest at the rate
+            observer.request_handler(&request(Method::Put, 5684)).await;
+            assert!(rx.try_recv().is_ok());
+            assert!(rx.try_recv().is_ok());
+            assert!(rx.try_recv().is_err());
+            observer.timer_handler().await;
+            assert!(rx.try_recv().is_err());
+
+            // a further change is coalesced into the queued notifications, and queues those
+            // already sent again
+            observer.request_handler(&request(Method::Put, 5685)).await;
+            assert!(rx.try_recv().is_err());
+            let mut peers = HashSet::new();
+            for _ in 0..5 {
+                std::thread::sleep(Duration::from_millis(110));
+                observer.timer_handler().await;
+                let (notification, peer) = rx.try_recv().unwrap();
+                assert_eq!(notification.payload, b"5685".to_vec());
+                peers.insert(peer);
+                assert!(rx.try_recv().is_err());
+            }
+            assert_eq!(peers.len(), 5);
+            std::thread::sleep(Duration::from_millis(110));
+            observer.timer_handler().await;
+            assert!(rx.try_recv().is_err());
+        });
+    }
+
+    #[test]
+    fn test_notification_window() {
+        tokio::runtime::Runtime::new().unwrap().block_on(async move {
+            let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+            let mut observer = Observer::new(tx);
+            let pacing = NotificationPacing::default().with_window(Duration::from_millis(300));
+            observer.set_notification_pacing(pacing);
+            let request = |method: Method, port: u16| {
+                let mut packet = Packet::new();
+                packet.header.set_type(MessageType::Confirmable);
+                let mut request = CoAPRequest::from_packet(packet, &SocketAddr::from(([127, 0, 0, 1], port)));
+                request.set_method(method);
+                request.set_path("/temp");
+                request
+            };
+
+            observer.request_handler(&request(Method::Put, 5683)).await;
+            for port in 6000..6003 {
+                let mut register = request(Method::Get, port);
+                register.set_observe(vec![ObserveOption::Register as u8]);
+                observer.request_handler(&register).await;
+                rx.try_recv().unwrap();
+            }
+
+            // spread over the window although the bucket holds enough tokens
+            observer.request_handler(&request(Method::Put, 5684)).await;
+            assert!(rx.try_recv().is_ok());
+            assert!(rx.try_recv().is_err());
+            std::thread::sleep(Duration::from_millis(110));
+            observer.timer_handler().await;
+            assert!(rx.try_recv().is_ok());
+            assert!(rx.try_recv().is_err());
+            std::thread::sleep(Duration::from_millis(110));
+            observer.timer_handler().await;
+            assert!(rx.try_recv().is_ok());
+        });
+    }
 }
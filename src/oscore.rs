@@ -0,0 +1,58 @@
+use super::message::packet::{CoAPOption, Packet};
+
+/// How OSCORE protects an option (RFC 8613 §4.1).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OptionClass {
+    /// Encrypted and integrity protected, carried inside the protected payload.
+    E,
+    /// Integrity protected only, carried in the outer message.
+    I,
+    /// Unprotected, carried in the outer message for proxies to act on.
+    U,
+}
+
+/// Returns the class of an option in a protected message. Observe, Max-Age, the block
+/// options, Size1, Size2 and No-Response have an inner class E value and may also appear
+/// as outer options for proxies; they are reported as U. Unknown options are class E.
+pub fn option_class(number: usize) -> OptionClass {
+    match number {
+        3 | 7 | 9 | 35 | 39 => OptionClass::U, // Uri-Host, Uri-Port, OSCORE, Proxy-Uri, Proxy-Scheme
+        6 | 14 | 23 | 27 | 28 | 60 | 258 => OptionClass::U,
+        _ => OptionClass::E,
+    }
+}
+
+/// Checks whether a message is protected with OSCORE, i.e. carries the OSCORE option.
+pub fn is_protected(message: &Packet) -> bool {
+    message.get_option(CoAPOption::Oscore).is_some_and(|list| !list.is_empty())
+}
+
+/// The input parameters of an OSCORE security context (RFC 8613 §3.2), as
+/// established by a key exchange such as EDHOC.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SecurityContext {
+    pub master_secret: Vec<u8>,
+    pub master_salt: Vec<u8>,
+    pub sender_id: Vec<u8>,
+    pub recipient_id: Vec<u8>,
+    pub id_context: Option<Vec<u8>>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_option_classes() {
+        assert_eq!(option_class(3), OptionClass::U);
+        assert_eq!(option_class(9), OptionClass::U);
+        assert_eq!(option_class(11), OptionClass::E);
+        assert_eq!(option_class(12), OptionClass::E);
+        assert_eq!(option_class(65000), OptionClass::E);
+
+        let mut packet = Packet::new();
+        assert!(!is_protected(&packet));
+        packet.add_option(CoAPOption::Oscore, vec![]);
+        assert!(is_protected(&packet));
+    }
+}
@@ -0,0 +1,490 @@
+//! Non-blocking client and server drivers for single-threaded targets.
+//!
+//! Instead of blocking or spawning threads, `PollingClient` and `PollingServer` do a bounded
+//! amount of work per `poll` call: at most `budget` received packets and expired timers.
+//! A superloop calls `poll` whenever the socket is readable or `next_timeout` is reached,
+//! and is free to do other work in between.
+
+use std::io::{Error, ErrorKind, Result};
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+use log::*;
+
+use super::client::TransmissionParameters;
+use super::ids::{IdGenerator, IdState};
+use super::message::header::{MessageClass, MessageType};
+use super::message::packet::Packet;
+use super::message::request::CoAPRequest;
+use super::message::response::CoAPResponse;
+use super::message::IsMessage;
+use super::server::{ResponseCache, EXCHANGE_LIFETIME};
+
+/// What became of a request sent by a `PollingClient`, identified by its message ID.
+#[derive(Clone, Debug)]
+pub enum Outcome {
+    Response(u16, CoAPResponse),
+    /// No response arrived in time.
+    TimedOut(u16),
+    /// The server rejected the request with a Reset.
+    Reset(u16),
+}
+
+struct Outstanding {
+    message_id: u16,
+    token: Vec<u8>,
+    message: Packet,
+    confirmable: bool,
+    acknowledged: bool,
+    retransmissions: u32,
+    timeout: Duration,
+    // when the request is retransmitted, or given up on once no retransmission is left
+    deadline: Instant,
+    expires_at: Instant,
+}
+
+/// A client whose exchanges progress only in `poll`.
+pub struct PollingClient {
+    socket: UdpSocket,
+    peer_addr: SocketAddr,
+    transmission: TransmissionParameters,
+    outstanding: Vec<Outstanding>,
+    ids: IdGenerator,
+}
+
+impl PollingClient {
+    /// Creates a client for the peer on a non-blocking socket.
+    pub fn new<A: ToSocketAddrs>(addr: A) -> Result<PollingClient> {
+        let peer_addr = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| Error::other("no address"))?;
+        let bind_addr = if peer_addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = UdpSocket::bind(bind_addr)?;
+        socket.set_nonblocking(true)?;
+        Ok(PollingClient {
+            socket,
+            peer_addr,
+            transmission: TransmissionParameters::default(),
+            outstanding: Vec::new(),
+            ids: IdGenerator::new(IdState::seeded()),
+        })
+    }
+
+    /// The socket to wait on for readability, e.g. with `poll(2)`.
+    pub fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    /// The state of the message ID generator to store, see the `ids` module.
+    pub fn id_state(&self) -> IdState {
+        self.ids.state()
+    }
+
+    /// Continues the message IDs from a state stored before a reboot.
+    pub fn restore_id_state(&mut self, state: IdState) {
+        self.ids.restore(state);
+    }
+
+    /// Sets a hook receiving the state to store whenever the generator reserves further
+    /// message IDs.
+    pub fn set_id_state_hook<F: FnMut(IdState) + Send + 'static>(&mut self, hook: F) {
+        self.ids.set_hook(hook);
+    }
+
+    pub fn set_transmission_parameters(&mut self, transmission: TransmissionParameters) {
+        self.transmission = transmission;
+    }
+
+    /// Sends a request without waiting for its response, which a later `poll` reports.
+    /// The request gets a fresh message ID, returned, and a token unless it has one.
+    pub fn send(&mut self, request: &mut CoAPRequest) -> Result<u16> {
+        let transmission = self.transmission;
+        let message_id = self.ids.next_message_id();
+        request.set_message_id(message_id);
+        request.set_type(if transmission.confirmable {
+            MessageType::Confirmable
+        } else {
+            MessageType::NonConfirmable
+        });
+        if request.get_token().is_empty() {
+            request.set_token(self.ids.next_token());
+        }
+        send_packet(&self.socket, &self.peer_addr, &request.message)?;
+
+        // a NON request waits as long as a CON request may be retransmitted
+        let now = Instant::now();
+        let max_wait = transmission.timeout * (2u32.pow(transmission.max_retransmit + 1) - 1);
+        self.outstanding.push(Outstanding {
+            message_id,
+            token: request.get_token().clone(),
+            message: request.message.clone(),
+            confirmable: transmission.confirmable,
+            acknowledged: false,
+            retransmissions: 0,
+            timeout: transmission.timeout,
+            deadline: if transmission.confirmable { now + transmission.timeout } else { now + max_wait },
+            expires_at: now + max_wait,
+        });
+        Ok(message_id)
+    }
+
+    /// The number of requests awaiting their response.
+    pub fn pending(&self) -> usize {
+        self.outstanding.len()
+    }
+
+    /// When `poll` next has a timer to process, if any request is outstanding.
+    pub fn next_timeout(&self) -> Option<Instant> {
+        self.outstanding.iter().map(|exchange| exchange.deadline).min()
+    }
+
+    /// Processes at most `budget` received packets and expired timers, returning the
+    /// exchanges that completed.
+    pub fn poll(&mut self, budget: usize) -> Result<Vec<Outcome>> {
+        let mut outcomes = Vec::new();
+        let mut done = 0;
+        let mut buf = [0; 1500];
+        while done < budget {
+            let (nread, src) = match self.socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            };
+            done += 1;
+            match Packet::from_bytes(&buf[..nread]) {
+                Ok(packet) if src == self.peer_addr => {
+                    if let Some(outcome) = self.receive(packet)? {
+                        outcomes.push(outcome);
+                    }
+                }
+                Ok(_) => debug!("dropping packet from unknown peer {}", src),
+                Err(e) => debug!("dropping malformed packet from {}: {:?}", src, e),
+            }
+        }
+
+        let now = Instant::now();
+        while done < budget {
+            let idx = match self.outstanding.iter().position(|exchange| exchange.deadline <= now) {
+                Some(idx) => idx,
+                None => break,
+            };
+            done += 1;
+            let max_retransmit = self.transmission.max_retransmit;
+            let exchange = &mut self.outstanding[idx];
+            if exchange.confirmable && !exchange.acknowledged && exchange.retransmissions < max_retransmit {
+                exchange.retransmissions += 1;
+                exchange.timeout *= 2;
+                exchange.deadline = (now + exchange.timeout).min(exchange.expires_at);
+                send_packet(&self.socket, &self.peer_addr, &exchange.message)?;
+            } else {
+                outcomes.push(Outcome::TimedOut(self.outstanding.remove(idx).message_id));
+            }
+        }
+        Ok(outcomes)
+    }
+
+    fn receive(&mut self, packet: Packet) -> Result<Option<Outcome>> {
+        let message_id = packet.header.get_message_id();
+        let by_id = self.outstanding.iter().position(|exchange| exchange.message_id == message_id);
+        match packet.header.get_type() {
+            MessageType::Reset => {
+                Ok(by_id.map(|idx| Outcome::Reset(self.outstanding.remove(idx).message_id)))
+            }
+            MessageType::Acknowledgement if packet.header.code == MessageClass::Empty => {
+                // a separate response follows
+                if let Some(idx) = by_id {
+                    let exchange = &mut self.outstanding[idx];
+                    exchange.acknowledged = true;
+                    exchange.deadline = exchange.expires_at;
+                }
+                Ok(None)
+            }
+            MessageType::Acknowledgement => {
+                let idx = by_id.filter(|&idx| self.outstanding[idx].token == *packet.get_token());
+                Ok(idx.map(|idx| {
+                    let exchange = self.outstanding.remove(idx);
+                    Outcome::Response(exchange.message_id, CoAPResponse::received(packet))
+                }))
+            }
+            message_type => {
+                let idx = self.outstanding.iter().position(|exchange| exchange.token == *packet.get_token());
+                let matched = idx.is_some() && matches!(packet.header.code, MessageClass::Response(_));
+                if message_type == MessageType::Confirmable {
+                    let mut reply = Packet::new();
+                    reply.header.set_type(if matched { MessageType::Acknowledgement } else { MessageType::Reset });
+                    reply.header.code = MessageClass::Empty;
+                    reply.header.set_message_id(message_id);
+                    send_packet(&self.socket, &self.peer_addr, &reply)?;
+                }
+                if !matched {
+                    return Ok(None);
+                }
+                let exchange = self.outstanding.remove(idx.unwrap());
+                Ok(Some(Outcome::Response(exchange.message_id, CoAPResponse::received(packet))))
+            }
+        }
+    }
+}
+
+/// A server answering requests with its handler only in `poll`.
+///
+/// Duplicates of a confirmable request get the response sent to the first transmission for
+/// EXCHANGE_LIFETIME instead of reaching the handler again (RFC 7252 §4.5), as they do with
+/// `Server::set_exchange_lifetime`. At most 1024 responses are kept by default.
+pub struct PollingServer<F> {
+    socket: UdpSocket,
+    handler: F,
+    responses: ResponseCache,
+}
+
+impl<F: FnMut(CoAPRequest) -> Option<CoAPResponse>> PollingServer<F> {
+    /// Creates a server on a non-blocking socket bound to the address.
+    pub fn bind<A: ToSocketAddrs>(addr: A, handler: F) -> Result<PollingServer<F>> {
+        let socket = UdpSocket::bind(addr)?;
+        socket.set_nonblocking(true)?;
+        Ok(PollingServer {
+            socket,
+            handler,
+            responses: ResponseCache::new(Duration::from_secs(EXCHANGE_LIFETIME)),
+        })
+    }
+
+    /// The socket to wait on for readability, e.g. with `poll(2)`.
+    pub fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Set how long duplicates of a request are answered from the response cache.
+    pub fn set_exchange_lifetime(&mut self, exchange_lifetime: Duration) {
+        self.responses.set_lifetime(exchange_lifetime);
+    }
+
+    /// Caps the responses kept for duplicates, evicting the oldest first.
+    pub fn set_max_cached_responses(&mut self, max: usize) {
+        self.responses.set_max_entries(max);
+    }
+
+    /// When `poll` next has a timer to process, if any.
+    pub fn next_timeout(&self) -> Option<Instant> {
+        self.responses.next_expiration()
+    }
+
+    /// Processes at most `budget` received requests and expired timers, returning how many
+    /// were processed.
+    pub fn poll(&mut self, budget: usize) -> Result<usize> {
+        let mut done = self.responses.expire(Instant::now(), budget);
+
+        let mut buf = [0; 1500];
+        while done < budget {
+            let (nread, src) = match self.socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            };
+            done += 1;
+            let packet = match Packet::from_bytes(&buf[..nread]) {
+                Ok(packet) => packet,
+                Err(e) => {
+                    debug!("dropping malformed packet from {}: {:?}", src, e);
+                    continue;
+                }
+            };
+            self.handle(packet, src)?;
+        }
+        Ok(done)
+    }
+
+    fn handle(&mut self, packet: Packet, src: SocketAddr) -> Result<()> {
+        let message_id = packet.header.get_message_id();
+        let confirmable = packet.header.get_type() == MessageType::Confirmable;
+        if confirmable {
+            if let Some(response) = self.responses.duplicate(&src, message_id) {
+                debug!("answering duplicate {} from {}", message_id, src);
+                return match response {
+                    Some(response) => send_packet(&self.socket, &src, response),
+                    None => Ok(()),
+                };
+            }
+        }
+        match packet.header.code {
+            MessageClass::Request(_) => (),
+            // a CoAP ping is answered with a Reset
+            MessageClass::Empty if confirmable => {
+                let mut reset = Packet::new();
+                reset.header.set_type(MessageType::Reset);
+                reset.header.code = MessageClass::Empty;
+                reset.header.set_message_id(message_id);
+                return send_packet(&self.socket, &src, &reset);
+            }
+            _ => return Ok(()),
+        }
+
+        if confirmable {
+            self.responses.start(src, message_id);
+        }
+        let response = (self.handler)(CoAPRequest::from_packet(packet, &src)).map(|response| response.message);
+        if let Some(ref response) = response {
+            self.responses.answer(&src, response);
+            send_packet(&self.socket, &src, response)?;
+        }
+        Ok(())
+    }
+}
+
+fn send_packet(socket: &UdpSocket, addr: &SocketAddr, packet: &Packet) -> Result<()> {
+    let bytes = packet.to_bytes().map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    socket.send_to(&bytes, addr)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::message::request::Method;
+    use std::thread;
+
+    // blocks until a datagram is waiting on the non-blocking socket
+    fn wait_readable(socket: &UdpSocket) {
+        socket.set_nonblocking(false).unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        socket.peek_from(&mut [0; 1]).unwrap();
+        socket.set_nonblocking(true).unwrap();
+    }
+
+    fn wait_until(deadline: Instant) {
+        thread::sleep(deadline.saturating_duration_since(Instant::now()));
+    }
+
+    #[test]
+    fn test_superloop() {
+        let mut calls = 0;
+        let mut server = PollingServer::bind("127.0.0.1:0", |request: CoAPRequest| {
+            calls += 1;
+            let mut response = request.response?;
+            response.message.payload = request.message.payload;
+            Some(response)
+        })
+        .unwrap();
+        server.set_exchange_lifetime(Duration::from_millis(100));
+        let mut client = PollingClient::new(server.local_addr().unwrap()).unwrap();
+        client.set_transmission_parameters(TransmissionParameters::default().with_timeout(Duration::from_millis(50)));
+
+        let mut ids = Vec::new();
+        for payload in [b"a", b"b", b"c"].iter() {
+            let mut request = CoAPRequest::new();
+            request.set_method(Method::Post);
+            request.set_payload(payload.to_vec());
+            ids.push(client.send(&mut request).unwrap());
+        }
+        assert_eq!(client.pending(), 3);
+        assert!(client.next_timeout().is_some());
+
+        // nothing is processed until polled, and never more than the budget
+        wait_readable(server.socket());
+        assert_eq!(server.poll(2).unwrap(), 2);
+        assert_eq!(server.poll(10).unwrap(), 1);
+        let mut outcomes = Vec::new();
+        while outcomes.len() < 3 {
+            wait_readable(client.socket());
+            outcomes.extend(client.poll(10).unwrap());
+        }
+        match outcomes.remove(1) {
+            Outcome::Response(id, response) => {
+                assert_eq!(id, ids[1]);
+                assert_eq!(response.message.payload, b"b".to_vec());
+            }
+            other => panic!("unexpected outcome {:?}", other),
+        }
+        assert_eq!(client.pending(), 0);
+        assert!(client.next_timeout().is_none());
+
+        // a lost response is retransmitted and answered from the server's cache
+        let mut request = CoAPRequest::new();
+        request.set_method(Method::Post);
+        let id = client.send(&mut request).unwrap();
+        wait_readable(server.socket());
+        server.poll(10).unwrap();
+        let mut lost = [0; 1500];
+        wait_readable(client.socket());
+        client.socket().recv_from(&mut lost).unwrap();
+        wait_until(client.next_timeout().unwrap());
+        assert!(client.poll(10).unwrap().is_empty());
+        wait_readable(server.socket());
+        server.poll(10).unwrap();
+        wait_readable(client.socket());
+        match client.poll(10).unwrap().pop() {
+            Some(Outcome::Response(response_id, _)) => assert_eq!(response_id, id),
+            other => panic!("unexpected outcome {:?}", other),
+        }
+        // the cached responses expire one after the other
+        assert!(server.next_timeout().is_some());
+        while let Some(expires_at) = server.next_timeout() {
+            wait_until(expires_at);
+            server.poll(10).unwrap();
+        }
+        drop(server);
+        assert_eq!(calls, 4);
+    }
+
+    #[test]
+    fn test_cached_responses_bounded() {
+        let mut calls = 0;
+        let mut server = PollingServer::bind("127.0.0.1:0", |request: CoAPRequest| {
+            calls += 1;
+            request.response
+        })
+        .unwrap();
+        server.set_max_cached_responses(1);
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut requests = Vec::new();
+        for message_id in 1..=2 {
+            let mut request = CoAPRequest::new();
+            request.set_type(MessageType::Confirmable);
+            request.set_message_id(message_id);
+            requests.push(request.message.to_bytes().unwrap());
+        }
+
+        // the response to the first request is evicted by the second
+        let mut buf = [0; 1500];
+        for request in requests.iter().chain(requests.iter().rev()) {
+            client.send_to(request, server.local_addr().unwrap()).unwrap();
+            wait_readable(server.socket());
+            server.poll(1).unwrap();
+            client.recv_from(&mut buf).unwrap();
+        }
+        drop(server);
+        assert_eq!(calls, 3);
+    }
+
+    #[test]
+    fn test_timeout() {
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut client = PollingClient::new(silent.local_addr().unwrap()).unwrap();
+        client.set_transmission_parameters(TransmissionParameters::default()
+            .with_timeout(Duration::from_millis(10))
+            .with_max_retransmit(1));
+        let id = client.send(&mut CoAPRequest::new()).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(1);
+        let outcome = loop {
+            let mut outcomes = client.poll(1).unwrap();
+            if let Some(outcome) = outcomes.pop() {
+                break outcome;
+            }
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(5));
+        };
+        match outcome {
+            Outcome::TimedOut(timed_out) => assert_eq!(timed_out, id),
+            other => panic!("unexpected outcome {:?}", other),
+        }
+        let mut buf = [0; 1500];
+        silent.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        silent.recv_from(&mut buf).unwrap();
+        silent.recv_from(&mut buf).unwrap();
+    }
+}
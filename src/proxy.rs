@@ -0,0 +1,803 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use log::*;
+use url::Url;
+
+use super::budget::MemoryBudget;
+use super::cache::{remaining_max_age, CacheKey, CacheStats, CacheStore, ResponseCache};
+use super::client::{ends_observation, CoAPClient, ObservationHandle, ObservationState, TransmissionParameters};
+use super::message::header::MessageType;
+use super::message::packet::{decode_uint, encode_uint, CoAPOption, ObserveOption, Packet};
+use super::message::request::{CoAPRequest, Method};
+use super::message::response::{CoAPResponse, Status};
+use super::message::IsMessage;
+use super::oscore::{self, OptionClass};
+use super::resolve::{Resolver, SystemResolver};
+use super::server::MessageSender;
+use super::uri;
+
+const DEFAULT_CACHE_ENTRIES: usize = 1024;
+
+/// A forward proxy resolving requests that carry a Proxy-Uri option. Only coap URIs are
+/// forwarded; others are answered with 5.05 Proxying Not Supported.
+///
+/// Upstream exchanges are blocking, so call `handle` from a blocking context
+/// such as `tokio::task::spawn_blocking` when used inside a server handler.
+///
+/// Requests protected with OSCORE are forwarded with their outer options only and are
+/// not relayed as observations. The Proxy-Uri of such a request must not carry a path,
+/// which travels encrypted. Their responses are not cached unless enabled with
+/// `set_cache_protected`.
+pub struct ForwardProxy {
+    cache: ResponseCache,
+    cache_protected: bool,
+    transmission: TransmissionParameters,
+    notifier: Option<MessageSender>,
+    observations: HashMap<String, UpstreamObservation>,
+    resolver: Box<dyn Resolver>,
+}
+
+/// A single upstream observation shared by every downstream observer of a resource.
+struct UpstreamObservation {
+    // dropping the handle deregisters from the upstream server
+    observation: ObservationHandle,
+    relay: Arc<Mutex<Relay>>,
+}
+
+impl UpstreamObservation {
+    fn is_active(&self) -> bool {
+        !self.relay.lock().unwrap().ended && self.observation.state() == ObservationState::Active
+    }
+}
+
+#[derive(Default)]
+struct Relay {
+    subscribers: Vec<Subscriber>,
+    latest: Option<CoAPResponse>,
+    message_id: u16,
+    ended: bool,
+}
+
+struct Subscriber {
+    address: SocketAddr,
+    token: Vec<u8>,
+    sequence: u32,
+}
+
+impl Relay {
+    /// Fans an upstream notification out to the downstream observers. The last one, e.g. an
+    /// error response, is relayed without an Observe option and ends their observations too.
+    fn notify(&mut self, packet: Packet, notifier: &MessageSender) {
+        self.ended = ends_observation(&packet);
+        let response = CoAPResponse::received(packet);
+        for subscriber in self.subscribers.iter_mut() {
+            subscriber.sequence = (subscriber.sequence + 1) & 0xFF_FFFF;
+            self.message_id = self.message_id.wrapping_add(1);
+
+            let mut message = ForwardProxy::reply_message(&response);
+            message.header.set_type(MessageType::NonConfirmable);
+            message.header.set_message_id(self.message_id);
+            message.set_token(subscriber.token.clone());
+            if !self.ended {
+                message.set_observe(encode_uint(subscriber.sequence));
+            }
+
+            if let Err(e) = notifier.send((message, subscriber.address)) {
+                warn!("relay notification failed {}", e);
+            }
+        }
+        if self.ended {
+            self.subscribers.clear();
+        }
+        self.latest = Some(response);
+    }
+}
+
+impl ForwardProxy {
+    /// Creates a proxy with the default cache size and transmission parameters.
+    pub fn new() -> ForwardProxy {
+        Self::with_cache_entries(DEFAULT_CACHE_ENTRIES)
+    }
+
+    /// Creates a proxy caching at most `max_entries` responses.
+    pub fn with_cache_entries(max_entries: usize) -> ForwardProxy {
+        ForwardProxy {
+            cache: ResponseCache::new(max_entries),
+            cache_protected: false,
+            transmission: TransmissionParameters::default(),
+            notifier: None,
+            observations: HashMap::new(),
+            resolver: Box::new(SystemResolver),
+        }
+    }
+
+    /// Creates a proxy caching responses in the given store, e.g. one shared between replicas.
+    pub fn with_cache_store<S: CacheStore + 'static>(store: S) -> ForwardProxy {
+        ForwardProxy {
+            cache: ResponseCache::with_store(store),
+            cache_protected: false,
+            transmission: TransmissionParameters::default(),
+            notifier: None,
+            observations: HashMap::new(),
+            resolver: Box::new(SystemResolver),
+        }
+    }
+
+    /// Enables observe relaying, sending notifications to downstream observers through the
+    /// given sender, usually `Server::message_sender`.
+    ///
+    /// A single upstream observation is shared by all downstream observers of the same
+    /// Proxy-Uri and is deregistered when the last of them leaves. Without a sender, Observe
+    /// options are stripped and requests are answered once.
+    pub fn set_notification_sender(&mut self, sender: MessageSender) {
+        self.notifier = Some(sender);
+    }
+
+    /// Set the resolver for the hosts of Proxy-Uri options, the system's by default.
+    pub fn set_resolver<R: Resolver + 'static>(&mut self, resolver: R) {
+        self.resolver = Box::new(resolver);
+    }
+
+    /// Set how requests are transmitted upstream, the defaults of `CoAPClient` unless set.
+    /// The proxy answers 5.04 Gateway Timeout once the retransmissions run out.
+    pub fn set_upstream_transmission(&mut self, transmission: TransmissionParameters) {
+        self.transmission = transmission;
+    }
+
+    /// Set whether responses protected with OSCORE are cached. Only deterministic
+    /// requests, which repeat the same OSCORE option, can be answered from the cache.
+    pub fn set_cache_protected(&mut self, cache_protected: bool) {
+        self.cache_protected = cache_protected;
+    }
+
+    /// Caps the bytes of the cached responses, see the `budget` module.
+    pub fn set_memory_budget(&mut self, budget: MemoryBudget) {
+        self.cache.set_memory_budget(budget);
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    /// Resolves a proxied request, from the cache when possible.
+    ///
+    /// Upstream failures never surface as errors: timeouts are answered with 5.04 Gateway
+    /// Timeout, resets and malformed responses with 5.02 Bad Gateway, each carrying the
+    /// upstream diagnostic as payload.
+    pub fn handle(&mut self, request: &CoAPRequest) -> Option<CoAPResponse> {
+        let template = request.response.clone()?;
+
+        // relays whose upstream observation ended are torn down, so that a new observer
+        // registers upstream again
+        self.observations.retain(|proxy_uri, observation| {
+            let active = observation.is_active();
+            if !active {
+                debug!("upstream observation of {} ended", proxy_uri);
+            }
+            active
+        });
+
+        let proxy_uri = match request.get_option(CoAPOption::ProxyUri).and_then(|list| list.front()) {
+            // equivalent URIs share upstream observations
+            Some(proxy_uri) => {
+                let proxy_uri = String::from_utf8_lossy(proxy_uri).to_string();
+                uri::normalize(&proxy_uri).unwrap_or(proxy_uri)
+            }
+            None => return Some(Self::error_reply(template, Status::ProxyingNotSupported, "")),
+        };
+
+        let protected = oscore::is_protected(&request.message);
+        let cached = !protected || self.cache_protected;
+        if *request.get_method() == Method::Get && self.notifier.is_some() && !protected {
+            let observe = request.get_observe().and_then(|value| decode_uint(value));
+            if observe == Some(ObserveOption::Register as u32) {
+                return Some(self.register(request, template, &proxy_uri));
+            }
+            if observe == Some(ObserveOption::Deregister as u32) {
+                self.deregister(request, &proxy_uri);
+            }
+        }
+
+        let key = CacheKey::from_request(request);
+        if cached {
+            if let Some(cached) = self.cache.get(&key) {
+                return Some(Self::reply(&template, &cached));
+            }
+        }
+
+        let mut upstream_request = match Self::upstream_request(request, &proxy_uri) {
+            Ok(upstream_request) => upstream_request,
+            Err(e) => return Some(Self::error_reply(template, Self::uri_status(&e), &e.to_string())),
+        };
+        let stale_etag = if cached && !protected { self.cache.get_stale_etag(&key) } else { None };
+        if let Some(ref etag) = stale_etag {
+            upstream_request.add_option(CoAPOption::ETag, etag.clone());
+        }
+        upstream_request.message.normalize();
+
+        let upstream_response = match self.exchange(&proxy_uri, &mut upstream_request) {
+            Ok(upstream_response) => upstream_response,
+            Err(e) => {
+                let status = Self::gateway_status(&e);
+                return Some(Self::error_reply(template, status, &format!("upstream: {}", e)));
+            }
+        };
+        if stale_etag.is_some() && *upstream_response.get_status() == Status::Valid {
+            if let Some(cached) = self.cache.revalidate(&key, &upstream_response) {
+                return Some(Self::reply(&template, &cached));
+            }
+        }
+
+        if protected {
+            if self.cache_protected && oscore::is_protected(&upstream_response.message) {
+                self.cache.store(key, upstream_response.clone());
+            }
+        } else {
+            self.cache.insert(key, upstream_response.clone());
+        }
+        Some(Self::reply(&template, &upstream_response))
+    }
+
+    fn register(&mut self, request: &CoAPRequest, template: CoAPResponse, proxy_uri: &str) -> CoAPResponse {
+        let source = match request.source {
+            Some(source) => source,
+            None => return Self::error_reply(template, Status::BadRequest, "unknown observer"),
+        };
+
+        if !self.observations.contains_key(proxy_uri) {
+            match self.observe_upstream(proxy_uri) {
+                Ok(observation) => {
+                    self.observations.insert(proxy_uri.to_string(), observation);
+                }
+                Err(ref e) if e.kind() == ErrorKind::NotFound => {
+                    return Self::error_reply(template, Status::NotFound, "");
+                }
+                Err(ref e) if e.kind() == ErrorKind::Unsupported => {
+                    return Self::error_reply(template, Status::ProxyingNotSupported, &e.to_string());
+                }
+                Err(e) => {
+                    let status = Self::gateway_status(&e);
+                    return Self::error_reply(template, status, &format!("upstream: {}", e));
+                }
+            }
+        }
+
+        let observation = &self.observations[proxy_uri];
+        let mut relay = observation.relay.lock().unwrap();
+        relay.subscribers.retain(|subscriber| subscriber.address != source);
+        relay.subscribers.push(Subscriber {
+            address: source,
+            token: request.get_token().clone(),
+            sequence: 0,
+        });
+
+        match relay.latest {
+            Some(ref latest) => {
+                let mut response = Self::reply(&template, latest);
+                response.set_observe(encode_uint(0));
+                response
+            }
+            None => Self::error_reply(template, Status::BadGateway, "upstream: no representation"),
+        }
+    }
+
+    fn deregister(&mut self, request: &CoAPRequest, proxy_uri: &str) {
+        let remaining = match self.observations.get(proxy_uri) {
+            Some(observation) => {
+                let mut relay = observation.relay.lock().unwrap();
+                relay.subscribers.retain(|subscriber| {
+                    Some(subscriber.address) != request.source || subscriber.token != *request.get_token()
+                });
+                relay.subscribers.len()
+            }
+            None => return,
+        };
+
+        if remaining == 0 {
+            debug!("last observer left {}", proxy_uri);
+            self.observations.remove(proxy_uri);
+        }
+    }
+
+    fn observe_upstream(&self, proxy_uri: &str) -> Result<UpstreamObservation> {
+        let url = Self::parse_proxy_uri(proxy_uri)?;
+        let (host, port, path) = CoAPClient::split_url(&url);
+        let resource_path = match url.query() {
+            Some(query) => format!("{}?{}", path, query),
+            None => path,
+        };
+        let notifier = match self.notifier {
+            Some(ref notifier) => notifier.clone(),
+            None => return Err(Error::other("no notification sender")),
+        };
+
+        let relay = Arc::new(Mutex::new(Relay::default()));
+        let shared_relay = relay.clone();
+        let client = CoAPClient::new_with_resolver(&host, port, self.resolver.as_ref())?;
+        let observation = client.observe(&resource_path, move |packet| {
+            shared_relay.lock().unwrap().notify(packet, &notifier);
+        })?;
+
+        Ok(UpstreamObservation {
+            observation,
+            relay,
+        })
+    }
+
+    /// Parses a Proxy-Uri. A URI of a scheme the proxy does not forward to, e.g. coaps, fails
+    /// with `Unsupported`, to be answered with 5.05 Proxying Not Supported (RFC 7252 §5.7.2).
+    fn parse_proxy_uri(proxy_uri: &str) -> Result<Url> {
+        if let Ok(url) = Url::parse(proxy_uri) {
+            if url.scheme() != "coap" {
+                let reason = format!("the proxy does not forward to {} URIs", url.scheme());
+                return Err(Error::new(ErrorKind::Unsupported, reason));
+            }
+        }
+        CoAPClient::parse_client_url(proxy_uri, &["coap"])
+    }
+
+    /// The status answering a Proxy-Uri that could not be used.
+    fn uri_status(error: &Error) -> Status {
+        match error.kind() {
+            ErrorKind::Unsupported => Status::ProxyingNotSupported,
+            _ => Status::BadOption,
+        }
+    }
+
+    /// Builds the request to the origin. Its message ID and token are those of the upstream
+    /// client, set by `exchange`, rather than the downstream client's.
+    fn upstream_request(request: &CoAPRequest, proxy_uri: &str) -> Result<CoAPRequest> {
+        let url = Self::parse_proxy_uri(proxy_uri)?;
+        let (_, _, path) = CoAPClient::split_url(&url);
+
+        let mut upstream_request = CoAPRequest::new();
+        upstream_request.message = request.message.clone();
+        if oscore::is_protected(&request.message) {
+            // Uri-Path and Uri-Query are class E and must stay inside the protected payload
+            if !path.trim_matches('/').is_empty() || url.query().is_some() {
+                return Err(Error::new(ErrorKind::InvalidInput, "protected request with a Proxy-Uri path or query"));
+            }
+            upstream_request
+                .message
+                .retain_options(|number| oscore::option_class(number) == OptionClass::U);
+            upstream_request.clear_option(CoAPOption::ProxyUri);
+            upstream_request.clear_option(CoAPOption::ProxyScheme);
+            upstream_request.clear_option(CoAPOption::Observe);
+            upstream_request.set_type(MessageType::Confirmable);
+            return Ok(upstream_request);
+        }
+        upstream_request.clear_option(CoAPOption::ProxyUri);
+        upstream_request.clear_option(CoAPOption::ProxyScheme);
+        upstream_request.clear_option(CoAPOption::Observe);
+        upstream_request.set_type(MessageType::Confirmable);
+        upstream_request.set_path(path.as_str());
+        upstream_request.set_query(url.query().unwrap_or(""));
+        Ok(upstream_request)
+    }
+
+    fn exchange(&self, proxy_uri: &str, request: &mut CoAPRequest) -> Result<CoAPResponse> {
+        let (host, port, _) = CoAPClient::split_url(&Self::parse_proxy_uri(proxy_uri)?);
+
+        let mut client = CoAPClient::new_with_resolver(&host, port, self.resolver.as_ref())?;
+        // 4.01 and 4.03 responses are the downstream client's to recover from
+        client.set_max_auth_retries(0);
+        request.set_message_id(client.next_message_id());
+        request.set_token(client.next_token());
+        let response = client.request_with(request, self.transmission)?;
+        if response.get_type() == MessageType::Reset {
+            return Err(Error::new(ErrorKind::ConnectionReset, "exchange reset"));
+        }
+        if response.get_token() != request.get_token() {
+            return Err(Error::new(ErrorKind::InvalidData, "upstream token mismatch"));
+        }
+        Ok(response)
+    }
+
+    /// Maps an upstream failure to the status reported downstream.
+    fn gateway_status(error: &Error) -> Status {
+        match error.kind() {
+            ErrorKind::WouldBlock | ErrorKind::TimedOut => Status::GatewayTimeout,
+            _ => Status::BadGateway,
+        }
+    }
+
+    fn error_reply(mut template: CoAPResponse, status: Status, diagnostic: &str) -> CoAPResponse {
+        template.set_error(status, diagnostic);
+        template
+    }
+
+    /// Adapts an upstream response to the downstream exchange.
+    fn reply(template: &CoAPResponse, upstream: &CoAPResponse) -> CoAPResponse {
+        let mut message = Self::reply_message(upstream);
+        message.header.set_type(template.get_type());
+        message.header.set_message_id(template.get_message_id());
+        message.set_token(template.get_token().clone());
+
+        let mut response = template.clone();
+        response.message = message;
+        response.received_at = None;
+        response
+    }
+
+    /// Copies an upstream response, rewriting Max-Age to the freshness left.
+    fn reply_message(upstream: &CoAPResponse) -> Packet {
+        let mut message = upstream.message.clone();
+        if upstream.received_at.is_some() {
+            message.set_max_age(remaining_max_age(upstream).as_secs() as u32);
+        }
+        message
+    }
+}
+
+impl Default for ForwardProxy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::*;
+    use super::super::message::header::MessageClass;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[test]
+    fn test_proxy_protected_requests() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let upstream_seen = seen.clone();
+        let upstream_port = server::test::spawn_server(move |req: CoAPRequest| {
+            let seen = upstream_seen.clone();
+            async move {
+                let numbers: Vec<usize> = req.message.options().map(|(number, _)| *number).collect();
+                seen.lock().unwrap().push(numbers);
+                let mut response = req.response?;
+                response.set_status(Status::Changed);
+                response.set_payload(b"ciphertext".to_vec());
+                response.message.add_option(CoAPOption::Oscore, vec![]);
+                response.message.set_max_age(30);
+                Some(response)
+            }
+        }).recv().unwrap();
+
+        let request = |message_id: u16, path: &str| {
+            let mut packet = Packet::new();
+            packet.header.set_type(MessageType::Confirmable);
+            packet.header.set_message_id(message_id);
+            packet.add_option(CoAPOption::ProxyUri, format!("coap://127.0.0.1:{}{}", upstream_port, path).into_bytes());
+            packet.add_option(CoAPOption::Oscore, vec![0x09, 0x14]);
+            packet.add_option(CoAPOption::Accept, vec![50]);
+            CoAPRequest::from_packet(packet, &"127.0.0.1:1234".parse().unwrap())
+        };
+
+        let mut proxy = ForwardProxy::new();
+        let response = proxy.handle(&request(1, "/secret")).unwrap();
+        assert_eq!(*response.get_status(), Status::BadOption);
+
+        for message_id in 2..4 {
+            let response = proxy.handle(&request(message_id, "")).unwrap();
+            assert_eq!(*response.get_status(), Status::Changed);
+            assert_eq!(response.message.payload, b"ciphertext".to_vec());
+        }
+        assert_eq!(seen.lock().unwrap().len(), 2);
+        // the stray class E Accept option is not forwarded
+        assert_eq!(seen.lock().unwrap()[0], vec![9]);
+        assert_eq!(proxy.cache_stats().entries, 0);
+
+        proxy.set_cache_protected(true);
+        for message_id in 4..6 {
+            let response = proxy.handle(&request(message_id, "")).unwrap();
+            assert_eq!(response.message.payload, b"ciphertext".to_vec());
+        }
+        assert_eq!(seen.lock().unwrap().len(), 3);
+        assert_eq!(proxy.cache_stats().hits, 1);
+    }
+
+    #[test]
+    fn test_proxy_caches_responses() {
+        let hits = Arc::new(Mutex::new(0usize));
+        let upstream_hits = hits.clone();
+        let upstream_port = server::test::spawn_server(move |req: CoAPRequest| {
+            let hits = upstream_hits.clone();
+            async move {
+                *hits.lock().unwrap() += 1;
+                let mut response = req.response?;
+                response.set_payload(b"upstream".to_vec());
+                response.message.set_max_age(30);
+                Some(response)
+            }
+        }).recv().unwrap();
+
+        let mut proxy = ForwardProxy::new();
+        for message_id in 1..3 {
+            let mut packet = Packet::new();
+            packet.header.set_type(MessageType::Confirmable);
+            packet.header.set_message_id(message_id);
+            packet.add_option(
+                CoAPOption::ProxyUri,
+                format!("coap://127.0.0.1:{}/resource", upstream_port).into_bytes(),
+            );
+            let request = CoAPRequest::from_packet(packet, &"127.0.0.1:1234".parse().unwrap());
+
+            let response = proxy.handle(&request).unwrap();
+            assert_eq!(*response.get_status(), Status::Content);
+            assert_eq!(response.get_message_id(), message_id);
+            assert_eq!(response.message.payload, b"upstream".to_vec());
+        }
+
+        assert_eq!(*hits.lock().unwrap(), 1);
+        let stats = proxy.cache_stats();
+        assert_eq!(stats.hits, 1);
+        assert_eq!(stats.entries, 1);
+    }
+
+    #[test]
+    fn test_proxy_forwards_query() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let upstream_seen = seen.clone();
+        let upstream_port = server::test::spawn_server(move |req: CoAPRequest| {
+            let seen = upstream_seen.clone();
+            async move {
+                seen.lock().unwrap().push(req.message.clone());
+                req.response
+            }
+        }).recv().unwrap();
+
+        let mut packet = Packet::new();
+        packet.header.set_type(MessageType::Confirmable);
+        packet.header.set_message_id(7);
+        packet.set_token(vec![7]);
+        packet.add_option(
+            CoAPOption::ProxyUri,
+            format!("coap://127.0.0.1:{}/sensors?unit=c&fresh", upstream_port).into_bytes(),
+        );
+        let request = CoAPRequest::from_packet(packet, &"127.0.0.1:1234".parse().unwrap());
+
+        let mut proxy = ForwardProxy::new();
+        let response = proxy.handle(&request).unwrap();
+        assert_eq!(*response.get_status(), Status::Content);
+        assert_eq!(response.get_message_id(), 7);
+        assert_eq!(*response.get_token(), vec![7]);
+
+        let upstream = seen.lock().unwrap()[0].clone();
+        let query: Vec<Vec<u8>> = upstream.get_option(CoAPOption::UriQuery).unwrap().iter().cloned().collect();
+        assert_eq!(query, vec![b"unit=c".to_vec(), b"fresh".to_vec()]);
+        assert_ne!(*upstream.get_token(), vec![7]);
+        assert!(!upstream.get_token().is_empty());
+    }
+
+    #[test]
+    fn test_proxy_unsupported_scheme() {
+        let mut packet = Packet::new();
+        packet.header.set_type(MessageType::Confirmable);
+        packet.add_option(CoAPOption::ProxyUri, b"coaps://127.0.0.1:5684/resource".to_vec());
+        let request = CoAPRequest::from_packet(packet, &"127.0.0.1:1234".parse().unwrap());
+
+        let mut proxy = ForwardProxy::new();
+        let response = proxy.handle(&request).unwrap();
+        assert_eq!(*response.get_status(), Status::ProxyingNotSupported);
+    }
+
+    #[test]
+    fn test_proxy_without_proxy_uri() {
+        let mut packet = Packet::new();
+        packet.header.set_type(MessageType::Confirmable);
+        let request = CoAPRequest::from_packet(packet, &"127.0.0.1:1234".parse().unwrap());
+
+        let mut proxy = ForwardProxy::new();
+        let response = proxy.handle(&request).unwrap();
+        assert_eq!(*response.get_status(), Status::ProxyingNotSupported);
+    }
+
+    fn proxied_request(upstream_port: u16) -> CoAPRequest {
+        let mut packet = Packet::new();
+        packet.header.set_type(MessageType::Confirmable);
+        packet.add_option(
+            CoAPOption::ProxyUri,
+            format!("coap://127.0.0.1:{}/resource", upstream_port).into_bytes(),
+        );
+        CoAPRequest::from_packet(packet, &"127.0.0.1:1234".parse().unwrap())
+    }
+
+    // gives up on the upstream after 200ms to 300ms
+    fn quick_timeout() -> TransmissionParameters {
+        TransmissionParameters::default()
+            .with_timeout(Duration::from_millis(200))
+            .with_max_retransmit(0)
+    }
+
+    #[test]
+    fn test_proxy_upstream_timeout() {
+        let upstream_port = server::test::spawn_server(|_req: CoAPRequest| async { None }).recv().unwrap();
+
+        let mut proxy = ForwardProxy::new();
+        proxy.set_upstream_transmission(quick_timeout());
+        let response = proxy.handle(&proxied_request(upstream_port)).unwrap();
+        assert_eq!(*response.get_status(), Status::GatewayTimeout);
+        assert!(!response.message.payload.is_empty());
+    }
+
+    #[test]
+    fn test_proxy_separate_response() {
+        let upstream = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        upstream.set_read_timeout(Some(Duration::new(5, 0))).unwrap();
+        let upstream_port = upstream.local_addr().unwrap().port();
+        let origin = std::thread::spawn(move || {
+            let mut buf = [0; 1500];
+            let (nread, src) = upstream.recv_from(&mut buf).unwrap();
+            let request = Packet::from_bytes(&buf[..nread]).unwrap();
+
+            let mut ack = Packet::new();
+            ack.header.set_type(MessageType::Acknowledgement);
+            ack.header.code = MessageClass::Empty;
+            ack.header.set_message_id(request.header.get_message_id());
+            upstream.send_to(&ack.to_bytes().unwrap(), src).unwrap();
+
+            let mut response = Packet::new();
+            response.header.set_type(MessageType::Confirmable);
+            response.header.code = MessageClass::Response(Status::Content);
+            response.header.set_message_id(0x4242);
+            response.set_token(request.get_token().clone());
+            response.payload = b"slow".to_vec();
+            upstream.send_to(&response.to_bytes().unwrap(), src).unwrap();
+
+            let (nread, _) = upstream.recv_from(&mut buf).unwrap();
+            Packet::from_bytes(&buf[..nread]).unwrap()
+        });
+
+        let mut proxy = ForwardProxy::new();
+        let response = proxy.handle(&proxied_request(upstream_port)).unwrap();
+        assert_eq!(*response.get_status(), Status::Content);
+        assert_eq!(response.message.payload, b"slow".to_vec());
+
+        let ack = origin.join().unwrap();
+        assert_eq!(ack.header.get_type(), MessageType::Acknowledgement);
+        assert_eq!(ack.header.code, MessageClass::Empty);
+        assert_eq!(ack.header.get_message_id(), 0x4242);
+    }
+
+    #[test]
+    fn test_proxy_upstream_reset() {
+        let upstream_port = server::test::spawn_server(|req: CoAPRequest| async move {
+            let mut response = req.response?;
+            response.set_type(MessageType::Reset);
+            Some(response)
+        }).recv().unwrap();
+
+        let mut proxy = ForwardProxy::new();
+        let response = proxy.handle(&proxied_request(upstream_port)).unwrap();
+        assert_eq!(*response.get_status(), Status::BadGateway);
+        assert_eq!(response.message.payload, b"upstream: exchange reset".to_vec());
+        assert_eq!(proxy.cache_stats().entries, 0);
+    }
+
+    fn observe_request(upstream_port: u16, source: &str, token: u8, observe: ObserveOption) -> CoAPRequest {
+        let mut packet = proxied_request(upstream_port).message;
+        packet.set_token(vec![token]);
+        packet.set_observe(vec![observe as u8]);
+        CoAPRequest::from_packet(packet, &source.parse().unwrap())
+    }
+
+    #[test]
+    fn test_proxy_relay_ends_with_upstream() {
+        let upstream = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        upstream.set_read_timeout(Some(Duration::new(5, 0))).unwrap();
+        let upstream_port = upstream.local_addr().unwrap().port();
+        let origin = std::thread::spawn(move || {
+            let mut buf = [0; 1500];
+            let (nread, src) = upstream.recv_from(&mut buf).unwrap();
+            let register = Packet::from_bytes(&buf[..nread]).unwrap();
+
+            let mut response = Packet::new();
+            response.header.set_type(MessageType::Acknowledgement);
+            response.header.code = MessageClass::Response(Status::Content);
+            response.header.set_message_id(register.header.get_message_id());
+            response.set_token(register.get_token().clone());
+            response.set_observe(vec![1]);
+            response.payload = b"v1".to_vec();
+            upstream.send_to(&response.to_bytes().unwrap(), src).unwrap();
+
+            // the resource goes away, ending the observation
+            let mut gone = Packet::new();
+            gone.header.set_type(MessageType::NonConfirmable);
+            gone.header.code = MessageClass::Response(Status::NotFound);
+            gone.header.set_message_id(register.header.get_message_id().wrapping_add(1));
+            gone.set_token(register.get_token().clone());
+            upstream.send_to(&gone.to_bytes().unwrap(), src).unwrap();
+            register
+        });
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut proxy = ForwardProxy::new();
+        proxy.set_notification_sender(tx);
+
+        let mut packet = Packet::new();
+        packet.header.set_type(MessageType::Confirmable);
+        packet.set_token(vec![1]);
+        packet.set_observe(vec![ObserveOption::Register as u8]);
+        packet.add_option(
+            CoAPOption::ProxyUri,
+            format!("coap://127.0.0.1:{}/resource?unit=c", upstream_port).into_bytes(),
+        );
+        let request = CoAPRequest::from_packet(packet, &"127.0.0.1:1001".parse().unwrap());
+        let response = proxy.handle(&request).unwrap();
+        assert_eq!(response.message.payload, b"v1".to_vec());
+
+        let register = origin.join().unwrap();
+        let query: Vec<Vec<u8>> = register.get_option(CoAPOption::UriQuery).unwrap().iter().cloned().collect();
+        assert_eq!(query, vec![b"unit=c".to_vec()]);
+
+        let mut notification = None;
+        for _ in 0..50 {
+            if let Ok(received) = rx.try_recv() {
+                notification = Some(received);
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        let (packet, _) = notification.unwrap();
+        assert_eq!(packet.header.code, MessageClass::Response(Status::NotFound));
+        assert_eq!(packet.get_observe(), None);
+
+        // the next request tears the relay down
+        proxy.set_upstream_transmission(quick_timeout());
+        proxy.handle(&proxied_request(upstream_port)).unwrap();
+        assert!(proxy.observations.is_empty());
+    }
+
+    #[test]
+    fn test_proxy_relays_observations() {
+        let upstream_port = server::test::spawn_server(|req: CoAPRequest| async { req.response }).recv().unwrap();
+        let upstream = CoAPClient::new(format!("127.0.0.1:{}", upstream_port)).unwrap();
+        let mut update = CoAPRequest::new();
+        update.set_method(Method::Put);
+        update.set_path("/resource");
+        update.set_payload(b"v1".to_vec());
+        upstream.send(&update).unwrap();
+        upstream.receive().unwrap();
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut proxy = ForwardProxy::new();
+        proxy.set_notification_sender(tx);
+
+        for (source, token) in &[("127.0.0.1:1001", 1), ("127.0.0.1:1002", 2)] {
+            let request = observe_request(upstream_port, source, *token, ObserveOption::Register);
+            let response = proxy.handle(&request).unwrap();
+            assert_eq!(*response.get_status(), Status::Content);
+            assert_eq!(response.message.payload, b"v1".to_vec());
+            assert_eq!(*response.get_token(), vec![*token]);
+        }
+        assert_eq!(proxy.observations.len(), 1);
+
+        update.set_payload(b"v2".to_vec());
+        upstream.send(&update).unwrap();
+        upstream.receive().unwrap();
+
+        let mut notifications = Vec::new();
+        for _ in 0..50 {
+            if let Ok(notification) = rx.try_recv() {
+                notifications.push(notification);
+                if notifications.len() == 2 {
+                    break;
+                }
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        notifications.sort_by_key(|(_, address)| address.port());
+        assert_eq!(notifications.len(), 2);
+        for ((packet, address), token) in notifications.iter().zip(&[1u8, 2]) {
+            assert_eq!(address.port(), 1000 + *token as u16);
+            assert_eq!(*packet.get_token(), vec![*token]);
+            assert_eq!(packet.get_observe(), Some(&vec![1]));
+            assert_eq!(packet.payload, b"v2".to_vec());
+        }
+
+        proxy.handle(&observe_request(upstream_port, "127.0.0.1:1001", 1, ObserveOption::Deregister)).unwrap();
+        assert_eq!(proxy.observations.len(), 1);
+        proxy.handle(&observe_request(upstream_port, "127.0.0.1:1002", 2, ObserveOption::Deregister)).unwrap();
+        assert_eq!(proxy.observations.len(), 0);
+    }
+}
@@ -0,0 +1,298 @@
+//! A Python module over the client, for test scripts driving devices over CoAP and DTLS.
+//! Built with the `python` feature.
+//!
+//! The extension module is built with maturin from the `pyproject.toml` at the crate root,
+//! e.g. `maturin develop --release`, and used as:
+//!
+//! ```text
+//! import coap
+//!
+//! client = coap.DtlsClient("192.0.2.1:5684", identity=b"device-1", key=b"secret")
+//! response = client.get("/sensors/temp")
+//! print(response.code, response.payload)
+//!
+//! observation = client.observe("/state", lambda response: print(response.payload))
+//! observation.cancel()
+//! ```
+//!
+//! Requests block without holding the GIL, so other Python threads keep running, and
+//! observe callbacks run on the background thread of the observation. Failures raise
+//! `TimeoutError` when the server did not answer in time and `OSError` otherwise.
+
+use std::io;
+use std::time::Duration;
+
+use pyo3::exceptions::{PyIOError, PyTimeoutError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+
+use super::client::{CoAPClient, ObservationHandle};
+use super::dtls_client::DTLSCoAPClient;
+use super::message::header::{class_to_code, code_to_str};
+use super::message::packet::{decode_uint, encode_uint, CoAPOption, Packet};
+use super::message::request::{CoAPRequest, Method};
+use super::message::IsMessage;
+
+/// A response or notification.
+#[pyclass(name = "Response", module = "coap")]
+pub struct PyResponse {
+    /// The response code, e.g. `2.05`.
+    #[pyo3(get)]
+    code: String,
+    payload: Vec<u8>,
+    #[pyo3(get)]
+    content_format: Option<u32>,
+}
+
+impl PyResponse {
+    fn from_packet(packet: &Packet) -> PyResponse {
+        PyResponse {
+            code: code_to_str(&class_to_code(&packet.header.code)),
+            payload: packet.payload.clone(),
+            content_format: packet
+                .get_option(CoAPOption::ContentFormat)
+                .and_then(|values| values.front())
+                .and_then(|value| decode_uint(value)),
+        }
+    }
+}
+
+#[pymethods]
+impl PyResponse {
+    #[getter]
+    fn payload<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new_bound(py, &self.payload)
+    }
+
+    /// Whether the code is 4.xx or 5.xx.
+    fn is_error(&self) -> bool {
+        !self.code.starts_with('2')
+    }
+
+    fn __repr__(&self) -> String {
+        format!("<Response {} ({} bytes)>", self.code, self.payload.len())
+    }
+}
+
+/// An observation, cancelled with `cancel` or when garbage collected.
+#[pyclass(name = "Observation", module = "coap")]
+pub struct PyObservation {
+    handle: ObservationHandle,
+}
+
+#[pymethods]
+impl PyObservation {
+    /// Whether notifications are still being received.
+    fn is_active(&self) -> bool {
+        self.handle.is_active()
+    }
+
+    /// Deregisters from the server.
+    fn cancel(&mut self, py: Python) -> PyResult<()> {
+        let handle = &mut self.handle;
+        py.allow_threads(|| handle.cancel()).map_err(to_py_err)
+    }
+}
+
+/// A client over UDP.
+#[pyclass(name = "Client", module = "coap")]
+pub struct PyClient {
+    client: CoAPClient,
+}
+
+#[pymethods]
+impl PyClient {
+    #[new]
+    fn new(addr: &str) -> PyResult<PyClient> {
+        CoAPClient::new(addr).map(|client| PyClient { client }).map_err(to_py_err)
+    }
+
+    fn get(&self, py: Python, path: &str) -> PyResult<PyResponse> {
+        self.request(py, build_request(Method::Get, path, None, None))
+    }
+
+    #[pyo3(signature = (path, payload, content_format = None))]
+    fn post(&self, py: Python, path: &str, payload: &[u8], content_format: Option<u32>) -> PyResult<PyResponse> {
+        self.request(py, build_request(Method::Post, path, Some(payload), content_format))
+    }
+
+    #[pyo3(signature = (path, payload, content_format = None))]
+    fn put(&self, py: Python, path: &str, payload: &[u8], content_format: Option<u32>) -> PyResult<PyResponse> {
+        self.request(py, build_request(Method::Put, path, Some(payload), content_format))
+    }
+
+    fn delete(&self, py: Python, path: &str) -> PyResult<PyResponse> {
+        self.request(py, build_request(Method::Delete, path, None, None))
+    }
+
+    /// Observes the resource, calling `callback` with the current representation and every
+    /// notification.
+    fn observe(&self, py: Python, path: &str, callback: PyObject) -> PyResult<PyObservation> {
+        let client = &self.client;
+        py.allow_threads(|| client.observe(path, notify(callback)))
+            .map(|handle| PyObservation { handle })
+            .map_err(to_py_err)
+    }
+
+    /// Sets how long a confirmable request waits for its response before retransmitting.
+    fn set_timeout(&mut self, seconds: f64) -> PyResult<()> {
+        let transmission = self.client.transmission_parameters().with_timeout(to_duration(seconds)?);
+        self.client.set_transmission_parameters(transmission);
+        Ok(())
+    }
+}
+
+impl PyClient {
+    fn request(&self, py: Python, mut request: CoAPRequest) -> PyResult<PyResponse> {
+        let client = &self.client;
+        request.set_message_id(client.next_message_id());
+        request.set_token(client.next_token());
+        py.allow_threads(|| client.request(&mut request))
+            .map(|response| PyResponse::from_packet(&response.message))
+            .map_err(to_py_err)
+    }
+}
+
+/// A client over DTLS, authenticating with a PSK identity and key, or else with the
+/// credentials configured in the environment.
+#[pyclass(name = "DtlsClient", module = "coap")]
+pub struct PyDtlsClient {
+    client: DTLSCoAPClient,
+    message_id: u16,
+}
+
+#[pymethods]
+impl PyDtlsClient {
+    #[new]
+    #[pyo3(signature = (addr, identity = None, key = None))]
+    fn new(py: Python, addr: &str, identity: Option<&[u8]>, key: Option<&[u8]>) -> PyResult<PyDtlsClient> {
+        let client = py.allow_threads(|| match (identity, key) {
+            (Some(identity), Some(key)) => DTLSCoAPClient::new_with_psk(addr, identity, key),
+            _ => DTLSCoAPClient::new(addr),
+        });
+        client.map(|client| PyDtlsClient { client, message_id: 0 }).map_err(to_py_err)
+    }
+
+    fn get(&mut self, py: Python, path: &str) -> PyResult<PyResponse> {
+        self.request(py, build_request(Method::Get, path, None, None))
+    }
+
+    #[pyo3(signature = (path, payload, content_format = None))]
+    fn post(&mut self, py: Python, path: &str, payload: &[u8], content_format: Option<u32>) -> PyResult<PyResponse> {
+        self.request(py, build_request(Method::Post, path, Some(payload), content_format))
+    }
+
+    #[pyo3(signature = (path, payload, content_format = None))]
+    fn put(&mut self, py: Python, path: &str, payload: &[u8], content_format: Option<u32>) -> PyResult<PyResponse> {
+        self.request(py, build_request(Method::Put, path, Some(payload), content_format))
+    }
+
+    fn delete(&mut self, py: Python, path: &str) -> PyResult<PyResponse> {
+        self.request(py, build_request(Method::Delete, path, None, None))
+    }
+
+    /// Observes the resource over the session of the client, calling `callback` with the
+    /// current representation and every notification.
+    fn observe(&mut self, py: Python, path: &str, callback: PyObject) -> PyResult<PyObservation> {
+        let client = &mut self.client;
+        py.allow_threads(|| client.observe(path, notify(callback)))
+            .map(|handle| PyObservation { handle })
+            .map_err(to_py_err)
+    }
+
+    /// Sets how long a request waits for its response.
+    fn set_timeout(&self, seconds: f64) -> PyResult<()> {
+        self.client.set_receive_timeout(Some(to_duration(seconds)?)).map_err(to_py_err)
+    }
+}
+
+impl PyDtlsClient {
+    fn request(&mut self, py: Python, mut request: CoAPRequest) -> PyResult<PyResponse> {
+        self.message_id = self.message_id.wrapping_add(1);
+        request.set_message_id(self.message_id);
+        let client = &self.client;
+        py.allow_threads(|| client.send(&request).and_then(|_| client.receive()))
+            .map(|response| PyResponse::from_packet(&response.message))
+            .map_err(to_py_err)
+    }
+}
+
+fn build_request(method: Method, path: &str, payload: Option<&[u8]>, content_format: Option<u32>) -> CoAPRequest {
+    let (path, query) = match path.find('?') {
+        Some(idx) => (&path[..idx], &path[idx + 1..]),
+        None => (path, ""),
+    };
+    let mut request = CoAPRequest::new();
+    request.set_method(method);
+    request.set_path(path);
+    request.set_query(query);
+    if let Some(payload) = payload {
+        request.set_payload(payload.to_vec());
+    }
+    if let Some(format) = content_format {
+        request.message.add_option(CoAPOption::ContentFormat, encode_uint(format));
+    }
+    request
+}
+
+// hands notifications to a Python callable, printing the exceptions it raises
+fn notify(callback: PyObject) -> impl FnMut(Packet) + Send + 'static {
+    move |packet| {
+        Python::with_gil(|py| {
+            if let Err(e) = callback.call1(py, (PyResponse::from_packet(&packet),)) {
+                e.print(py);
+            }
+        })
+    }
+}
+
+// raises ValueError rather than panicking on a timeout that is not a positive duration
+fn to_duration(seconds: f64) -> PyResult<Duration> {
+    if seconds.is_nan() || seconds <= 0.0 {
+        return Err(PyValueError::new_err(format!("timeout must be positive, not {}", seconds)));
+    }
+    Duration::try_from_secs_f64(seconds).map_err(|e| PyValueError::new_err(e.to_string()))
+}
+
+fn to_py_err(e: io::Error) -> PyErr {
+    match e.kind() {
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => PyTimeoutError::new_err(e.to_string()),
+        _ => PyIOError::new_err(e.to_string()),
+    }
+}
+
+/// The `coap` Python module.
+#[pymodule]
+fn coap(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyClient>()?;
+    m.add_class::<PyDtlsClient>()?;
+    m.add_class::<PyObservation>()?;
+    m.add_class::<PyResponse>()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::*;
+    use pyo3::types::IntoPyDict;
+
+    #[test]
+    fn test_python_client() {
+        let server_port = server::test::spawn_server(|req: CoAPRequest| async { req.response }).recv().unwrap();
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let client = Py::new(py, PyClient::new(&format!("127.0.0.1:{}", server_port)).unwrap()).unwrap();
+            let globals = [("client", client)].into_py_dict_bound(py);
+            let response = py
+                .eval_bound("client.put('/sensors/temp?unit=c', b'21.5', content_format=0)", Some(&globals), None)
+                .unwrap();
+            assert_eq!(response.getattr("code").unwrap().extract::<String>().unwrap(), "2.05");
+            assert_eq!(response.getattr("payload").unwrap().extract::<Vec<u8>>().unwrap(), b"21.5".to_vec());
+            assert!(!response.call_method0("is_error").unwrap().extract::<bool>().unwrap());
+
+            let error = PyClient::new("no address").err().unwrap();
+            assert!(error.is_instance_of::<PyIOError>(py));
+        });
+    }
+}
@@ -0,0 +1,339 @@
+//! Recording the exchanges of a client into a session log, like a HAR file for HTTP.
+//!
+//! A `Recorder` set with `CoAPClient::set_recorder` captures every request as sent, its
+//! response, its retransmissions and timings: those of `request` and its variants, of
+//! `upload`, of requests made with `send` whose response arrives through `receive`, and of
+//! the registrations of observations. One installed with `set_default_recorder` also captures
+//! the clients created by `CoAPClient::get_with_timeout` and the other static helpers. The
+//! `SessionLog` is stored with `to_bytes` and loaded with `from_bytes`, or exported with
+//! `to_json` to attach to a bug report and read back with `from_json`.
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+use super::codec;
+use super::json;
+use super::message::packet::Packet;
+use super::message::header::MessageClass;
+use super::message::response::CoAPResponse;
+
+// the exchanges a log keeps by default, the oldest dropped beyond
+const DEFAULT_MAX_EXCHANGES: usize = 10_000;
+
+/// A request and what became of it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RecordedExchange {
+    pub peer: SocketAddr,
+    /// When the request was first sent, since the recorder was created.
+    pub started: Duration,
+    /// The request with the client's default options, as it went on the wire.
+    pub request: Packet,
+    /// The response, `None` if the exchange failed.
+    pub response: Option<Packet>,
+    pub retransmissions: u32,
+    /// How long the exchange took until the response or the failure.
+    pub elapsed: Duration,
+    /// Why the exchange failed.
+    pub error: Option<String>,
+}
+
+/// The exchanges recorded in order of their completion.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SessionLog {
+    pub exchanges: Vec<RecordedExchange>,
+}
+
+impl SessionLog {
+    pub fn len(&self) -> usize {
+        self.exchanges.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.exchanges.is_empty()
+    }
+
+    /// Encodes the log for storage.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    /// Decodes a log produced by `to_bytes`.
+    pub fn from_bytes(buf: &[u8]) -> Result<SessionLog> {
+        bincode::deserialize(buf).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+
+    /// The log as JSON text, with packets as `Packet` serializes them and durations as
+    /// `secs` and `nanos`.
+    pub fn to_json(&self) -> String {
+        // a log holds nothing the data model cannot represent
+        json::to_string(&codec::to_value(self).unwrap())
+    }
+
+    /// Reads a log produced by `to_json`.
+    pub fn from_json(text: &str) -> Result<SessionLog> {
+        let value = json::parse(text).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        codec::from_value(value).map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))
+    }
+}
+
+lazy_static! {
+    static ref DEFAULT_RECORDER: RwLock<Option<Recorder>> = RwLock::new(None);
+}
+
+/// Sets the recorder every client created afterwards starts with, including those the static
+/// helpers such as `CoAPClient::get` create, or removes it.
+pub fn set_default_recorder(recorder: Option<Recorder>) {
+    *DEFAULT_RECORDER.write().unwrap() = recorder;
+}
+
+pub(crate) fn default_recorder() -> Option<Recorder> {
+    DEFAULT_RECORDER.read().unwrap().clone()
+}
+
+/// Collects the exchanges of the clients it is set on, at most 10000 by default, dropping
+/// the oldest first. Clones share the log.
+#[derive(Clone)]
+pub struct Recorder {
+    recording: Arc<Mutex<Recording>>,
+    created: Instant,
+}
+
+struct Recording {
+    log: SessionLog,
+    // the requests made with `CoAPClient::send` awaiting their response, by peer and token
+    pending: HashMap<(SocketAddr, Vec<u8>), (Instant, Packet)>,
+    max_exchanges: usize,
+}
+
+impl Recording {
+    fn push(&mut self, exchange: RecordedExchange) {
+        if self.max_exchanges == 0 {
+            return;
+        }
+        let excess = (self.log.exchanges.len() + 1).saturating_sub(self.max_exchanges);
+        self.log.exchanges.drain(..excess);
+        self.log.exchanges.push(exchange);
+    }
+}
+
+impl Default for Recorder {
+    fn default() -> Recorder {
+        Recorder::new()
+    }
+}
+
+impl Recorder {
+    pub fn new() -> Recorder {
+        Recorder {
+            recording: Arc::new(Mutex::new(Recording {
+                log: SessionLog::default(),
+                pending: HashMap::new(),
+                max_exchanges: DEFAULT_MAX_EXCHANGES,
+            })),
+            created: Instant::now(),
+        }
+    }
+
+    /// Keeps at most `max` exchanges, dropping the oldest first.
+    pub fn with_max_exchanges(self, max: usize) -> Recorder {
+        {
+            let mut recording = self.recording.lock().unwrap();
+            recording.max_exchanges = max;
+            let excess = recording.log.exchanges.len().saturating_sub(max);
+            recording.log.exchanges.drain(..excess);
+        }
+        self
+    }
+
+    /// A copy of the exchanges recorded so far.
+    pub fn log(&self) -> SessionLog {
+        self.recording.lock().unwrap().log.clone()
+    }
+
+    /// Takes the exchanges recorded so far, leaving the log empty.
+    pub fn take(&self) -> SessionLog {
+        std::mem::take(&mut self.recording.lock().unwrap().log)
+    }
+
+    pub(crate) fn record(
+        &self,
+        peer: SocketAddr,
+        started: Instant,
+        request: &Packet,
+        result: &Result<CoAPResponse>,
+        retransmissions: u32,
+    ) {
+        let exchange = self.exchange(peer, started, request.clone(), result, retransmissions);
+        self.recording.lock().unwrap().push(exchange);
+    }
+
+    /// Notes a request sent on its own, which `received` or `failed` completes.
+    pub(crate) fn sent(&self, peer: SocketAddr, request: &Packet) {
+        let mut recording = self.recording.lock().unwrap();
+        if recording.pending.len() >= recording.max_exchanges {
+            // a request whose response never arrived makes room
+            let oldest = recording.pending.iter().min_by_key(|(_, &(started, _))| started).map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                recording.pending.remove(&oldest);
+            }
+        }
+        if recording.max_exchanges > 0 {
+            recording.pending.insert((peer, request.get_token().clone()), (Instant::now(), request.clone()));
+        }
+    }
+
+    /// Completes the exchange of a request noted by `sent` with its response. An empty ACK
+    /// announcing a separate response does not.
+    pub(crate) fn received(&self, peer: SocketAddr, response: &CoAPResponse) {
+        if response.message.header.code == MessageClass::Empty {
+            return;
+        }
+        self.complete(peer, response.message.get_token(), &Ok(response.clone()));
+    }
+
+    /// Completes the exchange of a request noted by `sent` with the error that ended it.
+    pub(crate) fn failed(&self, peer: SocketAddr, request: &Packet, error: &Error) {
+        self.complete(peer, request.get_token(), &Err(Error::new(error.kind(), error.to_string())));
+    }
+
+    fn complete(&self, peer: SocketAddr, token: &[u8], result: &Result<CoAPResponse>) {
+        let mut recording = self.recording.lock().unwrap();
+        if let Some((started, request)) = recording.pending.remove(&(peer, token.to_vec())) {
+            let exchange = self.exchange(peer, started, request, result, 0);
+            recording.push(exchange);
+        }
+    }
+
+    fn exchange(
+        &self,
+        peer: SocketAddr,
+        started: Instant,
+        request: Packet,
+        result: &Result<CoAPResponse>,
+        retransmissions: u32,
+    ) -> RecordedExchange {
+        let (response, error) = match *result {
+            Ok(ref response) => (Some(response.message.clone()), None),
+            Err(ref e) => (None, Some(e.to_string())),
+        };
+        RecordedExchange {
+            peer,
+            started: started.saturating_duration_since(self.created),
+            request,
+            response,
+            retransmissions,
+            elapsed: started.elapsed(),
+            error,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::client::{CoAPClient, TransmissionParameters};
+    use super::super::message::request::CoAPRequest;
+    use super::super::server;
+    use std::net::UdpSocket;
+
+    #[test]
+    fn test_recorder() {
+        let attempts = Arc::new(Mutex::new(0));
+        let server_attempts = attempts.clone();
+        let server_port = server::test::spawn_server(move |req: CoAPRequest| {
+            let attempts = server_attempts.clone();
+            async move {
+                let mut attempts = attempts.lock().unwrap();
+                *attempts += 1;
+                if *attempts == 1 { None } else { req.response }
+            }
+        }).recv().unwrap();
+
+        let recorder = Recorder::new();
+        let mut client = CoAPClient::new(format!("127.0.0.1:{}", server_port)).unwrap();
+        client.set_transmission_parameters(TransmissionParameters::default().with_timeout(Duration::from_millis(100)));
+        client.set_recorder(Some(recorder.clone()));
+        let mut request = CoAPRequest::new();
+        request.set_path("/fw");
+        client.request(&mut request).unwrap();
+
+        let dead = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut dead_client = CoAPClient::new(dead.local_addr().unwrap()).unwrap();
+        dead_client.set_transmission_parameters(TransmissionParameters::default()
+            .with_timeout(Duration::from_millis(50))
+            .with_max_retransmit(0));
+        dead_client.set_recorder(Some(recorder.clone()));
+        assert!(dead_client.request(&mut request).is_err());
+
+        let log = recorder.log();
+        assert_eq!(log.len(), 2);
+        let answered = &log.exchanges[0];
+        assert_eq!(answered.peer.port(), server_port);
+        assert_eq!(answered.request.header.get_message_id(), answered.response.as_ref().unwrap().header.get_message_id());
+        assert_eq!(answered.retransmissions, 1);
+        assert!(answered.elapsed >= Duration::from_millis(100));
+        assert!(answered.error.is_none());
+        let failed = &log.exchanges[1];
+        assert!(failed.response.is_none());
+        assert_eq!(failed.error.as_ref().unwrap(), "request timed out");
+        assert!(failed.started >= answered.started + answered.elapsed);
+
+        assert_eq!(SessionLog::from_bytes(&log.to_bytes()).unwrap(), log);
+        let json = log.to_json();
+        assert!(json.starts_with(r#"{"exchanges":[{"peer":"127.0.0.1:"#));
+        assert!(json.contains(r#""retransmissions":1"#));
+        assert!(json.contains(r#""error":"request timed out""#));
+
+        assert_eq!(recorder.take().len(), 2);
+        assert!(recorder.log().is_empty());
+    }
+
+    #[test]
+    fn test_send_receive_recorded() {
+        let server_port = server::test::spawn_server(|req: CoAPRequest| async { req.response }).recv().unwrap();
+
+        let recorder = Recorder::new().with_max_exchanges(2);
+        let mut client = CoAPClient::new(format!("127.0.0.1:{}", server_port)).unwrap();
+        client.set_recorder(Some(recorder.clone()));
+        client.set_receive_timeout(Some(Duration::from_secs(1))).unwrap();
+        for path in &["/a", "/b", "/c"] {
+            let mut request = CoAPRequest::new();
+            request.set_path(path);
+            request.message.set_token(path.as_bytes().to_vec());
+            client.send(&request).unwrap();
+            client.receive().unwrap();
+        }
+
+        let log = recorder.log();
+        assert_eq!(log.len(), 2);
+        assert_eq!(log.exchanges[0].request.get_token(), &b"/b".to_vec());
+        assert_eq!(log.exchanges[1].response.as_ref().unwrap().get_token(), &b"/c".to_vec());
+        assert_eq!(SessionLog::from_json(&log.to_json()).unwrap(), log);
+        assert!(SessionLog::from_json("{}").is_err());
+    }
+
+    #[test]
+    fn test_default_recorder() {
+        let server_port = server::test::spawn_server(|req: CoAPRequest| async { req.response }).recv().unwrap();
+
+        let recorder = Recorder::new();
+        set_default_recorder(Some(recorder.clone()));
+        let response = CoAPClient::get_with_timeout(
+            &format!("coap://127.0.0.1:{}/fw", server_port),
+            Duration::from_secs(1),
+        );
+        set_default_recorder(None);
+        response.unwrap();
+
+        // clients of tests running alongside may record as well
+        let log = recorder.log();
+        assert!(log.exchanges.iter().any(|exchange| {
+            exchange.peer.port() == server_port && exchange.response.is_some()
+        }));
+    }
+}
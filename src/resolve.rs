@@ -0,0 +1,145 @@
+//! Resolving host names to socket addresses in place of the system resolver.
+//!
+//! Constructors taking a `ToSocketAddrs` go through the system's DNS, which many devices
+//! lack. A `Resolver`, e.g. a closure querying trust-dns or DNS-over-HTTPS, a `HostsTable`
+//! or an `MdnsResolver` for `.local` names, is passed to `CoAPClient::new_with_resolver`,
+//! `DTLSClientBuilder::connect_with_resolver` or `ForwardProxy::set_resolver` instead.
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+
+use super::mdns::MdnsDiscovery;
+
+/// Resolves a host name to the addresses of a port, in order of preference.
+pub trait Resolver: Send + Sync {
+    fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>>;
+}
+
+impl<F> Resolver for F
+where
+    F: Fn(&str, u16) -> Result<Vec<SocketAddr>> + Send + Sync,
+{
+    fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+        self(host, port)
+    }
+}
+
+/// Resolves with the system's resolver, as `ToSocketAddrs` does.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+        Ok((host, port).to_socket_addrs()?.collect())
+    }
+}
+
+/// A static table of host names, like `/etc/hosts`. Names not in the table are not found.
+#[derive(Clone, Debug, Default)]
+pub struct HostsTable {
+    hosts: HashMap<String, Vec<IpAddr>>,
+}
+
+impl HostsTable {
+    pub fn new() -> HostsTable {
+        HostsTable::default()
+    }
+
+    /// Adds an address of the host, after those added before. Names are case-insensitive.
+    pub fn insert(&mut self, host: &str, ip: IpAddr) {
+        let addrs = self.hosts.entry(host.to_lowercase()).or_default();
+        if !addrs.contains(&ip) {
+            addrs.push(ip);
+        }
+    }
+}
+
+impl Resolver for HostsTable {
+    fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+        match self.hosts.get(&host.to_lowercase()) {
+            Some(addrs) => Ok(addrs.iter().map(|ip| SocketAddr::new(*ip, port)).collect()),
+            None => Err(Error::new(ErrorKind::NotFound, format!("unknown host {}", host))),
+        }
+    }
+}
+
+/// Resolves `.local` names with mDNS (RFC 6762) and other names with a fallback resolver,
+/// the system's by default.
+pub struct MdnsResolver {
+    discovery: MdnsDiscovery,
+    fallback: Box<dyn Resolver>,
+}
+
+impl MdnsResolver {
+    /// A resolver querying the mDNS group.
+    pub fn new() -> Result<MdnsResolver> {
+        Ok(Self::with_discovery(MdnsDiscovery::new()?))
+    }
+
+    /// A resolver querying with the given discovery, e.g. one with a shorter timeout.
+    pub fn with_discovery(discovery: MdnsDiscovery) -> MdnsResolver {
+        MdnsResolver {
+            discovery,
+            fallback: Box::new(SystemResolver),
+        }
+    }
+
+    /// Set the resolver for names outside `.local`.
+    pub fn with_fallback<R: Resolver + 'static>(mut self, fallback: R) -> MdnsResolver {
+        self.fallback = Box::new(fallback);
+        self
+    }
+}
+
+impl Resolver for MdnsResolver {
+    fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+        if !host.trim_end_matches('.').to_lowercase().ends_with(".local") {
+            return self.fallback.resolve(host, port);
+        }
+        let addrs = self.discovery.resolve_host(host)?;
+        if addrs.is_empty() {
+            return Err(Error::new(ErrorKind::NotFound, format!("no mDNS answer for {}", host)));
+        }
+        Ok(addrs.into_iter().map(|ip| SocketAddr::new(ip, port)).collect())
+    }
+}
+
+/// Resolves the host with the resolver unless it is an IP literal, which needs none.
+pub(crate) fn resolve<R: Resolver + ?Sized>(resolver: &R, host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(vec![SocketAddr::new(ip, port)]);
+    }
+    let addrs = resolver.resolve(host, port)?;
+    if addrs.is_empty() {
+        return Err(Error::other("no address"));
+    }
+    Ok(addrs)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_resolvers() {
+        let mut hosts = HostsTable::new();
+        hosts.insert("Sensor.example", IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)));
+        assert_eq!(
+            resolve(&hosts, "sensor.example", 5683).unwrap(),
+            vec!["192.0.2.1:5683".parse().unwrap()]
+        );
+        assert_eq!(hosts.resolve("other.example", 5683).unwrap_err().kind(), ErrorKind::NotFound);
+        // literals never reach the resolver
+        assert_eq!(resolve(&hosts, "::1", 5683).unwrap(), vec!["[::1]:5683".parse().unwrap()]);
+
+        let closure = |host: &str, port: u16| -> Result<Vec<SocketAddr>> {
+            assert_eq!(host, "gateway");
+            Ok(vec![SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port)])
+        };
+        assert_eq!(resolve(&closure, "gateway", 1).unwrap(), vec!["127.0.0.1:1".parse().unwrap()]);
+        let nothing = |_: &str, _: u16| -> Result<Vec<SocketAddr>> { Ok(Vec::new()) };
+        assert!(resolve(&nothing, "gateway", 1).is_err());
+    }
+}
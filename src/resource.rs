@@ -0,0 +1,281 @@
+//! A piece of state exposed as a resource, versioned for conditional requests and observers.
+
+use std::sync::{Arc, Mutex};
+use serde::Serialize;
+
+use super::cbor::Value;
+use super::codec::{self, CodecRegistry};
+use super::message::packet::{decode_uint, encode_uint, CoAPOption, ContentFormat, Packet};
+use super::message::request::{CoAPRequest, Method};
+use super::message::response::{CoAPResponse, ContentError, Status};
+use super::message::IsMessage;
+use super::observer::ResourcePublisher;
+use super::rng;
+
+/// A value served at a path, with an ETag that changes whenever the value is `set`.
+///
+/// `respond` answers GET requests with the value encoded in the Content-Format the request
+/// accepts, CBOR by default, or with 2.03 Valid if the request names the current ETag of
+/// that Content-Format. Each representation has an ETag of its own, as a cache must not take
+/// the CBOR encoding for the JSON one. The versions start at a random value, so that a
+/// restarted server does not hand out the ETags of its earlier values again. With
+/// a publisher from `Server::resource_publisher`, every `set` notifies the observers of the
+/// path, which the server registers like those of a resource changed by a PUT. Clones share
+/// the value and the publisher, e.g. with the request handler.
+pub struct VersionedResource<T> {
+    path: String,
+    state: Arc<Mutex<Versioned<T>>>,
+    codecs: CodecRegistry,
+    content_format: u32,
+}
+
+struct Versioned<T> {
+    value: T,
+    // the value in the CBOR data model, encoded for each request
+    data: Value,
+    version: u32,
+    publisher: Option<ResourcePublisher>,
+}
+
+impl<T> Clone for VersionedResource<T> {
+    fn clone(&self) -> VersionedResource<T> {
+        VersionedResource {
+            path: self.path.clone(),
+            state: self.state.clone(),
+            codecs: self.codecs.clone(),
+            content_format: self.content_format,
+        }
+    }
+}
+
+impl<T: Serialize> VersionedResource<T> {
+    pub fn new(path: &str, value: T) -> Result<VersionedResource<T>, ContentError> {
+        let data = codec::to_value(&value)?;
+        Ok(VersionedResource {
+            path: path.trim_matches('/').to_string(),
+            state: Arc::new(Mutex::new(Versioned { value, data, version: rng::next_u32(), publisher: None })),
+            codecs: CodecRegistry::default(),
+            content_format: ContentFormat::ApplicationCBOR as u32,
+        })
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Notifies the observers of the path through the publisher on every change, publishing
+    /// the current value right away so that observers can register.
+    pub fn set_publisher(&self, publisher: ResourcePublisher) -> Result<(), ContentError> {
+        let mut state = self.state.lock().unwrap();
+        state.publisher = Some(publisher);
+        self.publish(&state)
+    }
+
+    /// Set the codecs the value is encoded with.
+    pub fn set_codecs(&mut self, codecs: CodecRegistry) {
+        self.codecs = codecs;
+    }
+
+    /// Set the Content-Format of responses to requests without an Accept option and of
+    /// notifications.
+    pub fn set_content_format(&mut self, content_format: u32) {
+        self.content_format = content_format;
+    }
+
+    /// The version of the value, incremented by each `set`.
+    pub fn version(&self) -> u32 {
+        self.state.lock().unwrap().version
+    }
+
+    /// The ETag of the current value in the Content-Format of responses without an Accept
+    /// option, see `etag_for`.
+    pub fn etag(&self) -> Vec<u8> {
+        self.etag_for(self.content_format)
+    }
+
+    /// The ETag of the current value encoded in the Content-Format: the big-endian version
+    /// followed by the big-endian format, e.g. for `CoAPRequest::detect_conflict`.
+    pub fn etag_for(&self, content_format: u32) -> Vec<u8> {
+        format_etag(self.version(), content_format)
+    }
+
+    pub fn get(&self) -> T
+    where
+        T: Clone,
+    {
+        self.state.lock().unwrap().value.clone()
+    }
+
+    /// Replaces the value, bumping the version and notifying the observers.
+    pub fn set(&self, value: T) -> Result<(), ContentError> {
+        let data = codec::to_value(&value)?;
+        let mut state = self.state.lock().unwrap();
+        state.value = value;
+        state.data = data;
+        state.version = state.version.wrapping_add(1);
+        // published under the lock, so that observers see concurrent changes in order
+        self.publish(&state)
+    }
+
+    /// Whether the request targets the path of the resource.
+    pub fn matches(&self, request: &CoAPRequest) -> bool {
+        request.get_path().trim_matches('/') == self.path
+    }
+
+    /// Answers a request to the resource, `None` for messages that are not answered.
+    pub fn respond(&self, request: &CoAPRequest) -> Option<CoAPResponse> {
+        let mut response = request.response.clone()?;
+        response.message.payload.clear();
+        if *request.get_method() != Method::Get {
+            response.set_status(Status::MethodNotAllowed);
+            return Some(response);
+        }
+
+        let format = match request.get_option(CoAPOption::Accept).and_then(|list| list.front()) {
+            Some(accept) => match decode_uint(accept) {
+                Some(format) if self.codecs.supports(format) => format,
+                _ => {
+                    response.set_status(Status::NotAcceptable);
+                    return Some(response);
+                }
+            },
+            None => self.content_format,
+        };
+
+        let state = self.state.lock().unwrap();
+        let etag = format_etag(state.version, format);
+        let valid = request
+            .get_option(CoAPOption::ETag)
+            .is_some_and(|list| list.iter().any(|value| *value == etag));
+        if valid {
+            response.set_status(Status::Valid);
+            response.message.set_etag(etag);
+            return Some(response);
+        }
+
+        match self.codecs.encode(format, &state.data) {
+            Ok(payload) => {
+                response.set_status(Status::Content);
+                response.message.add_option(CoAPOption::ContentFormat, encode_uint(format));
+                response.message.set_etag(etag);
+                response.message.payload = payload;
+            }
+            Err(e) => response.set_error(Status::InternalServerError, &e.to_string()),
+        }
+        Some(response)
+    }
+
+    fn publish(&self, state: &Versioned<T>) -> Result<(), ContentError> {
+        let publisher = match state.publisher {
+            Some(ref publisher) => publisher,
+            None => return Ok(()),
+        };
+        let mut representation = Packet::new();
+        representation.payload = self.codecs.encode(self.content_format, &state.data)?;
+        representation.add_option(CoAPOption::ContentFormat, encode_uint(self.content_format));
+        representation.set_etag(format_etag(state.version, self.content_format));
+        publisher.publish(&self.path, &representation);
+        Ok(())
+    }
+}
+
+fn format_etag(version: u32, content_format: u32) -> Vec<u8> {
+    let mut etag = version.to_be_bytes().to_vec();
+    etag.extend_from_slice(&(content_format as u16).to_be_bytes());
+    etag
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::client::CoAPClient;
+    use super::super::server::Server;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[derive(Clone, Serialize)]
+    struct Lamp {
+        on: bool,
+        level: u8,
+    }
+
+    #[test]
+    fn test_versioned_resource() {
+        let lamp = VersionedResource::new("/lamp", Lamp { on: false, level: 0 }).unwrap();
+        let handler_lamp = lamp.clone();
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new().unwrap().block_on(async move {
+                let mut server = Server::new("127.0.0.1:0").unwrap();
+                tx.send((server.socket_addr().unwrap(), server.resource_publisher())).unwrap();
+                server
+                    .run(move |request: CoAPRequest| {
+                        let response = if handler_lamp.matches(&request) {
+                            handler_lamp.respond(&request)
+                        } else {
+                            None
+                        };
+                        async move { response }
+                    })
+                    .await
+                    .unwrap();
+            })
+        });
+        let (server_addr, publisher) = rx.recv().unwrap();
+        lamp.set_publisher(publisher).unwrap();
+
+        let client = CoAPClient::new(server_addr).unwrap();
+        let mut request = CoAPRequest::new();
+        request.set_path("/lamp");
+        let response = client.request(&mut request).unwrap();
+        assert_eq!(*response.get_status(), Status::Content);
+        let version = lamp.version();
+        assert_eq!(response.message.get_etag(), Some(&lamp.etag()));
+        assert_eq!(response.message.payload, vec![0xA2, 0x62, b'o', b'n', 0xF4, 0x65, b'l', b'e', b'v', b'e', b'l', 0x00]);
+
+        // a cached representation is still valid, in its own Content-Format only
+        let mut request = CoAPRequest::new();
+        request.set_path("/lamp");
+        request.add_option(CoAPOption::ETag, lamp.etag());
+        let response = client.request(&mut request).unwrap();
+        assert_eq!(*response.get_status(), Status::Valid);
+        assert!(response.message.payload.is_empty());
+
+        request.add_option(CoAPOption::Accept, vec![50]);
+        let response = client.request(&mut request).unwrap();
+        assert_eq!(*response.get_status(), Status::Content);
+        assert_eq!(response.message.get_etag(), Some(&lamp.etag_for(50)));
+        assert_ne!(lamp.etag_for(50), lamp.etag());
+
+        let (notifications_tx, notifications) = mpsc::channel();
+        let notifications_tx = Mutex::new(notifications_tx);
+        // on a client of its own, whose socket the observation does not share with requests
+        let observer = CoAPClient::new(server_addr).unwrap();
+        let _observation = observer
+            .observe("/lamp", move |packet| notifications_tx.lock().unwrap().send(packet).unwrap())
+            .unwrap();
+        notifications.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        lamp.set(Lamp { on: true, level: 80 }).unwrap();
+        let notification = notifications.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(lamp.version(), version.wrapping_add(1));
+        assert_eq!(notification.get_etag(), Some(&lamp.etag()));
+        assert_eq!(notification.get_content_format(), Some(ContentFormat::ApplicationCBOR));
+        assert_eq!(
+            codec::to_value(&lamp.get()).unwrap(),
+            CodecRegistry::default().decode(60, &notification.payload).unwrap()
+        );
+
+        let mut request = CoAPRequest::new();
+        request.set_path("/lamp");
+        request.add_option(CoAPOption::ETag, format_etag(version, 50));
+        request.add_option(CoAPOption::Accept, vec![50]);
+        let response = client.request(&mut request).unwrap();
+        assert_eq!(*response.get_status(), Status::Content);
+        assert_eq!(response.message.payload, br#"{"on":true,"level":80}"#.to_vec());
+
+        request.clear_option(CoAPOption::Accept);
+        request.add_option(CoAPOption::Accept, vec![0xFF, 0xFF]);
+        assert_eq!(*client.request(&mut request).unwrap().get_status(), Status::NotAcceptable);
+    }
+}
@@ -0,0 +1,132 @@
+//! The source of the randomness of the crate: tokens, initial message IDs, trace IDs, the
+//! dithering of retransmission timeouts (ACK_RANDOM_FACTOR) and the delay of replies to
+//! multicast requests.
+//!
+//! By default the randomness comes from the operating system through OpenSSL. A constrained
+//! platform installs its hardware generator with `set_random_source`, and a test installs a
+//! `SeededRandom` to get the same sequence on every run. The keys generated inside OpenSSL,
+//! e.g. the ephemeral keys of EDHOC, and the ACE nonces, which key material is derived from,
+//! do not go through this source but always come from the operating system.
+
+use lazy_static::lazy_static;
+use openssl::rand::rand_bytes;
+use std::io;
+use std::sync::{Arc, Mutex, RwLock};
+
+/// Generates random bytes.
+///
+/// Closures filling a buffer implement it.
+pub trait RandomSource: Send + Sync {
+    fn fill_bytes(&self, buf: &mut [u8]);
+}
+
+impl<F: Fn(&mut [u8]) + Send + Sync> RandomSource for F {
+    fn fill_bytes(&self, buf: &mut [u8]) {
+        self(buf)
+    }
+}
+
+/// The generator of the operating system, the default source.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OsRandom;
+
+impl OsRandom {
+    /// Fills the buffer, failing rather than panicking if the system generator fails.
+    pub fn try_fill_bytes(&self, buf: &mut [u8]) -> io::Result<()> {
+        rand_bytes(buf).map_err(io::Error::other)
+    }
+}
+
+impl RandomSource for OsRandom {
+    fn fill_bytes(&self, buf: &mut [u8]) {
+        self.try_fill_bytes(buf).expect("the system random generator failed");
+    }
+}
+
+/// A xorshift64* generator producing the same sequence for the same seed, for reproducible
+/// tests. It is not suitable for security.
+#[derive(Debug)]
+pub struct SeededRandom(Mutex<u64>);
+
+impl SeededRandom {
+    pub fn new(seed: u64) -> SeededRandom {
+        SeededRandom(Mutex::new(if seed == 0 { 0x5EED } else { seed }))
+    }
+}
+
+impl RandomSource for SeededRandom {
+    fn fill_bytes(&self, buf: &mut [u8]) {
+        let mut state = self.0.lock().unwrap();
+        for chunk in buf.chunks_mut(8) {
+            *state ^= *state >> 12;
+            *state ^= *state << 25;
+            *state ^= *state >> 27;
+            let value = state.wrapping_mul(0x2545_F491_4F6C_DD1D);
+            chunk.copy_from_slice(&value.to_be_bytes()[..chunk.len()]);
+        }
+    }
+}
+
+lazy_static! {
+    static ref SOURCE: RwLock<Arc<dyn RandomSource>> = RwLock::new(Arc::new(OsRandom));
+}
+
+/// Replaces the source of randomness of the whole process, e.g. with a hardware generator.
+/// Clients and servers created before keep the identifiers they already drew.
+pub fn set_random_source<R: RandomSource + 'static>(source: R) {
+    *SOURCE.write().unwrap() = Arc::new(source);
+}
+
+/// The current source of randomness.
+pub fn random_source() -> Arc<dyn RandomSource> {
+    SOURCE.read().unwrap().clone()
+}
+
+pub(crate) fn fill_bytes(buf: &mut [u8]) {
+    random_source().fill_bytes(buf);
+}
+
+pub(crate) fn next_u32() -> u32 {
+    next_u32_from(&*random_source())
+}
+
+/// A number in [0, 1).
+pub(crate) fn next_f64() -> f64 {
+    next_f64_from(&*random_source())
+}
+
+pub(crate) fn next_u32_from(source: &dyn RandomSource) -> u32 {
+    let mut buf = [0; 4];
+    source.fill_bytes(&mut buf);
+    u32::from_be_bytes(buf)
+}
+
+pub(crate) fn next_f64_from(source: &dyn RandomSource) -> f64 {
+    let mut buf = [0; 8];
+    source.fill_bytes(&mut buf);
+    // the 53 bits a double holds
+    (u64::from_be_bytes(buf) >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_random_source() {
+        let sequence = |source: &dyn RandomSource| (0..4).map(|_| next_u32_from(source)).collect::<Vec<u32>>();
+        assert_eq!(sequence(&SeededRandom::new(7)), sequence(&SeededRandom::new(7)));
+        assert_ne!(sequence(&SeededRandom::new(7)), sequence(&SeededRandom::new(8)));
+
+        let mut odd = [0; 5];
+        SeededRandom::new(7).fill_bytes(&mut odd);
+        let mut even = [0; 8];
+        SeededRandom::new(7).fill_bytes(&mut even);
+        assert_eq!(odd[..], even[..5]);
+
+        let constant = |buf: &mut [u8]| buf.iter_mut().for_each(|byte| *byte = 0xFF);
+        assert_eq!(next_u32_from(&constant), u32::max_value());
+        assert!(next_f64_from(&constant) < 1.0);
+        assert!((0..100).map(|_| next_f64_from(&OsRandom)).all(|value| value >= 0.0 && value < 1.0));
+    }
+}
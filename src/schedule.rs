@@ -0,0 +1,316 @@
+//! Requests scheduled for later execution, e.g. during the next radio window of a
+//! duty-cycled device.
+//!
+//! A `RequestScheduler` keeps requests until they are due and sends them through a client
+//! with `send_due`, or all at once with `flush` when the radio comes up. A PUT scheduled while
+//! another PUT to the same resource is pending replaces it, so only the latest value is
+//! written. The pending requests can be persisted through a state hook and restored after a
+//! reboot, which is why due times are wall-clock times.
+
+use std::io;
+use std::time::{Duration, SystemTime};
+use serde::{Deserialize, Serialize};
+
+use super::client::CoAPClient;
+use super::message::packet::{CoAPOption, Packet};
+use super::message::request::{CoAPRequest, Method};
+use super::message::response::CoAPResponse;
+use super::message::IsMessage;
+
+// the options identifying the resource a request targets
+const URI_OPTIONS: [CoAPOption; 4] = [CoAPOption::UriHost, CoAPOption::UriPort, CoAPOption::UriPath, CoAPOption::UriQuery];
+
+/// The requests pending in a scheduler, to be stored and restored after a reboot.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ScheduleState {
+    entries: Vec<ScheduledState>,
+    next_id: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct ScheduledState {
+    id: u64,
+    due: SystemTime,
+    request: Packet,
+}
+
+impl ScheduleState {
+    /// The number of pending requests.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Encodes the state for storage.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    /// Decodes a state produced by `to_bytes`.
+    pub fn from_bytes(buf: &[u8]) -> io::Result<ScheduleState> {
+        bincode::deserialize(buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+struct Scheduled {
+    id: u64,
+    due: SystemTime,
+    request: CoAPRequest,
+}
+
+/// Requests waiting for their due time, sent earliest first.
+#[derive(Default)]
+pub struct RequestScheduler {
+    pending: Vec<Scheduled>,
+    next_id: u64,
+    state_hook: Option<Box<dyn FnMut(ScheduleState) + Send>>,
+}
+
+impl RequestScheduler {
+    pub fn new() -> RequestScheduler {
+        Self::default()
+    }
+
+    /// Schedules a request to be sent at `due`, returning an ID to cancel it with.
+    ///
+    /// A PUT replaces a pending PUT to the same resource, taking its ID and the earlier of
+    /// both due times.
+    pub fn schedule(&mut self, request: CoAPRequest, due: SystemTime) -> u64 {
+        let existing = if *request.get_method() == Method::Put {
+            self.pending.iter().position(|scheduled| {
+                *scheduled.request.get_method() == Method::Put && same_resource(&scheduled.request, &request)
+            })
+        } else {
+            None
+        };
+        let id = match existing {
+            Some(idx) => {
+                let scheduled = &mut self.pending[idx];
+                scheduled.due = scheduled.due.min(due);
+                scheduled.request = request;
+                scheduled.id
+            }
+            None => {
+                self.next_id += 1;
+                self.pending.push(Scheduled { id: self.next_id, due, request });
+                self.next_id
+            }
+        };
+        self.state_changed();
+        id
+    }
+
+    /// Schedules a request to be sent after a delay.
+    pub fn schedule_in(&mut self, request: CoAPRequest, delay: Duration) -> u64 {
+        self.schedule(request, SystemTime::now() + delay)
+    }
+
+    /// Removes a pending request.
+    pub fn cancel(&mut self, id: u64) -> Option<CoAPRequest> {
+        let idx = self.pending.iter().position(|scheduled| scheduled.id == id)?;
+        let scheduled = self.pending.remove(idx);
+        self.state_changed();
+        Some(scheduled.request)
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// When the earliest pending request is due, e.g. to plan the next radio window.
+    pub fn next_due(&self) -> Option<SystemTime> {
+        self.pending.iter().map(|scheduled| scheduled.due).min()
+    }
+
+    /// Removes the requests due at `now`, earliest first.
+    pub fn take_due(&mut self, now: SystemTime) -> Vec<CoAPRequest> {
+        let mut due = Vec::new();
+        let mut idx = 0;
+        while idx < self.pending.len() {
+            if self.pending[idx].due <= now {
+                due.push(self.pending.remove(idx));
+            } else {
+                idx += 1;
+            }
+        }
+        if !due.is_empty() {
+            self.state_changed();
+        }
+        due.sort_by_key(|scheduled| (scheduled.due, scheduled.id));
+        due.into_iter().map(|scheduled| scheduled.request).collect()
+    }
+
+    /// Sends the requests that are due with `CoAPClient::request`, returning each with its
+    /// outcome. Each request gets a message ID and token of the client when it is sent, and
+    /// is only removed once it was answered, so a failed request stays pending.
+    pub fn send_due(&mut self, client: &CoAPClient) -> Vec<(CoAPRequest, io::Result<CoAPResponse>)> {
+        self.send_until(SystemTime::now(), client)
+    }
+
+    /// Sends every pending request whether due or not, e.g. when the radio comes up.
+    pub fn flush(&mut self, client: &CoAPClient) -> Vec<(CoAPRequest, io::Result<CoAPResponse>)> {
+        match self.pending.iter().map(|scheduled| scheduled.due).max() {
+            Some(latest) => self.send_until(latest, client),
+            None => Vec::new(),
+        }
+    }
+
+    fn send_until(&mut self, now: SystemTime, client: &CoAPClient) -> Vec<(CoAPRequest, io::Result<CoAPResponse>)> {
+        let mut due: Vec<(SystemTime, u64)> = self.pending
+            .iter()
+            .filter(|scheduled| scheduled.due <= now)
+            .map(|scheduled| (scheduled.due, scheduled.id))
+            .collect();
+        due.sort();
+        let mut sent = Vec::new();
+        for (_, id) in due {
+            let idx = match self.pending.iter().position(|scheduled| scheduled.id == id) {
+                Some(idx) => idx,
+                None => continue,
+            };
+            let mut request = self.pending[idx].request.clone();
+            request.set_message_id(client.next_message_id());
+            request.set_token(client.next_token());
+            let response = client.request(&mut request);
+            if response.is_ok() {
+                self.pending.remove(idx);
+                self.state_changed();
+            }
+            sent.push((request, response));
+        }
+        sent
+    }
+
+    /// Takes a snapshot of the pending requests.
+    pub fn export_state(&self) -> ScheduleState {
+        ScheduleState {
+            entries: self.pending
+                .iter()
+                .map(|scheduled| ScheduledState {
+                    id: scheduled.id,
+                    due: scheduled.due,
+                    request: scheduled.request.message.clone(),
+                })
+                .collect(),
+            next_id: self.next_id,
+        }
+    }
+
+    /// Restores a snapshot taken by `export_state`, replacing the pending requests. Requests
+    /// whose due time passed while the device was down are due at once.
+    pub fn restore_state(&mut self, state: ScheduleState) {
+        self.pending = state.entries
+            .into_iter()
+            .map(|entry| {
+                let mut request = CoAPRequest::new();
+                request.message = entry.request;
+                Scheduled { id: entry.id, due: entry.due, request }
+            })
+            .collect();
+        self.next_id = state.next_id;
+    }
+
+    /// Sets a hook receiving a snapshot whenever a request is scheduled, cancelled or taken,
+    /// so that the pending requests can be persisted as they change.
+    pub fn set_state_hook<F: FnMut(ScheduleState) + Send + 'static>(&mut self, hook: F) {
+        self.state_hook = Some(Box::new(hook));
+    }
+
+    fn state_changed(&mut self) {
+        if self.state_hook.is_some() {
+            let state = self.export_state();
+            if let Some(ref mut hook) = self.state_hook {
+                hook(state);
+            }
+        }
+    }
+}
+
+fn same_resource(a: &CoAPRequest, b: &CoAPRequest) -> bool {
+    URI_OPTIONS.iter().all(|&option| a.message.get_option(option) == b.message.get_option(option))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::*;
+    use std::sync::{Arc, Mutex};
+
+    fn put(path: &str, payload: &[u8]) -> CoAPRequest {
+        let mut request = CoAPRequest::new();
+        request.set_method(Method::Put);
+        request.set_path(path);
+        request.set_payload(payload.to_vec());
+        request
+    }
+
+    #[test]
+    fn test_scheduler() {
+        let now = SystemTime::now();
+        let states = Arc::new(Mutex::new(Vec::new()));
+        let hook_states = states.clone();
+        let mut scheduler = RequestScheduler::new();
+        scheduler.set_state_hook(move |state| hook_states.lock().unwrap().push(state.len()));
+
+        let setpoint = scheduler.schedule(put("setpoint", b"20"), now + Duration::from_secs(60));
+        let mut post = put("log", b"boot");
+        post.set_method(Method::Post);
+        scheduler.schedule(post, now + Duration::from_secs(30));
+        // the newer value replaces the pending one and keeps the earlier due time
+        assert_eq!(scheduler.schedule(put("setpoint", b"21"), now + Duration::from_secs(90)), setpoint);
+        assert_eq!(scheduler.len(), 2);
+        assert_eq!(scheduler.next_due(), Some(now + Duration::from_secs(30)));
+
+        let restored = ScheduleState::from_bytes(&scheduler.export_state().to_bytes()).unwrap();
+        let mut rebooted = RequestScheduler::new();
+        rebooted.restore_state(restored);
+        assert_eq!(rebooted.export_state(), scheduler.export_state());
+
+        assert!(scheduler.take_due(now).is_empty());
+        let due = scheduler.take_due(now + Duration::from_secs(60));
+        let paths: Vec<String> = due.iter().map(|request| request.get_path()).collect();
+        assert_eq!(paths, vec!["log", "setpoint"]);
+        assert_eq!(due[1].message.payload, b"21".to_vec());
+        assert_eq!(*states.lock().unwrap(), vec![1, 2, 2, 0]);
+
+        let server_port = server::test::spawn_server(|req: CoAPRequest| async { req.response }).recv().unwrap();
+        let client = CoAPClient::new(format!("127.0.0.1:{}", server_port)).unwrap();
+        let late = rebooted.schedule_in(put("valve", b"open"), Duration::from_secs(3600));
+        assert!(rebooted.send_due(&client).is_empty());
+        assert!(rebooted.cancel(late).is_some());
+        let sent = rebooted.flush(&client);
+        assert_eq!(sent.len(), 2);
+        assert!(sent.iter().all(|(_, response)| response.is_ok()));
+        assert!(rebooted.is_empty());
+        assert_ne!(sent[0].0.message.header.get_message_id(), sent[1].0.message.header.get_message_id());
+        assert!(!sent[0].0.get_token().is_empty());
+        assert_ne!(sent[0].0.get_token(), sent[1].0.get_token());
+    }
+
+    #[test]
+    fn test_failed_request_stays_pending() {
+        let states = Arc::new(Mutex::new(Vec::new()));
+        let hook_states = states.clone();
+        let mut scheduler = RequestScheduler::new();
+        scheduler.set_state_hook(move |state| hook_states.lock().unwrap().push(state.len()));
+        scheduler.schedule(put("valve", b"open"), SystemTime::now());
+
+        // nothing listens on the port, so the request is refused
+        let port = std::net::UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let mut client = CoAPClient::new(format!("127.0.0.1:{}", port)).unwrap();
+        let transmission = client.transmission_parameters().with_timeout(Duration::from_millis(100)).with_max_retransmit(0);
+        client.set_transmission_parameters(transmission);
+        let sent = scheduler.send_due(&client);
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0].1.is_err());
+        assert_eq!(scheduler.len(), 1);
+        assert_eq!(*states.lock().unwrap(), vec![1]);
+    }
+}
@@ -1,29 +1,53 @@
 use std::{
     self,
+    collections::{HashMap, VecDeque},
     pin::Pin,
-    net::{self, SocketAddr, ToSocketAddrs},
+    net::{SocketAddr, ToSocketAddrs},
+    sync::{Arc, Mutex},
     task::Context,
     future::Future,
+    time::{Duration, Instant},
 };
-use log::{debug, error};
-use futures::{SinkExt, Stream, StreamExt, select, stream::FusedStream, task::Poll};
+use log::{debug, error, warn};
+use futures::{FutureExt, Stream, StreamExt, select, stream::{self, FusedStream, SelectAll}, task::Poll};
+use openssl::ssl::SslContextBuilder;
 use tokio::{
     io,
     sync::mpsc,
-    net::UdpSocket
 };
-use tokio_util::udp::{UdpFramed};
 
 use super::message::{
-    packet::Packet,
-    request::{CoAPRequest},
-    response::CoAPResponse,
-    Codec,
+    header::{MessageClass, MessageType},
+    packet::{ContentFormat, Packet},
+    request::{CoAPRequest, Method},
+    response::{CoAPResponse, Status},
 };
-use super::observer::Observer;
+use super::acl::Acl;
+use super::budget::MemoryBudget;
+use super::capability::Capabilities;
+use super::context::Transport;
+use super::datagram::{with_flow_label, DatagramInfo, DatagramSocket, MAX_FLOW_LABEL};
+use super::exchange::ExchangeRegistry;
+use super::observer::{NotificationPacing, ObserveDecision, ObserveState, Observer, ResourcePublisher};
+use super::diag::Diagnostics;
+use super::dtls_server::{DtlsListener, EvictionHook, HANDSHAKE_TIMEOUT};
+use super::rng;
+use super::session::{EvictionReason, SessionPolicy};
+use super::stats::PeerStatsRegistry;
+use super::trace::{self, Tracing};
+use super::transfer::BodyTransfers;
 
 pub type MessageSender = mpsc::UnboundedSender<(Packet, SocketAddr)>;
 type MessageReceiver = mpsc::UnboundedReceiver<(Packet, SocketAddr)>;
+type ResponseFilter<'a> = Box<dyn FnMut(&CoAPRequest, &mut CoAPResponse) + Send + 'a>;
+
+// peers whose last datagram the server remembers, to address notifications from
+const MAX_REMEMBERED_PEERS: usize = 4096;
+const DEFAULT_HANDLER_TIMEOUT: u64 = 2; // 2s, the ACK_TIMEOUT of the client
+// how long duplicates of a confirmable request are answered from the cache (EXCHANGE_LIFETIME)
+pub(crate) const EXCHANGE_LIFETIME: u64 = 247; // 247s
+// the responses kept for duplicates by default, the oldest evicted beyond
+pub(crate) const DEFAULT_MAX_CACHED_RESPONSES: usize = 1024;
 
 #[derive(Debug)]
 pub enum CoAPServerError {
@@ -41,13 +65,79 @@ pub struct QueuedMessage {
 
 pub enum Message {
     NeedSend(Packet, SocketAddr),
-    Received(Packet, SocketAddr),
+    Received(Packet, SocketAddr, DatagramInfo),
+}
+
+/// How long the handler may run before the server answers a request with 5.00 (Internal
+/// Server Error) in its place, so that runaway application code does not stall the server.
+/// Built from the defaults with the `with_*` methods.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HandlerTimeout {
+    /// How long the handler may take to produce its response.
+    pub timeout: Duration,
+    /// When set, a confirmable request still unanswered after this time is acknowledged with
+    /// an empty ACK, so that the client stops retransmitting, and the response or the 5.00
+    /// follows as a separate non-confirmable response.
+    pub separate_after: Option<Duration>,
+    /// Whether an overrunning handler is dropped. Otherwise it keeps running after the 5.00
+    /// was sent, alongside the requests the server goes on handling, and its response is
+    /// discarded.
+    pub cancel: bool,
+}
+
+impl Default for HandlerTimeout {
+    fn default() -> HandlerTimeout {
+        HandlerTimeout {
+            timeout: Duration::from_secs(DEFAULT_HANDLER_TIMEOUT),
+            separate_after: None,
+            cancel: true,
+        }
+    }
+}
+
+impl HandlerTimeout {
+    pub fn with_timeout(mut self, timeout: Duration) -> HandlerTimeout {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_separate_after(mut self, separate_after: Option<Duration>) -> HandlerTimeout {
+        self.separate_after = separate_after;
+        self
+    }
+
+    pub fn with_cancel(mut self, cancel: bool) -> HandlerTimeout {
+        self.cancel = cancel;
+        self
+    }
 }
 
 pub struct Server<'a, HandlerRet> where HandlerRet: Future<Output=Option<CoAPResponse>> {
     server: CoAPServer,
     observer: Observer,
     handler: Option<Box<dyn FnMut(CoAPRequest) -> HandlerRet + Send + 'a>>,
+    response_filters: Vec<ResponseFilter<'a>>,
+    peer_stats: PeerStatsRegistry,
+    peer_stats_path: Option<String>,
+    diagnostics: Option<Diagnostics>,
+    transfers: BodyTransfers,
+    // the content formats declared in the capability document, once exposed
+    capability_formats: Option<Vec<u32>>,
+    // the block sizes declared in the capability document
+    capability_block_sizes: Vec<usize>,
+    tracing: Option<Tracing>,
+    multicast_leisure: Option<Duration>,
+    handler_timeout: Option<HandlerTimeout>,
+    // handler timeouts of single resources, by path
+    resource_timeouts: HashMap<String, HandlerTimeout>,
+    // the message ID of the last separate response
+    message_id: u16,
+    // the resources only the peers allowed by the ACL may reach, by path
+    resource_acls: Vec<(String, Acl)>,
+    // the handlers running under a timeout, polled alongside the sockets
+    supervised: SelectAll<Pin<Box<dyn Stream<Item=Supervised> + Send + 'a>>>,
+    // the replies duplicates of confirmable requests are answered with, once enabled
+    responses: Option<ResponseCache>,
 }
 
 impl<'a, HandlerRet> Server<'a, HandlerRet> where HandlerRet: Future<Output=Option<CoAPResponse>> {
@@ -58,9 +148,252 @@ impl<'a, HandlerRet> Server<'a, HandlerRet> where HandlerRet: Future<Output=Opti
             server: CoAPServer::new(addr, rx)?,
             observer: Observer::new(tx),
             handler: None,
+            response_filters: Vec::new(),
+            peer_stats: PeerStatsRegistry::new(),
+            peer_stats_path: None,
+            diagnostics: None,
+            transfers: BodyTransfers::default(),
+            capability_formats: None,
+            capability_block_sizes: Vec::new(),
+            tracing: None,
+            multicast_leisure: None,
+            handler_timeout: None,
+            resource_timeouts: HashMap::new(),
+            message_id: rng::next_u32() as u16,
+            resource_acls: Vec::new(),
+            supervised: SelectAll::new(),
+            responses: None,
         })
     }
 
+    /// Return the local address that the server is listening on. This can be useful when starting
+    /// a server on a random port as part of unit testing.
+    pub fn socket_addr(&self) -> std::io::Result<SocketAddr> {
+        self.server.socket_addr()
+    }
+
+    /// Listens on a further UDP address, e.g. another port or interface, returning the
+    /// address bound. All listeners share the handler, the observation registry and the
+    /// statistics; replies leave from the listener the request arrived on and notifications
+    /// from the one the observer registered on.
+    ///
+    /// Peers are told apart by their IP address and port throughout the server, so there is
+    /// no Unix domain socket listener.
+    pub fn add_listener<A: ToSocketAddrs>(&mut self, addr: A) -> Result<SocketAddr, io::Error> {
+        self.server.add_listener(addr)
+    }
+
+    /// Listens for DTLS (`coaps`) peers on a further UDP address, e.g. port 5684, accepting
+    /// their sessions with the context, which holds the server's certificate and key or its
+    /// PSK callback and must be built for `SslMethod::dtls()`. The listener adds the cookie
+    /// exchange to the context, replacing any cookie callbacks, so that peers prove they
+    /// receive at their address before the server keeps a handshake for them.
+    ///
+    /// The listener shares the handler and the registries with the other listeners. Requests
+    /// arriving on it carry `Transport::Dtls` in their context, and the PSK identity the peer
+    /// authenticated with, if any. Messages to peers without a session are not sent.
+    pub fn add_dtls_listener<A: ToSocketAddrs>(&mut self, addr: A, context: SslContextBuilder) -> Result<SocketAddr, io::Error> {
+        self.server.add_dtls_listener(addr, context)
+    }
+
+    /// Keeps the sessions of the DTLS listeners as the policy says, e.g. evicting the sessions
+    /// of devices idle for an hour, or the least recently used ones beyond 10000. Established
+    /// sessions are kept indefinitely by default. Handshakes in progress are capped and
+    /// timed out separately, so they never evict an established session.
+    pub fn set_dtls_session_policy(&mut self, policy: SessionPolicy) {
+        self.server.set_dtls_session_policy(policy)
+    }
+
+    /// Sets a hook called with the address and PSK identity of every peer whose DTLS session
+    /// is evicted or closed, e.g. to mark the device offline. It runs on the server's task
+    /// and must not block.
+    pub fn set_dtls_eviction_hook<F>(&mut self, hook: F)
+    where
+        F: FnMut(SocketAddr, Option<&[u8]>, EvictionReason) + Send + 'static,
+    {
+        self.server.set_dtls_eviction_hook(hook)
+    }
+
+    /// Return the local addresses of all listeners, in the order they were added.
+    pub fn socket_addrs(&self) -> std::io::Result<Vec<SocketAddr>> {
+        self.server.socket_addrs()
+    }
+
+    /// Marks the datagrams the server sends with the traffic class, see
+    /// `datagram::set_traffic_class`.
+    pub fn set_traffic_class(&mut self, traffic_class: u8) -> Result<(), io::Error> {
+        self.server.set_traffic_class(traffic_class)
+    }
+
+    /// Labels the datagrams the server sends to IPv6 peers with the flow label, or stops
+    /// labeling them, see `datagram::lease_flow_label`.
+    pub fn set_flow_label(&mut self, label: Option<u32>) -> Result<(), io::Error> {
+        self.server.set_flow_label(label)
+    }
+
+    /// Returns a sender for packets the server should send on its socket, such as
+    /// notifications relayed by a `ForwardProxy`.
+    pub fn message_sender(&self) -> MessageSender {
+        self.observer.message_sender()
+    }
+
+    /// Returns a publisher setting the representations of resources and notifying their
+    /// observers from outside the handler.
+    pub fn resource_publisher(&self) -> ResourcePublisher {
+        self.observer.resource_publisher()
+    }
+
+    /// Returns a handle listing the notifications awaiting acknowledgement, which can also
+    /// abort them.
+    pub fn exchanges(&self) -> ExchangeRegistry {
+        self.observer.exchanges()
+    }
+
+    /// Adds a filter applied to every handler response before it is sent, in the order the
+    /// filters were added, e.g. `filter::transcode`.
+    pub fn add_response_filter<F: FnMut(&CoAPRequest, &mut CoAPResponse) + Send + 'a>(&mut self, filter: F) {
+        self.response_filters.push(Box::new(filter));
+    }
+
+    /// Returns a handle to the per-peer statistics of the server.
+    pub fn peer_stats(&self) -> PeerStatsRegistry {
+        self.peer_stats.clone()
+    }
+
+    /// Serves the per-peer statistics as a CBOR map on GET requests to the path, e.g.
+    /// `.diag/peers`, ahead of the handler.
+    pub fn expose_peer_stats(&mut self, path: &str) {
+        self.peer_stats_path = Some(normalize_path(path));
+    }
+
+    /// Serves the built-in diagnostic resources under `/.diag` to the peers allowed by their
+    /// ACL.
+    pub fn enable_diagnostics(&mut self, diagnostics: Diagnostics) {
+        self.diagnostics = Some(diagnostics);
+    }
+
+    /// Serves the capability document at `/.well-known/coap` ahead of the handler, declaring
+    /// the content formats the handler accepts and produces, e.g. the `formats` of its
+    /// `CodecRegistry`.
+    pub fn expose_capabilities(&mut self, content_formats: Vec<u32>) {
+        self.capability_formats = Some(content_formats);
+    }
+
+    /// Declares the block sizes in the capability document, none by default. Only bodies the
+    /// handler streams with `CoAPResponse::set_body_stream` are served block-wise, in any
+    /// size from 16 to 1024 bytes, so a handler declares the sizes it streams bodies in.
+    pub fn set_capability_block_sizes(&mut self, mut block_sizes: Vec<usize>) {
+        block_sizes.sort_unstable();
+        block_sizes.dedup();
+        self.capability_block_sizes = block_sizes;
+    }
+
+    /// Describes what the server supports, as served by `expose_capabilities`.
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            max_message_size: self.server.max_message_size(),
+            content_formats: self.capability_formats.clone().unwrap_or_default(),
+            block_sizes: self.capability_block_sizes.clone(),
+            observe: self.observer.accepts_registrations(),
+        }
+    }
+
+    /// Extracts the trace IDs of requests into `RequestContext::trace_id` and logs every
+    /// answered request with its trace ID to the `coap::access` log target, see the `trace`
+    /// module.
+    pub fn set_tracing(&mut self, tracing: Tracing) {
+        self.tracing = Some(tracing);
+    }
+
+    /// Bounds the time the handler may take for every request, see `HandlerTimeout`.
+    pub fn set_handler_timeout(&mut self, timeout: HandlerTimeout) {
+        self.handler_timeout = Some(timeout);
+    }
+
+    /// Bounds the time the handler may take for requests to the resource at `path`, in place
+    /// of the timeout set by `set_handler_timeout`.
+    pub fn set_resource_handler_timeout(&mut self, path: &str, timeout: HandlerTimeout) {
+        self.resource_timeouts.insert(normalize_path(path), timeout);
+    }
+
+    /// Lets only the peers allowed by the ACL reach the resource at `path` and the resources
+    /// below it, including observing them; others get 4.03 Forbidden.
+    pub fn set_resource_acl(&mut self, path: &str, acl: Acl) {
+        self.resource_acls.push((normalize_path(path), acl));
+    }
+
+    /// Delays the replies to multicast requests by a random time within `leisure` (RFC 7252
+    /// §8.2), so that the members of a group do not all answer at once. Replies to unicast
+    /// requests are sent right away.
+    pub fn set_multicast_leisure(&mut self, leisure: Duration) {
+        self.multicast_leisure = Some(leisure);
+    }
+
+    /// Takes a snapshot of the observation registry, e.g. before shutting down for an upgrade.
+    pub fn export_observations(&self) -> ObserveState {
+        self.observer.export_state()
+    }
+
+    /// Restores a snapshot taken by `export_observations` before the server is run.
+    pub fn restore_observations(&mut self, state: ObserveState) {
+        self.observer.restore_state(state);
+    }
+
+    /// Exports the observations of the peers and paths `select` picks and removes them, so
+    /// that another instance behind the same anycast address can take them over with
+    /// `import_observations`, e.g. while this one is drained for a rolling upgrade. Observers
+    /// registered over a DTLS listener are kept, since their sessions cannot be handed over.
+    pub fn drain_observations<F: FnMut(&SocketAddr, &str) -> bool>(&mut self, select: F) -> ObserveState {
+        self.observer.drain_state(select)
+    }
+
+    /// Takes over observations drained from another instance, keeping those already
+    /// registered here.
+    pub fn import_observations(&mut self, state: ObserveState) {
+        self.observer.import_state(state);
+    }
+
+    /// Sets a hook receiving a snapshot of the observation registry whenever it changes.
+    pub fn set_observe_state_hook<F: FnMut(ObserveState) + Send + 'static>(&mut self, hook: F) {
+        self.observer.set_state_hook(hook);
+    }
+
+    /// Sets the policy deciding whether to accept each observation registration, given the
+    /// request and the number of resources the peer already observes.
+    pub fn set_observe_policy<F>(&mut self, policy: F)
+    where
+        F: FnMut(&CoAPRequest, usize) -> ObserveDecision + Send + 'static,
+    {
+        self.observer.set_observe_policy(policy);
+    }
+
+    /// Paces the notifications sent to observers, e.g. to spread those caused by a change of a
+    /// resource with thousands of observers.
+    pub fn set_notification_pacing(&mut self, pacing: NotificationPacing) {
+        self.observer.set_notification_pacing(pacing);
+    }
+
+    /// Answers duplicates of a confirmable request with the reply to its first transmission
+    /// for `exchange_lifetime`, EXCHANGE_LIFETIME by default (RFC 7252 §4.5), instead of
+    /// handling them again, e.g. for a handler that is not idempotent. Duplicates arriving
+    /// while the request is handled are dropped. Observe registrations are not cached.
+    pub fn set_exchange_lifetime(&mut self, exchange_lifetime: Duration) {
+        match self.responses {
+            Some(ref mut responses) => responses.set_lifetime(exchange_lifetime),
+            None => self.responses = Some(ResponseCache::new(exchange_lifetime)),
+        }
+    }
+
+    /// Caps the observe registrations and the streamed bodies kept for block-wise transfers,
+    /// see the `budget` module.
+    pub fn set_memory_budget(&mut self, budget: MemoryBudget) {
+        self.observer.set_memory_budget(budget.clone());
+        self.transfers.set_memory_budget(budget);
+    }
+
+}
+
+impl<'a, HandlerRet> Server<'a, HandlerRet> where HandlerRet: Future<Output=Option<CoAPResponse>> + Send + 'a {
     /// run the server.
     pub async fn run<F: FnMut(CoAPRequest) -> HandlerRet + Send + 'a>(&mut self, handler: F) -> Result<(), io::Error> {
         self.handler = Some(Box::new(handler));
@@ -72,83 +405,629 @@ impl<'a, HandlerRet> Server<'a, HandlerRet> where HandlerRet: Future<Output=Opti
                         Ok(Message::NeedSend(packet, addr)) => {
                             self.server.send((packet, addr)).await?;
                         }
-                        Ok(Message::Received(packet, addr)) => {
-                            self.dispatch_msg(packet, addr).await?;
+                        Ok(Message::Received(packet, addr, info)) => {
+                            self.dispatch_msg(packet, addr, info).await?;
                         }
                         Err(e) => {
                             error!("select error: {:?}", e);
                         }
                     }
                 }
-                _ = self.observer.select_next_some() => {
-                    self.observer.timer_handler().await;
+                supervised = self.supervised.select_next_some() => {
+                    self.supervised(supervised).await?;
                 }
+                _ = self.observer.next_event().fuse() => {}
                 complete => break,
             }
         }
         Ok(())
     }
 
-    /// Return the local address that the server is listening on. This can be useful when starting
-    /// a server on a random port as part of unit testing.
-    pub fn socket_addr(&self) -> std::io::Result<SocketAddr> {
-        self.server.socket_addr()
-    }
-
-    async fn dispatch_msg(&mut self, packet: Packet, addr: SocketAddr) -> Result<(), io::Error> {
-        let request = CoAPRequest::from_packet(packet, &addr);
+    async fn dispatch_msg(&mut self, packet: Packet, addr: SocketAddr, info: DatagramInfo) -> Result<(), io::Error> {
+        self.peer_stats.received(addr, &packet);
+        if let Some(ref mut responses) = self.responses {
+            responses.expire(Instant::now(), usize::MAX);
+            let duplicate = match packet.header.code {
+                MessageClass::Request(_) if packet.header.get_type() == MessageType::Confirmable => {
+                    responses.duplicate(&addr, packet.header.get_message_id())
+                }
+                _ => None,
+            };
+            if let Some(reply) = duplicate {
+                debug!("answering duplicate {} from {}", packet.header.get_message_id(), addr);
+                return match reply.cloned() {
+                    Some(reply) => self.server.send_from((reply, addr), Some(&info)).await,
+                    None => Ok(()),
+                };
+            }
+        }
+        let mut request = CoAPRequest::from_packet(packet, &addr);
+        if self.server.is_dtls(&info) {
+            request.context.transport = Transport::Dtls;
+            request.context.identity = self.server.psk_identity(&addr, &info);
+        }
+        request.context.datagram = Some(info.clone());
+        if let Some(ref tracing) = self.tracing {
+            request.context.trace_id = tracing.extract(&request.message);
+        }
+        if let Some(mut response) = self.deny(&request) {
+            self.remember(&request);
+            response.set_error(Status::Forbidden, "");
+            self.peer_stats.responded(&addr, &response.message);
+            return self.reply(response.message, addr, &info).await;
+        }
         let filtered = !self.observer.request_handler(&request).await;
         if filtered {
             return Ok(());
         }
+        self.remember(&request);
 
-        if let Some(ref mut handler) = self.handler {
-            match handler(request).await {
-                Some(response) => {
-                    debug!("Response: {:?}", response);
-                    self.server.send((response.message, addr)).await?;
+        let diagnostics = match self.diagnostics {
+            Some(ref diagnostics) => {
+                let observer = &self.observer;
+                diagnostics.respond(&request, &self.peer_stats, || observer.export_state())
+            }
+            None => None,
+        };
+        let response = diagnostics
+            .or_else(|| self.peer_stats_response(&request))
+            .or_else(|| self.capabilities_response(&request));
+        let response = match response {
+            Some(response) => Some(response),
+            None => self.transfers.continue_transfer(&request).await,
+        };
+        if let Some(response) = response {
+            if self.tracing.is_some() {
+                trace::log_access(&addr, &request, &response.message.header.get_code());
+            }
+            self.peer_stats.responded(&addr, &response.message);
+            self.reply(response.message, addr, &info).await?;
+            return Ok(());
+        }
+
+        let timeout = self
+            .resource_timeouts
+            .get(&normalize_path(&request.get_path()))
+            .or(self.handler_timeout.as_ref())
+            .cloned();
+        if timeout.is_some() {
+            self.message_id = self.message_id.wrapping_add(1);
+        }
+        let handler = match self.handler {
+            Some(ref mut handler) => handler,
+            None => return Ok(()),
+        };
+        let kept_request = request.clone();
+        match timeout {
+            Some(timeout) => {
+                let handling = handler(request);
+                self.supervised.push(Box::pin(supervise(handling, kept_request, info, timeout, self.message_id)));
+                Ok(())
+            }
+            None => {
+                let response = handler(request).await;
+                self.respond(kept_request, response, addr, &info).await
+            }
+        }
+    }
+
+    async fn respond(
+        &mut self,
+        request: CoAPRequest,
+        response: Option<CoAPResponse>,
+        addr: SocketAddr,
+        info: &DatagramInfo,
+    ) -> Result<(), io::Error> {
+        match response {
+            Some(mut response) => {
+                match response.take_body_stream() {
+                    Some(stream) => self.transfers.start_transfer(&request, &mut response, stream).await,
+                    None => {
+                        for filter in self.response_filters.iter_mut() {
+                            filter(&request, &mut response);
+                        }
+                    }
                 }
-                None => {
-                    debug!("No response");
+                debug!("Response: {:?}", response);
+                if self.tracing.is_some() {
+                    trace::log_access(&addr, &request, &response.message.header.get_code());
                 }
+                self.peer_stats.responded(&addr, &response.message);
+                self.reply(response.message, addr, info).await
+            }
+            None => {
+                debug!("No response");
+                Ok(())
             }
         }
-        Ok(())
+    }
+
+    async fn supervised(&mut self, supervised: Supervised) -> Result<(), io::Error> {
+        match supervised {
+            Supervised::Acknowledge(request, info) => {
+                let mut ack = Packet::new();
+                ack.header.set_version(1);
+                ack.header.set_type(MessageType::Acknowledgement);
+                ack.header.code = MessageClass::Empty;
+                ack.header.set_message_id(request.message.header.get_message_id());
+                if let Some(ref mut responses) = self.responses {
+                    responses.answer(&request.source.unwrap(), &ack);
+                }
+                self.server.send_from((ack, request.source.unwrap()), Some(&info)).await
+            }
+            Supervised::Respond(request, response, info) => {
+                let addr = request.source.unwrap();
+                self.respond(request, response, addr, &info).await
+            }
+            Supervised::Late(request) => {
+                debug!("Discarding the late response to {}", request.source.unwrap());
+                Ok(())
+            }
+        }
+    }
+
+    async fn reply(&mut self, packet: Packet, addr: SocketAddr, info: &DatagramInfo) -> Result<(), io::Error> {
+        if let Some(ref mut responses) = self.responses {
+            responses.answer(&addr, &packet);
+        }
+        match self.multicast_leisure {
+            Some(leisure) if info.is_multicast() => {
+                let delay = leisure.mul_f64(rng::next_f64());
+                let sender = self.observer.message_sender();
+                tokio::spawn(async move {
+                    tokio::time::delay_for(delay).await;
+                    let _ = sender.send((packet, addr));
+                });
+                Ok(())
+            }
+            _ => self.server.send_from((packet, addr), Some(info)).await,
+        }
+    }
+}
+
+// what a handler running under a timeout came to
+enum Supervised {
+    // the request is acknowledged ahead of its separate response
+    Acknowledge(CoAPRequest, DatagramInfo),
+    // the handler's response, or the 5.00 answered in its place
+    Respond(CoAPRequest, Option<CoAPResponse>, DatagramInfo),
+    // an overrunning handler that was not cancelled produced a response after all
+    Late(CoAPRequest),
+}
+
+enum Stage {
+    Separate,
+    Respond { separate: bool },
+    Overrun,
+    Done,
+}
+
+// runs the handler within its timeout, acknowledging the request early if the timeout asks
+// for a separate response, and answers 5.00 in its place if it overruns. The request is
+// kept as the template of the 5.00, as the handler consumed its own copy.
+fn supervise<R>(
+    handling: R,
+    request: CoAPRequest,
+    info: DatagramInfo,
+    timeout: HandlerTimeout,
+    message_id: u16,
+) -> impl Stream<Item=Supervised>
+where
+    R: Future<Output=Option<CoAPResponse>>,
+{
+    let started = Instant::now();
+    let state = (Box::pin(handling), request, info, Stage::Separate);
+    stream::unfold(state, move |(mut handling, request, info, mut stage)| async move {
+        loop {
+            match stage {
+                Stage::Separate => {
+                    let confirmable = request.message.header.get_type() == MessageType::Confirmable;
+                    let after = match timeout.separate_after.filter(|after| confirmable && *after < timeout.timeout) {
+                        Some(after) => after,
+                        None => {
+                            stage = Stage::Respond { separate: false };
+                            continue;
+                        }
+                    };
+                    return match tokio::time::timeout(after, &mut handling).await {
+                        Ok(response) => {
+                            let event = Supervised::Respond(request.clone(), response, info.clone());
+                            Some((event, (handling, request, info, Stage::Done)))
+                        }
+                        Err(_) => {
+                            let event = Supervised::Acknowledge(request.clone(), info.clone());
+                            Some((event, (handling, request, info, Stage::Respond { separate: true })))
+                        }
+                    };
+                }
+                Stage::Respond { separate } => {
+                    let remaining = timeout.timeout.checked_sub(started.elapsed()).unwrap_or_default();
+                    let (mut response, next) = match tokio::time::timeout(remaining, &mut handling).await {
+                        Ok(response) => (response, Stage::Done),
+                        Err(_) => {
+                            warn!(
+                                "handler for {} from {} overran its timeout of {:?}",
+                                request.get_path(),
+                                request.source.unwrap(),
+                                timeout.timeout
+                            );
+                            let mut response = request.response.clone();
+                            if let Some(ref mut response) = response {
+                                response.set_error(Status::InternalServerError, "handler timed out");
+                            }
+                            (response, if timeout.cancel { Stage::Done } else { Stage::Overrun })
+                        }
+                    };
+                    if let (Some(response), true) = (response.as_mut(), separate) {
+                        response.message.header.set_type(MessageType::NonConfirmable);
+                        response.message.header.set_message_id(message_id);
+                    }
+                    let event = Supervised::Respond(request.clone(), response, info.clone());
+                    return Some((event, (handling, request, info, next)));
+                }
+                Stage::Overrun => {
+                    handling.as_mut().await?;
+                    let event = Supervised::Late(request.clone());
+                    return Some((event, (handling, request, info, Stage::Done)));
+                }
+                Stage::Done => return None,
+            }
+        }
+    })
+}
+
+// a path with its empty segments dropped, as routes match it regardless of extra slashes
+fn normalize_path(path: &str) -> String {
+    path.split('/').filter(|segment| !segment.is_empty()).collect::<Vec<_>>().join("/")
+}
+
+impl<'a, HandlerRet> Server<'a, HandlerRet> where HandlerRet: Future<Output=Option<CoAPResponse>> {
+    fn peer_stats_response(&self, request: &CoAPRequest) -> Option<CoAPResponse> {
+        let path = self.peer_stats_path.as_ref()?;
+        if *request.get_method() != Method::Get || normalize_path(&request.get_path()) != *path {
+            return None;
+        }
+        let mut response = request.response.clone()?;
+        response.message.set_content_format(ContentFormat::ApplicationCBOR);
+        response.message.payload = self.peer_stats.to_cbor();
+        Some(response)
+    }
+
+    // the response template of a request the ACL of its resource denies
+    fn deny(&self, request: &CoAPRequest) -> Option<CoAPResponse> {
+        if self.resource_acls.is_empty() {
+            return None;
+        }
+        let path = normalize_path(&request.get_path());
+        let denied = self.resource_acls.iter().any(|(prefix, acl)| {
+            let below = path == *prefix || prefix.is_empty() || path.starts_with(&format!("{}/", prefix));
+            below && !acl.permits(&request.context)
+        });
+        if denied { request.response.clone() } else { None }
+    }
+
+    // keeps the replies to the request for its duplicates, once enabled
+    fn remember(&mut self, request: &CoAPRequest) {
+        if let (Some(responses), Some(source)) = (self.responses.as_mut(), request.source) {
+            let confirmable = request.message.header.get_type() == MessageType::Confirmable;
+            if confirmable && matches!(request.message.header.code, MessageClass::Request(_)) {
+                responses.start(source, request.message.header.get_message_id());
+            }
+        }
+    }
+
+    fn capabilities_response(&self, request: &CoAPRequest) -> Option<CoAPResponse> {
+        self.capability_formats.as_ref()?;
+        self.capabilities().respond(request)
     }
 }
 
+/// The replies to confirmable requests by peer and message ID, so that a duplicate of a
+/// request is answered with the reply to its first transmission for EXCHANGE_LIFETIME instead
+/// of reaching the handler again (RFC 7252 §4.5). At most `max_entries` are kept, the oldest
+/// evicted first.
+pub(crate) struct ResponseCache {
+    // `None` while the request is handled, or if it got no reply
+    responses: HashMap<(SocketAddr, u16), Option<Packet>>,
+    expirations: VecDeque<(Instant, SocketAddr, u16)>,
+    lifetime: Duration,
+    max_entries: usize,
+}
+
+impl ResponseCache {
+    pub fn new(lifetime: Duration) -> ResponseCache {
+        ResponseCache {
+            responses: HashMap::new(),
+            expirations: VecDeque::new(),
+            lifetime,
+            max_entries: DEFAULT_MAX_CACHED_RESPONSES,
+        }
+    }
+
+    pub fn set_lifetime(&mut self, lifetime: Duration) {
+        self.lifetime = lifetime;
+    }
+
+    pub fn set_max_entries(&mut self, max_entries: usize) {
+        self.max_entries = max_entries;
+        while self.responses.len() > max_entries {
+            self.evict_oldest();
+        }
+    }
+
+    /// The reply to an earlier transmission of the request, `Some(None)` if it is still
+    /// handled or got no reply, `None` if the request is new.
+    pub fn duplicate(&self, peer: &SocketAddr, message_id: u16) -> Option<Option<&Packet>> {
+        self.responses.get(&(*peer, message_id)).map(Option::as_ref)
+    }
+
+    /// Remembers a new request, to which duplicates get no reply until `answer` is called.
+    pub fn start(&mut self, peer: SocketAddr, message_id: u16) {
+        if self.max_entries == 0 {
+            return;
+        }
+        while self.responses.len() >= self.max_entries {
+            self.evict_oldest();
+        }
+        self.responses.insert((peer, message_id), None);
+        self.expirations.push_back((Instant::now() + self.lifetime, peer, message_id));
+    }
+
+    /// Keeps the reply to a request remembered by `start`, a piggybacked response or an empty
+    /// ACK ahead of a separate one, for its duplicates.
+    pub fn answer(&mut self, peer: &SocketAddr, reply: &Packet) {
+        if reply.header.get_type() != MessageType::Acknowledgement {
+            return;
+        }
+        if let Some(kept @ None) = self.responses.get_mut(&(*peer, reply.header.get_message_id())) {
+            *kept = Some(reply.clone());
+        }
+    }
+
+    /// Forgets at most `budget` replies whose lifetime ended, returning how many.
+    pub fn expire(&mut self, now: Instant, budget: usize) -> usize {
+        let mut expired = 0;
+        while expired < budget {
+            match self.expirations.front() {
+                Some(&(expires_at, _, _)) if expires_at <= now => {
+                    self.evict_oldest();
+                    expired += 1;
+                }
+                _ => break,
+            }
+        }
+        expired
+    }
+
+    /// When the next reply is forgotten, if any is kept.
+    pub fn next_expiration(&self) -> Option<Instant> {
+        self.expirations.front().map(|&(expires_at, _, _)| expires_at)
+    }
+
+    fn evict_oldest(&mut self) {
+        if let Some((_, peer, message_id)) = self.expirations.pop_front() {
+            self.responses.remove(&(peer, message_id));
+        }
+    }
+}
+
+// a socket the server listens on, decrypting the datagrams of DTLS listeners
+struct Listener {
+    socket: DatagramSocket,
+    dtls: Option<DtlsListener>,
+}
+
 pub struct CoAPServer {
     receiver: MessageReceiver,
     is_terminated: bool,
-    socket: UdpFramed<Codec>,
+    listeners: Vec<Listener>,
+    // messages decrypted from a datagram of a DTLS peer, not yet handed out
+    decrypted: VecDeque<(Vec<u8>, SocketAddr, DatagramInfo)>,
+    // what each peer's last datagram arrived at, the listener and local address messages
+    // to it are sent from
+    peers: HashMap<SocketAddr, DatagramInfo>,
+    // the listener polled first, rotated so that a busy one does not starve the others
+    next_socket: usize,
+    traffic_class: Option<u8>,
+    flow_label: Option<u32>,
+    session_policy: SessionPolicy,
+    eviction_hook: Option<EvictionHook>,
+    // evicts the idle DTLS sessions, started by the first poll once there are any to evict
+    session_sweep: Option<tokio::time::Interval>,
+    buf: Vec<u8>,
 }
 
 impl CoAPServer {
     /// Creates a CoAP server listening on the given address.
     pub fn new<A: ToSocketAddrs>(addr: A, receiver: MessageReceiver) -> Result<CoAPServer, io::Error> {
-        let socket = UdpSocket::from_std(net::UdpSocket::bind(addr).unwrap())?;
-
         Ok(CoAPServer {
             receiver,
             is_terminated: false,
-            socket: UdpFramed::new(socket, Codec::new()),
+            listeners: vec![Listener { socket: DatagramSocket::bind(addr)?, dtls: None }],
+            decrypted: VecDeque::new(),
+            peers: HashMap::new(),
+            next_socket: 0,
+            traffic_class: None,
+            flow_label: None,
+            session_policy: SessionPolicy::default(),
+            eviction_hook: None,
+            session_sweep: None,
+            buf: vec![0; 65536],
         })
     }
 
+    /// Listens on a further UDP address, returning the address bound.
+    pub fn add_listener<A: ToSocketAddrs>(&mut self, addr: A) -> Result<SocketAddr, io::Error> {
+        self.bind_listener(addr, None)
+    }
+
+    /// Listens for DTLS peers on a further UDP address, see `Server::add_dtls_listener`.
+    pub fn add_dtls_listener<A: ToSocketAddrs>(&mut self, addr: A, context: SslContextBuilder) -> Result<SocketAddr, io::Error> {
+        let mut dtls = DtlsListener::new(context, self.session_policy)?;
+        if let Some(ref hook) = self.eviction_hook {
+            dtls.set_eviction_hook(hook.clone());
+        }
+        self.bind_listener(addr, Some(dtls))
+    }
+
+    /// Keeps the sessions of the DTLS listeners as the policy says, see
+    /// `Server::set_dtls_session_policy`.
+    pub fn set_dtls_session_policy(&mut self, policy: SessionPolicy) {
+        for dtls in self.listeners.iter_mut().filter_map(|listener| listener.dtls.as_mut()) {
+            dtls.set_policy(policy);
+        }
+        self.session_policy = policy;
+        self.session_sweep = None;
+    }
+
+    /// Sets the hook told about evicted DTLS sessions, see `Server::set_dtls_eviction_hook`.
+    pub fn set_dtls_eviction_hook<F>(&mut self, hook: F)
+    where
+        F: FnMut(SocketAddr, Option<&[u8]>, EvictionReason) + Send + 'static,
+    {
+        let hook: EvictionHook = Arc::new(Mutex::new(Box::new(hook)));
+        for dtls in self.listeners.iter_mut().filter_map(|listener| listener.dtls.as_mut()) {
+            dtls.set_eviction_hook(hook.clone());
+        }
+        self.eviction_hook = Some(hook);
+    }
+
+    fn poll_session_sweep(&mut self, cx: &mut Context<'_>) {
+        if !self.listeners.iter().any(|listener| listener.dtls.is_some()) {
+            return;
+        }
+        let idle_timeout = match self.session_policy.idle_timeout {
+            Some(idle_timeout) => idle_timeout.min(HANDSHAKE_TIMEOUT),
+            None => HANDSHAKE_TIMEOUT,
+        };
+        // sessions and stalled handshakes are evicted at most half their timeout late
+        let period = (idle_timeout / 2).max(Duration::from_millis(10));
+        let sweep = self.session_sweep.get_or_insert_with(|| tokio::time::interval(period));
+        while sweep.poll_tick(cx).is_ready() {
+            for dtls in self.listeners.iter_mut().filter_map(|listener| listener.dtls.as_mut()) {
+                dtls.evict_idle();
+            }
+        }
+    }
+
+    fn bind_listener<A: ToSocketAddrs>(&mut self, addr: A, dtls: Option<DtlsListener>) -> Result<SocketAddr, io::Error> {
+        let socket = DatagramSocket::bind(addr)?;
+        let local_addr = socket.local_addr()?;
+        if let Some(traffic_class) = self.traffic_class {
+            socket.set_traffic_class(traffic_class)?;
+        }
+        if let Some(label) = self.flow_label {
+            if local_addr.is_ipv6() {
+                socket.lease_flow_label(label)?;
+            }
+        }
+        self.listeners.push(Listener { socket, dtls });
+        Ok(local_addr)
+    }
+
+    /// Whether the datagram arrived on a DTLS listener.
+    pub(crate) fn is_dtls(&self, info: &DatagramInfo) -> bool {
+        self.listeners
+            .iter()
+            .any(|listener| listener.dtls.is_some() && listener.socket.local_addr().ok() == info.listener)
+    }
+
+    /// The PSK identity of the peer's session with the DTLS listener the datagram arrived on.
+    pub(crate) fn psk_identity(&self, addr: &SocketAddr, info: &DatagramInfo) -> Option<Vec<u8>> {
+        let listener = self.listeners.iter().find(|listener| listener.socket.local_addr().ok() == info.listener)?;
+        listener.dtls.as_ref()?.psk_identity(addr)
+    }
+
+    /// Marks the datagrams of all listeners with the traffic class, see
+    /// `Server::set_traffic_class`.
+    pub fn set_traffic_class(&mut self, traffic_class: u8) -> Result<(), io::Error> {
+        for listener in self.listeners.iter() {
+            listener.socket.set_traffic_class(traffic_class)?;
+        }
+        self.traffic_class = Some(traffic_class);
+        Ok(())
+    }
+
+    /// Labels the datagrams of all listeners to IPv6 peers with the flow label, see
+    /// `Server::set_flow_label`.
+    pub fn set_flow_label(&mut self, label: Option<u32>) -> Result<(), io::Error> {
+        if let Some(label) = label {
+            if label > MAX_FLOW_LABEL {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "flow labels have 20 bits"));
+            }
+            for listener in self.listeners.iter() {
+                if listener.socket.local_addr()?.is_ipv6() {
+                    listener.socket.lease_flow_label(label)?;
+                }
+            }
+        }
+        self.flow_label = label;
+        Ok(())
+    }
+
     /// Stop the server.
     pub fn stop(&mut self) {
         self.is_terminated = true;
     }
 
-    /// send the packet to the specific address.
+    /// send the packet to the specific address, e.g. a notification, from the listener and
+    /// local address the peer was last heard on.
     pub async fn send(&mut self, frame: (Packet, SocketAddr)) -> Result<(), io::Error> {
-        self.socket.send(frame).await
+        self.send_from(frame, None).await
+    }
+
+    /// send the packet in reply to a datagram, from the local address the datagram arrived at.
+    ///
+    /// Without a datagram, packets are sent like the reply to the peer's last datagram, or
+    /// for peers not heard from from the first listener of the peer's address family.
+    pub async fn send_from(&mut self, frame: (Packet, SocketAddr), info: Option<&DatagramInfo>) -> Result<(), io::Error> {
+        let (packet, addr) = frame;
+        let bytes = packet
+            .to_bytes()
+            .map_err(|cause| io::Error::new(io::ErrorKind::InvalidData, cause))?;
+        let peers = &self.peers;
+        let info = info.or_else(|| peers.get(&addr));
+        let index = self.listener_for(&addr, info);
+        let listener = &mut self.listeners[index];
+        let datagrams = match listener.dtls {
+            Some(ref mut dtls) => dtls.send(&addr, &bytes)?,
+            None => vec![bytes],
+        };
+        let target = with_flow_label(&addr, self.flow_label);
+        for datagram in datagrams {
+            futures::future::poll_fn(|cx| listener.socket.poll_send_from(cx, &datagram, &target, info)).await?;
+        }
+        Ok(())
+    }
+
+    fn listener_for(&self, addr: &SocketAddr, info: Option<&DatagramInfo>) -> usize {
+        let listener = info.and_then(|info| info.listener);
+        let bound = |listener: &Listener| listener.socket.local_addr().ok();
+        listener
+            .and_then(|listener| self.listeners.iter().position(|candidate| bound(candidate) == Some(listener)))
+            .or_else(|| {
+                // peers not heard from are not sent to over DTLS, they have no session
+                self.listeners.iter().position(|listener| {
+                    listener.dtls.is_none() && bound(listener).is_some_and(|local| local.is_ipv4() == addr.is_ipv4())
+                })
+            })
+            .unwrap_or(0)
     }
 
     /// Return the local address that the server is listening on. This can be useful when starting
     /// a server on a random port as part of unit testing.
     pub fn socket_addr(&self) -> std::io::Result<SocketAddr> {
-        self.socket.get_ref().local_addr()
+        self.listeners[0].socket.local_addr()
+    }
+
+    /// Return the local addresses of all listeners, in the order they were added.
+    pub fn socket_addrs(&self) -> std::io::Result<Vec<SocketAddr>> {
+        self.listeners.iter().map(|listener| listener.socket.local_addr()).collect()
+    }
+
+    /// The largest message the server reads, in bytes.
+    pub fn max_message_size(&self) -> usize {
+        self.buf.len()
     }
 }
 
@@ -167,19 +1046,56 @@ impl Stream for CoAPServer {
             return Poll::Ready(Some(Ok(Message::NeedSend(p, a))));
         }
 
-        let result: Option<_> = futures::ready!(self.socket.poll_next_unpin(cx));
-
-        Poll::Ready(match result {
-            Some(Ok(message)) => {
-                let (my_packet, addr) = message;
-                Some(Ok(Message::Received(my_packet, addr)))
+        let this = &mut *self;
+        this.poll_session_sweep(cx);
+        if let Some((message, addr, info)) = this.decrypted.pop_front() {
+            return Poll::Ready(Some(received(&message, addr, info)));
+        }
+        let count = this.listeners.len();
+        for i in 0..count {
+            let index = (this.next_socket + i) % count;
+            // a DTLS listener is read on while its datagrams carry handshake messages only
+            loop {
+                let listener = &mut this.listeners[index];
+                let (nread, addr, info) = match listener.socket.poll_recv_from(cx, &mut this.buf) {
+                    Poll::Pending => break,
+                    Poll::Ready(Ok(received)) => received,
+                    Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(e))),
+                };
+                this.next_socket = (index + 1) % count;
+                if this.peers.len() >= MAX_REMEMBERED_PEERS && !this.peers.contains_key(&addr) {
+                    // forgotten peers are reached through the default listener of their family
+                    this.peers.clear();
+                }
+                this.peers.insert(addr, info.clone());
+                let dtls = match listener.dtls {
+                    Some(ref mut dtls) => dtls,
+                    None => return Poll::Ready(Some(received(&this.buf[..nread], addr, info))),
+                };
+                let decrypted = dtls.receive(addr, &this.buf[..nread]);
+                let target = with_flow_label(&addr, this.flow_label);
+                for reply in decrypted.replies {
+                    // a lost flight is retransmitted when the peer repeats its own
+                    if let Poll::Ready(Err(e)) = listener.socket.poll_send_from(cx, &reply, &target, Some(&info)) {
+                        debug!("DTLS reply to {} failed: {}", addr, e);
+                    }
+                }
+                this.decrypted.extend(decrypted.messages.into_iter().map(|message| (message, addr, info.clone())));
+                if let Some((message, addr, info)) = this.decrypted.pop_front() {
+                    return Poll::Ready(Some(received(&message, addr, info)));
+                }
             }
-            Some(Err(e)) => Some(Err(e)),
-            None => None,
-        })
+        }
+        Poll::Pending
     }
 }
 
+fn received(bytes: &[u8], addr: SocketAddr, info: DatagramInfo) -> Result<Message, io::Error> {
+    Packet::from_bytes(bytes)
+        .map(|packet| Message::Received(packet, addr, info))
+        .map_err(|cause| io::Error::new(io::ErrorKind::InvalidData, cause))
+}
+
 impl FusedStream for CoAPServer {
     fn is_terminated(&self) -> bool {
         self.is_terminated
@@ -190,12 +1106,12 @@ impl FusedStream for CoAPServer {
 pub mod test {
     use std::{
         time::Duration,
-        sync::mpsc,
+        sync::{Arc, mpsc, atomic::{AtomicUsize, Ordering}},
     };
     use super::super::*;
     use super::*;
 
-    pub fn spawn_server<F: FnMut(CoAPRequest) -> HandlerRet + Send + 'static, HandlerRet>(request_handler: F) -> mpsc::Receiver<u16>  where HandlerRet: Future<Output=Option<CoAPResponse>> {
+    pub fn spawn_server<F: FnMut(CoAPRequest) -> HandlerRet + Send + 'static, HandlerRet>(request_handler: F) -> mpsc::Receiver<u16>  where HandlerRet: Future<Output=Option<CoAPResponse>> + Send + 'static {
         let (tx, rx) = mpsc::channel();
 
         std::thread::Builder::new().name(String::from("server")).spawn(move || {
@@ -242,6 +1158,558 @@ pub mod test {
         assert_eq!(recv_packet.message.payload, b"test-echo".to_vec());
     }
 
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_datagram_info() {
+        let server_port = spawn_server(|mut req: CoAPRequest| async move {
+            let info = req.context.datagram.clone().unwrap();
+            assert_eq!(info.local_addr, Some("127.0.0.1".parse().unwrap()));
+            assert!(!info.is_multicast());
+            assert_eq!(info.interface_name(), Some("lo".to_string()));
+            assert_eq!(info.hop_limit, Some(64));
+            if let Some(ref mut response) = req.response {
+                response.set_payload(b"checked".to_vec());
+            }
+            req.response
+        })
+        .recv()
+        .unwrap();
+
+        let client = CoAPClient::new(format!("127.0.0.1:{}", server_port)).unwrap();
+        let mut request = CoAPRequest::new();
+        request.set_path("/info");
+        client.send(&request).unwrap();
+        assert_eq!(client.receive().unwrap().message.payload, b"checked".to_vec());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_reply_from_local_addr() {
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new().unwrap().block_on(async move {
+                let mut server = server::Server::new("0.0.0.0:0").unwrap();
+                tx.send(server.socket_addr().unwrap().port()).unwrap();
+                server
+                    .run(|req: CoAPRequest| async move {
+                        let info = req.context.datagram.clone().unwrap();
+                        assert_eq!(info.reply_addr(), Some("127.0.0.2".parse().unwrap()));
+                        req.response
+                    })
+                    .await
+                    .unwrap();
+            })
+        });
+        let server_port = rx.recv().unwrap();
+
+        // the system would answer 127.0.0.1 from 127.0.0.1 rather than the address it was asked at
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::new(5, 0))).unwrap();
+        let mut request = CoAPRequest::new();
+        request.set_path("/multi-homed");
+        socket
+            .send_to(&request.message.to_bytes().unwrap(), ("127.0.0.2", server_port))
+            .unwrap();
+        let mut buf = [0; 1500];
+        let (_, src) = socket.recv_from(&mut buf).unwrap();
+        assert_eq!(src, format!("127.0.0.2:{}", server_port).parse().unwrap());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_notify_from_local_addr() {
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new().unwrap().block_on(async move {
+                let mut server = server::Server::new("0.0.0.0:0").unwrap();
+                tx.send((server.socket_addr().unwrap().port(), server.resource_publisher())).unwrap();
+                server.run(|req: CoAPRequest| async move { req.response }).await.unwrap();
+            })
+        });
+        let (server_port, publisher) = rx.recv().unwrap();
+
+        // notifications, like replies, leave from the address the observer registered at
+        let mut representation = Packet::new();
+        representation.payload = b"1".to_vec();
+        publisher.publish("/temp", &representation);
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::new(5, 0))).unwrap();
+        let mut register = CoAPRequest::new();
+        register.set_path("/temp");
+        register.set_observe(vec![0]);
+        register.set_token(vec![7]);
+        socket
+            .send_to(&register.message.to_bytes().unwrap(), ("127.0.0.2", server_port))
+            .unwrap();
+        let mut buf = [0; 1500];
+        let (_, src) = socket.recv_from(&mut buf).unwrap();
+        assert_eq!(src, format!("127.0.0.2:{}", server_port).parse().unwrap());
+        representation.payload = b"2".to_vec();
+        publisher.publish("/temp", &representation);
+        let (nread, src) = socket.recv_from(&mut buf).unwrap();
+        assert_eq!(src, format!("127.0.0.2:{}", server_port).parse().unwrap());
+        assert_eq!(Packet::from_bytes(&buf[..nread]).unwrap().payload, b"2".to_vec());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_traffic_marking() {
+        use std::os::unix::io::AsRawFd;
+
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new().unwrap().block_on(async move {
+                let mut server = server::Server::new("[::1]:0").unwrap();
+                server.set_traffic_class(0xB8).unwrap();
+                server.set_flow_label(Some(0x2C0DE)).unwrap();
+                tx.send(server.socket_addr().unwrap()).unwrap();
+                server.run(|req: CoAPRequest| async move { req.response }).await.unwrap();
+            })
+        });
+        let server_addr = rx.recv().unwrap();
+
+        let mut client = CoAPClient::new(server_addr).unwrap();
+        client.set_traffic_class(0x20).unwrap();
+        client.set_flow_label(Some(0x1C0DE)).unwrap();
+        assert!(client.set_flow_label(Some(0x100000)).is_err());
+        let mut request = CoAPRequest::new();
+        request.set_path("/marked");
+        assert_eq!(*client.request(&mut request).unwrap().get_status(), Status::Content);
+
+        // the response carries the server's marking
+        let socket = std::net::UdpSocket::bind("[::1]:0").unwrap();
+        socket.set_read_timeout(Some(Duration::new(5, 0))).unwrap();
+        let enable = |name| {
+            let on: libc::c_int = 1;
+            let result = unsafe {
+                libc::setsockopt(
+                    socket.as_raw_fd(),
+                    libc::IPPROTO_IPV6,
+                    name,
+                    &on as *const _ as *const libc::c_void,
+                    std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+                )
+            };
+            assert_eq!(result, 0);
+        };
+        enable(libc::IPV6_RECVTCLASS);
+        enable(libc::IPV6_FLOWINFO);
+        socket.send_to(&request.message.to_bytes().unwrap(), server_addr).unwrap();
+
+        let mut buf = [0u8; 1500];
+        let mut control = [0u64; 16];
+        let mut iov = libc::iovec { iov_base: buf.as_mut_ptr() as *mut libc::c_void, iov_len: buf.len() };
+        let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = std::mem::size_of_val(&control) as _;
+        assert!(unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, 0) } > 0);
+        let (mut traffic_class, mut flow_info) = (None, None);
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+            while !cmsg.is_null() {
+                let data = libc::CMSG_DATA(cmsg);
+                match (*cmsg).cmsg_type {
+                    libc::IPV6_TCLASS => traffic_class = Some((data as *const libc::c_int).read_unaligned()),
+                    libc::IPV6_FLOWINFO => flow_info = Some(u32::from_be((data as *const u32).read_unaligned())),
+                    _ => (),
+                }
+                cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+            }
+        }
+        assert_eq!(traffic_class, Some(0xB8));
+        assert_eq!(flow_info.map(|info| info & 0xFFFFF), Some(0x2C0DE));
+    }
+
+    #[test]
+    fn test_multiple_listeners() {
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new().unwrap().block_on(async move {
+                let mut server = server::Server::new("127.0.0.1:0").unwrap();
+                server.add_listener("127.0.0.1:0").unwrap();
+                tx.send((server.socket_addrs().unwrap(), server.resource_publisher())).unwrap();
+                server
+                    .run(|mut req: CoAPRequest| async move {
+                        let listener = req.context.datagram.as_ref().unwrap().listener.unwrap();
+                        if let Some(ref mut response) = req.response {
+                            response.set_payload(listener.port().to_string().into_bytes());
+                        }
+                        req.response
+                    })
+                    .await
+                    .unwrap();
+            })
+        });
+        let (addrs, publisher) = rx.recv().unwrap();
+        assert_eq!(addrs.len(), 2);
+        assert_ne!(addrs[0], addrs[1]);
+
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::new(5, 0))).unwrap();
+        let mut buf = [0; 1500];
+        for addr in addrs.iter() {
+            let mut request = CoAPRequest::new();
+            request.set_path("/where");
+            socket.send_to(&request.message.to_bytes().unwrap(), addr).unwrap();
+            let (nread, src) = socket.recv_from(&mut buf).unwrap();
+            assert_eq!(src, *addr);
+            let response = Packet::from_bytes(&buf[..nread]).unwrap();
+            assert_eq!(response.payload, addr.port().to_string().into_bytes());
+        }
+
+        // notifications reach the observer from the listener it registered on
+        let mut representation = Packet::new();
+        representation.payload = b"1".to_vec();
+        publisher.publish("/temp", &representation);
+        let mut register = CoAPRequest::new();
+        register.set_path("/temp");
+        register.set_observe(vec![0]);
+        register.set_token(vec![7]);
+        socket.send_to(&register.message.to_bytes().unwrap(), addrs[1]).unwrap();
+        let (_, src) = socket.recv_from(&mut buf).unwrap();
+        assert_eq!(src, addrs[1]);
+        representation.payload = b"2".to_vec();
+        publisher.publish("/temp", &representation);
+        let (nread, src) = socket.recv_from(&mut buf).unwrap();
+        assert_eq!(src, addrs[1]);
+        assert_eq!(Packet::from_bytes(&buf[..nread]).unwrap().payload, b"2".to_vec());
+    }
+
+    // a DTLS context accepting the PSK identities `sensor-*` with the key `secret`
+    fn psk_context() -> SslContextBuilder {
+        use openssl::ssl::SslMethod;
+
+        let mut context = SslContextBuilder::new(SslMethod::dtls()).unwrap();
+        context.set_cipher_list("ECDHE-PSK-AES128-CBC-SHA256").unwrap();
+        context.set_psk_server_callback(|_ssl, identity, psk| {
+            if !matches!(identity, Some(identity) if identity.starts_with(b"sensor-")) {
+                return Ok(0);
+            }
+            psk[..6].copy_from_slice(b"secret");
+            Ok(6)
+        });
+        context
+    }
+
+    #[test]
+    fn test_dtls_listener() {
+        use crate::dtls_client::DTLSCoAPClient;
+
+        let context = psk_context();
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new().unwrap().block_on(async move {
+                let mut server = server::Server::new("127.0.0.1:0").unwrap();
+                let dtls_addr = server.add_dtls_listener("127.0.0.1:0", context).unwrap();
+                tx.send((server.socket_addr().unwrap(), dtls_addr)).unwrap();
+                server
+                    .run(|mut req: CoAPRequest| async move {
+                        let transport = format!("{:?}", req.context.transport);
+                        let identity = req.context.identity.clone().unwrap_or_default();
+                        if let Some(ref mut response) = req.response {
+                            response.set_payload([transport.as_bytes(), b" ", &identity].concat());
+                        }
+                        req.response
+                    })
+                    .await
+                    .unwrap();
+            })
+        });
+        let (udp_addr, dtls_addr) = rx.recv().unwrap();
+
+        let client = DTLSCoAPClient::new_with_psk(dtls_addr, b"sensor-1", b"secret").unwrap();
+        client.set_receive_timeout(Some(Duration::new(5, 0))).unwrap();
+        for path in &["/first", "/second"] {
+            let mut request = CoAPRequest::new();
+            request.set_path(path);
+            request.set_message_id(client.next_message_id());
+            client.send(&request).unwrap();
+            assert_eq!(client.receive().unwrap().message.payload, b"Dtls sensor-1".to_vec());
+        }
+
+        // the plain listener answers in the clear, and does not decrypt
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::new(5, 0))).unwrap();
+        let request = CoAPRequest::new();
+        socket.send_to(&request.message.to_bytes().unwrap(), udp_addr).unwrap();
+        let mut buf = [0; 1500];
+        let (nread, _) = socket.recv_from(&mut buf).unwrap();
+        assert_eq!(Packet::from_bytes(&buf[..nread]).unwrap().payload, b"Udp ".to_vec());
+
+        assert!(DTLSCoAPClient::new_with_psk(dtls_addr, b"intruder", b"secret").is_err());
+    }
+
+    #[test]
+    fn test_dtls_session_eviction() {
+        use crate::dtls_client::DTLSCoAPClient;
+
+        let (tx, rx) = mpsc::channel();
+        let (evicted_tx, evicted_rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new().unwrap().block_on(async move {
+                let mut server = server::Server::new("127.0.0.1:0").unwrap();
+                let policy = SessionPolicy::default()
+                    .with_max_sessions(1)
+                    .with_idle_timeout(Duration::from_millis(300));
+                server.set_dtls_session_policy(policy);
+                server.set_dtls_eviction_hook(move |_peer, identity, reason| {
+                    evicted_tx.send((identity.unwrap().to_vec(), reason)).unwrap();
+                });
+                tx.send(server.add_dtls_listener("127.0.0.1:0", psk_context()).unwrap()).unwrap();
+                server.run(|req: CoAPRequest| async { req.response }).await.unwrap();
+            })
+        });
+        let dtls_addr = rx.recv().unwrap();
+
+        let request = |client: &DTLSCoAPClient| {
+            let mut request = CoAPRequest::new();
+            request.set_message_id(client.next_message_id());
+            client.set_receive_timeout(Some(Duration::new(5, 0))).unwrap();
+            client.send(&request).unwrap();
+            client.receive().unwrap();
+        };
+        let first = DTLSCoAPClient::new_with_psk(dtls_addr, b"sensor-1", b"secret").unwrap();
+        request(&first);
+        assert!(evicted_rx.try_recv().is_err());
+
+        // the second device takes the only session
+        let second = DTLSCoAPClient::new_with_psk(dtls_addr, b"sensor-2", b"secret").unwrap();
+        request(&second);
+        let evicted = evicted_rx.recv_timeout(Duration::new(5, 0)).unwrap();
+        assert_eq!(evicted, (b"sensor-1".to_vec(), EvictionReason::Capacity));
+
+        let evicted = evicted_rx.recv_timeout(Duration::new(5, 0)).unwrap();
+        assert_eq!(evicted, (b"sensor-2".to_vec(), EvictionReason::Idle));
+    }
+
+    #[test]
+    fn test_body_stream() {
+        let pulled = Arc::new(AtomicUsize::new(0));
+        let handler_pulled = pulled.clone();
+        let server_port = spawn_server(move |mut req: CoAPRequest| {
+            let pulled = handler_pulled.clone();
+            async move {
+                let path = req.get_path();
+                if let Some(ref mut response) = req.response {
+                    if path == "log" {
+                        response.message.set_content_format(ContentFormat::TextPlain);
+                        let lines = futures::stream::iter(0..100u8).map(move |i| {
+                            pulled.fetch_add(1, Ordering::SeqCst);
+                            Ok(vec![b'a' + i % 26; 50])
+                        });
+                        response.set_body_stream(lines);
+                    } else {
+                        let broken = futures::stream::iter(vec![
+                            Ok(vec![0; 1024]),
+                            Ok(vec![0; 1024]),
+                            Err(std::io::Error::new(std::io::ErrorKind::Other, "disk failure")),
+                        ]);
+                        response.set_body_stream(broken);
+                    }
+                }
+                req.response
+            }
+        }).recv().unwrap();
+
+        let client = CoAPClient::new(format!("127.0.0.1:{}", server_port)).unwrap();
+        let mut request = CoAPRequest::new();
+        request.set_path("/log");
+        let mut reader = client.request_streaming(request);
+        assert_eq!(reader.next_block().unwrap().unwrap().len(), 1024);
+        // the stream is pulled only a block ahead
+        assert_eq!(pulled.load(Ordering::SeqCst), 21);
+        let rest: Vec<u8> = reader.map(|block| block.unwrap()).flatten().collect();
+        assert_eq!(rest.len(), 5000 - 1024);
+        assert_eq!(&rest[rest.len() - 50..], &[b'a' + 99 % 26; 50][..]);
+        assert_eq!(pulled.load(Ordering::SeqCst), 100);
+
+        let mut request = CoAPRequest::new();
+        request.set_path("/broken");
+        let mut reader = client.request_streaming(request);
+        assert!(reader.next_block().unwrap().is_some());
+        let error = reader.next_block().unwrap_err();
+        assert!(error.to_string().contains("disk failure"));
+    }
+
+    #[test]
+    fn test_duplicate_requests() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let handler_calls = calls.clone();
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new().unwrap().block_on(async move {
+                let mut server = server::Server::new("127.0.0.1:0").unwrap();
+                server.set_exchange_lifetime(Duration::from_secs(10));
+                tx.send(server.socket_addr().unwrap()).unwrap();
+                server.run(move |req: CoAPRequest| {
+                    let call = handler_calls.fetch_add(1, Ordering::SeqCst) + 1;
+                    async move {
+                        let mut response = req.response?;
+                        response.message.payload = vec![call as u8];
+                        Some(response)
+                    }
+                }).await.unwrap();
+            })
+        });
+        let server_addr = rx.recv().unwrap();
+
+        let client = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut request = CoAPRequest::new();
+        request.set_type(MessageType::Confirmable);
+        request.set_message_id(7);
+        request.set_path("/counter");
+        let bytes = request.message.to_bytes().unwrap();
+        let mut replies = Vec::new();
+        for _ in 0..2 {
+            client.send_to(&bytes, server_addr).unwrap();
+            let mut buf = [0; 1500];
+            let (nread, _) = client.recv_from(&mut buf).unwrap();
+            replies.push(Packet::from_bytes(&buf[..nread]).unwrap());
+        }
+        assert_eq!(replies[0].payload, vec![1]);
+        assert_eq!(replies[1].payload, vec![1]);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // a non-confirmable request is handled every time
+        request.set_type(MessageType::NonConfirmable);
+        let bytes = request.message.to_bytes().unwrap();
+        for _ in 0..2 {
+            client.send_to(&bytes, server_addr).unwrap();
+            client.recv_from(&mut [0; 1500]).unwrap();
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_handler_timeout() {
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new().unwrap().block_on(async move {
+                let mut server = server::Server::new("127.0.0.1:0").unwrap();
+                server.set_handler_timeout(HandlerTimeout::default().with_timeout(Duration::from_millis(200)));
+                server.set_resource_handler_timeout(
+                    "/slow",
+                    HandlerTimeout::default()
+                        .with_timeout(Duration::from_secs(2))
+                        .with_separate_after(Some(Duration::from_millis(50))),
+                );
+                server.set_resource_handler_timeout(
+                    "/lingering",
+                    HandlerTimeout::default().with_timeout(Duration::from_millis(100)).with_cancel(false),
+                );
+                tx.send(server.socket_addr().unwrap().port()).unwrap();
+                server.run(|req: CoAPRequest| async move {
+                    match req.get_path().as_str() {
+                        "runaway" | "lingering" => tokio::time::delay_for(Duration::from_secs(10)).await,
+                        "slow" => tokio::time::delay_for(Duration::from_millis(300)).await,
+                        _ => (),
+                    }
+                    req.response
+                }).await.unwrap();
+            })
+        });
+        let server_port = rx.recv().unwrap();
+
+        let mut client = CoAPClient::new(format!("127.0.0.1:{}", server_port)).unwrap();
+        client.set_transmission_parameters(client::TransmissionParameters::default().with_max_retransmit(0));
+        let started = std::time::Instant::now();
+        let mut request = CoAPRequest::new();
+        request.set_path("/runaway");
+        let response = client.request(&mut request).unwrap();
+        assert_eq!(*response.get_status(), Status::InternalServerError);
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        // acknowledged at once, answered separately
+        let mut request = CoAPRequest::new();
+        request.set_path("/slow");
+        let response = client.request(&mut request).unwrap();
+        assert_eq!(*response.get_status(), Status::Content);
+        assert_eq!(response.message.header.get_type(), MessageType::NonConfirmable);
+
+        // the cancelled handler no longer holds up the server
+        let mut request = CoAPRequest::new();
+        request.set_path("/fast");
+        assert_eq!(*client.request(&mut request).unwrap().get_status(), Status::Content);
+
+        // nor does one left running after its 5.00
+        let started = std::time::Instant::now();
+        let mut request = CoAPRequest::new();
+        request.set_path("/lingering");
+        assert_eq!(*client.request(&mut request).unwrap().get_status(), Status::InternalServerError);
+        let mut request = CoAPRequest::new();
+        request.set_path("/fast");
+        assert_eq!(*client.request(&mut request).unwrap().get_status(), Status::Content);
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_peer_stats_resource() {
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new().unwrap().block_on(async move {
+                let mut server = server::Server::new("127.0.0.1:0").unwrap();
+                server.expose_peer_stats("/.diag/peers");
+                tx.send(server.socket_addr().unwrap().port()).unwrap();
+                server.run(request_handler).await.unwrap();
+            })
+        });
+        let server_port = rx.recv().unwrap();
+
+        let client = CoAPClient::new(format!("127.0.0.1:{}", server_port)).unwrap();
+        let mut request = CoAPRequest::new();
+        request.set_path("/test-echo");
+        client.send(&request).unwrap();
+        client.receive().unwrap();
+
+        request.set_path("/.diag/peers");
+        request.set_message_id(2);
+        client.send(&request).unwrap();
+        let response = client.receive().unwrap();
+        assert_eq!(response.message.get_content_format(), Some(ContentFormat::ApplicationCBOR));
+        let (map, _) = cbor::decode(&response.message.payload).unwrap();
+        match map {
+            cbor::Value::Map(ref entries) => {
+                assert_eq!(entries.len(), 1);
+                assert!(entries[0].0.as_text().unwrap().starts_with("127.0.0.1:"));
+            }
+            _ => panic!("not a map"),
+        }
+    }
+
+    #[test]
+    fn test_resource_acl() {
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new().unwrap().block_on(async move {
+                let mut server = server::Server::new("127.0.0.1:0").unwrap();
+                server.set_resource_acl("/admin", Acl::new().allow_identity(b"operator"));
+                tx.send(server.socket_addr().unwrap().port()).unwrap();
+                server.run(|request: CoAPRequest| async move { request.response }).await.unwrap();
+            })
+        });
+        let server_port = rx.recv().unwrap();
+
+        let client = CoAPClient::new(format!("127.0.0.1:{}", server_port)).unwrap();
+        let mut request = CoAPRequest::new();
+        request.set_path("/public");
+        assert_eq!(*client.request(&mut request).unwrap().get_status(), Status::Content);
+
+        let mut request = CoAPRequest::new();
+        request.set_path("/admin/reboot");
+        assert_eq!(*client.request(&mut request).unwrap().get_status(), Status::Forbidden);
+
+        // a leading empty Uri-Path segment does not get past the ACL
+        let mut request = CoAPRequest::new();
+        request.message.add_option(CoAPOption::UriPath, vec![]);
+        request.message.add_option(CoAPOption::UriPath, b"admin".to_vec());
+        request.message.add_option(CoAPOption::UriPath, b"reboot".to_vec());
+        assert_eq!(request.get_path(), "/admin/reboot");
+        assert_eq!(*client.request(&mut request).unwrap().get_status(), Status::Forbidden);
+    }
+
     #[test]
     fn test_echo_server_no_token() {
         let server_port = spawn_server(request_handler).recv().unwrap();
@@ -270,7 +1738,7 @@ pub mod test {
 
         let server_port = spawn_server(request_handler).recv().unwrap();
 
-        let mut client = CoAPClient::new(format!("127.0.0.1:{}", server_port)).unwrap();
+        let client = CoAPClient::new(format!("127.0.0.1:{}", server_port)).unwrap();
 
         tx.send(step).unwrap();
         let mut request = CoAPRequest::new();
@@ -283,7 +1751,7 @@ pub mod test {
         let mut receive_step = 1;
         let payload1_clone = payload1.clone();
         let payload2_clone = payload2.clone();
-        client.observe(path, move |msg| {
+        let _observation = client.observe(path, move |msg| {
             match rx.try_recv() {
                 Ok(n) => receive_step = n,
                 _ => (),
@@ -0,0 +1,193 @@
+//! Adapters between the server and tower services, built with the `tower` feature, so that
+//! tower middleware such as timeouts, concurrency limits and load shedding can wrap CoAP
+//! handlers and the forward proxy:
+//!
+//! ```text
+//! let service = ServiceBuilder::new()
+//!     .load_shed()
+//!     .timeout(Duration::from_secs(2))
+//!     .service(ProxyService::new(ForwardProxy::new()));
+//! server.run(service::handler(service)).await
+//! ```
+//!
+//! An exchange is a `Service<CoAPRequest, Response = CoAPResponse>`. Requests the service
+//! fails are answered in its place: 5.03 (Service Unavailable) when a load shedder rejected
+//! them and 5.00 (Internal Server Error) otherwise, with the error as diagnostic payload. The
+//! server awaits one request at a time, so a concurrency limit only bounds a service shared
+//! with other servers.
+
+use std::future::Future;
+use std::io::{Error, ErrorKind};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use futures::future::poll_fn;
+use tower::load_shed::error::Overloaded;
+use tower::Service;
+
+use super::message::request::CoAPRequest;
+use super::message::response::{CoAPResponse, Status};
+use super::proxy::ForwardProxy;
+
+/// The errors of services, as boxed by tower middleware.
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+/// Turns a service into a handler for `Server::run`. Every request is handled by a clone of
+/// the service, as tower services are shared.
+pub fn handler<S>(service: S) -> impl FnMut(CoAPRequest) -> BoxFuture<Option<CoAPResponse>> + Send
+where
+    S: Service<CoAPRequest, Response = CoAPResponse> + Clone + Send + 'static,
+    S::Future: Send,
+    S::Error: Into<BoxError>,
+{
+    move |request| {
+        let mut service = service.clone();
+        Box::pin(async move {
+            let template = request.response.clone();
+            let ready: Result<(), BoxError> = poll_fn(|cx| service.poll_ready(cx)).await.map_err(Into::into);
+            let result = match ready {
+                Ok(()) => service.call(request).await.map_err(Into::into),
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(response) => Some(response),
+                Err(e) => template.map(|template| error_response(template, e)),
+            }
+        })
+    }
+}
+
+/// The forward proxy as a service, running its blocking upstream exchanges on the blocking
+/// threads of the runtime one at a time. Requests without a response, e.g. ACKs, fail.
+#[derive(Clone)]
+pub struct ProxyService {
+    proxy: Arc<Mutex<ForwardProxy>>,
+}
+
+impl ProxyService {
+    pub fn new(proxy: ForwardProxy) -> ProxyService {
+        ProxyService { proxy: Arc::new(Mutex::new(proxy)) }
+    }
+
+    /// Returns the proxy, e.g. to read its cache statistics.
+    pub fn proxy(&self) -> Arc<Mutex<ForwardProxy>> {
+        self.proxy.clone()
+    }
+}
+
+impl Service<CoAPRequest> for ProxyService {
+    type Response = CoAPResponse;
+    type Error = Error;
+    type Future = BoxFuture<Result<CoAPResponse, Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: CoAPRequest) -> Self::Future {
+        let proxy = self.proxy.clone();
+        Box::pin(async move {
+            let response = tokio::task::spawn_blocking(move || proxy.lock().unwrap().handle(&request))
+                .await
+                .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
+            response.ok_or_else(|| Error::new(ErrorKind::InvalidInput, "not a request"))
+        })
+    }
+}
+
+fn error_response(mut response: CoAPResponse, error: BoxError) -> CoAPResponse {
+    let status = if error.is::<Overloaded>() {
+        Status::ServiceUnavailable
+    } else {
+        Status::InternalServerError
+    };
+    response.set_error(status, &error.to_string());
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::*;
+    use super::super::message::packet::Packet;
+    use std::sync::mpsc;
+    use std::time::Duration;
+    use tower::{service_fn, ServiceBuilder};
+
+    fn spawn_service<S>(service: S) -> u16
+    where
+        S: Service<CoAPRequest, Response = CoAPResponse> + Clone + Send + 'static,
+        S::Future: Send,
+        S::Error: Into<BoxError>,
+    {
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new().unwrap().block_on(async move {
+                let mut server = Server::new("127.0.0.1:0").unwrap();
+                tx.send(server.socket_addr().unwrap().port()).unwrap();
+                server.run(handler(service)).await.unwrap();
+            })
+        });
+        rx.recv().unwrap()
+    }
+
+    #[test]
+    fn test_service_handler() {
+        let service = ServiceBuilder::new()
+            .timeout(Duration::from_millis(100))
+            .service(service_fn(|request: CoAPRequest| async move {
+                if request.get_path() == "slow" {
+                    tokio::time::delay_for(Duration::from_secs(5)).await;
+                }
+                request.response.ok_or_else(|| Error::new(ErrorKind::InvalidInput, "not a request"))
+            }));
+        let server_port = spawn_service(service);
+
+        let client = CoAPClient::new(format!("127.0.0.1:{}", server_port)).unwrap();
+        let mut request = CoAPRequest::new();
+        request.set_path("/fast");
+        assert_eq!(*client.request(&mut request).unwrap().get_status(), Status::Content);
+
+        let mut request = CoAPRequest::new();
+        request.set_path("/slow");
+        let response = client.request(&mut request).unwrap();
+        assert_eq!(*response.get_status(), Status::InternalServerError);
+        assert_eq!(response.message.payload, b"request timed out".to_vec());
+
+        // a service that never gets ready is shed
+        let unavailable = ServiceBuilder::new()
+            .load_shed()
+            .concurrency_limit(0)
+            .service(service_fn(|request: CoAPRequest| async move { Ok::<_, Error>(request.response.unwrap()) }));
+        let mut packet = Packet::new();
+        packet.header.set_type(MessageType::Confirmable);
+        let request = CoAPRequest::from_packet(packet, &"127.0.0.1:1234".parse().unwrap());
+        let response = tokio::runtime::Runtime::new().unwrap().block_on(handler(unavailable)(request));
+        assert_eq!(*response.unwrap().get_status(), Status::ServiceUnavailable);
+    }
+
+    #[test]
+    fn test_proxy_service() {
+        let upstream_port = server::test::spawn_server(|req: CoAPRequest| async move {
+            let mut response = req.response?;
+            response.set_payload(b"upstream".to_vec());
+            Some(response)
+        }).recv().unwrap();
+        let proxy = ProxyService::new(ForwardProxy::new());
+        let proxy_port = spawn_service(ServiceBuilder::new().concurrency_limit(4).service(proxy.clone()));
+
+        let client = CoAPClient::new(format!("127.0.0.1:{}", proxy_port)).unwrap();
+        let mut request = CoAPRequest::new();
+        request.message.add_option(
+            CoAPOption::ProxyUri,
+            format!("coap://127.0.0.1:{}/resource", upstream_port).into_bytes(),
+        );
+        let response = client.request(&mut request).unwrap();
+        assert_eq!(*response.get_status(), Status::Content);
+        assert_eq!(response.message.payload, b"upstream".to_vec());
+        assert_eq!(proxy.proxy().lock().unwrap().cache_stats().entries, 1);
+    }
+}
@@ -0,0 +1,228 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Why a session left a `SessionTable`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EvictionReason {
+    /// No datagram was exchanged within the idle timeout.
+    Idle,
+    /// The table was full and the session was the least recently used one.
+    Capacity,
+    /// The session was removed explicitly, e.g. after a close_notify alert.
+    Closed,
+}
+
+/// How many sessions a server keeps and for how long, built from the defaults with the
+/// `with_*` methods. By default sessions are neither capped nor timed out.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SessionPolicy {
+    /// The most sessions kept, the least recently used one evicted beyond.
+    pub max_sessions: Option<usize>,
+    /// How long a session is kept without a datagram exchanged.
+    pub idle_timeout: Option<Duration>,
+}
+
+impl SessionPolicy {
+    pub fn with_max_sessions(mut self, max_sessions: usize) -> SessionPolicy {
+        self.max_sessions = Some(max_sessions);
+        self
+    }
+
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> SessionPolicy {
+        self.idle_timeout = Some(idle_timeout);
+        self
+    }
+}
+
+type Hook<S> = Box<dyn FnMut(SocketAddr, &S, EvictionReason) + Send>;
+
+struct Entry<S> {
+    session: S,
+    last_active: Instant,
+    // the position of the session in the recency order
+    tick: u64,
+}
+
+/// Per-peer sessions of a datagram server, such as DTLS sessions, with an idle timeout and a
+/// cap on the number of sessions enforced by evicting the least recently used one.
+///
+/// The eviction hook lets the application learn that a device went away, e.g. to mark it
+/// offline.
+pub struct SessionTable<S> {
+    sessions: HashMap<SocketAddr, Entry<S>>,
+    // the peers by the tick of their last activity, least recently active first
+    recency: BTreeMap<u64, SocketAddr>,
+    next_tick: u64,
+    policy: SessionPolicy,
+    eviction_hook: Option<Hook<S>>,
+}
+
+impl<S> SessionTable<S> {
+    pub fn new(policy: SessionPolicy) -> SessionTable<S> {
+        SessionTable {
+            sessions: HashMap::new(),
+            recency: BTreeMap::new(),
+            next_tick: 0,
+            policy,
+            eviction_hook: None,
+        }
+    }
+
+    /// Sets a hook called with every session leaving the table.
+    pub fn set_eviction_hook<F: FnMut(SocketAddr, &S, EvictionReason) + Send + 'static>(&mut self, hook: F) {
+        self.eviction_hook = Some(Box::new(hook));
+    }
+
+    /// Changes the policy, evicting the least recently used sessions beyond a lower cap.
+    pub fn set_policy(&mut self, policy: SessionPolicy) {
+        self.policy = policy;
+        if let Some(max_sessions) = policy.max_sessions {
+            while self.sessions.len() > max_sessions && self.evict_least_recently_used() {}
+        }
+    }
+
+    pub fn policy(&self) -> SessionPolicy {
+        self.policy
+    }
+
+    /// Adds the session of a peer, replacing its previous session and evicting the least
+    /// recently used session if the table is full.
+    pub fn insert(&mut self, peer: SocketAddr, session: S) {
+        self.remove(&peer);
+        if let Some(max_sessions) = self.policy.max_sessions {
+            while self.sessions.len() >= max_sessions.max(1) && self.evict_least_recently_used() {}
+        }
+        let tick = self.next_tick();
+        self.recency.insert(tick, peer);
+        self.sessions.insert(peer, Entry { session, last_active: Instant::now(), tick });
+    }
+
+    /// Returns the session of a peer.
+    pub fn get(&self, peer: &SocketAddr) -> Option<&S> {
+        self.sessions.get(peer).map(|entry| &entry.session)
+    }
+
+    /// Returns the session of a peer, marking it as active.
+    pub fn get_mut(&mut self, peer: &SocketAddr) -> Option<&mut S> {
+        let tick = self.next_tick();
+        let entry = self.sessions.get_mut(peer)?;
+        self.recency.remove(&entry.tick);
+        self.recency.insert(tick, *peer);
+        entry.tick = tick;
+        entry.last_active = Instant::now();
+        Some(&mut entry.session)
+    }
+
+    pub fn contains(&self, peer: &SocketAddr) -> bool {
+        self.sessions.contains_key(peer)
+    }
+
+    /// Removes the session of a peer.
+    pub fn remove(&mut self, peer: &SocketAddr) -> Option<S> {
+        self.evict(peer, EvictionReason::Closed)
+    }
+
+    /// Evicts the sessions idle for longer than the idle timeout, returning how many were
+    /// evicted. Call it periodically, e.g. from the server's timer.
+    pub fn evict_idle(&mut self) -> usize {
+        let idle_timeout = match self.policy.idle_timeout {
+            Some(idle_timeout) => idle_timeout,
+            None => return 0,
+        };
+        let now = Instant::now();
+        // the least recently active sessions come first, so the idle ones are a prefix
+        let idle: Vec<SocketAddr> = self
+            .recency
+            .values()
+            .take_while(|peer| now.duration_since(self.sessions[*peer].last_active) >= idle_timeout)
+            .copied()
+            .collect();
+        for peer in idle.iter() {
+            self.evict(peer, EvictionReason::Idle);
+        }
+        idle.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+
+    fn evict_least_recently_used(&mut self) -> bool {
+        match self.recency.values().next().copied() {
+            Some(victim) => self.evict(&victim, EvictionReason::Capacity).is_some(),
+            None => false,
+        }
+    }
+
+    fn evict(&mut self, peer: &SocketAddr, reason: EvictionReason) -> Option<S> {
+        let entry = self.sessions.remove(peer)?;
+        self.recency.remove(&entry.tick);
+        self.notify(*peer, &entry.session, reason);
+        Some(entry.session)
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.next_tick += 1;
+        self.next_tick
+    }
+
+    fn notify(&mut self, peer: SocketAddr, session: &S, reason: EvictionReason) {
+        if let Some(ref mut hook) = self.eviction_hook {
+            hook(peer, session, reason);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    fn peer(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        let mut table = SessionTable::new(SessionPolicy::default().with_max_sessions(2));
+        let hook_evicted = evicted.clone();
+        table.set_eviction_hook(move |peer, session: &&str, reason| {
+            hook_evicted.lock().unwrap().push((peer.port(), session.to_string(), reason));
+        });
+
+        table.insert(peer(1), "a");
+        thread::sleep(Duration::from_millis(5));
+        table.insert(peer(2), "b");
+        thread::sleep(Duration::from_millis(5));
+        assert!(table.get_mut(&peer(1)).is_some());
+        table.insert(peer(3), "c");
+
+        assert_eq!(table.len(), 2);
+        assert!(!table.contains(&peer(2)));
+        assert_eq!(table.remove(&peer(1)), Some("a"));
+        assert_eq!(*evicted.lock().unwrap(), vec![
+            (2, "b".to_string(), EvictionReason::Capacity),
+            (1, "a".to_string(), EvictionReason::Closed),
+        ]);
+    }
+
+    #[test]
+    fn test_idle_eviction() {
+        let mut table = SessionTable::new(SessionPolicy::default().with_idle_timeout(Duration::from_millis(20)));
+        table.insert(peer(1), ());
+        table.insert(peer(2), ());
+        thread::sleep(Duration::from_millis(30));
+        assert!(table.get_mut(&peer(2)).is_some());
+
+        assert_eq!(table.evict_idle(), 1);
+        assert!(table.contains(&peer(2)));
+        assert!(!table.contains(&peer(1)));
+    }
+}
@@ -1,5 +1,7 @@
 use lazy_static::lazy_static;
-use openssl::ssl::{SslConnector, SslMethod};
+use log::*;
+use openssl::error::ErrorStack;
+use openssl::ssl::{SslConnector, SslConnectorBuilder, SslMethod};
 use std::io::Result;
 use std::io::Write;
 
@@ -19,16 +21,54 @@ lazy_static! {
 }
 
 pub fn get_ssl_connector() -> Result<SslConnector> {
-    let mut builder = SslConnector::builder(SslMethod::dtls())?;
+    get_psk_connector(ID.as_bytes().to_vec(), KEY.as_bytes().to_vec())
+}
 
-    builder.set_psk_client_callback(move |_ssl, _hint, mut identity_buffer, mut psk_buffer| {
-        identity_buffer.write_all(ID.as_bytes()).unwrap();
-        psk_buffer.write_all(KEY.as_bytes()).unwrap();
-        Ok(KEY.len())
-    });
-    builder
-        .set_cipher_list("ECDHE-PSK-AES128-CBC-SHA256:PSK-AES128-CCM8:ECDHE-ECDSA-AES128-CCM8")?;
+/// Builds a DTLS connector authenticating with the given PSK identity and key.
+pub fn get_psk_connector(identity: Vec<u8>, key: Vec<u8>) -> Result<SslConnector> {
+    get_psk_selector_connector(move |_hint| Some((identity.clone(), key.clone())))
+}
+
+/// Builds a DTLS connector authenticating with the PSK identity and key that `select`
+/// returns for the server's identity hint. The handshake fails if it returns none.
+pub fn get_psk_selector_connector<F>(select: F) -> Result<SslConnector>
+where
+    F: Fn(Option<&[u8]>) -> Option<(Vec<u8>, Vec<u8>)> + Send + Sync + 'static,
+{
+    let mut builder = get_dtls_connector_builder()?;
+    set_psk_selector(&mut builder, select);
 
     let connector = builder.build();
     return Ok(connector);
 }
+
+/// Returns a DTLS connector builder restricted to the cipher suites CoAP endpoints use.
+pub fn get_dtls_connector_builder() -> Result<SslConnectorBuilder> {
+    let mut builder = SslConnector::builder(SslMethod::dtls())?;
+    builder
+        .set_cipher_list("ECDHE-PSK-AES128-CBC-SHA256:PSK-AES128-CCM8:ECDHE-ECDSA-AES128-CCM8:ECDHE-ECDSA-AES128-CCM")?;
+    Ok(builder)
+}
+
+/// Authenticates with the PSK identity and key that `select` returns for the server's
+/// identity hint.
+pub fn set_psk_selector<F>(builder: &mut SslConnectorBuilder, select: F)
+where
+    F: Fn(Option<&[u8]>) -> Option<(Vec<u8>, Vec<u8>)> + Send + Sync + 'static,
+{
+    builder.set_psk_client_callback(move |_ssl, hint, mut identity_buffer, mut psk_buffer| {
+        let (identity, key) = match select(hint) {
+            Some(credential) => credential,
+            None => {
+                warn!("no PSK for identity hint {:?}", hint.map(String::from_utf8_lossy));
+                return Err(ErrorStack::get());
+            }
+        };
+        // the identity is a C string, the buffer is zeroed and one byte longer than its length
+        if identity_buffer.write_all(&identity).is_err() || psk_buffer.write_all(&key).is_err() {
+            warn!("PSK identity or key too long");
+            return Err(ErrorStack::get());
+        }
+        Ok(key.len())
+    });
+}
@@ -0,0 +1,193 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::cbor::Value;
+use super::message::header::{class_to_code, MessageClass, MessageType};
+use super::message::packet::Packet;
+
+const MAX_TRACKED_PEERS: usize = 10000;
+const RECENT_MESSAGE_IDS: usize = 16;
+
+/// What a server has seen from one peer.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PeerStats {
+    pub requests: u64,
+    /// Responses with a 4.xx or 5.xx code sent to the peer.
+    pub errors: u64,
+    /// Confirmable messages received again with a message ID seen recently.
+    pub retransmissions: u64,
+    pub last_activity: Instant,
+    payload_bytes: u64,
+    recent_ids: VecDeque<u16>,
+}
+
+impl PeerStats {
+    fn new() -> PeerStats {
+        PeerStats {
+            requests: 0,
+            errors: 0,
+            retransmissions: 0,
+            last_activity: Instant::now(),
+            payload_bytes: 0,
+            recent_ids: VecDeque::new(),
+        }
+    }
+
+    /// The average request payload size in bytes.
+    pub fn average_payload_size(&self) -> f64 {
+        if self.requests == 0 {
+            return 0.0;
+        }
+        self.payload_bytes as f64 / self.requests as f64
+    }
+
+    /// How long ago the peer was last heard from.
+    pub fn idle(&self) -> Duration {
+        self.last_activity.elapsed()
+    }
+
+    fn to_cbor(&self) -> Value {
+        Value::Map(vec![
+            (Value::Text("requests".to_string()), Value::Integer(self.requests as i64)),
+            (Value::Text("errors".to_string()), Value::Integer(self.errors as i64)),
+            (Value::Text("retransmissions".to_string()), Value::Integer(self.retransmissions as i64)),
+            (Value::Text("idle_ms".to_string()), Value::Integer(self.idle().as_millis() as i64)),
+            (Value::Text("avg_payload".to_string()), Value::Float(self.average_payload_size())),
+        ])
+    }
+}
+
+/// Per-peer counters of a server, for spotting misbehaving devices in a fleet.
+///
+/// Handles are cheap to clone and can be read from another thread while the server runs.
+/// When more peers are seen than tracked, the least recently active one is forgotten.
+#[derive(Clone, Default)]
+pub struct PeerStatsRegistry {
+    peers: Arc<Mutex<HashMap<SocketAddr, PeerStats>>>,
+}
+
+impl PeerStatsRegistry {
+    pub fn new() -> PeerStatsRegistry {
+        Self::default()
+    }
+
+    pub fn get(&self, peer: &SocketAddr) -> Option<PeerStats> {
+        self.peers.lock().unwrap().get(peer).cloned()
+    }
+
+    /// Returns the statistics of every tracked peer.
+    pub fn list(&self) -> Vec<(SocketAddr, PeerStats)> {
+        let peers = self.peers.lock().unwrap();
+        peers.iter().map(|(peer, stats)| (*peer, stats.clone())).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.lock().unwrap().is_empty()
+    }
+
+    pub fn clear(&self) {
+        self.peers.lock().unwrap().clear();
+    }
+
+    /// Encodes the statistics as a CBOR map keyed by peer address, the representation of
+    /// the diagnostic resource.
+    pub fn to_cbor(&self) -> Vec<u8> {
+        let peers = self.peers.lock().unwrap();
+        let mut entries: Vec<(Value, Value)> = peers
+            .iter()
+            .map(|(peer, stats)| (Value::Text(peer.to_string()), stats.to_cbor()))
+            .collect();
+        entries.sort_by(|a, b| a.0.as_text().cmp(&b.0.as_text()));
+        Value::Map(entries).to_vec()
+    }
+
+    /// Records a message received from a peer.
+    pub(crate) fn received(&self, peer: SocketAddr, packet: &Packet) {
+        let mut peers = self.peers.lock().unwrap();
+        if !peers.contains_key(&peer) && peers.len() >= MAX_TRACKED_PEERS {
+            let oldest = peers
+                .iter()
+                .min_by_key(|(_, stats)| stats.last_activity)
+                .map(|(peer, _)| *peer);
+            if let Some(oldest) = oldest {
+                peers.remove(&oldest);
+            }
+        }
+
+        let stats = peers.entry(peer).or_insert_with(PeerStats::new);
+        stats.last_activity = Instant::now();
+        let message_id = packet.header.get_message_id();
+        if packet.header.get_type() == MessageType::Confirmable && stats.recent_ids.contains(&message_id) {
+            stats.retransmissions += 1;
+            return;
+        }
+        stats.recent_ids.push_back(message_id);
+        if stats.recent_ids.len() > RECENT_MESSAGE_IDS {
+            stats.recent_ids.pop_front();
+        }
+
+        if let MessageClass::Request(_) = packet.header.code {
+            stats.requests += 1;
+            stats.payload_bytes += packet.payload.len() as u64;
+        }
+    }
+
+    /// Records a response sent to a peer.
+    pub(crate) fn responded(&self, peer: &SocketAddr, packet: &Packet) {
+        let class = class_to_code(&packet.header.code) >> 5;
+        if class == 4 || class == 5 {
+            if let Some(stats) = self.peers.lock().unwrap().get_mut(peer) {
+                stats.errors += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::cbor;
+    use super::super::message::header::ResponseType;
+
+    fn request(message_id: u16, payload: &[u8]) -> Packet {
+        let mut packet = Packet::new();
+        packet.header.set_type(MessageType::Confirmable);
+        packet.header.set_code("0.02");
+        packet.header.set_message_id(message_id);
+        packet.payload = payload.to_vec();
+        packet
+    }
+
+    #[test]
+    fn test_peer_stats() {
+        let peer: SocketAddr = "127.0.0.1:5683".parse().unwrap();
+        let registry = PeerStatsRegistry::new();
+        registry.received(peer, &request(1, b"abcd"));
+        registry.received(peer, &request(2, b"ab"));
+        registry.received(peer, &request(2, b"ab"));
+
+        let mut response = Packet::new();
+        response.header.code = MessageClass::Response(ResponseType::NotFound);
+        registry.responded(&peer, &response);
+
+        let stats = registry.clone().get(&peer).unwrap();
+        assert_eq!(stats.requests, 2);
+        assert_eq!(stats.retransmissions, 1);
+        assert_eq!(stats.errors, 1);
+        assert_eq!(stats.average_payload_size(), 3.0);
+
+        let (map, _) = cbor::decode(&registry.to_cbor()).unwrap();
+        match map {
+            Value::Map(ref entries) => {
+                assert_eq!(entries[0].0, Value::Text("127.0.0.1:5683".to_string()));
+            }
+            _ => panic!("not a map"),
+        }
+    }
+}
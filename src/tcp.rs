@@ -0,0 +1,1132 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use log::*;
+use url::Url;
+
+use super::client::{UnsolicitedHandler, UnsolicitedReply};
+use super::context::Transport;
+use super::message::header::{MessageClass, SignalingType};
+use super::message::packet::{decode_uint, encode_uint, Encoder, MessageError, Packet};
+use super::message::request::{CoAPRequest, Method};
+use super::message::response::CoAPResponse;
+use super::message::IsMessage;
+use super::rng;
+
+const DEFAULT_MAX_MESSAGE_SIZE: u32 = 1152;
+const DEFAULT_RECEIVE_TIMEOUT: u64 = 5; // 5s
+// how often a server connection checks for requests to send to its client
+const SERVER_POLL_INTERVAL: Duration = Duration::from_millis(50);
+const DEFAULT_MAX_IN_FLIGHT: usize = 1;
+// the largest frame header: a 7 byte header and an 8 byte token
+const MAX_FRAME_OVERHEAD: usize = 15;
+
+// signaling option numbers (RFC 8323 §5.3 - §5.6)
+const MAX_MESSAGE_SIZE: usize = 2;
+const BLOCK_WISE_TRANSFER: usize = 4;
+const ALTERNATIVE_ADDRESS: usize = 2;
+const HOLD_OFF: usize = 4;
+const BAD_CSM_OPTION: usize = 2;
+
+/// What an endpoint of a reliable transport announces in its CSM message (RFC 8323 §5.3).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Capabilities {
+    /// The largest message the endpoint accepts.
+    pub max_message_size: u32,
+    /// Whether the endpoint supports block-wise transfers of large messages.
+    pub block_wise_transfer: bool,
+}
+
+impl Default for Capabilities {
+    fn default() -> Capabilities {
+        Capabilities {
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            block_wise_transfer: false,
+        }
+    }
+}
+
+/// How a `TcpConnection` is set up, built from the defaults with the `with_*` methods.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TcpParameters {
+    /// The capabilities announced to the peer.
+    pub capabilities: Capabilities,
+    /// How long to wait for a message from the peer.
+    pub timeout: Duration,
+    /// How long the connection may stay idle before `keepalive` checks it with a Ping.
+    pub keepalive: Option<Duration>,
+    /// Which requests a client sends again when the connection drops before their response.
+    pub replay_policy: ReplayPolicy,
+}
+
+impl Default for TcpParameters {
+    fn default() -> TcpParameters {
+        TcpParameters {
+            capabilities: Capabilities::default(),
+            timeout: Duration::new(DEFAULT_RECEIVE_TIMEOUT, 0),
+            keepalive: None,
+            replay_policy: ReplayPolicy::Idempotent,
+        }
+    }
+}
+
+impl TcpParameters {
+    pub fn with_max_message_size(mut self, max_message_size: u32) -> TcpParameters {
+        self.capabilities.max_message_size = max_message_size;
+        self
+    }
+
+    pub fn with_block_wise_transfer(mut self, block_wise_transfer: bool) -> TcpParameters {
+        self.capabilities.block_wise_transfer = block_wise_transfer;
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> TcpParameters {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_keepalive(mut self, keepalive: Option<Duration>) -> TcpParameters {
+        self.keepalive = keepalive;
+        self
+    }
+
+    pub fn with_replay_policy(mut self, replay_policy: ReplayPolicy) -> TcpParameters {
+        self.replay_policy = replay_policy;
+        self
+    }
+}
+
+/// Which outstanding requests are replayed over a new connection when the connection drops
+/// mid-exchange. Requests that are not replayed fail with the error that broke the connection,
+/// as the server may or may not have processed them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReplayPolicy {
+    /// Never replay; the client still reconnects for the next request.
+    Never,
+    /// Replay GET and FETCH requests, which are safe to process twice.
+    Idempotent,
+}
+
+impl ReplayPolicy {
+    fn replays(self, method: &Method) -> bool {
+        match self {
+            ReplayPolicy::Never => false,
+            ReplayPolicy::Idempotent => *method == Method::Get || *method == Method::Fetch,
+        }
+    }
+}
+
+/// How the peer ended a connection.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Termination {
+    /// The peer released the connection gracefully (7.04), possibly pointing to another
+    /// address to connect to and asking to wait before connecting again.
+    Released {
+        alternative_address: Option<String>,
+        hold_off: Option<Duration>,
+    },
+    /// The peer aborted the connection (7.05), explaining why in the diagnostic.
+    Aborted {
+        diagnostic: String,
+        bad_csm_option: Option<u32>,
+    },
+}
+
+/// A CoAP over TCP connection (RFC 8323), usable by either end.
+///
+/// Signaling messages are handled as messages are received: the peer's CSM updates its
+/// capabilities, Pings are answered with Pongs and a Release or Abort ends the connection
+/// with a `ConnectionAborted` error, recording the `Termination`.
+pub struct TcpConnection {
+    stream: TcpStream,
+    buf: Vec<u8>,
+    encoder: Encoder,
+    parameters: TcpParameters,
+    peer_capabilities: Option<Capabilities>,
+    pending: VecDeque<Packet>,
+    termination: Option<Termination>,
+    last_activity: Instant,
+}
+
+impl TcpConnection {
+    /// Sets up a connection over an established stream, sending the CSM that must open it.
+    pub fn new(stream: TcpStream, parameters: TcpParameters) -> Result<TcpConnection> {
+        stream.set_read_timeout(Some(parameters.timeout))?;
+        stream.set_nodelay(true)?;
+        let mut connection = TcpConnection {
+            stream,
+            buf: Vec::new(),
+            encoder: Encoder::new(),
+            parameters,
+            peer_capabilities: None,
+            pending: VecDeque::new(),
+            termination: None,
+            last_activity: Instant::now(),
+        };
+
+        let mut csm = signal(SignalingType::Csm);
+        let capabilities = parameters.capabilities;
+        if capabilities.max_message_size != DEFAULT_MAX_MESSAGE_SIZE {
+            csm.add_option_value(MAX_MESSAGE_SIZE, encode_uint(capabilities.max_message_size));
+        }
+        if capabilities.block_wise_transfer {
+            csm.add_option_value(BLOCK_WISE_TRANSFER, Vec::new());
+        }
+        connection.send(&csm)?;
+        Ok(connection)
+    }
+
+    pub fn peer_addr(&self) -> Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    pub fn parameters(&self) -> TcpParameters {
+        self.parameters
+    }
+
+    /// Set how long `receive` waits for a message, which must not be zero. `new` sets the
+    /// timeout of the parameters.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+
+    /// The capabilities of the peer, the defaults until its CSM arrives.
+    pub fn peer_capabilities(&self) -> Capabilities {
+        self.peer_capabilities.unwrap_or_default()
+    }
+
+    /// How the peer ended the connection, if it did.
+    pub fn termination(&self) -> Option<&Termination> {
+        self.termination.as_ref()
+    }
+
+    /// Sends a message, which must fit the Max-Message-Size of the peer.
+    pub fn send(&mut self, message: &Packet) -> Result<()> {
+        let max_message_size = self.peer_capabilities().max_message_size as usize;
+        let bytes = self
+            .encoder
+            .encode_framed(message)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        if bytes.len() > max_message_size + MAX_FRAME_OVERHEAD {
+            return Err(Error::new(ErrorKind::InvalidInput, "message exceeds the Max-Message-Size of the peer"));
+        }
+        self.stream.write_all(bytes)?;
+        self.last_activity = Instant::now();
+        Ok(())
+    }
+
+    /// Receives the next request or response, handling the signaling messages before it.
+    pub fn receive(&mut self) -> Result<Packet> {
+        if let Some(message) = self.pending.pop_front() {
+            return Ok(message);
+        }
+        loop {
+            let message = self.read_message()?;
+            match message.header.code {
+                MessageClass::Signaling(signal) => self.handle_signal(signal, &message)?,
+                _ => return Ok(message),
+            }
+        }
+    }
+
+    /// Checks that the peer is responsive, returning the round-trip time of a Ping.
+    pub fn ping(&mut self) -> Result<Duration> {
+        let mut ping = signal(SignalingType::Ping);
+        ping.set_token(fresh_token());
+        let sent_at = Instant::now();
+        self.send(&ping)?;
+        loop {
+            // the read timeout may be shorter than the time allowed for the Pong
+            let message = match self.read_message() {
+                Ok(message) => message,
+                Err(ref e) if is_timeout(e) && sent_at.elapsed() < self.parameters.timeout => continue,
+                Err(e) => return Err(e),
+            };
+            match message.header.code {
+                MessageClass::Signaling(SignalingType::Pong) if message.get_token() == ping.get_token() => {
+                    return Ok(sent_at.elapsed());
+                }
+                MessageClass::Signaling(signal) => self.handle_signal(signal, &message)?,
+                _ => self.pending.push_back(message),
+            }
+        }
+    }
+
+    /// Pings the peer if the connection was idle for longer than the keep-alive interval.
+    /// Call it periodically to keep NAT bindings open and notice dead connections.
+    pub fn keepalive(&mut self) -> Result<()> {
+        match self.parameters.keepalive {
+            Some(interval) if self.last_activity.elapsed() >= interval => self.ping().map(|_| ()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Releases the connection gracefully, optionally pointing the peer to another address.
+    pub fn release(&mut self, alternative_address: Option<&str>, hold_off: Option<Duration>) -> Result<()> {
+        let mut release = signal(SignalingType::Release);
+        if let Some(address) = alternative_address {
+            release.add_option_value(ALTERNATIVE_ADDRESS, address.as_bytes().to_vec());
+        }
+        if let Some(hold_off) = hold_off {
+            release.add_option_value(HOLD_OFF, encode_uint(hold_off.as_secs() as u32));
+        }
+        self.send(&release)?;
+        self.stream.shutdown(Shutdown::Write)
+    }
+
+    /// Aborts the connection, explaining why in the diagnostic payload.
+    pub fn abort(&mut self, diagnostic: &str) -> Result<()> {
+        let mut abort = signal(SignalingType::Abort);
+        abort.payload = diagnostic.as_bytes().to_vec();
+        let sent = self.send(&abort);
+        let _ = self.stream.shutdown(Shutdown::Both);
+        sent
+    }
+
+    fn read_message(&mut self) -> Result<Packet> {
+        let mut chunk = [0; 4096];
+        loop {
+            let (decoded, used) = {
+                let max_message_size = self.parameters.capabilities.max_message_size as usize;
+                let mut frames = Packet::decode_all(&self.buf).with_max_message_size(max_message_size);
+                let decoded = frames.next();
+                (decoded, frames.offset())
+            };
+            match decoded {
+                Some(Ok(message)) => {
+                    self.buf.drain(..used);
+                    self.last_activity = Instant::now();
+                    return Ok(message);
+                }
+                Some(Err(e)) => {
+                    let _ = match e {
+                        MessageError::MessageTooLarge { .. } => self.abort("message exceeds Max-Message-Size"),
+                        _ => self.abort("malformed message"),
+                    };
+                    return Err(Error::new(ErrorKind::InvalidData, e));
+                }
+                None => (),
+            }
+            if self.buf.len() > self.parameters.capabilities.max_message_size as usize + MAX_FRAME_OVERHEAD {
+                let _ = self.abort("message exceeds Max-Message-Size");
+                return Err(Error::new(ErrorKind::InvalidData, "message exceeds Max-Message-Size"));
+            }
+
+            let nread = self.stream.read(&mut chunk)?;
+            if nread == 0 {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "connection closed by the peer"));
+            }
+            self.buf.extend_from_slice(&chunk[..nread]);
+        }
+    }
+
+    fn handle_signal(&mut self, signal_type: SignalingType, message: &Packet) -> Result<()> {
+        let uint = |number| {
+            message
+                .get_option_values(number)
+                .and_then(|list| list.front())
+                .and_then(|value| decode_uint(value))
+        };
+        match signal_type {
+            SignalingType::Csm => {
+                let capabilities = Capabilities {
+                    max_message_size: uint(MAX_MESSAGE_SIZE).unwrap_or(DEFAULT_MAX_MESSAGE_SIZE),
+                    block_wise_transfer: message.get_option_values(BLOCK_WISE_TRANSFER).is_some(),
+                };
+                debug!("peer capabilities {:?}", capabilities);
+                self.peer_capabilities = Some(capabilities);
+                Ok(())
+            }
+            SignalingType::Ping => {
+                let mut pong = signal(SignalingType::Pong);
+                pong.set_token(message.get_token().clone());
+                self.send(&pong)
+            }
+            SignalingType::Pong => Ok(()),
+            SignalingType::Release => {
+                let alternative_address = message
+                    .get_option_values(ALTERNATIVE_ADDRESS)
+                    .and_then(|list| list.front())
+                    .map(|value| String::from_utf8_lossy(value).to_string());
+                let hold_off = uint(HOLD_OFF).map(|seconds| Duration::from_secs(seconds as u64));
+                self.termination = Some(Termination::Released { alternative_address, hold_off });
+                Err(Error::new(ErrorKind::ConnectionAborted, "connection released by the peer"))
+            }
+            SignalingType::Abort => {
+                let diagnostic = String::from_utf8_lossy(&message.payload).to_string();
+                let error = Error::new(ErrorKind::ConnectionAborted, format!("connection aborted by the peer: {}", diagnostic));
+                self.termination = Some(Termination::Aborted {
+                    diagnostic,
+                    bad_csm_option: uint(BAD_CSM_OPTION),
+                });
+                Err(error)
+            }
+        }
+    }
+}
+
+/// A CoAP over TCP client (RFC 8323), sending requests one at a time.
+///
+/// When the connection drops, the client reconnects, following the alternative address of a
+/// Release, and replays the outstanding request if the replay policy allows it.
+pub struct TcpCoAPClient {
+    connection: TcpConnection,
+    peer_addr: SocketAddr,
+    broken: bool,
+    hold_off_until: Option<Instant>,
+    unsolicited_handler: Option<UnsolicitedHandler>,
+}
+
+impl TcpCoAPClient {
+    /// Connect to a server with the default parameters.
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<TcpCoAPClient> {
+        Self::connect_with(addr, TcpParameters::default())
+    }
+
+    pub fn connect_with<A: ToSocketAddrs>(addr: A, parameters: TcpParameters) -> Result<TcpCoAPClient> {
+        let stream = TcpStream::connect(addr)?;
+        Ok(TcpCoAPClient {
+            peer_addr: stream.peer_addr()?,
+            connection: TcpConnection::new(stream, parameters)?,
+            broken: false,
+            hold_off_until: None,
+            unsolicited_handler: None,
+        })
+    }
+
+    /// Execute a request and wait for its response. Requests without a token get one, as
+    /// responses are matched by token.
+    pub fn request(&mut self, request: &mut CoAPRequest) -> Result<CoAPResponse> {
+        if self.broken {
+            self.reconnect()?;
+        }
+        if request.get_token().is_empty() {
+            request.set_token(fresh_token());
+        }
+        match self.exchange(request) {
+            Err(ref e) if is_disconnect(e) => {
+                self.broken = true;
+                let replay_policy = self.connection.parameters().replay_policy;
+                if !replay_policy.replays(request.get_method()) {
+                    return Err(Error::new(e.kind(), format!("{}, request not replayed", e)));
+                }
+                info!("connection to {} dropped ({}), replaying the request", self.peer_addr, e);
+                self.reconnect()?;
+                let result = self.exchange(request);
+                if let Err(ref e) = result {
+                    self.broken = is_disconnect(e);
+                }
+                result
+            }
+            result => result,
+        }
+    }
+
+    /// Replaces the connection with a new one, to the alternative address if the server
+    /// released the connection with one.
+    pub fn reconnect(&mut self) -> Result<()> {
+        let mut target = self.peer_addr;
+        match self.connection.termination() {
+            Some(Termination::Released { alternative_address: Some(address), .. }) => {
+                target = resolve_alternative_address(address)?;
+            }
+            Some(Termination::Released { hold_off: Some(hold_off), .. }) if self.hold_off_until.is_none() => {
+                self.hold_off_until = Some(Instant::now() + *hold_off);
+            }
+            _ => (),
+        }
+        if let Some(until) = self.hold_off_until {
+            if Instant::now() < until {
+                return Err(Error::new(ErrorKind::WouldBlock, "the server asked to hold off reconnecting"));
+            }
+        }
+
+        let stream = TcpStream::connect(target)?;
+        self.connection = TcpConnection::new(stream, self.connection.parameters())?;
+        self.peer_addr = target;
+        self.broken = false;
+        self.hold_off_until = None;
+        Ok(())
+    }
+
+    /// Set the handler deciding how to answer messages that answer no outstanding request,
+    /// e.g. requests from the server. Without a handler they are ignored, as reliable
+    /// transports have no Reset to reject them with.
+    pub fn set_unsolicited_handler<F>(&mut self, handler: F)
+    where
+        F: FnMut(&Packet, &SocketAddr) -> UnsolicitedReply + Send + 'static,
+    {
+        self.unsolicited_handler = Some(Box::new(handler));
+    }
+
+    fn handle_unsolicited(&mut self, message: Packet) -> Result<()> {
+        let reply = match self.unsolicited_handler {
+            Some(ref mut handler) => handler(&message, &self.peer_addr),
+            None => UnsolicitedReply::Ignore,
+        };
+        debug!("unsolicited message {:?} from {}: {:?}", message.header.code, self.peer_addr, reply);
+        match reply {
+            UnsolicitedReply::Reply(reply) => self.connection.send(&reply),
+            UnsolicitedReply::Reset | UnsolicitedReply::Ignore => Ok(()),
+        }
+    }
+
+    /// Waits up to `timeout` for a message from the server, e.g. a request pushed to a device
+    /// behind a NAT, and passes it to the unsolicited handler. Returns whether a message was
+    /// handled. A keep-alive Ping is sent first if one is due.
+    pub fn poll(&mut self, timeout: Duration) -> Result<bool> {
+        if self.broken {
+            self.reconnect()?;
+        }
+        self.connection.keepalive()?;
+        self.connection.set_read_timeout(Some(timeout))?;
+        let result = self.connection.receive();
+        self.connection.set_read_timeout(Some(self.connection.parameters().timeout))?;
+        match result {
+            Ok(message) => {
+                self.handle_unsolicited(message)?;
+                Ok(true)
+            }
+            Err(ref e) if is_timeout(e) => Ok(false),
+            Err(e) => {
+                self.broken = is_disconnect(&e);
+                Err(e)
+            }
+        }
+    }
+
+    /// The address of the server currently connected to.
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    fn exchange(&mut self, request: &CoAPRequest) -> Result<CoAPResponse> {
+        self.connection.keepalive()?;
+        if let Err(e) = self.connection.send(&request.message) {
+            // a peer refusing the connection may have closed it right after its Abort, which
+            // is still readable and explains the failure better
+            if is_disconnect(&e) {
+                if let Err(signal) = self.connection.receive() {
+                    if self.connection.termination().is_some() {
+                        return Err(signal);
+                    }
+                }
+            }
+            return Err(e);
+        }
+        loop {
+            let message = self.connection.receive()?;
+            match message.header.code {
+                MessageClass::Response(_) if message.get_token() == request.get_token() => {
+                    return Ok(CoAPResponse::received(message));
+                }
+                _ => self.handle_unsolicited(message)?,
+            }
+        }
+    }
+
+    /// Checks that the server is responsive, returning the round-trip time of a Ping.
+    pub fn ping(&mut self) -> Result<Duration> {
+        self.connection.ping()
+    }
+
+    /// Pings the server if the connection was idle for longer than the keep-alive interval.
+    pub fn keepalive(&mut self) -> Result<()> {
+        self.connection.keepalive()
+    }
+
+    pub fn peer_capabilities(&self) -> Capabilities {
+        self.connection.peer_capabilities()
+    }
+
+    /// How the server ended the connection, e.g. a Release pointing to another server.
+    pub fn termination(&self) -> Option<&Termination> {
+        self.connection.termination()
+    }
+}
+
+enum Command {
+    Request(Box<CoAPRequest>, mpsc::Sender<Result<CoAPResponse>>),
+    Close,
+}
+
+/// A connection of a `TcpCoAPServer`, as listed by `TcpPeers::connections`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConnectionInfo {
+    pub peer: SocketAddr,
+    pub connected_at: Instant,
+    /// When a request or a response was last exchanged; signaling does not count.
+    pub last_active: Instant,
+    /// The requests sent to the client and awaiting their response.
+    pub in_flight: usize,
+    /// The requests to the client waiting for an in-flight request to complete.
+    pub queued: usize,
+}
+
+impl ConnectionInfo {
+    /// How long no request or response was exchanged.
+    pub fn idle(&self) -> Duration {
+        self.last_active.elapsed()
+    }
+}
+
+struct Connection {
+    commands: mpsc::Sender<Command>,
+    info: ConnectionInfo,
+}
+
+/// The table of the connections of a `TcpCoAPServer`, to list and close them or to send
+/// requests to the connected clients (RFC 8323 allows either side to send requests).
+#[derive(Clone, Default)]
+pub struct TcpPeers {
+    inner: Arc<Mutex<HashMap<SocketAddr, Connection>>>,
+}
+
+impl TcpPeers {
+    /// The addresses of the connected clients.
+    pub fn list(&self) -> Vec<SocketAddr> {
+        let mut peers: Vec<SocketAddr> = self.inner.lock().unwrap().keys().cloned().collect();
+        peers.sort();
+        peers
+    }
+
+    /// Describes the live connections, ordered by peer address.
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        let mut connections: Vec<ConnectionInfo> = self.inner
+            .lock()
+            .unwrap()
+            .values()
+            .map(|connection| connection.info.clone())
+            .collect();
+        connections.sort_by_key(|info| info.peer);
+        connections
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Closes the connection of a client with a Release message. Returns false if the
+    /// client is not connected.
+    pub fn close(&self, peer: &SocketAddr) -> bool {
+        match self.inner.lock().unwrap().get(peer) {
+            Some(connection) => connection.commands.send(Command::Close).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Sends a request to a connected client and waits for its response, for at most the
+    /// timeout of the server's parameters once sent. Requests without a token get one.
+    pub fn request(&self, peer: &SocketAddr, request: CoAPRequest) -> Result<CoAPResponse> {
+        let not_connected = || Error::new(ErrorKind::NotConnected, "the client is not connected");
+        let (tx, rx) = mpsc::channel();
+        {
+            let peers = self.inner.lock().unwrap();
+            let connection = peers.get(peer).ok_or_else(not_connected)?;
+            connection.commands.send(Command::Request(Box::new(request), tx)).map_err(|_| not_connected())?;
+        }
+        rx.recv().unwrap_or_else(|_| Err(not_connected()))
+    }
+
+    fn update<F: FnOnce(&mut ConnectionInfo)>(&self, peer: &SocketAddr, update: F) {
+        if let Some(connection) = self.inner.lock().unwrap().get_mut(peer) {
+            update(&mut connection.info);
+        }
+    }
+}
+
+/// A CoAP over TCP server (RFC 8323), serving each connection on its own thread.
+///
+/// Besides answering requests with the handler, the server can send requests to the
+/// connected clients through `peers`, e.g. to push commands to devices behind a NAT. The
+/// connections are pinged when the keep-alive interval of the parameters elapses without
+/// traffic, and released once idle for longer than the idle timeout.
+pub struct TcpCoAPServer {
+    listener: TcpListener,
+    parameters: TcpParameters,
+    peers: TcpPeers,
+    max_connections: usize,
+    idle_timeout: Option<Duration>,
+    max_in_flight: usize,
+}
+
+impl TcpCoAPServer {
+    /// Creates a server listening on the address with the default parameters.
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<TcpCoAPServer> {
+        Self::bind_with(addr, TcpParameters::default())
+    }
+
+    pub fn bind_with<A: ToSocketAddrs>(addr: A, parameters: TcpParameters) -> Result<TcpCoAPServer> {
+        Ok(TcpCoAPServer {
+            listener: TcpListener::bind(addr)?,
+            parameters,
+            peers: TcpPeers::default(),
+            max_connections: 0,
+            idle_timeout: None,
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Returns a handle to the connection table.
+    pub fn peers(&self) -> TcpPeers {
+        self.peers.clone()
+    }
+
+    /// Set how many connections are served at once, 0 for no limit. Connections beyond the
+    /// limit are aborted right away.
+    pub fn set_max_connections(&mut self, max_connections: usize) {
+        self.max_connections = max_connections;
+    }
+
+    /// Set how long a connection may go without requests or responses before it is released.
+    pub fn set_idle_timeout(&mut self, idle_timeout: Option<Duration>) {
+        self.idle_timeout = idle_timeout;
+    }
+
+    /// Set how many requests to a client may await their response at once; further requests
+    /// are queued.
+    pub fn set_max_in_flight(&mut self, max_in_flight: usize) {
+        self.max_in_flight = max_in_flight.max(1);
+    }
+
+    /// Accepts connections and answers their requests with the handler, until accepting fails.
+    pub fn run<F>(&self, handler: F) -> Result<()>
+    where
+        F: Fn(CoAPRequest) -> Option<CoAPResponse> + Send + Sync + 'static,
+    {
+        let handler = Arc::new(handler);
+        loop {
+            let (stream, peer) = self.listener.accept()?;
+            let mut connection = match TcpConnection::new(stream, self.parameters) {
+                Ok(connection) => connection,
+                Err(e) => {
+                    warn!("failed to set up the connection from {}: {}", peer, e);
+                    continue;
+                }
+            };
+            if self.max_connections > 0 && self.peers.len() >= self.max_connections {
+                warn!("refusing the connection from {}: too many connections", peer);
+                let _ = connection.abort("too many connections");
+                continue;
+            }
+
+            let (tx, rx) = mpsc::channel();
+            let now = Instant::now();
+            self.peers.inner.lock().unwrap().insert(peer, Connection {
+                commands: tx,
+                info: ConnectionInfo { peer, connected_at: now, last_active: now, in_flight: 0, queued: 0 },
+            });
+            let peers = self.peers.clone();
+            let handler = handler.clone();
+            let limits = (self.idle_timeout, self.max_in_flight);
+            thread::spawn(move || {
+                if let Err(e) = serve(connection, &peers, peer, rx, limits, &*handler) {
+                    debug!("connection from {} ended: {}", peer, e);
+                }
+                peers.inner.lock().unwrap().remove(&peer);
+            });
+        }
+    }
+}
+
+fn serve<F>(
+    mut connection: TcpConnection,
+    peers: &TcpPeers,
+    peer: SocketAddr,
+    commands: mpsc::Receiver<Command>,
+    (idle_timeout, max_in_flight): (Option<Duration>, usize),
+    handler: &F,
+) -> Result<()>
+where
+    F: Fn(CoAPRequest) -> Option<CoAPResponse>,
+{
+    let timeout = connection.parameters().timeout;
+    let mut queued: VecDeque<(CoAPRequest, mpsc::Sender<Result<CoAPResponse>>)> = VecDeque::new();
+    let mut outstanding: Vec<(Vec<u8>, Instant, mpsc::Sender<Result<CoAPResponse>>)> = Vec::new();
+    let mut last_active = Instant::now();
+    connection.set_read_timeout(Some(SERVER_POLL_INTERVAL))?;
+    let result = loop {
+        let mut closed = false;
+        while let Ok(command) = commands.try_recv() {
+            match command {
+                Command::Request(request, reply) => queued.push_back((*request, reply)),
+                Command::Close => closed = true,
+            }
+        }
+        if closed || idle_timeout.is_some_and(|idle_timeout| last_active.elapsed() >= idle_timeout) {
+            debug!("releasing the connection from {}", peer);
+            break connection.release(None, None);
+        }
+
+        while outstanding.len() < max_in_flight {
+            let (mut request, reply) = match queued.pop_front() {
+                Some(queued) => queued,
+                None => break,
+            };
+            if request.get_token().is_empty() {
+                request.set_token(fresh_token());
+            }
+            match connection.send(&request.message) {
+                Ok(()) => outstanding.push((request.get_token().clone(), Instant::now(), reply)),
+                Err(e) => {
+                    let _ = reply.send(Err(e));
+                }
+            }
+        }
+        outstanding.retain(|(_, sent_at, reply)| {
+            if sent_at.elapsed() < timeout {
+                return true;
+            }
+            let _ = reply.send(Err(Error::new(ErrorKind::TimedOut, "request timed out")));
+            false
+        });
+        peers.update(&peer, |info| {
+            info.last_active = last_active;
+            info.in_flight = outstanding.len();
+            info.queued = queued.len();
+        });
+
+        if let Err(e) = connection.keepalive() {
+            break Err(e);
+        }
+        let message = match connection.receive() {
+            Ok(message) => message,
+            Err(ref e) if is_timeout(e) => continue,
+            Err(e) => break Err(e),
+        };
+        last_active = Instant::now();
+        match message.header.code {
+            MessageClass::Request(_) => {
+                let mut request = CoAPRequest::from_packet(message, &peer);
+                request.context.transport = Transport::Tcp;
+                if let Some(response) = handler(request) {
+                    if let Err(e) = connection.send(&response.message) {
+                        break Err(e);
+                    }
+                }
+            }
+            MessageClass::Response(_) => {
+                match outstanding.iter().position(|(token, _, _)| token == message.get_token()) {
+                    Some(idx) => {
+                        let (_, _, reply) = outstanding.remove(idx);
+                        let _ = reply.send(Ok(CoAPResponse::received(message)));
+                    }
+                    None => debug!("dropping unexpected response from {}", peer),
+                }
+            }
+            _ => (),
+        }
+    };
+    let pending = outstanding.into_iter().map(|(_, _, reply)| reply);
+    for reply in pending.chain(queued.into_iter().map(|(_, reply)| reply)) {
+        let _ = reply.send(Err(Error::new(ErrorKind::NotConnected, "the connection was closed")));
+    }
+    result
+}
+
+fn is_timeout(error: &Error) -> bool {
+    error.kind() == ErrorKind::WouldBlock || error.kind() == ErrorKind::TimedOut
+}
+
+fn is_disconnect(error: &Error) -> bool {
+    matches!(
+        error.kind(),
+        ErrorKind::ConnectionAborted
+            | ErrorKind::ConnectionReset
+            | ErrorKind::BrokenPipe
+            | ErrorKind::NotConnected
+            | ErrorKind::UnexpectedEof
+    )
+}
+
+/// Resolves the Alternative-Address of a Release, a URI such as `coap+tcp://host:port`.
+fn resolve_alternative_address(address: &str) -> Result<SocketAddr> {
+    let invalid = || Error::new(ErrorKind::InvalidData, format!("invalid alternative address {}", address));
+    let url = Url::parse(address).map_err(|_| invalid())?;
+    let host = match url.host() {
+        Some(url::Host::Domain(domain)) => domain.to_string(),
+        Some(url::Host::Ipv4(ip)) => ip.to_string(),
+        Some(url::Host::Ipv6(ip)) => ip.to_string(),
+        None => return Err(invalid()),
+    };
+    let port = url.port().unwrap_or(5683);
+    (host.as_str(), port).to_socket_addrs()?.next().ok_or_else(invalid)
+}
+
+fn signal(signal_type: SignalingType) -> Packet {
+    let mut packet = Packet::new();
+    packet.header.code = MessageClass::Signaling(signal_type);
+    packet
+}
+
+fn fresh_token() -> Vec<u8> {
+    static COUNTER: AtomicU32 = AtomicU32::new(0);
+    (rng::next_u32() ^ COUNTER.fetch_add(1, Ordering::Relaxed).rotate_left(16))
+        .to_be_bytes()
+        .to_vec()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn reply(connection: &mut TcpConnection, request: &Packet, payload: &[u8]) {
+        let mut response = Packet::new();
+        response.header.code = MessageClass::Response(super::super::Status::Content);
+        response.set_token(request.get_token().clone());
+        response.payload = payload.to_vec();
+        connection.send(&response).unwrap();
+    }
+
+    #[test]
+    fn test_signaling() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let parameters = TcpParameters::default()
+                .with_max_message_size(4096)
+                .with_block_wise_transfer(true);
+            let mut connection = TcpConnection::new(stream, parameters).unwrap();
+
+            let request = connection.receive().unwrap();
+            // the client answers a Ping while it waits for the response
+            connection.ping().unwrap();
+            reply(&mut connection, &request, b"first");
+
+            // the client's Ping is answered while waiting for its next request
+            connection.receive().unwrap();
+            connection
+                .release(Some("coap+tcp://[2001:db8::1]"), Some(Duration::from_secs(30)))
+                .unwrap();
+            connection.peer_capabilities()
+        });
+
+        let mut client = TcpCoAPClient::connect(addr).unwrap();
+        let mut request = CoAPRequest::new();
+        request.set_path("/first");
+        assert_eq!(client.request(&mut request).unwrap().message.payload, b"first".to_vec());
+        assert_eq!(client.peer_capabilities(), Capabilities {
+            max_message_size: 4096,
+            block_wise_transfer: true,
+        });
+        client.ping().unwrap();
+
+        let mut request = CoAPRequest::new();
+        request.set_method(Method::Post);
+        request.set_path("/second");
+        let error = client.request(&mut request).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::ConnectionAborted);
+        assert_eq!(client.termination(), Some(&Termination::Released {
+            alternative_address: Some("coap+tcp://[2001:db8::1]".to_string()),
+            hold_off: Some(Duration::from_secs(30)),
+        }));
+        assert_eq!(server.join().unwrap(), Capabilities::default());
+    }
+
+    #[test]
+    fn test_keepalive() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut connection = TcpConnection::new(stream, TcpParameters::default()).unwrap();
+            // the keep-alive Ping is answered and the request then arrives
+            let request = connection.receive().unwrap();
+            reply(&mut connection, &request, b"");
+        });
+
+        let parameters = TcpParameters::default().with_keepalive(Some(Duration::from_millis(10)));
+        let mut client = TcpCoAPClient::connect_with(addr, parameters).unwrap();
+        thread::sleep(Duration::from_millis(20));
+        client.keepalive().unwrap();
+        let mut request = CoAPRequest::new();
+        client.request(&mut request).unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_replay() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let backup = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let backup_addr = backup.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            // the connection drops before the GET is answered, and it is answered once replayed
+            let (stream, _) = listener.accept().unwrap();
+            let mut connection = TcpConnection::new(stream, TcpParameters::default()).unwrap();
+            connection.receive().unwrap();
+            drop(connection);
+            let (stream, _) = listener.accept().unwrap();
+            let mut connection = TcpConnection::new(stream, TcpParameters::default()).unwrap();
+            let request = connection.receive().unwrap();
+            reply(&mut connection, &request, b"replayed");
+
+            // the POST is not replayed, and the next request reconnects
+            connection.receive().unwrap();
+            drop(connection);
+            let (stream, _) = listener.accept().unwrap();
+            let mut connection = TcpConnection::new(stream, TcpParameters::default()).unwrap();
+            connection.receive().unwrap();
+            let address = format!("coap+tcp://{}", backup_addr);
+            connection.release(Some(&address), None).unwrap();
+
+            // the GET follows the Release to the alternative address
+            let (stream, _) = backup.accept().unwrap();
+            let mut connection = TcpConnection::new(stream, TcpParameters::default()).unwrap();
+            let request = connection.receive().unwrap();
+            reply(&mut connection, &request, b"backup");
+        });
+
+        let mut client = TcpCoAPClient::connect(addr).unwrap();
+        let mut request = CoAPRequest::new();
+        request.set_path("/replayed");
+        assert_eq!(client.request(&mut request).unwrap().message.payload, b"replayed".to_vec());
+
+        let mut request = CoAPRequest::new();
+        request.set_method(Method::Post);
+        assert!(is_disconnect(&client.request(&mut request).unwrap_err()));
+
+        let mut request = CoAPRequest::new();
+        request.set_path("/moved");
+        assert_eq!(client.request(&mut request).unwrap().message.payload, b"backup".to_vec());
+        assert_eq!(client.peer_addr(), backup_addr);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_unsolicited_requests() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut connection = TcpConnection::new(stream, TcpParameters::default()).unwrap();
+            let request = connection.receive().unwrap();
+
+            // ask the client something before answering it
+            let mut push = CoAPRequest::new();
+            push.set_path("/config");
+            push.set_token(vec![0x42]);
+            connection.send(&push.message).unwrap();
+            let answer = connection.receive().unwrap();
+            reply(&mut connection, &request, b"");
+            answer
+        });
+
+        let mut client = TcpCoAPClient::connect(addr).unwrap();
+        client.set_unsolicited_handler(|message: &Packet, _: &SocketAddr| {
+            let mut answer = Packet::new();
+            answer.header.code = MessageClass::Response(super::super::Status::Changed);
+            answer.set_token(message.get_token().clone());
+            UnsolicitedReply::Reply(answer)
+        });
+        let mut request = CoAPRequest::new();
+        client.request(&mut request).unwrap();
+        let answer = server.join().unwrap();
+        assert_eq!(answer.get_token(), &vec![0x42]);
+        assert_eq!(answer.header.code, MessageClass::Response(super::super::Status::Changed));
+    }
+
+    #[test]
+    fn test_server_initiated_requests() {
+        let server = TcpCoAPServer::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        let peers = server.peers();
+        thread::spawn(move || {
+            server.run(|request: CoAPRequest| {
+                assert_eq!(request.context.transport, Transport::Tcp);
+                let mut response = request.response?;
+                response.message.payload = request.message.payload;
+                Some(response)
+            })
+        });
+
+        let mut client = TcpCoAPClient::connect(addr).unwrap();
+        client.set_unsolicited_handler(|message: &Packet, _: &SocketAddr| {
+            let mut answer = Packet::new();
+            answer.header.code = MessageClass::Response(super::super::Status::Changed);
+            answer.set_token(message.get_token().clone());
+            answer.payload = message.payload.clone();
+            UnsolicitedReply::Reply(answer)
+        });
+        let mut request = CoAPRequest::new();
+        request.set_payload(b"echo".to_vec());
+        assert_eq!(client.request(&mut request).unwrap().message.payload, b"echo".to_vec());
+        assert_eq!(client.poll(Duration::from_millis(10)).unwrap(), false);
+
+        let device = peers.list()[0];
+        let push = thread::spawn(move || {
+            let mut command = CoAPRequest::new();
+            command.set_method(Method::Put);
+            command.set_path("/led");
+            command.set_payload(b"on".to_vec());
+            peers.request(&device, command)
+        });
+        assert!(client.poll(Duration::from_secs(5)).unwrap());
+        let response = push.join().unwrap().unwrap();
+        assert_eq!(*response.get_status(), super::super::Status::Changed);
+        assert_eq!(response.message.payload, b"on".to_vec());
+
+        let unknown: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let error = TcpPeers::default().request(&unknown, CoAPRequest::new()).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::NotConnected);
+    }
+
+    #[test]
+    fn test_connection_table() {
+        let mut server = TcpCoAPServer::bind("127.0.0.1:0").unwrap();
+        server.set_max_connections(1);
+        server.set_idle_timeout(Some(Duration::from_millis(500)));
+        let addr = server.local_addr().unwrap();
+        let peers = server.peers();
+        thread::spawn(move || server.run(|request: CoAPRequest| request.response));
+
+        let parameters = TcpParameters::default().with_replay_policy(ReplayPolicy::Never);
+        let mut first = TcpCoAPClient::connect_with(addr, parameters).unwrap();
+        let mut request = CoAPRequest::new();
+        request.set_method(Method::Post);
+        first.request(&mut request).unwrap();
+        let connections = peers.connections();
+        assert_eq!(connections.len(), 1);
+        assert_eq!(connections[0].in_flight, 0);
+        assert_eq!(connections[0].queued, 0);
+        assert!(connections[0].connected_at <= connections[0].last_active);
+
+        let mut second = TcpCoAPClient::connect_with(addr, parameters).unwrap();
+        assert!(second.request(&mut request).is_err());
+        match second.termination() {
+            Some(Termination::Aborted { diagnostic, .. }) => {
+                assert_eq!(diagnostic, "too many connections")
+            }
+            other => panic!("unexpected termination {:?}", other),
+        }
+        assert_eq!(peers.len(), 1);
+
+        assert!(peers.close(&connections[0].peer));
+        assert!(first.poll(Duration::from_secs(5)).is_err());
+        assert!(matches!(first.termination(), Some(Termination::Released { .. })));
+        thread::sleep(Duration::from_millis(100));
+        assert!(peers.is_empty());
+        assert!(!peers.close(&connections[0].peer));
+
+        let mut idle = TcpCoAPClient::connect_with(addr, parameters).unwrap();
+        idle.request(&mut request).unwrap();
+        assert!(idle.poll(Duration::from_secs(5)).is_err());
+        assert!(matches!(idle.termination(), Some(Termination::Released { .. })));
+    }
+}
@@ -0,0 +1,152 @@
+//! Carrying a trace ID across the CoAP hop in an option of the request.
+//!
+//! A client set up with `CoAPClient::set_tracing` adds a trace ID to every request that does
+//! not carry one, taken from the application's tracing context or generated. A server set
+//! up with `Server::set_tracing` extracts it into `RequestContext::trace_id`, from where the
+//! handler can attach it to its own spans, and writes it to the access log.
+
+use log::info;
+use std::fmt::Write;
+use std::net::SocketAddr;
+
+use super::message::packet::Packet;
+use super::message::request::CoAPRequest;
+use super::rng;
+
+/// The option carrying trace IDs by default: an experimental number (RFC 7252 §12.2) that
+/// is elective, safe to forward and not part of the cache key, so proxies relay it and
+/// caches ignore it.
+pub const DEFAULT_TRACE_OPTION: usize = 65020;
+
+/// The log target of the access log.
+pub const ACCESS_LOG_TARGET: &str = "coap::access";
+
+const TRACE_ID_LEN: usize = 8;
+
+/// Where trace IDs travel and where a client takes them from.
+pub struct Tracing {
+    option: usize,
+    source: Option<Box<dyn Fn() -> Option<Vec<u8>> + Send + Sync>>,
+}
+
+impl Default for Tracing {
+    fn default() -> Tracing {
+        Tracing::new()
+    }
+}
+
+impl Tracing {
+    /// Carries trace IDs in `DEFAULT_TRACE_OPTION`, generating a random ID per request.
+    pub fn new() -> Tracing {
+        Tracing {
+            option: DEFAULT_TRACE_OPTION,
+            source: None,
+        }
+    }
+
+    /// Carries trace IDs in another option, e.g. one agreed on with other implementations.
+    pub fn with_option(mut self, number: usize) -> Tracing {
+        self.option = number;
+        self
+    }
+
+    /// Takes the trace ID of each request from the source, e.g. the ID of the current span.
+    /// When it returns `None` a random ID is generated.
+    pub fn with_source<F: Fn() -> Option<Vec<u8>> + Send + Sync + 'static>(mut self, source: F) -> Tracing {
+        self.source = Some(Box::new(source));
+        self
+    }
+
+    pub fn option(&self) -> usize {
+        self.option
+    }
+
+    /// The trace ID a message carries.
+    pub fn extract(&self, packet: &Packet) -> Option<Vec<u8>> {
+        packet.get_option_values(self.option).and_then(|values| values.front().cloned())
+    }
+
+    /// Adds a trace ID to a message that carries none.
+    pub(crate) fn inject(&self, packet: &mut Packet) {
+        if self.extract(packet).is_some() {
+            return;
+        }
+        let trace_id = self.source.as_ref().and_then(|source| source()).unwrap_or_else(|| {
+            let mut trace_id = vec![0; TRACE_ID_LEN];
+            rng::fill_bytes(&mut trace_id);
+            trace_id
+        });
+        packet.add_option_value(self.option, trace_id);
+    }
+}
+
+/// Formats a trace ID as lowercase hex, as tracing systems usually show them.
+pub fn format_trace_id(trace_id: &[u8]) -> String {
+    let mut text = String::with_capacity(trace_id.len() * 2);
+    for byte in trace_id {
+        write!(text, "{:02x}", byte).unwrap();
+    }
+    text
+}
+
+/// Logs an answered request: the peer, method, path, response code and trace ID.
+pub(crate) fn log_access(peer: &SocketAddr, request: &CoAPRequest, code: &str) {
+    let trace_id = request.context.trace_id.as_ref().map_or("-".to_string(), |id| format_trace_id(id));
+    info!(
+        target: ACCESS_LOG_TARGET,
+        "{} {:?} /{} {} trace={}",
+        peer,
+        request.get_method(),
+        request.get_path(),
+        code,
+        trace_id
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_trace_propagation() {
+        let (port_tx, port_rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new().unwrap().block_on(async move {
+                let mut server = Server::new("127.0.0.1:0").unwrap();
+                server.set_tracing(Tracing::new());
+                port_tx.send(server.socket_addr().unwrap().port()).unwrap();
+                server.run(|req: CoAPRequest| async move {
+                    let trace_id = req.context.trace_id.clone().unwrap_or_default();
+                    let mut response = req.response?;
+                    response.message.payload = trace_id;
+                    Some(response)
+                }).await.unwrap();
+            })
+        });
+        let server_address = format!("127.0.0.1:{}", port_rx.recv().unwrap());
+
+        let mut client = CoAPClient::new(&server_address).unwrap();
+        client.set_tracing(Some(Tracing::new().with_source(|| Some(vec![0xAB, 0xCD]))));
+        let mut request = CoAPRequest::new();
+        request.set_path("/orders");
+        assert_eq!(client.request(&mut request).unwrap().message.payload, vec![0xAB, 0xCD]);
+
+        // an ID the request carries already is kept
+        let mut request = CoAPRequest::new();
+        request.message.add_option_value(DEFAULT_TRACE_OPTION, vec![0x01]);
+        assert_eq!(client.request(&mut request).unwrap().message.payload, vec![0x01]);
+
+        client.set_tracing(Some(Tracing::new()));
+        let mut request = CoAPRequest::new();
+        assert_eq!(client.request(&mut request).unwrap().message.payload.len(), TRACE_ID_LEN);
+
+        client.set_tracing(None);
+        let mut request = CoAPRequest::new();
+        assert!(client.request(&mut request).unwrap().message.payload.is_empty());
+
+        assert_eq!(format_trace_id(&[0x0A, 0xBC]), "0abc");
+        assert!(cache::is_no_cache_key(DEFAULT_TRACE_OPTION));
+    }
+}
@@ -0,0 +1,212 @@
+//! Serving streamed response bodies block by block (RFC 7959 §2.4).
+
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use futures::StreamExt;
+
+use super::budget::{BudgetExceeded, BudgetResource, MemoryBudget};
+use super::message::packet::{BlockValue, CoAPOption, Packet};
+use super::message::request::CoAPRequest;
+use super::message::response::{BodyStream, CoAPResponse, Status};
+
+const DEFAULT_BLOCK_SIZE: usize = 1024;
+// the transfers kept at most when the budget does not limit the block contexts
+const DEFAULT_MAX_TRANSFERS: usize = 256;
+// a transfer the client stopped asking blocks of is dropped after this
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// The streamed bodies being transferred, by peer and request URI.
+#[derive(Default)]
+pub(crate) struct BodyTransfers {
+    transfers: HashMap<(SocketAddr, String), BodyTransfer>,
+    budget: MemoryBudget,
+}
+
+struct BodyTransfer {
+    stream: BodyStream,
+    // the code and options of every block
+    template: Packet,
+    // the body from `offset` on, pulled from the stream but not yet passed by the client
+    buffered: Vec<u8>,
+    offset: usize,
+    ended: bool,
+    last_active: Instant,
+}
+
+impl BodyTransfers {
+    /// Counts the transfers kept against the block contexts of a budget.
+    pub fn set_memory_budget(&mut self, budget: MemoryBudget) {
+        let kept = self.transfers.len();
+        self.budget.release(BudgetResource::BlockContexts, kept);
+        // the transfers already kept are counted even beyond the limit
+        let _ = budget.reserve(BudgetResource::BlockContexts, kept);
+        self.budget = budget;
+    }
+
+    /// Answers a request for a later block of a transfer in progress.
+    pub async fn continue_transfer(&mut self, request: &CoAPRequest) -> Option<CoAPResponse> {
+        let block = request.message.get_block2().filter(|block| block.num > 0)?;
+        let key = (request.source?, Self::uri(request));
+        let transfer = self.transfers.get_mut(&key)?;
+        let mut response = request.response.clone()?;
+        response.message.payload.clear();
+        response.message.header.code = transfer.template.header.code.clone();
+        response.message.merge_options(&transfer.template);
+        let ended = transfer.serve(block, &mut response).await;
+        if ended {
+            self.transfers.remove(&key);
+            self.budget.release(BudgetResource::BlockContexts, 1);
+        }
+        Some(response)
+    }
+
+    /// Turns a response with a body stream into the first block requested, keeping the
+    /// transfer if more blocks follow. A transfer beyond the budget, or beyond
+    /// `DEFAULT_MAX_TRANSFERS` without a limit on the block contexts, is answered with 5.03.
+    pub async fn start_transfer(&mut self, request: &CoAPRequest, response: &mut CoAPResponse, stream: BodyStream) {
+        let block = request
+            .message
+            .get_block2()
+            .unwrap_or_else(|| BlockValue::new(0, false, DEFAULT_BLOCK_SIZE));
+        let mut template = response.message.clone();
+        template.payload.clear();
+        template.clear_option(CoAPOption::Block2);
+        let mut transfer = BodyTransfer {
+            stream,
+            template,
+            buffered: Vec::new(),
+            offset: 0,
+            ended: false,
+            last_active: Instant::now(),
+        };
+        let ended = transfer.serve(block, response).await;
+        let source = match request.source {
+            Some(source) if !ended => source,
+            _ => return,
+        };
+        let kept = self.transfers.len();
+        self.transfers.retain(|_, transfer| transfer.last_active.elapsed() < TRANSFER_TIMEOUT);
+        self.budget.release(BudgetResource::BlockContexts, kept - self.transfers.len());
+
+        let key = (source, Self::uri(request));
+        if !self.transfers.contains_key(&key) {
+            let reserved = match self.budget.limit(BudgetResource::BlockContexts) {
+                None if self.transfers.len() >= DEFAULT_MAX_TRANSFERS => Err(BudgetExceeded {
+                    resource: BudgetResource::BlockContexts,
+                    limit: DEFAULT_MAX_TRANSFERS,
+                }),
+                _ => self.budget.reserve(BudgetResource::BlockContexts, 1),
+            };
+            if let Err(e) = reserved {
+                response.set_error(Status::ServiceUnavailable, &e.to_string());
+                response.message.clear_option(CoAPOption::Block2);
+                return;
+            }
+        }
+        self.transfers.insert(key, transfer);
+    }
+
+    fn uri(request: &CoAPRequest) -> String {
+        let queries = request.message.get_option(CoAPOption::UriQuery);
+        let query: Vec<String> = queries
+            .iter()
+            .flat_map(|list| list.iter())
+            .map(|query| String::from_utf8_lossy(query).to_string())
+            .collect();
+        format!("{}?{}", request.get_path(), query.join("&"))
+    }
+}
+
+impl Drop for BodyTransfers {
+    fn drop(&mut self) {
+        self.budget.release(BudgetResource::BlockContexts, self.transfers.len());
+    }
+}
+
+impl BodyTransfer {
+    /// Fills the response with the block, returning whether the transfer is over.
+    async fn serve(&mut self, block: BlockValue, response: &mut CoAPResponse) -> bool {
+        self.last_active = Instant::now();
+        match self.read(block).await {
+            Ok(Some((payload, more))) => {
+                response.message.payload = payload;
+                response.message.set_block2(BlockValue { more, ..block });
+                !more
+            }
+            Ok(None) => {
+                response.set_error(Status::BadRequest, "block not available");
+                response.message.clear_option(CoAPOption::Block2);
+                true
+            }
+            Err(e) => {
+                response.set_error(Status::InternalServerError, &e.to_string());
+                response.message.clear_option(CoAPOption::Block2);
+                true
+            }
+        }
+    }
+
+    /// Reads the block and whether more follow, `None` if the block lies before the data kept
+    /// or after the end of the body.
+    async fn read(&mut self, block: BlockValue) -> io::Result<Option<(Vec<u8>, bool)>> {
+        let start = block.offset();
+        if start < self.offset {
+            return Ok(None);
+        }
+        loop {
+            // the blocks before the one requested are never asked for again
+            let passed = (start - self.offset).min(self.buffered.len());
+            self.buffered.drain(..passed);
+            self.offset += passed;
+            // one byte past the block tells whether more follow
+            if self.ended || self.offset + self.buffered.len() > start + block.size() {
+                break;
+            }
+            match self.stream.next().await {
+                Some(chunk) => self.buffered.extend(chunk?),
+                None => self.ended = true,
+            }
+        }
+        if self.offset < start || (start > 0 && self.buffered.is_empty()) {
+            return Ok(None);
+        }
+        let len = block.size().min(self.buffered.len());
+        Ok(Some((self.buffered[..len].to_vec(), self.buffered.len() > len)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::message::header::MessageType;
+    use super::super::message::IsMessage;
+    use futures::stream;
+
+    fn start(transfers: &mut BodyTransfers, port: u16) -> Status {
+        let mut packet = Packet::new();
+        packet.header.set_type(MessageType::Confirmable);
+        let mut request = CoAPRequest::from_packet(packet, &SocketAddr::from(([127, 0, 0, 1], port)));
+        request.set_path("/fw");
+        let mut response = request.response.clone().unwrap();
+        let body = stream::iter(vec![Ok(vec![0; 2 * DEFAULT_BLOCK_SIZE])]);
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(transfers.start_transfer(&request, &mut response, Box::pin(body)));
+        response.get_status().clone()
+    }
+
+    #[test]
+    fn test_transfers_capped_without_budget() {
+        let mut transfers = BodyTransfers::default();
+        for port in 0..DEFAULT_MAX_TRANSFERS as u16 {
+            assert_eq!(start(&mut transfers, 1000 + port), Status::Content);
+        }
+        assert_eq!(start(&mut transfers, 999), Status::ServiceUnavailable);
+
+        // a limit on the block contexts takes the place of the default cap
+        transfers.set_memory_budget(MemoryBudget::new().with_max_block_contexts(DEFAULT_MAX_TRANSFERS + 1));
+        assert_eq!(start(&mut transfers, 999), Status::Content);
+        assert_eq!(start(&mut transfers, 998), Status::ServiceUnavailable);
+    }
+}
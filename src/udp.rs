@@ -17,9 +17,15 @@ impl UDPWrapper {
     pub fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
         self.0.recv_from(buf)
     }
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.0.local_addr()
+    }
     pub fn set_read_timeout(&self, dur: Option<Duration>) -> Result<()> {
         self.0.set_read_timeout(dur)
     }
+    pub fn peek(&self, buf: &mut [u8]) -> Result<usize> {
+        self.0.peek(buf)
+    }
     pub fn send_to<A: ToSocketAddrs>(&self, buf: &[u8], addr: A) -> Result<usize> {
         self.0.send_to(buf, addr)
     }
@@ -0,0 +1,133 @@
+//! Normalizing and comparing CoAP URIs (RFC 7252 §6.3).
+//!
+//! Two URIs are equivalent when they only differ in the case of the scheme and host, in
+//! whether the default port is given, in percent-encoding of unreserved characters or the
+//! case of percent-encodings, in dot segments, or in an empty path standing for `/`. The
+//! cache key and the forward proxy compare URIs in their normalized form, so that such URIs
+//! share cache entries and upstream observations.
+
+use std::io::Result;
+
+use super::client::CoAPClient;
+
+/// Normalizes a URI, e.g. `COAP://Example.COM:5683/a/./b/%7euser` to
+/// `coap://example.com/a/b/~user`. A URI without a scheme is taken as a coap URI.
+pub fn normalize(uri: &str) -> Result<String> {
+    let url = CoAPClient::parse_url(uri, "coap")?;
+    let (host, port, path) = CoAPClient::split_url(&url);
+
+    let mut normalized = format!("{}://", url.scheme());
+    if host.contains(':') {
+        normalized.push_str(&format!("[{}]", host.to_ascii_lowercase()));
+    } else {
+        normalized.push_str(&normalize_percent(&host).to_ascii_lowercase());
+    }
+    if port != default_port(url.scheme()) {
+        normalized.push_str(&format!(":{}", port));
+    }
+    normalized.push_str(&normalize_path(&path));
+    if let Some(query) = url.query().filter(|query| !query.is_empty()) {
+        normalized.push('?');
+        normalized.push_str(&normalize_percent(query));
+    }
+    Ok(normalized)
+}
+
+/// Whether two URIs identify the same resource. Invalid URIs are equivalent to none.
+pub fn equivalent(a: &str, b: &str) -> bool {
+    match (normalize(a), normalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Normalizes the path of a URI: percent-encodings are normalized, dot segments removed
+/// (RFC 3986 §5.2.4) and an empty path becomes `/`.
+pub fn normalize_path(path: &str) -> String {
+    let path = normalize_percent(path);
+    let mut segments: Vec<&str> = Vec::new();
+    let mut trailing = false;
+    for segment in path.split('/').skip(if path.starts_with('/') { 1 } else { 0 }) {
+        trailing = false;
+        match segment {
+            "." => trailing = true,
+            ".." => {
+                segments.pop();
+                trailing = true;
+            }
+            _ => segments.push(segment),
+        }
+    }
+    // a path ending in a dot segment names a directory
+    if trailing {
+        segments.push("");
+    }
+    format!("/{}", segments.join("/"))
+}
+
+/// Decodes the percent-encoded unreserved characters of a URI component and upper-cases
+/// the remaining percent-encodings (RFC 3986 §6.2.2).
+pub fn normalize_percent(component: &str) -> String {
+    let bytes = component.as_bytes();
+    let mut normalized = String::with_capacity(component.len());
+    let mut idx = 0;
+    while idx < bytes.len() {
+        let decoded = if bytes[idx] == b'%' && idx + 2 < bytes.len() {
+            std::str::from_utf8(&bytes[idx + 1..idx + 3]).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        } else {
+            None
+        };
+        match decoded {
+            Some(byte) if is_unreserved(byte) => {
+                normalized.push(byte as char);
+                idx += 3;
+            }
+            Some(byte) => {
+                normalized.push_str(&format!("%{:02X}", byte));
+                idx += 3;
+            }
+            None => {
+                let c = component[idx..].chars().next().unwrap();
+                normalized.push(c);
+                idx += c.len_utf8();
+            }
+        }
+    }
+    normalized
+}
+
+fn is_unreserved(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"-._~".contains(&byte)
+}
+
+fn default_port(scheme: &str) -> u16 {
+    match scheme {
+        "coaps" | "coaps+tcp" => 5684,
+        _ => 5683,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("COAP://Example.COM:5683/a/./b/%7euser").unwrap(), "coap://example.com/a/b/~user");
+        assert_eq!(normalize("coap://example.com").unwrap(), "coap://example.com/");
+        assert_eq!(normalize("coaps://example.com:5683/").unwrap(), "coaps://example.com:5683/");
+        assert_eq!(normalize("coaps://example.com:5684/x?a=%2f&b=%41").unwrap(), "coaps://example.com/x?a=%2F&b=A");
+        assert_eq!(normalize("coap://[FE80::1]/").unwrap(), "coap://[fe80::1]/");
+        assert_eq!(normalize("example.com/a/b/../c").unwrap(), "coap://example.com/a/c");
+        assert!(normalize("coap://example.com/#top").is_err());
+
+        assert_eq!(normalize_path("/a/b/.."), "/a/");
+        assert_eq!(normalize_path(""), "/");
+        assert_eq!(normalize_percent("%e2%82%ac%"), "%E2%82%AC%");
+
+        assert!(equivalent("coap://example.com:5683/~sensor/temp", "coap://EXAMPLE.com/%7Esensor/temp"));
+        assert!(!equivalent("coap://example.com/temp", "coaps://example.com/temp"));
+        assert!(!equivalent("coap://example.com/temp?a&b", "coap://example.com/temp?b&a"));
+        assert!(!equivalent("coap://example.com/", "http://example.com/"));
+    }
+}